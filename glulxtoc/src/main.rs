@@ -9,21 +9,20 @@ https://github.com/curiousdannii/if-decompiler
 
 */
 
-use std::collections::BTreeMap;
 use std::env;
+use std::error::Error;
 use std::fs::File;
 use std::io;
-use std::io::{BufReader, Cursor, Write};
+use std::io::{BufReader, Write};
 use std::path::PathBuf;
 use std::time::Instant;
 use std::thread;
 
-use bytes::Buf;
-use quick_xml;
 use structopt::StructOpt;
 
-use if_decompiler;
-use if_decompiler::DebugFunctionData;
+use if_decompiler::blorb::{self, Blorb, ChunkContents, ResourceUsage};
+use if_decompiler::glulx::GlulxHeader;
+use if_decompiler::inform_debug::parse_debug_file;
 
 mod output;
 
@@ -50,10 +49,26 @@ struct Cli {
     #[structopt(short, long)]
     disassemble: bool,
 
+    /// With --recursive-descent, also follow anything in RAM or constant operands which looks like a function address
+    #[structopt(long)]
+    guess_functions: bool,
+
+    /// Output #line directives pointing to the Inform source code (requires debug data with sequence points)
+    #[structopt(long)]
+    line_directives: bool,
+
+    /// Find functions by following calls from the start function, instead of scanning through the ROM
+    #[structopt(long)]
+    recursive_descent: bool,
+
     /// Safe function overrides
     #[structopt(long, use_delimiter = true)]
     safe_function_overrides: Option<Vec<u32>>,
 
+    /// Stop disassembling at the first string, instead of continuing on until RAM
+    #[structopt(long)]
+    stop_on_string: bool,
+
     /// Unsafe function overrides
     #[structopt(long, use_delimiter = true)]
     unsafe_function_overrides: Option<Vec<u32>>,
@@ -96,56 +111,84 @@ fn run(args: Cli) -> Result<(), Box<std::io::Error>> {
     println!("Starting to decompile {:?}", storyfile_path);
     let start = Instant::now();
     let data = std::fs::read(storyfile_path)?;
-    let data_length = data.len();
-
-    // Start parsing the file
-    fn get_file_header(data: &[u8]) -> (u32, u32) {
-        let mut cursor = Cursor::new(data);
-        let magic = cursor.get_u32();
-        cursor.set_position(8);
-        let iff_type = cursor.get_u32();
-        (magic, iff_type)
-    }
-    let (magic, iff_type) = get_file_header(&data);
 
     // Check for a blorb
-    let image = if magic == 0x464F524D /* FORM */ && iff_type == 0x49465253 /* IFRS */ {
-        parse_blorb(&data)
+    let (image, blorb_debug_data, resources) = if data.starts_with(b"FORM") {
+        let blorb = Blorb::new(&data).map_err(invalid_data)?;
+        let image = match blorb.executable() {
+            Some(chunk) if chunk.chunk_type == blorb::GLUL => chunk.data,
+            _ => return Err(invalid_data("Blorb file does not have a GLUL chunk")),
+        };
+        // Keep the other resources so they can be embedded in the compiled game
+        let resources = if blorb.resources.iter().any(|resource| resource.usage != ResourceUsage::Executable) {
+            Some(blorb.write(|chunk| !matches!(chunk.contents, ChunkContents::DebugData(_) | ChunkContents::Executable(_))))
+        }
+        else {
+            None
+        };
+        (image, blorb.debug_data(), resources)
     }
     // A bare Glulx file
-    else if magic == 0x476C756C /* Glul */ {
-        Some(&*data)
+    else if data.starts_with(b"Glul") {
+        (&*data, None, None)
     }
     else {
-        panic!("Unrecognised file format");
+        return Err(invalid_data("Unrecognised file format"));
     };
 
-    // Read the debug file if specified
-    let debug_function_data = match args.debug_file {
-        Some(path) => {
+    // Check the header before going any further
+    let header = GlulxHeader::new(image).map_err(invalid_data)?;
+    println!("{}", header);
+    header.validate(image.len() as u32).map_err(invalid_data)?;
+    // A bad checksum usually just means the storyfile has been patched, so only warn about it
+    if let Err(err) = header.verify_checksum(image) {
+        println!("Warning: {}", err);
+    }
+
+    // Read the debug file if specified, or else the debug data embedded in the blorb
+    let debug_data = match (args.debug_file, blorb_debug_data) {
+        (Some(path), _) => {
             print!("Parsing the debug file...");
             io::stdout().flush().unwrap();
             let start_parse_debug_file = Instant::now();
             let file = File::open(path)?;
-            let result = Some(parse_debug_file(BufReader::new(file)).expect("Error parsing XML"));
+            let result = Some(parse_debug_file(BufReader::new(file)).map_err(invalid_data)?);
             println!(" completed in {:?}", start_parse_debug_file.elapsed());
             result
         },
-        None => None,
+        (None, Some(debug_data)) => {
+            print!("Parsing the debug data from the blorb...");
+            io::stdout().flush().unwrap();
+            let start_parse_debug_file = Instant::now();
+            let result = Some(parse_debug_file(debug_data).map_err(invalid_data)?);
+            println!(" completed in {:?}", start_parse_debug_file.elapsed());
+            result
+        },
+        (None, None) => None,
     };
 
     // Decompile the storyfile
     print!("Disassembling the storyfile...");
     io::stdout().flush().unwrap();
     let start_disassemble = Instant::now();
-    let mut decompiler = if_decompiler::glulx::GlulxState::new(debug_function_data, args.safe_function_overrides, true, args.unsafe_function_overrides);
-    decompiler.decompile_rom(image.unwrap());
+    let mut decompiler = if_decompiler::glulx::GlulxState::new(debug_data.as_ref().map(|debug_data| debug_data.function_data()), args.guess_functions, args.recursive_descent, args.safe_function_overrides, args.stop_on_string, args.unsafe_function_overrides);
+    if let Err(err) = decompiler.decompile_rom(image) {
+        println!();
+        return Err(invalid_data(err));
+    }
     let duration = start_disassemble.elapsed();
     println!(" completed in {:?}", duration);
+    for warning in &decompiler.warnings {
+        println!("Warning: {}", warning);
+    }
 
     // Output the C files
-    let mut output = output::GlulxOutput::new(args.disassemble, data_length as u32, name, out_dir, decompiler);
-    output.output(&data)?;
+    if args.line_directives && debug_data.is_none() {
+        println!("Warning: #line directives can only be output when there is debug data");
+    }
+    let mut output = output::GlulxOutput::new(args.disassemble, name, out_dir, resources, debug_data.as_ref(), decompiler);
+    output.line_directives = args.line_directives;
+    output.output(image)?;
 
     let duration = start.elapsed();
     println!("Total decompilation time: {:?}", duration);
@@ -153,100 +196,6 @@ fn run(args: Cli) -> Result<(), Box<std::io::Error>> {
     Ok(())
 }
 
-// Parse a blorb file
-// TODO: parse debug data from blorb
-fn parse_blorb<'a>(data: &'a [u8]) -> Option<&'a [u8]> {
-    let length = data.len() as u64;
-    let mut cursor = Cursor::new(data);
-    cursor.set_position(12);
-    let mut glulx_chunk = None;
-    while cursor.position() < length {
-        let chunk_type = cursor.get_u32();
-        let chunk_length = cursor.get_u32();
-        let chunk_end = cursor.position()as usize + chunk_length as usize;
-        match chunk_type {
-            0x474C554C /* GLUL */ => {
-                glulx_chunk = Some(&data[cursor.position() as usize..chunk_end]);
-            },
-            _ => {},
-        }
-        cursor.set_position(chunk_end as u64);
-    }
-    if glulx_chunk.is_none() {
-        panic!("Blorb file does not have a GLUL chunk");
-    }
-    glulx_chunk
+fn invalid_data<E: Into<Box<dyn Error + Send + Sync>>>(err: E) -> Box<io::Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, err))
 }
-
-// Parse an Inform debug file
-fn parse_debug_file(str: BufReader<File>) -> quick_xml::Result<BTreeMap<u32, DebugFunctionData>> {
-    use quick_xml::events::Event;
-    let mut reader = quick_xml::Reader::from_reader(str);
-    reader.trim_text(true);
-    let mut result = BTreeMap::default();
-    let mut buf = Vec::new();
-    let mut in_routine = false;
-    let mut process_text = false;
-    let mut text = String::new();
-    let mut addr = 0;
-    let mut len = 0;
-    let mut name = String::new();
-
-    loop {
-        match reader.read_event(&mut buf) {
-            Ok(Event::Start(ref e)) => {
-                match e.name() {
-                    b"byte-count" | b"identifier" | b"value" => {
-                        process_text = true;
-                        text.clear();
-                    },
-                    b"routine" => {
-                        in_routine = true;
-                    },
-                    _ => {},
-                };
-            },
-            Ok(Event::Text(e)) => {
-                if in_routine && process_text {
-                    text.push_str(&e.unescape_and_decode(&reader)?);
-                }
-            },
-            Ok(Event::End(ref e)) => {
-                match e.name() {
-                    b"byte-count" => {
-                        if in_routine {
-                            len = text.parse::<u32>().unwrap();
-                        }
-                    },
-                    b"identifier" => {
-                        if in_routine && name == "" {
-                            name = text.clone();
-                        }
-                    },
-                    b"routine" => {
-                        in_routine = false;
-                        result.insert(addr, DebugFunctionData {
-                            addr,
-                            len,
-                            name: name.clone(),
-                        });
-                        name.clear();
-                    },
-                    b"value" => {
-                        if in_routine {
-                            addr = text.parse::<u32>().unwrap();
-                        }
-                    },
-                    _ => {},
-                };
-                process_text = false;
-            },
-            Ok(Event::Eof) => break,
-            Err(e) => panic!("XML error in debug file at position {}: {:?}", reader.buffer_position(), e),
-            _ => (),
-        };
-        buf.clear();
-    };
-
-    Ok(result)
-}
\ No newline at end of file
@@ -21,6 +21,12 @@ impl GlulxOutput {
         output_path.push("image.data");
         fs::write(output_path, data)?;
 
+        // And the resources, which will be empty if the storyfile wasn't in a blorb
+        let resources: &[u8] = self.resources.as_deref().unwrap_or_default();
+        let mut output_path = self.out_dir.clone();
+        output_path.push("resources.data");
+        fs::write(output_path, resources)?;
+
         // Output the Glulx sources
         let glulx_sources = [
             ("files.c", include_str!("../upstream/glulxe/files.c")),
@@ -64,6 +70,7 @@ impl GlulxOutput {
         let replacements = [
             ["GLULXE_FILES", &glulx_sources.iter().map(|(file, _)| *file).collect::<Vec<&str>>().join(" ")],
             ["IMAGE_LENGTH_VALUE", &data.len().to_string()],
+            ["RESOURCES_LENGTH_VALUE", &resources.len().to_string()],
             ["EXENAME", &self.name],
         ];
 
@@ -21,7 +21,7 @@ impl GlulxOutput {
     pub fn output_common_instruction(&self, instruction: &Instruction, args: Vec<String>) -> String {
         let opcode = instruction.opcode;
         let null = String::from("NULL");
-        let op_a = args.get(0).unwrap_or(&null);
+        let op_a = args.first().unwrap_or(&null);
         let op_b = args.get(1).unwrap_or(&null);
         use opcodes::*;
         match opcode {
@@ -96,6 +96,8 @@ impl GlulxOutput {
             // OP_RESTORE
             // OP_SAVEUNDO
             // OP_RESTOREUNDO
+            OP_HASUNDO => runtime("OP_HASUNDO", &args),
+            OP_DISCARDUNDO => runtime("OP_DISCARDUNDO", &args),
             // OP_QUIT
             OP_LINEARSEARCH => runtime("linear_search", &args),
             OP_BINARYSEARCH => runtime("binary_search", &args),
@@ -135,6 +137,39 @@ impl GlulxOutput {
             OP_JFGT => format_safe_stack_pops_expression("decode_float({}) > decode_float({})", &args),
             OP_JFLE => format_safe_stack_pops_expression("decode_float({}) <= decode_float({})", &args),
             OP_JFGE => format_safe_stack_pops_expression("decode_float({}) >= decode_float({})", &args),
+            // Doubles are passed as two words, high word first, and are stored low word first into temp0 and temp1
+            OP_NUMTOD => format!("VM_ENCODE_DOUBLE((double) ((glsi32) {}), &temp0, &temp1)", op_a),
+            OP_DTONUMZ => runtime("OP_DTONUMZ", &args),
+            OP_DTONUMN => runtime("OP_DTONUMN", &args),
+            OP_FTOD => format!("VM_ENCODE_DOUBLE((double) decode_float({}), &temp0, &temp1)", op_a),
+            OP_DTOF => format_safe_stack_pops_expression("encode_float((gfloat32) VM_DECODE_DOUBLE({}, {}))", &args),
+            OP_DCEIL => runtime("OP_DCEIL", &double_storer_args(&args)),
+            OP_DFLOOR => runtime_double("floor", &args),
+            OP_DADD => runtime_double_binary("+", &args),
+            OP_DSUB => runtime_double_binary("-", &args),
+            OP_DMUL => runtime_double_binary("*", &args),
+            OP_DDIV => runtime_double_binary("/", &args),
+            OP_DMODR => format_safe_stack_pops_expression("VM_ENCODE_DOUBLE(fmod(VM_DECODE_DOUBLE({}, {}), VM_DECODE_DOUBLE({}, {})), &temp0, &temp1)", &args),
+            OP_DMODQ => runtime("OP_DMODQ", &double_storer_args(&args)),
+            OP_DSQRT => runtime_double("sqrt", &args),
+            OP_DEXP => runtime_double("exp", &args),
+            OP_DLOG => runtime_double("log", &args),
+            OP_DPOW => runtime("OP_DPOW", &double_storer_args(&args)),
+            OP_DSIN => runtime_double("sin", &args),
+            OP_DCOS => runtime_double("cos", &args),
+            OP_DTAN => runtime_double("tan", &args),
+            OP_DASIN => runtime_double("asin", &args),
+            OP_DACOS => runtime_double("acos", &args),
+            OP_DATAN => runtime_double("atan", &args),
+            OP_DATAN2 => format_safe_stack_pops_expression("VM_ENCODE_DOUBLE(atan2(VM_DECODE_DOUBLE({}, {}), VM_DECODE_DOUBLE({}, {})), &temp0, &temp1)", &args),
+            OP_JDISINF => format!("temp0 = {}, temp1 = {}, (temp0 == 0x7FF00000 || temp0 == 0xFFF00000) && temp1 == 0", op_a, op_b),
+            OP_JDISNAN => format!("temp0 = {}, temp1 = {}, (temp0 & 0x7FF00000) == 0x7FF00000 && ((temp0 & 0x000FFFFF) != 0 || temp1 != 0)", op_a, op_b),
+            OP_JDEQ => format_safe_stack_pops_expression("OP_JDEQ({}, {}, {}, {}, {}, {})", &args),
+            OP_JDNE => format_safe_stack_pops_expression("!OP_JDEQ({}, {}, {}, {}, {}, {})", &args),
+            OP_JDLT => runtime_double_comparison("<", &args),
+            OP_JDGT => runtime_double_comparison(">", &args),
+            OP_JDLE => runtime_double_comparison("<=", &args),
+            OP_JDGE => runtime_double_comparison(">=", &args),
             _ => panic!("Unknown opcode {:>3X} at address {}", opcode, instruction.addr),
         }
     }
@@ -142,7 +177,7 @@ impl GlulxOutput {
 
 fn runtime(name: &str, operands: &Vec<String>) -> String {
     let (prelude, new_operands) = safe_stack_pops(operands, false);
-    if prelude == "" {
+    if prelude.is_empty() {
         return format!("{}({})", name, operands.join(", "));
     }
     format!("({}, {}({}))", prelude, name, new_operands.join(", "))
@@ -150,4 +185,24 @@ fn runtime(name: &str, operands: &Vec<String>) -> String {
 
 fn runtime_float(func: &str, operand: &String) -> String {
     format!("encode_float({}(decode_float({})))", func, operand)
+}
+
+fn runtime_double(func: &str, operands: &Vec<String>) -> String {
+    format_safe_stack_pops_expression(&format!("VM_ENCODE_DOUBLE({}(VM_DECODE_DOUBLE({{}}, {{}})), &temp0, &temp1)", func), operands)
+}
+
+fn runtime_double_binary(operator: &str, operands: &Vec<String>) -> String {
+    format_safe_stack_pops_expression(&format!("VM_ENCODE_DOUBLE(VM_DECODE_DOUBLE({{}}, {{}}) {} VM_DECODE_DOUBLE({{}}, {{}}), &temp0, &temp1)", operator), operands)
+}
+
+fn runtime_double_comparison(operator: &str, operands: &Vec<String>) -> String {
+    format_safe_stack_pops_expression(&format!("VM_DECODE_DOUBLE({{}}, {{}}) {} VM_DECODE_DOUBLE({{}}, {{}})", operator), operands)
+}
+
+// Add the temp variables as the final arguments to runtime functions which store doubles
+fn double_storer_args(operands: &[String]) -> Vec<String> {
+    let mut args = operands.to_vec();
+    args.push(String::from("&temp0"));
+    args.push(String::from("&temp1"));
+    args
 }
\ No newline at end of file
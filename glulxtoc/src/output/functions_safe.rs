@@ -9,6 +9,7 @@ https://github.com/curiousdannii/if-decompiler
 
 */
 
+use std::collections::BTreeMap;
 use std::io::prelude::*;
 use std::time::Instant;
 
@@ -30,12 +31,13 @@ impl GlulxOutput {
         io::stdout().flush().unwrap();
         let start = Instant::now();
 
-        let mut code_file = self.make_file("functions_safe.c")?;
+        let mut code_file = self.make_code_file("functions_safe.c")?;
         let mut header_file = self.make_file("functions_safe.h")?;
 
         // Output the headers
         write!(code_file, "#include \"functions_safe.h\"
 #include \"glk.h\"
+#include \"globals.h\"
 #include \"glulxe.h\"
 #include \"glulxtoc.h\"
 #include <math.h>
@@ -48,31 +50,48 @@ impl GlulxOutput {
         // Output the function bodies
         let mut highest_arg_count = 0;
         let mut varargs_functions = Vec::new();
-        let mut zero_arg_functions = Vec::new();
+        let mut other_header_lengths = BTreeMap::new();
         for addr in &self.safe_functions {
             let function = &self.state.functions[addr];
-            if function.locals > highest_arg_count {
-                highest_arg_count = function.locals;
+            let locals_count = function.locals.len() as u32;
+            if locals_count > highest_arg_count {
+                highest_arg_count = locals_count;
             }
-            if function.locals == 0 {
-                zero_arg_functions.push(addr + 3);
+            // Most functions have one locals format entry, and so a header length of 5
+            let header_length = function.header_length();
+            if header_length != 5 {
+                other_header_lengths.entry(header_length).or_insert_with(Vec::new).push(addr + header_length);
             }
             if function.argument_mode == FunctionArgumentMode::Stack {
                 varargs_functions.push(*addr);
             }
 
-            let args_list = if function.argument_mode == FunctionArgumentMode::Stack { String::from("void") } else { function_arguments(function.locals, true, false) };
+            let local_names = self.local_names(function);
+            let args_list = if function.argument_mode == FunctionArgumentMode::Stack { String::from("void") } else { function_arguments(&local_names, true, false) };
             let function_spec = format!("glui32 VM_FUNC_{}({})", addr, args_list);
             let name_comment = self.state.debug_function_data.as_ref().map_or(String::new(), |functions| format!("// VM Function {} ({})\n", addr, functions.get(addr).unwrap().name));
 
-            writeln!(code_file, "{}{} {{
-    glui32 arg, label = 0, oldsp, oldvsb, res, temp0, temp1, temp2, temp3, temp4, temp5;", name_comment, function_spec)?;
+            writeln!(code_file, "{}{}{} {{
+    glui32 arg, label = 0, oldsp, oldvsb, res, temp0, temp1, temp2, temp3, temp4, temp5;", name_comment, self.locals_comment(function), function_spec)?;
             if function.argument_mode == FunctionArgumentMode::Stack {
-                writeln!(code_file, "    glui32 {};", function_arguments(function.locals, false, true))?;
+                writeln!(code_file, "    glui32 {};", function_arguments(&local_names, false, true))?;
             } else {
                 writeln!(code_file, "    valstackbase = stackptr;")?;
+                // Arguments must be truncated to fit in 1 and 2 byte locals
+                for (index, local) in function.locals.iter().enumerate() {
+                    if local.size < 4 {
+                        writeln!(code_file, "    {} &= {};", local_names[index], local_mask(local.size))?;
+                    }
+                }
+            }
+            // Functions in RAM could be modified, so check them before running them
+            if function.in_ram(self.ramstart) {
+                writeln!(code_file, "    VM_CHECK_RAM_FUNCTION({}, {});", addr, function.end_addr - addr)?;
+            }
+            code_file.write_code(&self.output_function_body(function))?;
+            if self.has_line_directives(function) {
+                code_file.reset_line_directive()?;
             }
-            code_file.write(self.output_function_body(function).as_bytes())?;
             writeln!(code_file, "    return 0;
 }}
 ")?;
@@ -122,17 +141,19 @@ impl GlulxOutput {
         // Output the VM_FUNC_SUBTRACT_HEADER function
         writeln!(code_file, "glui32 VM_FUNC_SUBTRACT_HEADER(glui32 pc) {{
     switch (pc) {{")?;
-        for row in zero_arg_functions.chunks(5) {
-            write!(code_file, "        ")?;
-            let mut row_str = String::new();
-            for addr in row {
-                row_str.push_str(&format!("case {}: ", addr));
+        for (header_length, pcs) in &other_header_lengths {
+            for row in pcs.chunks(5) {
+                write!(code_file, "        ")?;
+                let mut row_str = String::new();
+                for addr in row {
+                    row_str.push_str(&format!("case {}: ", addr));
+                }
+                row_str.truncate(row_str.len() - 1);
+                writeln!(code_file, "{}", row_str)?;
             }
-            row_str.truncate(row_str.len() - 1);
-            writeln!(code_file, "{}", row_str)?;
+            writeln!(code_file, "            return pc - {};", header_length)?;
         }
-        writeln!(code_file, "            return pc - 3;
-        default:
+        writeln!(code_file, "        default:
             return pc - 5;
     }}
 }}
@@ -144,14 +165,14 @@ impl GlulxOutput {
     if (VM_FUNC_IS_SAFE_VARARGS(addr)) {{
         PushStack(count);
     }}
-    else {{", function_arguments(highest_arg_count, false, true))?;
+    else {{", function_arguments(&generic_local_names(highest_arg_count as usize), false, true))?;
         for i in 0..highest_arg_count {
             writeln!(code_file, "        if (count > {}) {{ l{} = PopStack(); }}", i, i)?;
         }
         writeln!(code_file, "    }}\n    switch (addr) {{")?;
         for addr in &self.safe_functions {
             let function = &self.state.functions[addr];
-            let args_list = if function.argument_mode == FunctionArgumentMode::Stack { String::new() } else { function_arguments(function.locals, false, false) };
+            let args_list = if function.argument_mode == FunctionArgumentMode::Stack { String::new() } else { function_arguments(&generic_local_names(function.locals.len()), false, false) };
             writeln!(code_file, "        case {}: return VM_FUNC_{}({});", addr, addr, args_list)?;
         }
         write!(code_file, "        default: fatal_error_i(\"VM_CALL_SAFE_FUNCTION_WITH_STACK_ARGS called with non-safe function address:\", addr);
@@ -165,19 +186,10 @@ impl GlulxOutput {
 
     // Output a function
     fn output_function_body(&self, function: &Function) -> String {
-        // Prepare a map of block labels and branches
-        let mut input_blocks = Vec::default();
-        for (&label, block) in &function.blocks {
-            let mut branches = Vec::new();
-            for branch in &block.branches {
-                branches.push(*branch);
-            }
-            input_blocks.push((label, branches));
-        }
-
-        // Run the relooper
-        let mut block = reloop(input_blocks, *function.blocks.iter().next().unwrap().0);
-        self.output_shaped_block(function, &mut *block, 1)
+        // Functions which can't be relooped will have already been marked as unsafe
+        // Loops whose condition can't be output as a C expression will be kept as they are
+        let mut block = function.shaped_block.clone().unwrap().recognise_loop_shapes_with(&|condition, exit| loop_condition_exits(function, condition, exit).is_some());
+        self.output_shaped_block(function, &mut block, 1)
     }
 
     // Output a shaped block
@@ -190,7 +202,10 @@ impl GlulxOutput {
                 let mut last_next_instruction = 0;
                 let basicblock = function.blocks.get(&block.label).unwrap();
                 for instruction in &basicblock.code {
-                    output.push_str(&format!("{}/* {:>3X}/{} */ {}\n", indent, instruction.opcode, instruction.addr, self.output_instruction_safe(&function, block, &instruction, indents)));
+                    if let Some(directive) = self.line_directive(function, instruction.addr) {
+                        output.push_str(&format!("{}{}\n", indent, directive));
+                    }
+                    output.push_str(&format!("{}/* {:>3X}/{} */ {}\n", indent, instruction.opcode, instruction.addr, self.output_instruction_safe(function, block, instruction, indents)));
                     last_next_instruction = instruction.next;
                 }
                 // We might have one last branch left over, going to the next instruction
@@ -202,7 +217,7 @@ impl GlulxOutput {
                         block.branches.clear();
                     }
                 }
-                if block.branches.len() > 0 {
+                if !block.branches.is_empty() {
                     panic!("Unhandled leftover branch in function {}", function.addr);
                 }
                 if let Some(immediate) = block.immediate.as_deref_mut() {
@@ -214,7 +229,7 @@ impl GlulxOutput {
             },
             Loop(block) => {
                 output.push_str(&format!("{}while (1) {{\n{}    loop_{}_continue:\n", indent, indent, block.loop_id));
-                output.push_str(&self.output_shaped_block(function, &mut *block.inner, indents + 1));
+                output.push_str(&self.output_shaped_block(function, &mut block.inner, indents + 1));
                 output.push_str(&format!("{}}}\n{}loop_{}_break:;\n", indent, indent, block.loop_id));
                 if let Some(next) = block.next.as_deref_mut() {
                     output.push_str(&self.output_shaped_block(function, next, indents));
@@ -233,16 +248,94 @@ impl GlulxOutput {
                 }
                 output.push_str(&format!("{}}}\n", indent));
             },
+            While(block) => {
+                let (leading, condition, branch) = self.output_loop_condition(function, block.condition, block.exit, indents + 1);
+                let directive = self.line_directive(function, branch.addr);
+                // If the condition block only has the branch instruction then it can go in the while statement itself
+                if leading.is_empty() {
+                    if let Some(directive) = directive {
+                        output.push_str(&format!("{}{}\n", indent, directive));
+                    }
+                    output.push_str(&format!("{}/* {:>3X}/{} */ while ({}) {{\n", indent, branch.opcode, branch.addr, condition));
+                    output.push_str(&self.output_shaped_block(function, &mut block.body, indents + 1));
+                    output.push_str(&format!("{}    loop_{}_continue:;\n", indent, block.loop_id));
+                }
+                else {
+                    output.push_str(&format!("{}while (1) {{\n{}    loop_{}_continue:\n{}", indent, indent, block.loop_id, leading));
+                    if let Some(directive) = directive {
+                        output.push_str(&format!("{}    {}\n", indent, directive));
+                    }
+                    output.push_str(&format!("{}    /* {:>3X}/{} */ if (!({})) {{\n{}        break;\n{}    }}\n", indent, branch.opcode, branch.addr, condition, indent, indent));
+                    output.push_str(&self.output_shaped_block(function, &mut block.body, indents + 1));
+                }
+                output.push_str(&format!("{}}}\n{}loop_{}_break:;\n", indent, indent, block.loop_id));
+                if let Some(next) = block.next.as_deref_mut() {
+                    output.push_str(&self.output_shaped_block(function, next, indents));
+                }
+            },
+            DoWhile(block) => {
+                let (leading, condition, branch) = self.output_loop_condition(function, block.condition, block.exit, indents + 1);
+                output.push_str(&format!("{}do {{\n", indent));
+                if let Some(body) = block.body.as_deref_mut() {
+                    output.push_str(&self.output_shaped_block(function, body, indents + 1));
+                }
+                output.push_str(&leading);
+                if let Some(directive) = self.line_directive(function, branch.addr) {
+                    output.push_str(&format!("{}{}\n", indent, directive));
+                }
+                output.push_str(&format!("{}}} while ({}); /* {:>3X}/{} */\n{}loop_{}_break:;\n", indent, condition, branch.opcode, branch.addr, indent, block.loop_id));
+                if let Some(next) = block.next.as_deref_mut() {
+                    output.push_str(&self.output_shaped_block(function, next, indents));
+                }
+            },
+            Switch(block) => {
+                let (leading, branch) = self.output_leading_instructions(function, block.label, indents);
+                let chain = &function.switches[&block.label];
+                output.push_str(&leading);
+                if let Some(directive) = self.line_directive(function, branch.addr) {
+                    output.push_str(&format!("{}{}\n", indent, directive));
+                }
+                output.push_str(&format!("{}/* {:>3X}/{} */ switch ({}) {{\n", indent, branch.opcode, branch.addr, self.output_operand_safe(function, chain.value)));
+                // Output the cases which leave the switch first, so that no other case can fall through into them
+                let mut branches: Vec<(u32, BranchMode)> = block.branches.iter().map(|(&label, &branch_mode)| (label, branch_mode)).collect();
+                branches.sort_by_key(|&(label, _)| label);
+                for (label, branch_mode) in branches {
+                    output.push_str(&output_switch_cases(chain, label, indents + 1));
+                    output.push_str(&format!("{}        {};\n", indent, output_branchmode(&branch_mode, label)));
+                    if let MergedBranch | MergedBranchIntoMulti = branch_mode {
+                        output.push_str(&format!("{}        break;\n", indent));
+                    }
+                }
+                for handled in block.cases.iter_mut() {
+                    for &label in &handled.labels {
+                        output.push_str(&output_switch_cases(chain, label, indents + 1));
+                    }
+                    output.push_str(&self.output_shaped_block(function, &mut handled.inner, indents + 2));
+                    if handled.break_after {
+                        output.push_str(&format!("{}        break;\n", indent));
+                    }
+                }
+                output.push_str(&format!("{}}}\n", indent));
+                if let Some(next) = block.next.as_deref_mut() {
+                    output.push_str(&self.output_shaped_block(function, next, indents));
+                }
+            },
         };
         output
     }
 
     // Output an instruction
     fn output_instruction_safe(&self, function: &Function, block: &mut GlulxSimpleBlock, instruction: &Instruction, indents: usize) -> String {
+        let body_with_storer = self.output_instruction_body_safe(function, instruction);
+        self.output_branch_safe(function, block, instruction, body_with_storer, indents)
+    }
+
+    // Output an instruction without its branch
+    fn output_instruction_body_safe(&self, function: &Function, instruction: &Instruction) -> String {
         let opcode = instruction.opcode;
-        let operands = self.map_operands_safe(instruction);
+        let operands = self.map_operands_safe(function, instruction);
         let null = String::from("NULL");
-        let op_a = operands.get(0).unwrap_or(&null);
+        let op_a = operands.first().unwrap_or(&null);
         let op_b = operands.get(1).unwrap_or(&null);
         use opcodes::*;
         let body = match opcode {
@@ -250,59 +343,95 @@ impl GlulxOutput {
             OP_CALL => self.output_call_on_stack_safe(instruction, op_a, op_b),
             OP_RETURN => format!("return {}", op_a),
             OP_TAILCALL => format!("return {}", self.output_call_on_stack_safe(instruction, op_a, op_b)),
-            OP_COPYS => self.output_copys_safe(instruction),
-            OP_COPYB => self.output_copyb_safe(instruction),
+            OP_COPYS => self.output_copys_safe(function, instruction),
+            OP_COPYB => self.output_copyb_safe(function, instruction),
             OP_STREAMCHAR => format!("OP_STREAMX_SAFE(STREAM_CHAR, {})", op_a),
             OP_STREAMNUM => format!("OP_STREAMX_SAFE(STREAM_NUM, {})", op_a),
             OP_STREAMSTR => format!("OP_STREAMX_SAFE(STREAM_STRING, {})", op_a),
             OP_STREAMUNICHAR => format!("OP_STREAMX_SAFE(STREAM_UNICHAR, {})", op_a),
             OP_CALLF ..= OP_CALLFIII => self.output_callf_safe(instruction, operands),
-            OP_GETIOSYS => self.output_double_storer_safe(instruction, String::from("stream_get_iosys(&temp0, &temp1)")),
-            OP_FMOD => self.output_double_storer_safe(instruction, format_safe_stack_pops_expression("OP_FMOD({}, {}, &temp0, &temp1)", &operands)),
+            OP_GETIOSYS => self.output_double_storer_safe(function, instruction, String::from("stream_get_iosys(&temp0, &temp1)")),
+            OP_FMOD => self.output_double_storer_safe(function, instruction, format_safe_stack_pops_expression("OP_FMOD({}, {}, &temp0, &temp1)", &operands)),
+            OP_NUMTOD | OP_FTOD | OP_DCEIL ..= OP_DATAN2 => self.output_double_storer_safe(function, instruction, self.output_common_instruction(instruction, operands)),
             _ => self.output_common_instruction(instruction, operands),
         };
-        let body_with_storer = self.output_storer_safe(opcode, instruction.storer, body);
-        self.output_branch_safe(function, block, instruction, body_with_storer, indents)
+        self.output_storer_safe(function, opcode, instruction.storer, body)
+    }
+
+    // Output the instructions of a block before its final branch, which is returned for the caller to output
+    fn output_leading_instructions<'a>(&self, function: &'a Function, label: u32, indents: usize) -> (String, &'a Instruction) {
+        let indent = "    ".repeat(indents);
+        let basicblock = function.blocks.get(&label).unwrap();
+        let (branch, leading_instructions) = basicblock.code.split_last().unwrap();
+        // None of the leading instructions can branch to another block, so they won't use the SimpleBlock
+        let mut block = GlulxSimpleBlock {
+            label,
+            immediate: None,
+            branches: Default::default(),
+            next: None,
+        };
+        let mut leading = String::new();
+        for instruction in leading_instructions {
+            if let Some(directive) = self.line_directive(function, instruction.addr) {
+                leading.push_str(&format!("{}{}\n", indent, directive));
+            }
+            leading.push_str(&format!("{}/* {:>3X}/{} */ {}\n", indent, instruction.opcode, instruction.addr, self.output_instruction_safe(function, &mut block, instruction, indents)));
+        }
+        (leading, branch)
+    }
+
+    // Output a While or DoWhile loop's condition block
+    // Returns the instructions before the branch, the condition for staying in the loop, and the branch instruction
+    fn output_loop_condition<'a>(&self, function: &'a Function, label: u32, exit: u32, indents: usize) -> (String, String, &'a Instruction) {
+        let (leading, branch) = self.output_leading_instructions(function, label, indents);
+        let body = self.output_instruction_body_safe(function, branch);
+        // Stay in the loop when the branch isn't taken to the exit
+        // Other conditions were left as plain loops by output_function_body()
+        let condition = match loop_condition_exits(function, label, exit) {
+            Some(true) => format!("!({})", body),
+            _ => body,
+        };
+        (leading, condition, branch)
     }
 
     // Map operands into strings
-    fn map_operands_safe(&self, instruction: &Instruction) -> Vec<String> {
-        instruction.operands.iter().map(|&operand| self.output_operand_safe(operand)).collect()
+    fn map_operands_safe(&self, function: &Function, instruction: &Instruction) -> Vec<String> {
+        instruction.operands.iter().map(|&operand| self.output_operand_safe(function, operand)).collect()
     }
 
-    fn output_operand_safe(&self, operand: Operand) -> String {
+    fn output_operand_safe(&self, function: &Function, operand: Operand) -> String {
         match operand {
             Constant(val) => val.to_string(),
-            Memory(addr) => format!("Mem4({})", addr),
+            Memory(addr) => format!("Mem4({})", self.memory_address(addr)),
             Stack => String::from("PopStack()"),
-            Local(val) => format!("l{}", val / 4),
-            RAM(addr) => format!("Mem4({})", addr + self.ramstart),
+            Local(val) => self.local_name(function, local_index(function, val)),
+            RAM(addr) => format!("Mem4({})", self.memory_address(addr + self.ramstart)),
         }
     }
 
-    fn output_storer_safe(&self, opcode: u32, storer: Operand, inner: String) -> String {
+    fn output_storer_safe(&self, function: &Function, opcode: u32, storer: Operand, inner: String) -> String {
         use opcodes::*;
         // The double store opcodes are handled separately
-        if opcode == OP_GETIOSYS || opcode == OP_FMOD {
+        if instruction_stores(opcode) == StoreMode::LastTwoOperands {
             return inner;
         }
         match storer {
             Constant(_) => inner, // Must still output the inner code in case there are side-effects
-            Memory(addr) => format!("store_operand(1, {}, {})", addr, inner),
+            Memory(addr) => format!("store_operand(1, {}, {})", self.memory_address(addr), inner),
             Stack => format!("PushStack({})", inner),
-            Local(val) => format!("l{} = {}", val / 4, inner),
-            RAM(addr) => format!("store_operand(1, {}, {})", addr + self.ramstart, inner),
+            Local(val) => self.store_local(function, val, inner),
+            RAM(addr) => format!("store_operand(1, {}, {})", self.memory_address(addr + self.ramstart), inner),
         }
     }
 
-    fn output_double_storer_safe(&self, instruction: &Instruction, inner: String) -> String {
+    fn output_double_storer_safe(&self, function: &Function, instruction: &Instruction, inner: String) -> String {
         let store = |storer: Operand, i: u32| {
             match storer {
                 Constant(_) => String::from("NULL"),
-                Memory(addr) => format!("store_operand(1, {}, temp{})", addr, i),
+                Memory(addr) => format!("store_operand(1, {}, temp{})", self.memory_address(addr), i),
                 Stack => format!("PushStack(temp{})", i),
-                Local(val) => format!("l{} = temp{}", val / 4, i),
-                RAM(addr) => format!("store_operand(1, {}, temp{})", addr + self.ramstart, i),
+                Local(val) => self.store_local(function, val, format!("temp{}", i)),
+                RAM(addr) => format!("store_operand(1, {}, temp{})", self.memory_address(addr + self.ramstart), i),
             }
         };
         format!("{}; {}; {}", inner, store(instruction.storer, 0), store(instruction.storer2, 1))
@@ -316,7 +445,7 @@ impl GlulxOutput {
         };
         let callee = self.state.functions.get(&callee_addr).unwrap();
         let provided_args = args.len();
-        let callee_args = callee.locals as usize;
+        let callee_args = callee.locals.len();
 
         // Vararg functions
         if callee.argument_mode == FunctionArgumentMode::Stack {
@@ -324,7 +453,7 @@ impl GlulxOutput {
                 opcodes::OP_CALLFI ..= opcodes::OP_CALLFIII => {
                     let (mut prelude, new_operands) = safe_stack_pops(&args, true);
                     let pushed_args: Vec<String> = new_operands.iter().rev().map(|arg| format!("PushStack({})", arg)).collect();
-                    if prelude != "" {
+                    if !prelude.is_empty() {
                         prelude = format!("{}, ", prelude);
                     }
                     (prelude, format!("{}, ", pushed_args.join(", ")), 0)
@@ -344,12 +473,9 @@ impl GlulxOutput {
             if is_callf {
                 for i in callee_args..provided_args {
                     // Add 1 because we removed the callee address
-                    match instruction.operands[i + 1] {
-                        Stack => {
-                            surplus_stack_pops += 1;
-                        },
-                        _ => {},
-                    };
+                    if instruction.operands[i + 1] == Stack {
+                        surplus_stack_pops += 1;
+                    }
                 }
             } else {
                 surplus_stack_pops = provided_args - callee_args;
@@ -366,7 +492,7 @@ impl GlulxOutput {
         }
 
         let (mut prelude, new_operands) = safe_stack_pops(&args, true);
-        if prelude != "" {
+        if !prelude.is_empty() {
             prelude = format!("{}, ", prelude);
         }
         format!("({}CALL_FUNC(VM_FUNC_{}({}), 0))", prelude, callee_addr, new_operands.join(", "))
@@ -422,22 +548,18 @@ impl GlulxOutput {
                                 return output;
                             }
                             if let Some(immediate_block) = simple_block.immediate.as_deref_mut() {
-                                if let Simple(_) = immediate_block {
-                                    assert!(simple_block.branches.len() == 0, "Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block);
+                                if let Simple(_) | Switch(_) = immediate_block {
+                                    assert!(simple_block.branches.is_empty(), "Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block);
                                     let output = format!("/* Jumping into immediate */\n{}", self.output_shaped_block(function, immediate_block, indents));
                                     simple_block.immediate = None;
                                     return output;
                                 }
-                                // We can also jump into a Loop(Simple)
-                                if let Loop(loop_block) = immediate_block {
-                                    if let Simple(inner_block) = &*loop_block.inner {
-                                        if inner_block.label == addr {
-                                            assert!(simple_block.branches.len() == 0, "Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block);
-                                            let output = format!("/* Jumping into immediate */\n{}", self.output_shaped_block(function, immediate_block, indents));
-                                            simple_block.immediate = None;
-                                            return output;
-                                        }
-                                    }
+                                // We can also jump into a loop which starts with the target
+                                if loop_starts_with(immediate_block, addr) {
+                                    assert!(simple_block.branches.is_empty(), "Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block);
+                                    let output = format!("/* Jumping into immediate */\n{}", self.output_shaped_block(function, immediate_block, indents));
+                                    simple_block.immediate = None;
+                                    return output;
                                 }
                             }
                         }
@@ -448,7 +570,7 @@ impl GlulxOutput {
                                 // if-else with both blocks in handled
                                 if let Some(if_block_index) = find_multiple(&multiple_block.handled, addr) {
                                     assert!(multiple_block.handled.len() == 2, "Unhandled multiple block at address {}\nBlock: {:?}", instruction.addr, simple_block);
-                                    assert!(simple_block.branches.len() == 0, "Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block);
+                                    assert!(simple_block.branches.is_empty(), "Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block);
                                     let output = format!("if ({}) {{\n{}{}}}\n{}else {{\n{}{}}}", condition, self.output_multiple(function, &mut multiple_block.handled, if_block_index, indents + 1), indent, indent, self.output_multiple(function, &mut multiple_block.handled, next_block_index, indents + 1), indent);
                                     simple_block.immediate = None;
                                     return output;
@@ -524,47 +646,133 @@ impl GlulxOutput {
         }
     }
 
-    fn output_multiple(&self, function: &Function, handled: &mut Vec<HandledBlock<u32>>, index: usize, indents: usize) -> String {
-        let ref mut block = handled.get_mut(index).unwrap().inner;
+    fn output_multiple(&self, function: &Function, handled: &mut [HandledBlock<u32>], index: usize, indents: usize) -> String {
+        let block = &mut handled.get_mut(index).unwrap().inner;
         self.output_shaped_block(function, block, indents)
     }
 
-    fn output_copys_safe(&self, instruction: &Instruction) -> String {
+    fn output_copys_safe(&self, function: &Function, instruction: &Instruction) -> String {
         let inner = match instruction.operands[0] {
             Constant(val) => format!("{} & 0xFFFF", val),
-            Memory(addr) => format!("Mem2({})", addr),
+            Memory(addr) => format!("Mem2({})", self.memory_address(addr)),
             Stack => String::from("PopStack() & 0xFFFF"),
-            Local(val) => format!("l{} & 0xFFFF", val / 4),
-            RAM(addr) => format!("Mem2({})", addr + self.ramstart),
+            Local(val) => self.read_local_part(function, val, 2),
+            RAM(addr) => format!("Mem2({})", self.memory_address(addr + self.ramstart)),
         };
         match instruction.operands[1] {
             Constant(_) => inner,
-            Memory(addr) => format!("store_operand_s(1, {}, {})", addr, inner),
+            Memory(addr) => format!("store_operand_s(1, {}, {})", self.memory_address(addr), inner),
             Stack => format!("PushStack({})", inner),
-            Local(val) => format!("l{} = (l{} & 0xFFFF0000) | {}", val, val, inner),
-            RAM(addr) => format!("store_operand_s(1, {}, {})", addr + self.ramstart, inner),
+            Local(val) => self.write_local_part(function, val, 2, inner),
+            RAM(addr) => format!("store_operand_s(1, {}, {})", self.memory_address(addr + self.ramstart), inner),
         }
     }
 
-    fn output_copyb_safe(&self, instruction: &Instruction) -> String {
+    fn output_copyb_safe(&self, function: &Function, instruction: &Instruction) -> String {
         let inner = match instruction.operands[0] {
             Constant(val) => format!("{} & 0xFF", val),
-            Memory(addr) => format!("Mem1({})", addr),
+            Memory(addr) => format!("Mem1({})", self.memory_address(addr)),
             Stack => String::from("PopStack() & 0xFF"),
-            Local(val) => format!("l{} & 0xFF", val / 4),
-            RAM(addr) => format!("Mem1({})", addr + self.ramstart),
+            Local(val) => self.read_local_part(function, val, 1),
+            RAM(addr) => format!("Mem1({})", self.memory_address(addr + self.ramstart)),
         };
         match instruction.operands[1] {
             Constant(_) => inner,
-            Memory(addr) => format!("store_operand_b(1, {}, {})", addr, inner),
+            Memory(addr) => format!("store_operand_b(1, {}, {})", self.memory_address(addr), inner),
             Stack => format!("PushStack({})", inner),
-            Local(val) => format!("l{} = (l{} & 0xFFFFFF00) | {}", val, val, inner),
-            RAM(addr) => format!("store_operand_b(1, {}, {})", addr + self.ramstart, inner),
+            Local(val) => self.write_local_part(function, val, 1, inner),
+            RAM(addr) => format!("store_operand_b(1, {}, {})", self.memory_address(addr + self.ramstart), inner),
+        }
+    }
+
+    // The C name of a local, including its Inform identifier if we know it
+    fn local_name(&self, function: &Function, index: usize) -> String {
+        match self.local_identifier(function, index) {
+            Some(identifier) => format!("l{}_{}", index, sanitise_identifier(identifier)),
+            None => format!("l{}", index),
+        }
+    }
+
+    fn local_names(&self, function: &Function) -> Vec<String> {
+        (0..function.locals.len()).map(|index| self.local_name(function, index)).collect()
+    }
+
+    // Storing to a 1 or 2 byte local truncates the value
+    fn store_local(&self, function: &Function, offset: u32, inner: String) -> String {
+        let index = local_index(function, offset);
+        let name = self.local_name(function, index);
+        match function.locals[index].size {
+            4 => format!("{} = {}", name, inner),
+            size => format!("{} = ({}) & {}", name, inner, local_mask(size)),
+        }
+    }
+
+    fn read_local_part(&self, function: &Function, offset: u32, size: u8) -> String {
+        let (index, shift) = local_part(function, offset, size);
+        let name = self.local_name(function, index);
+        if shift == 0 && function.locals[index].size == size {
+            return name;
         }
+        format!("(({} >> {}) & {})", name, shift, local_mask(size))
+    }
+
+    fn write_local_part(&self, function: &Function, offset: u32, size: u8, inner: String) -> String {
+        let (index, shift) = local_part(function, offset, size);
+        let name = self.local_name(function, index);
+        if shift == 0 && function.locals[index].size == size {
+            return format!("{} = {}", name, inner);
+        }
+        format!("{} = ({} & ~({} << {})) | (({}) << {})", name, name, local_mask(size), shift, inner, shift)
+    }
+}
+
+// Find the local at an offset
+// Functions with locals which don't line up like this are made unsafe when they're disassembled
+fn local_index(function: &Function, offset: u32) -> usize {
+    match function.local_at(offset) {
+        Some(index) if function.locals[index].offset == offset => index,
+        _ => panic!("Invalid local {} in function {}", offset, function.addr),
     }
 }
 
-fn find_multiple(handled: &Vec<HandledBlock<u32>>, label: u32) -> Option<usize> {
+fn local_mask(size: u8) -> &'static str {
+    match size {
+        1 => "0xFF",
+        2 => "0xFFFF",
+        _ => "0xFFFFFFFF",
+    }
+}
+
+// The copys and copyb opcodes can access part of a local, which works like accessing memory in big-endian order
+// Returns the local's index and the shift of the part being accessed
+fn local_part(function: &Function, offset: u32, size: u8) -> (usize, u32) {
+    let index = match function.local_at(offset) {
+        Some(index) => index,
+        None => panic!("Invalid local {} in function {}", offset, function.addr),
+    };
+    let local = function.locals[index];
+    let local_end = local.offset + local.size as u32;
+    if offset + size as u32 > local_end {
+        panic!("Access to local {} overflows into the next local in function {}", offset, function.addr);
+    }
+    (index, (local_end - offset - size as u32) * 8)
+}
+
+// Whether a loop's condition block branches to the exit (or else to the rest of the loop)
+// Returns None if it doesn't end with a branch which can tell them apart
+fn loop_condition_exits(function: &Function, label: u32, exit: u32) -> Option<bool> {
+    let branch = function.blocks.get(&label)?.code.last()?;
+    if matches!(branch.opcode, opcodes::OP_JUMP | opcodes::OP_JUMPABS) {
+        return None;
+    }
+    match branch.branch {
+        Some(BranchTarget::Absolute(addr)) if addr == exit && branch.next != exit => Some(true),
+        Some(BranchTarget::Absolute(addr)) if addr != exit && branch.next == exit => Some(false),
+        _ => None,
+    }
+}
+
+fn find_multiple(handled: &[HandledBlock<u32>], label: u32) -> Option<usize> {
     for (index, block) in handled.iter().enumerate() {
         if block.labels.contains(&label) {
             return Some(index)
@@ -573,16 +781,48 @@ fn find_multiple(handled: &Vec<HandledBlock<u32>>, label: u32) -> Option<usize>
     None
 }
 
-fn function_arguments(count: u32, include_types: bool, include_initialiser: bool) -> String {
+// Whether a loop starts with a SimpleBlock (or a loop condition) with this label
+fn loop_starts_with(block: &ShapedBlock<u32>, label: u32) -> bool {
+    match block {
+        Loop(block) => matches!(&*block.inner, Simple(SimpleBlock { label: inner, .. }) | Switch(SwitchBlock { label: inner, .. }) if *inner == label),
+        While(block) => block.condition == label,
+        DoWhile(block) => match block.body.as_deref() {
+            Some(Simple(inner)) => inner.label == label,
+            Some(_) => false,
+            None => block.condition == label,
+        },
+        _ => false,
+    }
+}
+
+// Output the case labels of a switch which branch to a label
+fn output_switch_cases(chain: &SwitchChain<Operand>, label: u32, indents: usize) -> String {
+    let indent = "    ".repeat(indents);
     let mut output = String::new();
-    if count == 0 {
+    for constant in chain.constants_for(label) {
+        output.push_str(&format!("{}case {}:\n", indent, constant));
+    }
+    if chain.default == label {
+        output.push_str(&format!("{}default:\n", indent));
+    }
+    output
+}
+
+// Names for the locals of a function we're calling, which may not be the names it uses
+fn generic_local_names(count: usize) -> Vec<String> {
+    (0..count).map(|index| format!("l{}", index)).collect()
+}
+
+fn function_arguments(names: &[String], include_types: bool, include_initialiser: bool) -> String {
+    let mut output = String::new();
+    if names.is_empty() {
         return String::from(if include_types {"void"} else {""});
     }
-    for arg in 0..count {
+    for name in names {
         if include_types {
             output.push_str("glui32 ");
         }
-        output.push_str(&format!("l{}", arg));
+        output.push_str(name);
         if include_initialiser {
             output.push_str(" = 0");
         }
@@ -603,4 +843,102 @@ fn output_branchmode(branch_mode: &BranchMode, addr: u32) -> String {
         MergedBranchIntoMulti => format!("label = {} /* Branch continues below */", addr),
         SetLabelAndBreak => format!("label = {}; break /* Branch continues below */", addr),
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use fnv::{FnvHashMap, FnvHashSet};
+
+    use super::*;
+
+    fn instruction(addr: u32, opcode: u32, operands: Vec<Operand>, branch: Option<BranchTarget>, storer: Operand, next: u32) -> Instruction {
+        Instruction {
+            addr,
+            opcode,
+            operands,
+            branch,
+            storer,
+            storer2: Constant(0),
+            next,
+        }
+    }
+
+    // Make a function with one local, and reloop it as decompile_rom() would
+    fn make_function(code: Vec<Instruction>) -> Function {
+        let mut entry_points = FnvHashSet::default();
+        let mut exit_branches = FnvHashMap::default();
+        for instruction in &code {
+            if let Some(BranchTarget::Absolute(addr)) = instruction.branch {
+                entry_points.insert(addr);
+                let mut targets = vec![addr];
+                if instruction.opcode != opcodes::OP_JUMP {
+                    entry_points.insert(instruction.next);
+                    targets.push(instruction.next);
+                }
+                exit_branches.insert(instruction.addr, targets);
+            }
+        }
+        let end_addr = code.last().unwrap().next;
+        let mut function = Function {
+            addr: 0x100,
+            argument_mode: FunctionArgumentMode::Locals,
+            blocks: calculate_basic_blocks(code, entry_points, exit_branches),
+            end_addr,
+            locals: vec![glulx::Local {offset: 0, size: 4}],
+            locals_format: vec![LocalsFormat {size: 4, count: 1}],
+            safety: FunctionSafety::SafetyTBD,
+            shaped_block: None,
+            switches: BTreeMap::new(),
+        };
+        function.shaped_block = Some(function.reloop().unwrap());
+        function
+    }
+
+    fn make_output() -> GlulxOutput {
+        GlulxOutput::new(false, String::from("test"), PathBuf::new(), None, None, GlulxState::new(None, false, false, None, false, None))
+    }
+
+    // while (l0 < 10) { l0 += 1; }
+    fn while_function() -> Function {
+        make_function(vec![
+            instruction(0x105, opcodes::OP_JGE, vec![Local(0), Constant(10)], Some(BranchTarget::Absolute(0x112)), Constant(0), 0x10A),
+            instruction(0x10A, opcodes::OP_ADD, vec![Local(0), Constant(1)], None, Local(0), 0x10E),
+            instruction(0x10E, opcodes::OP_JUMP, vec![], Some(BranchTarget::Absolute(0x105)), Constant(0), 0x112),
+            instruction(0x112, opcodes::OP_RETURN, vec![Constant(0)], None, Constant(0), 0x114),
+        ])
+    }
+
+    #[test]
+    fn test_while_loop() {
+        let function = while_function();
+        let output = make_output().output_function_body(&function);
+        assert!(output.contains("while (!((glsi32) l0 >= (glsi32) 10)) {"), "{}", output);
+        assert!(!output.contains("while (1)"), "{}", output);
+    }
+
+    #[test]
+    fn test_do_while_loop() {
+        // do { l0 += 1; } while (l0 < 10);
+        let function = make_function(vec![
+            instruction(0x105, opcodes::OP_ADD, vec![Local(0), Constant(1)], None, Local(0), 0x109),
+            instruction(0x109, opcodes::OP_JLT, vec![Local(0), Constant(10)], Some(BranchTarget::Absolute(0x105)), Constant(0), 0x10E),
+            instruction(0x10E, opcodes::OP_RETURN, vec![Constant(0)], None, Constant(0), 0x110),
+        ]);
+        let output = make_output().output_function_body(&function);
+        assert!(output.contains("do {"), "{}", output);
+        assert!(output.contains("} while ((glsi32) l0 < (glsi32) 10);"), "{}", output);
+        assert!(!output.contains("while (1)"), "{}", output);
+    }
+
+    #[test]
+    fn test_untestable_loop_condition() {
+        // Only the conditional branch can be the loop's condition, not the jump back to it
+        let function = while_function();
+        assert_eq!(loop_condition_exits(&function, 0x105, 0x112), Some(true));
+        assert_eq!(loop_condition_exits(&function, 0x10A, 0x112), None);
+
+        // A loop which isn't given a while shape is output as an infinite loop with a break
+        let output = make_output().output_shaped_block(&function, &mut function.shaped_block.clone().unwrap(), 1);
+        assert!(output.contains("while (1) {"), "{}", output);
+        assert!(output.contains("if ((glsi32) l0 >= (glsi32) 10) {"), "{}", output);
+    }
+}
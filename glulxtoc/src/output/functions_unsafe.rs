@@ -9,9 +9,7 @@ https://github.com/curiousdannii/if-decompiler
 
 */
 
-use std::fs::File;
 use std::io::prelude::*;
-use std::io::BufWriter;
 use std::time::Instant;
 
 use if_decompiler::*;
@@ -28,10 +26,11 @@ impl GlulxOutput {
         io::stdout().flush().unwrap();
         let start = Instant::now();
 
-        let mut code_file = self.make_file("functions_unsafe.c")?;
+        let mut code_file = self.make_code_file("functions_unsafe.c")?;
 
         // Output the header
         writeln!(code_file, "#include \"glk.h\"
+#include \"globals.h\"
 #include \"glulxe.h\"
 #include \"glulxtoc.h\"
 #include <math.h>
@@ -107,7 +106,7 @@ impl GlulxOutput {
     }
 
     // Output a chunk of functions
-    fn output_functions_chunk(&self, code_file: &mut BufWriter<File>, functions: &[u32]) -> std::io::Result<(u32, bool)> {
+    fn output_functions_chunk(&self, code_file: &mut CodeWriter, functions: &[u32]) -> std::io::Result<(u32, bool)> {
         let mut need_to_warn = false;
 
         // Output the function bodies
@@ -120,27 +119,43 @@ impl GlulxOutput {
 
             let name = self.state.debug_function_data.as_ref().map_or(String::new(), |functions| format!(" ({})", functions.get(addr).unwrap().name));
             writeln!(code_file, "        // VM Function {}{}", addr, name)?;
+            let locals_comment = self.locals_comment(function);
+            if !locals_comment.is_empty() {
+                write!(code_file, "        {}", locals_comment)?;
+            }
+
+            // Functions in RAM could be modified, so check them when they're entered
+            let ram_check = if function.in_ram(self.ramstart) { format!(" VM_CHECK_RAM_FUNCTION({}, {});", addr, function.end_addr - addr) } else { String::new() };
+            let entry_addr = *function.blocks.keys().next().unwrap();
 
             for (label, block) in &function.blocks {
                 if function.safety != UnsafeDynamicBranches {
-                    writeln!(code_file, "        case {}:", label)?;
+                    writeln!(code_file, "        case {}:{}", label, if *label == entry_addr { &ram_check } else { "" })?;
                 }
                 for instruction in &block.code {
-                    let instruction_label = if function.safety == UnsafeDynamicBranches { format!("case {}: ", instruction.addr) } else { String::new() };
-                    writeln!(code_file, "            {}/* {:>3X}/{} */ {};", instruction_label, instruction.opcode, instruction.addr, self.output_instruction_unsafe(&instruction))?;
+                    let instruction_label = if function.safety == UnsafeDynamicBranches {
+                        format!("case {}:{} ", instruction.addr, if instruction.addr == entry_addr { &ram_check } else { "" })
+                    } else { String::new() };
+                    if let Some(directive) = self.line_directive(function, instruction.addr) {
+                        code_file.write_code(&format!("            {}\n", directive))?;
+                    }
+                    writeln!(code_file, "            {}/* {:>3X}/{} */ {};", instruction_label, instruction.opcode, instruction.addr, self.output_instruction_unsafe(function, instruction))?;
                 }
             }
+            if self.has_line_directives(function) {
+                code_file.reset_line_directive()?;
+            }
         }
 
         Ok((functions[0], need_to_warn))
     }
 
     // Output an instruction
-    fn output_instruction_unsafe(&self, instruction: &Instruction) -> String {
+    fn output_instruction_unsafe(&self, function: &Function, instruction: &Instruction) -> String {
         let opcode = instruction.opcode;
-        let operands = self.map_operands_unsafe(instruction);
+        let operands = self.map_operands_unsafe(function, instruction);
         let null = String::from("NULL");
-        let op_a = operands.get(0).unwrap_or(&null);
+        let op_a = operands.first().unwrap_or(&null);
         use opcodes::*;
         let body = match opcode {
             OP_CALL => self.output_call_unsafe(&operands, instruction),
@@ -148,81 +163,86 @@ impl GlulxOutput {
             OP_TAILCALL => format_safe_stack_pops_statement("VM_TAILCALL_FUNCTION({}, {}); if (stackptr == 0) {{return 1;}} break", &operands),
             OP_CATCH => format!("if (OP_CATCH({}, {}, {}, {})) {{return 1;}} break", storer_type(instruction.operands[0]), self.storer_value(instruction.operands[0]), operands[1], instruction.next),
             OP_THROW => format!("temp0 = {}; stackptr = {}; pop_callstub(temp0); break", op_a, operands[1]),
-            OP_COPYS => self.output_copys_unsafe(instruction),
-            OP_COPYB => self.output_copyb_unsafe(instruction),
+            OP_COPYS => self.output_copys_unsafe(function, instruction),
+            OP_COPYB => self.output_copyb_unsafe(function, instruction),
             OP_STREAMCHAR => format!("if (OP_STREAMX_UNSAFE(STREAM_CHAR, {}, {})) {{break;}}", op_a, instruction.next),
             OP_STREAMNUM => format!("if (OP_STREAMX_UNSAFE(STREAM_NUM, {}, {})) {{break;}}", op_a, instruction.next),
             OP_STREAMSTR => format!("if (OP_STREAMX_UNSAFE(STREAM_STRING, {}, {})) {{break;}}", op_a, instruction.next),
             OP_STREAMUNICHAR => format!("if (OP_STREAMX_UNSAFE(STREAM_UNICHAR, {}, {})) {{break;}}", op_a, instruction.next),
             OP_CALLF ..= OP_CALLFIII => self.output_callf_unsafe(instruction, operands),
-            OP_GETIOSYS => self.output_double_storer_unsafe(instruction, String::from("stream_get_iosys(&temp0, &temp1)")),
+            OP_GETIOSYS => self.output_double_storer_unsafe(function, instruction, String::from("stream_get_iosys(&temp0, &temp1)")),
             OP_RESTART => String::from("vm_restart(); break"),
             OP_SAVE => format!("OP_SAVE({}, {}, {}, {})", op_a, instruction.next, storer_type(instruction.storer), self.storer_value(instruction.storer)),
             OP_RESTORE => format!("if (OP_RESTORE({}, {}, {})) {{break;}}", op_a, storer_type(instruction.storer), self.storer_value(instruction.storer)),
             OP_SAVEUNDO => format!("OP_SAVEUNDO({}, {}, {})", instruction.next, storer_type(instruction.storer), self.storer_value(instruction.storer)),
             OP_RESTOREUNDO => format!("if (OP_RESTOREUNDO({}, {})) {{break;}}", storer_type(instruction.storer), self.storer_value(instruction.storer)),
             OP_QUIT => String::from("return 1"),
-            OP_FMOD => self.output_double_storer_unsafe(instruction, format_safe_stack_pops_expression("OP_FMOD({}, {}, &temp0, &temp1)", &operands)),
-            _ => self.output_storer_unsafe(instruction.storer, self.output_common_instruction(instruction, operands)),
+            OP_FMOD => self.output_double_storer_unsafe(function, instruction, format_safe_stack_pops_expression("OP_FMOD({}, {}, &temp0, &temp1)", &operands)),
+            OP_NUMTOD | OP_FTOD | OP_DCEIL ..= OP_DATAN2 => self.output_double_storer_unsafe(function, instruction, self.output_common_instruction(instruction, operands)),
+            _ => self.output_storer_unsafe(function, instruction.storer, self.output_common_instruction(instruction, operands)),
         };
-        self.output_branch_unsafe(instruction, body)
+        self.output_branch_unsafe(function, instruction, body)
     }
 
     // Map operands into strings
-    fn map_operands_unsafe(&self, instruction: &Instruction) -> Vec<String> {
-        instruction.operands.iter().map(|&operand| self.output_operand_unsafe(operand)).collect()
+    fn map_operands_unsafe(&self, function: &Function, instruction: &Instruction) -> Vec<String> {
+        instruction.operands.iter().map(|&operand| self.output_operand_unsafe(function, operand)).collect()
     }
 
-    fn output_operand_unsafe(&self, operand: Operand) -> String {
+    fn output_operand_unsafe(&self, function: &Function, operand: Operand) -> String {
         match operand {
             Constant(val) => val.to_string(),
-            Memory(addr) => format!("Mem4({})", addr),
+            Memory(addr) => format!("Mem4({})", self.memory_address(addr)),
             Stack => String::from("PopStack()"),
-            Local(addr) => format!("ReadLocal({})", addr),
-            RAM(addr) => format!("Mem4({})", addr + self.ramstart),
+            Local(addr) => match local_size(function, addr) {
+                1 => format!("Stk1({} + localsbase)", self.local_offset(function, addr)),
+                2 => format!("Stk2({} + localsbase)", self.local_offset(function, addr)),
+                _ => format!("ReadLocal({})", self.local_offset(function, addr)),
+            },
+            RAM(addr) => format!("Mem4({})", self.memory_address(addr + self.ramstart)),
         }
     }
 
-    fn output_storer_unsafe(&self, storer: Operand, inner: String) -> String {
+    fn output_storer_unsafe(&self, function: &Function, storer: Operand, inner: String) -> String {
         match storer {
             Constant(_) => inner, // Must still output the inner code in case there are side-effects
-            Memory(addr) => format!("store_operand(1, {}, {})", addr, inner),
+            Memory(addr) => format!("store_operand(1, {}, {})", self.memory_address(addr), inner),
             Stack => format!("PushStack({})", inner),
-            Local(addr) => format!("store_operand(2, {}, {})", addr, inner),
-            RAM(addr) => format!("store_operand(1, {}, {})", addr + self.ramstart, inner),
+            Local(addr) => format!("{}(2, {}, {})", local_store_function(function, addr), self.local_offset(function, addr), inner),
+            RAM(addr) => format!("store_operand(1, {}, {})", self.memory_address(addr + self.ramstart), inner),
         }
     }
 
-    fn output_double_storer_unsafe(&self, instruction: &Instruction, inner: String) -> String {
+    fn output_double_storer_unsafe(&self, function: &Function, instruction: &Instruction, inner: String) -> String {
         let store = |storer: Operand, i: u32| {
             match storer {
                 Constant(_) => String::from("NULL"),
-                Memory(addr) => format!("store_operand(1, {}, temp{})", addr, i),
+                Memory(addr) => format!("store_operand(1, {}, temp{})", self.memory_address(addr), i),
                 Stack => format!("PushStack(temp{})", i),
-                Local(addr) => format!("store_operand(2, {}, temp{})", addr, i),
-                RAM(addr) => format!("store_operand(1, {}, temp{})", addr + self.ramstart, i),
+                Local(addr) => format!("{}(2, {}, temp{})", local_store_function(function, addr), self.local_offset(function, addr), i),
+                RAM(addr) => format!("store_operand(1, {}, temp{})", self.memory_address(addr + self.ramstart), i),
             }
         };
         format!("{}; {}; {}", inner, store(instruction.storer, 0), store(instruction.storer2, 1))
     }
 
-    fn output_branch_unsafe(&self, instruction: &Instruction, condition: String) -> String {
+    fn output_branch_unsafe(&self, function: &Function, instruction: &Instruction, condition: String) -> String {
         use opcodes::*;
         match instruction.branch {
             None => condition,
             Some(target) => match instruction.opcode {
                 OP_CATCH => condition,
-                OP_JUMP => format!("{}; break", self.output_branch_action_unsafe(instruction, target)),
-                OP_JUMPABS => format!("pc = {}; break", self.output_operand_unsafe(*instruction.operands.last().unwrap())),
-                _ => format!("if ({}) {{{}; break;}}", condition, self.output_branch_action_unsafe(instruction, target)),
+                OP_JUMP => format!("{}; break", self.output_branch_action_unsafe(function, instruction, target)),
+                OP_JUMPABS => format!("pc = {}; break", self.output_operand_unsafe(function, *instruction.operands.last().unwrap())),
+                _ => format!("if ({}) {{{}; break;}}", condition, self.output_branch_action_unsafe(function, instruction, target)),
             },
         }
     }
 
-    fn output_branch_action_unsafe(&self, instruction: &Instruction, branch: BranchTarget) -> String {
+    fn output_branch_action_unsafe(&self, function: &Function, instruction: &Instruction, branch: BranchTarget) -> String {
         use BranchTarget::*;
         match branch {
-            Dynamic => format!("if (VM_BRANCH({}, {})) {{return 1;}}", self.output_operand_unsafe(*instruction.operands.last().unwrap()), instruction.next),
+            Dynamic => format!("if (VM_BRANCH({}, {})) {{return 1;}}", self.output_operand_unsafe(function, *instruction.operands.last().unwrap()), instruction.next),
             Absolute(addr) => format!("pc = {}", addr),
             Return(val) => format!("temp0 = {}; leave_function(); if (stackptr == 0) {{return 1;}} pop_callstub(temp0)", val),
         }
@@ -230,7 +250,7 @@ impl GlulxOutput {
 
     fn output_call_unsafe(&self, operands: &Vec<String>, instruction: &Instruction) -> String {
         let (prelude, new_operands) = safe_stack_pops(operands, false);
-        let prelude_out = if prelude == "" { String::new() } else { format!("{}; ", prelude) };
+        let prelude_out = if prelude.is_empty() { String::new() } else { format!("{}; ", prelude) };
         format!("{}if (VM_CALL_FUNCTION({}, {}, {}, {}, {})) {{break;}}", prelude_out, new_operands[0], new_operands[1], storer_type(instruction.storer), self.storer_value(instruction.storer), instruction.next)
     }
 
@@ -243,41 +263,53 @@ impl GlulxOutput {
             4 => format!("OP_CALLFIII({}", new_operands.join(", ")),
             _ => unreachable!(),
         };
-        let prelude_out = if prelude == "" { String::new() } else { format!("{}; ", prelude) };
+        let prelude_out = if prelude.is_empty() { String::new() } else { format!("{}; ", prelude) };
         format!("{}if ({}, {}, {}, {})) {{break;}}", prelude_out, inner, storer_type(instruction.storer), self.storer_value(instruction.storer), instruction.next)
     }
 
-    fn output_copys_unsafe(&self, instruction: &Instruction) -> String {
+    fn output_copys_unsafe(&self, function: &Function, instruction: &Instruction) -> String {
         let inner = match instruction.operands[0] {
             Constant(val) => format!("{} & 0xFFFF", val),
-            Memory(addr) => format!("Mem2({})", addr),
+            Memory(addr) => format!("Mem2({})", self.memory_address(addr)),
             Stack => String::from("PopStack() & 0xFFFF"),
-            Local(addr) => format!("Stk2({} + localsbase)", addr),
-            RAM(addr) => format!("Mem2({})", addr + self.ramstart),
+            Local(addr) => format!("Stk2({} + localsbase)", self.local_offset(function, addr)),
+            RAM(addr) => format!("Mem2({})", self.memory_address(addr + self.ramstart)),
         };
         match instruction.operands[1] {
             Constant(_) => inner,
-            Memory(addr) => format!("store_operand_s(1, {}, {})", addr, inner),
+            Memory(addr) => format!("store_operand_s(1, {}, {})", self.memory_address(addr), inner),
             Stack => format!("PushStack({})", inner),
-            Local(addr) => format!("store_operand_s(2, {}, {})", addr, inner),
-            RAM(addr) => format!("store_operand_s(1, {}, {})", addr + self.ramstart, inner),
+            Local(addr) => format!("store_operand_s(2, {}, {})", self.local_offset(function, addr), inner),
+            RAM(addr) => format!("store_operand_s(1, {}, {})", self.memory_address(addr + self.ramstart), inner),
         }
     }
 
-    fn output_copyb_unsafe(&self, instruction: &Instruction) -> String {
+    fn output_copyb_unsafe(&self, function: &Function, instruction: &Instruction) -> String {
         let inner = match instruction.operands[0] {
             Constant(val) => format!("{} & 0xFF", val),
-            Memory(addr) => format!("Mem1({})", addr),
+            Memory(addr) => format!("Mem1({})", self.memory_address(addr)),
             Stack => String::from("PopStack() & 0xFF"),
-            Local(addr) => format!("Stk1({} + localsbase)", addr),
-            RAM(addr) => format!("Mem1({})", addr + self.ramstart),
+            Local(addr) => format!("Stk1({} + localsbase)", self.local_offset(function, addr)),
+            RAM(addr) => format!("Mem1({})", self.memory_address(addr + self.ramstart)),
         };
         match instruction.operands[1] {
             Constant(_) => inner,
-            Memory(addr) => format!("store_operand_b(1, {}, {})", addr, inner),
+            Memory(addr) => format!("store_operand_b(1, {}, {})", self.memory_address(addr), inner),
             Stack => format!("PushStack({})", inner),
-            Local(addr) => format!("store_operand_b(2, {}, {})", addr, inner),
-            RAM(addr) => format!("store_operand_b(1, {}, {})", addr + self.ramstart, inner),
+            Local(addr) => format!("store_operand_b(2, {}, {})", self.local_offset(function, addr), inner),
+            RAM(addr) => format!("store_operand_b(1, {}, {})", self.memory_address(addr + self.ramstart), inner),
+        }
+    }
+
+    // A local's offset, with its Inform identifier if we know it
+    fn local_offset(&self, function: &Function, offset: u32) -> String {
+        let identifier = match function.local_at(offset) {
+            Some(index) if function.locals[index].offset == offset => self.local_identifier(function, index),
+            _ => None,
+        };
+        match identifier {
+            Some(identifier) => format!("{} /* {} */", offset, identifier),
+            None => offset.to_string(),
         }
     }
 
@@ -290,6 +322,22 @@ impl GlulxOutput {
     }
 }
 
+// The size of the local at an offset, defaulting to 4 like Glulxe does for offsets which aren't at the start of a local
+fn local_size(function: &Function, offset: u32) -> u8 {
+    match function.local_at(offset) {
+        Some(index) if function.locals[index].offset == offset => function.locals[index].size,
+        _ => 4,
+    }
+}
+
+fn local_store_function(function: &Function, offset: u32) -> &'static str {
+    match local_size(function, offset) {
+        1 => "store_operand_b",
+        2 => "store_operand_s",
+        _ => "store_operand",
+    }
+}
+
 fn storer_type(storer: Operand) -> u32 {
     match storer {
         Constant(_) => 0,
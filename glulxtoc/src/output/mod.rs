@@ -9,14 +9,18 @@ https://github.com/curiousdannii/if-decompiler
 
 */
 
+use std::collections::BTreeMap;
 use std::fs;
 use std::io;
+use std::io::prelude::*;
 use std::path::PathBuf;
 
 use dyn_fmt::AsStrFormatExt;
+use fnv::FnvHashMap;
 
 use if_decompiler::*;
-use glulx::GlulxState;
+use glulx::{Function, GlulxState};
+use inform_debug::{DebugData, SourceLocation};
 
 mod files;
 mod functions_common;
@@ -25,18 +29,27 @@ mod functions_unsafe;
 //mod image;
 
 pub struct GlulxOutput {
-    pub disassemble_mode: bool,
-    pub file_length: u32,
+    // Global variable addresses, with their C name and Inform identifier
+    pub globals: BTreeMap<u32, (String, String)>,
+    // Whether to output #line directives pointing back to the Inform source code
+    pub line_directives: bool,
+    // The Inform identifiers of each function's locals
+    pub local_identifiers: FnvHashMap<u32, Vec<Option<String>>>,
     pub name: String,
     pub out_dir: PathBuf,
     pub ramstart: u32,
+    // A blorb file with the storyfile's resources, but not the storyfile itself
+    pub resources: Option<Vec<u8>>,
     pub safe_functions: Vec<u32>,
+    // The source code locations of each statement, and the paths of the source files
+    pub sequence_points: BTreeMap<u32, SourceLocation>,
+    pub source_paths: FnvHashMap<u32, String>,
     pub state: GlulxState,
     pub unsafe_functions: Vec<u32>,
 }
 
 impl GlulxOutput {
-    pub fn new(disassemble_mode: bool, file_length: u32, name: String, out_dir: PathBuf, state: GlulxState) -> GlulxOutput {
+    pub fn new(disassemble_mode: bool, name: String, out_dir: PathBuf, resources: Option<Vec<u8>>, debug_data: Option<&DebugData>, state: GlulxState) -> GlulxOutput {
         let mut safe_functions = Vec::new();
         let mut unsafe_functions = Vec::new();
         for (&addr, function) in &state.functions {
@@ -47,13 +60,52 @@ impl GlulxOutput {
                 unsafe_functions.push(addr);
             }
         }
+
+        // Get the symbolic names from the debug file
+        let mut globals = BTreeMap::new();
+        let mut local_identifiers = FnvHashMap::default();
+        let mut sequence_points = BTreeMap::new();
+        let mut source_paths = FnvHashMap::default();
+        if let Some(debug_data) = debug_data {
+            let mut used_names = Vec::new();
+            for global in &debug_data.globals {
+                let mut c_name = format!("GLOBAL_{}", sanitise_identifier(&global.name));
+                // Sanitising could make two names the same
+                if used_names.contains(&c_name) {
+                    c_name = format!("{}_{}", c_name, global.addr);
+                }
+                used_names.push(c_name.clone());
+                globals.insert(global.addr, (c_name, global.name.clone()));
+            }
+            for (addr, function) in &state.functions {
+                if let Some(routine) = debug_data.routines.get(addr) {
+                    // Glulx locals should have frame offsets, but fall back to their (1-based) index
+                    let identifiers = function.locals.iter().enumerate().map(|(index, local)| routine.locals.iter()
+                        .find(|debug_local| debug_local.frame_offset == Some(local.offset) || (debug_local.frame_offset.is_none() && debug_local.index == Some(index as u32 + 1)))
+                        .map(|debug_local| debug_local.name.clone())).collect();
+                    local_identifiers.insert(*addr, identifiers);
+                    for point in &routine.sequence_points {
+                        sequence_points.insert(point.addr, point.location);
+                    }
+                }
+            }
+            for (&index, source) in &debug_data.sources {
+                let path = source.resolved_path.as_ref().unwrap_or(&source.given_path);
+                source_paths.insert(index, path.replace('\\', "\\\\").replace('"', "\\\""));
+            }
+        }
+
         GlulxOutput {
-            disassemble_mode,
-            file_length,
+            globals,
+            line_directives: false,
+            local_identifiers,
             name,
             out_dir,
             ramstart: state.ramstart,
+            resources,
             safe_functions,
+            sequence_points,
+            source_paths,
             state,
             unsafe_functions,
         }
@@ -64,11 +116,64 @@ impl GlulxOutput {
         fs::create_dir_all(&self.out_dir)?;
 
         self.output_from_templates(file)?;
+        self.output_globals()?;
         self.output_safe_functions()?;
         self.output_unsafe_functions()?;
         Ok(())
     }
 
+    // The address of some memory, using the name of the global variable there if there is one
+    fn memory_address(&self, addr: u32) -> String {
+        match self.globals.get(&addr) {
+            Some((c_name, _)) => c_name.clone(),
+            None => addr.to_string(),
+        }
+    }
+
+    // The Inform identifier of a local
+    fn local_identifier(&self, function: &Function, index: usize) -> Option<&String> {
+        self.local_identifiers.get(&function.addr).and_then(|identifiers| identifiers[index].as_ref())
+    }
+
+    // A comment listing the Inform identifiers of a function's locals
+    fn locals_comment(&self, function: &Function) -> String {
+        match self.local_identifiers.get(&function.addr) {
+            Some(identifiers) if identifiers.iter().any(|identifier| identifier.is_some()) => {
+                let identifiers: Vec<&str> = identifiers.iter().map(|identifier| identifier.as_deref().unwrap_or("?")).collect();
+                format!("// Locals: {}\n", identifiers.join(", "))
+            },
+            _ => String::new(),
+        }
+    }
+
+    // A #line directive pointing to the source code of an instruction
+    fn line_directive(&self, function: &Function, addr: u32) -> Option<String> {
+        if !self.line_directives {
+            return None;
+        }
+        let (&point_addr, location) = self.sequence_points.range(..=addr).next_back()?;
+        if point_addr < function.addr {
+            return None;
+        }
+        let path = self.source_paths.get(&location.file_index)?;
+        Some(format!("#line {} \"{}\"", location.line, path))
+    }
+
+    // Whether any #line directives will be output for a function
+    fn has_line_directives(&self, function: &Function) -> bool {
+        self.line_directives && self.sequence_points.range(function.addr..function.end_addr).next().is_some()
+    }
+
+    // Output the global variable names
+    fn output_globals(&self) -> io::Result<()> {
+        let mut file = self.make_file("globals.h")?;
+        writeln!(file, "// Global variables from the Inform debug file")?;
+        for (addr, (c_name, identifier)) in &self.globals {
+            writeln!(file, "#define {} {} /* {} */", c_name, addr, identifier)?;
+        }
+        Ok(())
+    }
+
     // A little helper function for making files in the output dir
     fn make_file(&self, name: &str) -> io::Result<io::BufWriter<fs::File>> {
         let mut path = self.out_dir.clone();
@@ -76,6 +181,66 @@ impl GlulxOutput {
         let file = fs::File::create(path)?;
         Ok(io::BufWriter::new(file))
     }
+
+    // Make a C file which may have #line directives
+    fn make_code_file(&self, name: &str) -> io::Result<CodeWriter> {
+        Ok(CodeWriter {
+            file: self.make_file(name)?,
+            last_directive: None,
+            line: 0,
+            name: name.to_string(),
+        })
+    }
+}
+
+// A file writer which counts lines, so that after a function with #line directives we can point back to the generated code
+pub struct CodeWriter {
+    file: io::BufWriter<fs::File>,
+    last_directive: Option<String>,
+    line: usize,
+    name: String,
+}
+
+impl CodeWriter {
+    fn reset_line_directive(&mut self) -> io::Result<()> {
+        self.last_directive = None;
+        // The directive gives the number of the line after itself
+        let line = self.line + 2;
+        let name = self.name.clone();
+        writeln!(self, "#line {} \"{}\"", line, name)
+    }
+
+    // Write some code, skipping any #line directives which wouldn't change the source location
+    fn write_code(&mut self, code: &str) -> io::Result<()> {
+        for line in code.split_inclusive('\n') {
+            let trimmed = line.trim();
+            if trimmed.starts_with("#line ") {
+                if self.last_directive.as_deref() == Some(trimmed) {
+                    continue;
+                }
+                self.last_directive = Some(trimmed.to_string());
+            }
+            self.write_all(line.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for CodeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.line += buf[..written].iter().filter(|&&byte| byte == b'\n').count();
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Turn an Inform identifier into something which can be used in a C identifier
+fn sanitise_identifier(identifier: &str) -> String {
+    identifier.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
 }
 
 // C says that the order function arguments are evaluated is undefined, which breaks stack pops
@@ -116,7 +281,7 @@ fn safe_stack_pops(operands: &Vec<String>, in_macro: bool) -> (String, Vec<Strin
 // And then a function to use the above with a format string for an expression
 fn format_safe_stack_pops_expression(format: &str, operands: &Vec<String>) -> String {
     let (prelude, new_operands) = safe_stack_pops(operands, false);
-    if prelude == "" {
+    if prelude.is_empty() {
         return format.format(operands);
     }
     format!("({}, {})", prelude, format.format(&new_operands))
@@ -125,7 +290,7 @@ fn format_safe_stack_pops_expression(format: &str, operands: &Vec<String>) -> St
 // Now an expression that uses a macro (such as Mem4)
 fn format_safe_stack_pops_macro(format: &str, operands: &Vec<String>) -> String {
     let (prelude, new_operands) = safe_stack_pops(operands, true);
-    if prelude == "" {
+    if prelude.is_empty() {
         return format.format(operands);
     }
     format!("({}, {})", prelude, format.format(&new_operands))
@@ -134,7 +299,7 @@ fn format_safe_stack_pops_macro(format: &str, operands: &Vec<String>) -> String
 // And the same but for a statement
 fn format_safe_stack_pops_statement(format: &str, operands: &Vec<String>) -> String {
     let (prelude, new_operands) = safe_stack_pops(operands, false);
-    if prelude == "" {
+    if prelude.is_empty() {
         return format.format(operands);
     }
     format!("{}; {}", prelude, format.format(&new_operands))
@@ -33,6 +33,9 @@ extern glui32 VM_CALL_SAFE_FUNCTION_WITH_STACK_ARGS(glui32 addr, glui32 count);
 #define GLULX_IMAGE_LENGTH IMAGE_LENGTH_VALUE
 extern char _binary_image_data_start[];
 #define GLULX_IMAGE _binary_image_data_start
+#define GLULX_RESOURCES_LENGTH RESOURCES_LENGTH_VALUE
+extern char _binary_resources_data_start[];
+#define GLULX_RESOURCES _binary_resources_data_start
 
 // runtime.c
 extern int iosys_mode;
@@ -59,6 +62,8 @@ extern void OP_SAVE(glui32 arg0, glui32 next, glui32 storetype, glui32 storeval)
 extern int OP_RESTORE(glui32 arg0, glui32 storetype, glui32 storeval);
 extern void OP_SAVEUNDO(glui32 next, glui32 storetype, glui32 storeval);
 extern int OP_RESTOREUNDO(glui32 storetype, glui32 storeval);
+extern glui32 OP_HASUNDO(void);
+extern void OP_DISCARDUNDO(void);
 extern int OP_CALLFI(glui32 addr, glui32 arg0, glui32 storetype, glui32 storeval, glui32 next);
 extern int OP_CALLFII(glui32 addr, glui32 arg0, glui32 arg1, glui32 storetype, glui32 storeval, glui32 next);
 extern int OP_CALLFIII(glui32 addr, glui32 arg0, glui32 arg1, glui32 arg2, glui32 storetype, glui32 storeval, glui32 next);
@@ -68,9 +73,18 @@ extern glsi32 OP_FTONUMN(glui32 arg0);
 extern void OP_FMOD(glui32 arg0, glui32 arg1, glui32 *dest0, glui32 *dest1);
 extern glui32 OP_CEIL(glui32 arg0);
 extern glui32 OP_JFEQ(glui32 arg0, glui32 arg1, glui32 arg2);
+extern double VM_DECODE_DOUBLE(glui32 hi, glui32 lo);
+extern void VM_ENCODE_DOUBLE(double val, glui32 *lo, glui32 *hi);
+extern glsi32 OP_DTONUMZ(glui32 arg0, glui32 arg1);
+extern glsi32 OP_DTONUMN(glui32 arg0, glui32 arg1);
+extern void OP_DCEIL(glui32 arg0, glui32 arg1, glui32 *dest0, glui32 *dest1);
+extern void OP_DMODQ(glui32 arg0, glui32 arg1, glui32 arg2, glui32 arg3, glui32 *dest0, glui32 *dest1);
+extern void OP_DPOW(glui32 arg0, glui32 arg1, glui32 arg2, glui32 arg3, glui32 *dest0, glui32 *dest1);
+extern glui32 OP_JDEQ(glui32 arg0, glui32 arg1, glui32 arg2, glui32 arg3, glui32 arg4, glui32 arg5);
 extern glui32 PopStack(void);
 extern void PushStack(glui32 storeval);
 extern int VM_BRANCH(glui32 offset, glui32 next);
+extern void VM_CHECK_RAM_FUNCTION(glui32 addr, glui32 len);
 extern int VM_CALL_FUNCTION(glui32 addr, glui32 count, glui32 storetype, glui32 storeval, glui32 next);
 extern int VM_JUMP_CALL(glui32 pc);
 extern void VM_TAILCALL_FUNCTION(glui32 addr, glui32 count);
\ No newline at end of file
@@ -14,6 +14,8 @@ https://github.com/curiousdannii/if-decompiler
 #include "glulxe.h"
 #include "glulxtoc.h"
 #include <math.h>
+#include <stdint.h>
+#include <string.h>
 
 int iosys_mode;
 
@@ -350,17 +352,30 @@ int OP_RESTORE(glui32 arg0, glui32 storetype, glui32 storeval) {
     }
 }
 
+// perform_hasundo() and perform_discardundo() were added in glulxe 0.6.0
+// For older versions we count the undo states ourselves, though a discarded state can only be hidden, not removed from glulxe's undo chain
+extern glui32 perform_hasundo(void) __attribute__((weak));
+extern glui32 perform_discardundo(void) __attribute__((weak));
+static glui32 undo_states = 0;
+
 void OP_SAVEUNDO(glui32 next, glui32 storetype, glui32 storeval) {
     pc = next;
     push_callstub(storetype, storeval);
-    pop_callstub(perform_saveundo());
+    glui32 value = perform_saveundo();
+    if (value == 0) {
+        undo_states++;
+    }
+    pop_callstub(value);
 }
 
 int OP_RESTOREUNDO(glui32 storetype, glui32 storeval) {
-    glui32 value = perform_restoreundo();
+    glui32 value = (perform_hasundo || undo_states) ? perform_restoreundo() : 1;
     if (value == 0) {
         /* We've succeeded, and the stack now contains the callstub
             saved during saveundo. Ignore this opcode's operand. */
+        if (undo_states) {
+            undo_states--;
+        }
         value = -1;
         pop_callstub(value);
         return 1;
@@ -368,11 +383,28 @@ int OP_RESTOREUNDO(glui32 storetype, glui32 storeval) {
     else {
         /* We've failed, so we must store the failure in this opcode's
             operand. */
+        undo_states = 0;
         store_operand(storetype, storeval, value);
         return 0;
     }
 }
 
+glui32 OP_HASUNDO(void) {
+    if (perform_hasundo) {
+        return perform_hasundo();
+    }
+    return undo_states ? 0 : 1;
+}
+
+void OP_DISCARDUNDO(void) {
+    if (perform_discardundo) {
+        perform_discardundo();
+    }
+    else if (undo_states) {
+        undo_states--;
+    }
+}
+
 int OP_CALLFI(glui32 addr, glui32 arg0, glui32 storetype, glui32 storeval, glui32 next) {
     PushStack(arg0);
     return VM_CALL_FUNCTION(addr, 1, storetype, storeval, next);
@@ -492,6 +524,106 @@ glui32 OP_JFEQ(glui32 arg0, glui32 arg1, glui32 arg2) {
     }
 }
 
+// Doubles are passed as two words, high word first
+double VM_DECODE_DOUBLE(glui32 hi, glui32 lo) {
+    uint64_t bits = ((uint64_t) hi << 32) | lo;
+    double val;
+    memcpy(&val, &bits, sizeof(val));
+    return val;
+}
+
+// But they are stored low word first
+void VM_ENCODE_DOUBLE(double val, glui32 *lo, glui32 *hi) {
+    uint64_t bits;
+    memcpy(&bits, &val, sizeof(bits));
+    *lo = (glui32) bits;
+    *hi = (glui32) (bits >> 32);
+}
+
+glsi32 OP_DTONUMZ(glui32 arg0, glui32 arg1) {
+    double vald = VM_DECODE_DOUBLE(arg0, arg1);
+    if (!signbit(vald)) {
+        if (isnan(vald) || isinf(vald) || (vald > 2147483647.0)) {
+            return 0x7FFFFFFF;
+        } else {
+            return (glsi32) (trunc(vald));
+        }
+    } else {
+        if (isnan(vald) || isinf(vald) || (vald < -2147483648.0)) {
+            return 0x80000000;
+        } else {
+            return (glsi32) (trunc(vald));
+        }
+    }
+}
+
+glsi32 OP_DTONUMN(glui32 arg0, glui32 arg1) {
+    double vald = VM_DECODE_DOUBLE(arg0, arg1);
+    if (!signbit(vald)) {
+        if (isnan(vald) || isinf(vald) || (vald > 2147483647.0)) {
+            return 0x7FFFFFFF;
+        } else {
+            return (glsi32) (round(vald));
+        }
+    } else {
+        if (isnan(vald) || isinf(vald) || (vald < -2147483648.0)) {
+            return 0x80000000;
+        } else {
+            return (glsi32) (round(vald));
+        }
+    }
+}
+
+void OP_DCEIL(glui32 arg0, glui32 arg1, glui32 *dest0, glui32 *dest1) {
+    VM_ENCODE_DOUBLE(ceil(VM_DECODE_DOUBLE(arg0, arg1)), dest0, dest1);
+    if (*dest0 == 0x0 && (*dest1 == 0x0 || *dest1 == 0x80000000)) {
+        /* When the result is zero, the sign may have been lost in the
+            shuffle. (This is a bug in some C libraries.) We'll set the
+            sign by hand, based on the original argument. */
+        *dest1 = arg0 & 0x80000000;
+    }
+}
+
+void OP_DMODQ(glui32 arg0, glui32 arg1, glui32 arg2, glui32 arg3, glui32 *dest0, glui32 *dest1) {
+    double vald1 = VM_DECODE_DOUBLE(arg0, arg1);
+    double vald2 = VM_DECODE_DOUBLE(arg2, arg3);
+    double vald = fmod(vald1, vald2);
+    VM_ENCODE_DOUBLE((vald1 - vald) / vald2, dest0, dest1);
+    if (*dest0 == 0x0 && (*dest1 == 0x0 || *dest1 == 0x80000000)) {
+        /* When the quotient is zero, the sign has been lost in the
+            shuffle. We'll set that by hand, based on the original
+            arguments. */
+        *dest1 = (arg0 ^ arg2) & 0x80000000;
+    }
+}
+
+void OP_DPOW(glui32 arg0, glui32 arg1, glui32 arg2, glui32 arg3, glui32 *dest0, glui32 *dest1) {
+    double vald1 = VM_DECODE_DOUBLE(arg0, arg1);
+    double vald2 = VM_DECODE_DOUBLE(arg2, arg3);
+    /* Some C libraries get these special cases wrong, so handle them here */
+    if (vald1 == 1.0 || vald2 == 0.0 || (vald1 == -1.0 && isinf(vald2))) {
+        VM_ENCODE_DOUBLE(1.0, dest0, dest1);
+    } else {
+        VM_ENCODE_DOUBLE(pow(vald1, vald2), dest0, dest1);
+    }
+}
+
+glui32 OP_JDEQ(glui32 arg0, glui32 arg1, glui32 arg2, glui32 arg3, glui32 arg4, glui32 arg5) {
+    if ((arg4 & 0x7FF00000) == 0x7FF00000 && ((arg4 & 0x000FFFFF) != 0 || arg5 != 0)) {
+        /* The delta is NaN, which can never match. */
+        return 0;
+    } else if ((arg0 == 0x7FF00000 || arg0 == 0xFFF00000) && arg1 == 0
+        && (arg2 == 0x7FF00000 || arg2 == 0xFFF00000) && arg3 == 0) {
+        /* Both are infinite. Opposite infinities are never equal,
+            even if the difference is infinite, so this is easy. */
+        return (arg0 == arg2);
+    } else {
+        double vald1 = VM_DECODE_DOUBLE(arg2, arg3) - VM_DECODE_DOUBLE(arg0, arg1);
+        double vald2 = fabs(VM_DECODE_DOUBLE(arg4, arg5));
+        return (vald1 <= vald2 && vald1 >= -vald2);
+    }
+}
+
 glui32 PopStack(void) {
     if (stackptr < valstackbase + 4) {
         fatal_error("Stack underflow in operand.");
@@ -523,6 +655,14 @@ int VM_BRANCH(glui32 offset, glui32 next) {
     return 0;
 }
 
+// Functions in RAM were decompiled from the original storyfile, so check they haven't been modified since
+// There is no interpreter to run a modified function with instead, so this has to be fatal
+void VM_CHECK_RAM_FUNCTION(glui32 addr, glui32 len) {
+    if (memcmp(memmap + addr, GLULX_IMAGE + addr, len) != 0) {
+        fatal_error_i("Function in RAM has been modified at runtime, which glulxtoc cannot support:", addr);
+    }
+}
+
 int VM_CALL_FUNCTION(glui32 addr, glui32 count, glui32 storetype, glui32 storeval, glui32 next) {
     if (VM_FUNC_IS_SAFE(addr)) {
         glui32 result, oldsp, oldvsb, res;
@@ -541,6 +681,31 @@ int VM_CALL_FUNCTION(glui32 addr, glui32 count, glui32 storetype, glui32 storeva
 }
 
 // Try to recover from an invalid unsafe PC by seeing if we can call a safe function
+// Find a local in the current call frame by reading the frame's locals format
+// Returns the local's offset and size, or the number of locals if the index is too high
+static glui32 VM_FRAME_LOCAL(glui32 index, glui32 *size) {
+    glui32 format = frameptr + 8;
+    glui32 offset = 0;
+    glui32 total = 0;
+    while (1) {
+        glui32 type = Stk1(format);
+        glui32 count = Stk1(format + 1);
+        format += 2;
+        if (type == 0) {
+            return total;
+        }
+        // Locals are aligned to their own size
+        offset = (offset + type - 1) & ~(type - 1);
+        if (index < count) {
+            *size = type;
+            return offset + index * type;
+        }
+        index -= count;
+        offset += count * type;
+        total += count;
+    }
+}
+
 int VM_JUMP_CALL(glui32 pc) {
     // The PC we've been given is the beginning of a function's code
     // The header is variable length though, so call a helper function to find the function address
@@ -553,11 +718,12 @@ int VM_JUMP_CALL(glui32 pc) {
         }
         // Or push the locals in reverse order for regular functions
         else {
-            glui32 locals = valstackbase - localsbase;
-            count = locals / 4;
-            while (locals > 0) {
-                locals -= 4;
-                PushStack(ReadLocal(locals));
+            glui32 index;
+            count = VM_FRAME_LOCAL(0xFFFFFFFF, NULL);
+            for (index = count; index > 0; index--) {
+                glui32 size;
+                glui32 addr = VM_FRAME_LOCAL(index - 1, &size) + localsbase;
+                PushStack(size == 4 ? Stk4(addr) : size == 2 ? Stk2(addr) : Stk1(addr));
             }
         }
         VM_TAILCALL_FUNCTION(pc, count);
@@ -64,6 +64,13 @@ int glkunix_startup_code(glkunix_startup_t *data)
   if (buf[0] == 'G' && buf[1] == 'l' && buf[2] == 'u' && buf[3] == 'l') {
     /* Load game directly from file. */
     locate_gamefile(FALSE);
+    /* The image doesn't include the Blorb resources, so load them separately. */
+    if (GLULX_RESOURCES_LENGTH) {
+      strid_t resources = glk_stream_open_memory(GLULX_RESOURCES, GLULX_RESOURCES_LENGTH, filemode_Read, 2);
+      if (!resources || giblorb_set_resource_map(resources)) {
+        init_err = "The game's resources could not be loaded.";
+      }
+    }
     return TRUE;
   }
   else if (buf[0] == 'F' && buf[1] == 'O' && buf[2] == 'R' && buf[3] == 'M'
@@ -0,0 +1,43 @@
+/*
+
+Blorb Errors
+============
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::error::Error;
+use std::fmt;
+
+use super::*;
+
+// Something wrong with the structure of a Blorb file
+// Offsets are of the chunk with the problem
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlorbError {
+    ChunkOutOfBounds { offset: u32, chunk_type: u32 },
+    InvalidChunk { offset: u32, chunk_type: u32, reason: &'static str },
+    InvalidResourceOffset { usage: u32, number: u32, offset: u32 },
+    MissingResourceIndex,
+    NotABlorb,
+    UnknownResourceUsage { usage: u32 },
+}
+
+impl fmt::Display for BlorbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use BlorbError::*;
+        match self {
+            ChunkOutOfBounds { offset, chunk_type } => write!(f, "{} chunk at {} extends past the end of the file", chunk_type_name(*chunk_type), offset),
+            InvalidChunk { offset, chunk_type, reason } => write!(f, "Invalid {} chunk at {}: {}", chunk_type_name(*chunk_type), offset, reason),
+            InvalidResourceOffset { usage, number, offset } => write!(f, "Resource {} {} points to {}, which is not the start of a chunk", chunk_type_name(*usage), number, offset),
+            MissingResourceIndex => write!(f, "Blorb file does not have a resource index (RIdx) chunk"),
+            NotABlorb => write!(f, "Not a Blorb file"),
+            UnknownResourceUsage { usage } => write!(f, "Unknown resource usage {}", chunk_type_name(*usage)),
+        }
+    }
+}
+
+impl Error for BlorbError {}
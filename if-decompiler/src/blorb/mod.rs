@@ -0,0 +1,547 @@
+/*
+
+Blorb
+=====
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::borrow::Cow;
+
+use fnv::FnvHashMap;
+
+mod error;
+pub use error::*;
+
+// IFF types
+pub const FORM: u32 = 0x464F524D;
+pub const IFRS: u32 = 0x49465253;
+
+// Resource usages
+pub const USAGE_DATA: u32 = 0x44617461; // Data
+pub const USAGE_EXEC: u32 = 0x45786563; // Exec
+pub const USAGE_PICT: u32 = 0x50696374; // Pict
+pub const USAGE_SND: u32 = 0x536E6420; // Snd
+
+// Executable chunks
+pub const ADRI: u32 = 0x41445249;
+pub const ADVS: u32 = 0x41445653;
+pub const AGT: u32 = 0x41475420;
+pub const ALAN: u32 = 0x414C414E;
+pub const EXEC: u32 = 0x45584543;
+pub const GLUL: u32 = 0x474C554C;
+pub const HUGO: u32 = 0x4855474F;
+pub const LEVE: u32 = 0x4C455645;
+pub const MAGS: u32 = 0x4D414753;
+pub const TAD2: u32 = 0x54414432;
+pub const TAD3: u32 = 0x54414433;
+pub const ZCOD: u32 = 0x5A434F44;
+
+// Picture chunks
+pub const JPEG: u32 = 0x4A504547;
+pub const PNG: u32 = 0x504E4720;
+pub const RECT: u32 = 0x52656374;
+
+// Sound chunks
+pub const AIFF: u32 = 0x41494646;
+pub const MOD: u32 = 0x4D4F4420;
+pub const OGGV: u32 = 0x4F474756;
+pub const SONG: u32 = 0x534F4E47;
+
+// Data chunks
+pub const BINA: u32 = 0x42494E41;
+pub const TEXT: u32 = 0x54455854;
+
+// Other chunks
+pub const ANNO: u32 = 0x414E4E4F;
+pub const APAL: u32 = 0x4150616C;
+pub const AUTH: u32 = 0x41555448;
+pub const COPYRIGHT: u32 = 0x28632920; // (c)
+pub const DBUG: u32 = 0x44627567;
+pub const FSPC: u32 = 0x46737063;
+pub const IFHD: u32 = 0x49466864;
+pub const IFMD: u32 = 0x49466D64;
+pub const PLTE: u32 = 0x506C7465;
+pub const RDES: u32 = 0x52446573;
+pub const RELN: u32 = 0x52656C4E;
+pub const RESL: u32 = 0x5265736C;
+pub const RIDX: u32 = 0x52496478;
+
+// A parsed Blorb file
+pub struct Blorb<'a> {
+    pub chunks: Vec<Chunk<'a>>,
+    pub resources: Vec<Resource>,
+}
+
+pub struct Chunk<'a> {
+    pub chunk_type: u32,
+    // Offset of the chunk header from the start of the file
+    pub offset: u32,
+    // The chunk's data, not including the header
+    pub data: &'a [u8],
+    pub contents: ChunkContents<'a>,
+}
+
+// The contents of each chunk type we know about
+pub enum ChunkContents<'a> {
+    AdaptivePalette(Vec<u32>),
+    // Text chunks which aren't valid UTF-8 are decoded lossily
+    Annotation(Cow<'a, str>),
+    Author(Cow<'a, str>),
+    Copyright(Cow<'a, str>),
+    // Resources of type FORM (AIFF sounds, and FORM data) include their own chunk header
+    Data(&'a [u8]),
+    DebugData(&'a [u8]),
+    Executable(&'a [u8]),
+    Frontispiece(u32),
+    GameIdentifier(&'a [u8]),
+    // A chunk we know about but which couldn't be parsed
+    // Only the resource index must be valid, so other bad chunks don't stop the rest of the Blorb from being used
+    Invalid(BlorbError),
+    Metadata(Cow<'a, str>),
+    Palette(Palette),
+    Picture(&'a [u8]),
+    PicturePlaceholder { width: u32, height: u32 },
+    ReleaseNumber(u16),
+    Resolution(Resolution),
+    ResourceDescriptions(Vec<ResourceDescription>),
+    ResourceIndex(Vec<Resource>),
+    Sound(&'a [u8]),
+    Unknown,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ResourceUsage {
+    Data,
+    Executable,
+    Picture,
+    Sound,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Resource {
+    pub usage: ResourceUsage,
+    pub number: u32,
+    pub offset: u32,
+}
+
+pub struct ResourceDescription {
+    pub usage: ResourceUsage,
+    pub number: u32,
+    pub text: String,
+}
+
+pub enum Palette {
+    Colours(Vec<[u8; 3]>),
+    // Direct colour, with either 16 or 32 bits
+    Direct(u8),
+}
+
+pub struct Resolution {
+    pub standard_width: u32,
+    pub standard_height: u32,
+    pub min_width: u32,
+    pub min_height: u32,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub images: Vec<ImageResolution>,
+}
+
+// Scaling ratios are stored as numerator/denominator pairs
+pub struct ImageResolution {
+    pub number: u32,
+    pub standard_ratio: (u32, u32),
+    pub min_ratio: (u32, u32),
+    pub max_ratio: (u32, u32),
+}
+
+impl<'a> Blorb<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, BlorbError> {
+        if data.len() < 12 || read_u32(data, 0) != FORM || read_u32(data, 8) != IFRS {
+            return Err(BlorbError::NotABlorb);
+        }
+        let form_end = read_u32(data, 4) as usize + 8;
+        if form_end > data.len() {
+            return Err(BlorbError::ChunkOutOfBounds { offset: 0, chunk_type: FORM });
+        }
+
+        // Read each chunk's header
+        let mut chunks = Vec::new();
+        let mut offset = 12;
+        while offset + 8 <= form_end {
+            let chunk_type = read_u32(data, offset);
+            let length = read_u32(data, offset + 4) as usize;
+            let data_start = offset + 8;
+            let data_end = data_start + length;
+            if data_end > form_end {
+                return Err(BlorbError::ChunkOutOfBounds { offset: offset as u32, chunk_type });
+            }
+            // FORM chunks are resources in their own right, so keep their header
+            let resource = if chunk_type == FORM { &data[offset..data_end] } else { &data[data_start..data_end] };
+            let chunk_data = &data[data_start..data_end];
+            chunks.push(Chunk {
+                chunk_type,
+                offset: offset as u32,
+                data: chunk_data,
+                contents: match parse_chunk(offset as u32, chunk_type, chunk_data, resource) {
+                    Ok(contents) => contents,
+                    Err(err) if chunk_type == RIDX => return Err(err),
+                    Err(err) => ChunkContents::Invalid(err),
+                },
+            });
+            // Chunks are padded to an even length
+            offset = data_end + (length & 1);
+        }
+
+        // The resource index should be the first chunk, but be lenient and accept it anywhere
+        let resources = chunks.iter().find_map(|chunk| match &chunk.contents {
+            ChunkContents::ResourceIndex(resources) => Some(resources.clone()),
+            _ => None,
+        }).ok_or(BlorbError::MissingResourceIndex)?;
+
+        let blorb = Blorb {
+            chunks,
+            resources,
+        };
+
+        // Check every resource points to a chunk
+        for resource in &blorb.resources {
+            if blorb.chunk_at(resource.offset).is_none() {
+                return Err(BlorbError::InvalidResourceOffset { usage: resource.usage.into(), number: resource.number, offset: resource.offset });
+            }
+        }
+
+        Ok(blorb)
+    }
+
+    // Get the chunk which starts at an offset
+    pub fn chunk_at(&self, offset: u32) -> Option<&Chunk<'a>> {
+        self.chunks.binary_search_by_key(&offset, |chunk| chunk.offset).ok().map(|index| &self.chunks[index])
+    }
+
+    // Get the first chunk of a type
+    pub fn find_chunk(&self, chunk_type: u32) -> Option<&Chunk<'a>> {
+        self.chunks.iter().find(|chunk| chunk.chunk_type == chunk_type)
+    }
+
+    // Get a resource's chunk
+    pub fn resource(&self, usage: ResourceUsage, number: u32) -> Option<&Chunk<'a>> {
+        self.resources.iter()
+            .find(|resource| resource.usage == usage && resource.number == number)
+            .and_then(|resource| self.chunk_at(resource.offset))
+    }
+
+    // The main executable is Exec resource 0
+    pub fn executable(&self) -> Option<&Chunk<'a>> {
+        self.resource(ResourceUsage::Executable, 0)
+    }
+
+    // Inform debug data, if the compiler was asked to include it
+    pub fn debug_data(&self) -> Option<&'a [u8]> {
+        self.chunks.iter().find_map(|chunk| match chunk.contents {
+            ChunkContents::DebugData(data) => Some(data),
+            _ => None,
+        })
+    }
+
+    pub fn frontispiece(&self) -> Option<u32> {
+        self.chunks.iter().find_map(|chunk| match chunk.contents {
+            ChunkContents::Frontispiece(number) => Some(number),
+            _ => None,
+        })
+    }
+
+    pub fn metadata(&self) -> Option<&str> {
+        self.chunks.iter().find_map(|chunk| match &chunk.contents {
+            ChunkContents::Metadata(xml) => Some(xml.as_ref()),
+            _ => None,
+        })
+    }
+
+    pub fn release_number(&self) -> Option<u16> {
+        self.chunks.iter().find_map(|chunk| match chunk.contents {
+            ChunkContents::ReleaseNumber(release) => Some(release),
+            _ => None,
+        })
+    }
+
+    // Write out a new blorb file with only the chunks which pass the filter
+    // The resource index is rebuilt to match, and will be the first chunk
+    pub fn write<F: Fn(&Chunk) -> bool>(&self, filter: F) -> Vec<u8> {
+        let chunks: Vec<&Chunk> = self.chunks.iter().filter(|chunk| chunk.chunk_type != RIDX && filter(chunk)).collect();
+
+        // Work out where each chunk will now be
+        let kept_resources: Vec<&Resource> = self.resources.iter().filter(|resource| chunks.iter().any(|chunk| chunk.offset == resource.offset)).collect();
+        let index_length = 4 + 12 * kept_resources.len();
+        let mut offset = 12 + 8 + index_length;
+        let mut new_offsets = FnvHashMap::default();
+        for chunk in &chunks {
+            new_offsets.insert(chunk.offset, offset as u32);
+            offset += 8 + chunk.data.len() + (chunk.data.len() & 1);
+        }
+
+        let mut result = Vec::with_capacity(offset);
+        let write_u32 = |result: &mut Vec<u8>, value: u32| result.extend_from_slice(&value.to_be_bytes());
+        write_u32(&mut result, FORM);
+        write_u32(&mut result, offset as u32 - 8);
+        write_u32(&mut result, IFRS);
+        write_u32(&mut result, RIDX);
+        write_u32(&mut result, index_length as u32);
+        write_u32(&mut result, kept_resources.len() as u32);
+        for resource in kept_resources {
+            write_u32(&mut result, resource.usage.into());
+            write_u32(&mut result, resource.number);
+            write_u32(&mut result, new_offsets[&resource.offset]);
+        }
+        for chunk in chunks {
+            write_u32(&mut result, chunk.chunk_type);
+            write_u32(&mut result, chunk.data.len() as u32);
+            result.extend_from_slice(chunk.data);
+            if chunk.data.len() & 1 == 1 {
+                result.push(0);
+            }
+        }
+        result
+    }
+}
+
+impl ResourceUsage {
+    fn from_u32(usage: u32) -> Result<Self, BlorbError> {
+        use ResourceUsage::*;
+        Ok(match usage {
+            USAGE_DATA => Data,
+            USAGE_EXEC => Executable,
+            USAGE_PICT => Picture,
+            USAGE_SND => Sound,
+            _ => return Err(BlorbError::UnknownResourceUsage { usage }),
+        })
+    }
+}
+
+impl From<ResourceUsage> for u32 {
+    fn from(usage: ResourceUsage) -> u32 {
+        use ResourceUsage::*;
+        match usage {
+            Data => USAGE_DATA,
+            Executable => USAGE_EXEC,
+            Picture => USAGE_PICT,
+            Sound => USAGE_SND,
+        }
+    }
+}
+
+fn parse_chunk<'a>(offset: u32, chunk_type: u32, data: &'a [u8], resource: &'a [u8]) -> Result<ChunkContents<'a>, BlorbError> {
+    use ChunkContents::*;
+    let invalid = |reason| BlorbError::InvalidChunk { offset, chunk_type, reason };
+    let text = || String::from_utf8_lossy(data);
+    let check_length = |length: usize| if data.len() < length { Err(invalid("chunk is too short")) } else { Ok(()) };
+    Ok(match chunk_type {
+        ADRI | ADVS | AGT | ALAN | EXEC | GLUL | HUGO | LEVE | MAGS | TAD2 | TAD3 | ZCOD => Executable(data),
+        JPEG | PNG => Picture(data),
+        RECT => {
+            check_length(8)?;
+            PicturePlaceholder {
+                width: read_u32(data, 0),
+                height: read_u32(data, 4),
+            }
+        },
+        MOD | OGGV | SONG => Sound(data),
+        // A FORM could be an AIFF sound or Data
+        FORM => {
+            check_length(4)?;
+            if read_u32(data, 0) == AIFF { Sound(resource) } else { Data(resource) }
+        },
+        BINA | TEXT => Data(data),
+        ANNO => Annotation(text()),
+        APAL => {
+            let entries = data.chunks_exact(4);
+            if !entries.remainder().is_empty() {
+                return Err(invalid("length is not a multiple of 4"));
+            }
+            AdaptivePalette(entries.map(|entry| read_u32(entry, 0)).collect())
+        },
+        AUTH => Author(text()),
+        COPYRIGHT => Copyright(text()),
+        DBUG => DebugData(data),
+        FSPC => {
+            check_length(4)?;
+            Frontispiece(read_u32(data, 0))
+        },
+        IFHD => GameIdentifier(data),
+        IFMD => Metadata(text()),
+        PLTE => {
+            if data.len() == 1 {
+                match data[0] {
+                    16 | 32 => Palette(self::Palette::Direct(data[0])),
+                    _ => return Err(invalid("direct colour depth must be 16 or 32")),
+                }
+            }
+            else {
+                let colours = data.chunks_exact(3);
+                if !colours.remainder().is_empty() {
+                    return Err(invalid("length is not a multiple of 3"));
+                }
+                Palette(self::Palette::Colours(colours.map(|colour| [colour[0], colour[1], colour[2]]).collect()))
+            }
+        },
+        RDES => {
+            check_length(4)?;
+            let count = read_u32(data, 0);
+            let mut descriptions = Vec::new();
+            let mut pos = 4;
+            for _ in 0..count {
+                if pos + 12 > data.len() {
+                    return Err(invalid("chunk is too short"));
+                }
+                let usage = ResourceUsage::from_u32(read_u32(data, pos))?;
+                let number = read_u32(data, pos + 4);
+                let length = read_u32(data, pos + 8) as usize;
+                pos += 12;
+                let text = data.get(pos..pos + length).ok_or_else(|| invalid("chunk is too short"))?;
+                descriptions.push(ResourceDescription {
+                    usage,
+                    number,
+                    text: String::from_utf8_lossy(text).into_owned(),
+                });
+                pos += length;
+            }
+            ResourceDescriptions(descriptions)
+        },
+        RELN => {
+            check_length(2)?;
+            ReleaseNumber(u16::from_be_bytes([data[0], data[1]]))
+        },
+        RESL => {
+            check_length(24)?;
+            let images = data[24..].chunks_exact(28);
+            if !images.remainder().is_empty() {
+                return Err(invalid("image entries must be 28 bytes long"));
+            }
+            let ratio = |entry: &[u8], pos| (read_u32(entry, pos), read_u32(entry, pos + 4));
+            Resolution(self::Resolution {
+                standard_width: read_u32(data, 0),
+                standard_height: read_u32(data, 4),
+                min_width: read_u32(data, 8),
+                min_height: read_u32(data, 12),
+                max_width: read_u32(data, 16),
+                max_height: read_u32(data, 20),
+                images: images.map(|entry| ImageResolution {
+                    number: read_u32(entry, 0),
+                    standard_ratio: ratio(entry, 4),
+                    min_ratio: ratio(entry, 12),
+                    max_ratio: ratio(entry, 20),
+                }).collect(),
+            })
+        },
+        RIDX => {
+            check_length(4)?;
+            let count = read_u32(data, 0) as usize;
+            let entries = data[4..].chunks_exact(12);
+            if entries.len() < count {
+                return Err(invalid("chunk is too short"));
+            }
+            let resources = entries.take(count).map(|entry| Ok(Resource {
+                usage: ResourceUsage::from_u32(read_u32(entry, 0))?,
+                number: read_u32(entry, 4),
+                offset: read_u32(entry, 8),
+            })).collect::<Result<Vec<Resource>, BlorbError>>()?;
+            ResourceIndex(resources)
+        },
+        _ => Unknown,
+    })
+}
+
+// Turn a chunk type into its four character name
+pub fn chunk_type_name(chunk_type: u32) -> String {
+    chunk_type.to_be_bytes().iter().map(|&c| if (0x20..0x7F).contains(&c) { c as char } else { '?' }).collect()
+}
+
+// The caller must check the bounds
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Make a Blorb with a resource index first, followed by the chunks
+    // Each resource is given as its usage, number, and the index of its chunk
+    fn make_blorb(resources: &[(u32, u32, usize)], chunks: &[(u32, &[u8])]) -> Vec<u8> {
+        let index_length = 4 + 12 * resources.len();
+        let mut offsets = Vec::new();
+        let mut offset = 12 + 8 + index_length;
+        for (_, data) in chunks {
+            offsets.push(offset as u32);
+            offset += 8 + data.len() + (data.len() & 1);
+        }
+        let mut words = vec![FORM, offset as u32 - 8, IFRS, RIDX, index_length as u32, resources.len() as u32];
+        for &(usage, number, chunk) in resources {
+            words.extend_from_slice(&[usage, number, offsets[chunk]]);
+        }
+        let mut blorb: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+        for (chunk_type, data) in chunks {
+            blorb.extend_from_slice(&chunk_type.to_be_bytes());
+            blorb.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            blorb.extend_from_slice(data);
+            if data.len() & 1 == 1 {
+                blorb.push(0);
+            }
+        }
+        blorb
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let original = make_blorb(&[(USAGE_EXEC, 0, 0), (USAGE_PICT, 1, 2), (USAGE_PICT, 2, 4)], &[
+            (GLUL, b"odd"),
+            (AUTH, b"Someone"),
+            (PNG, b"picture 1"),
+            (FSPC, &[0, 0, 0, 1]),
+            (RECT, &[0, 0, 0, 20, 0, 0, 0, 10]),
+        ]);
+        let blorb = Blorb::new(&original).unwrap();
+        assert_eq!(blorb.chunks.len(), 6);
+        assert!(matches!(blorb.executable().unwrap().contents, ChunkContents::Executable(b"odd")));
+        assert!(matches!(blorb.resource(ResourceUsage::Picture, 1).unwrap().contents, ChunkContents::Picture(b"picture 1")));
+        assert!(matches!(blorb.resource(ResourceUsage::Picture, 2).unwrap().contents, ChunkContents::PicturePlaceholder { width: 20, height: 10 }));
+        assert!(matches!(&blorb.find_chunk(AUTH).unwrap().contents, ChunkContents::Author(author) if author == "Someone"));
+        assert_eq!(blorb.frontispiece(), Some(1));
+
+        // Writing out every chunk reproduces the original file
+        assert_eq!(blorb.write(|_| true), original);
+
+        // Removing chunks moves the others, so the resource index must be rebuilt
+        let written = blorb.write(|chunk| chunk.chunk_type != GLUL && chunk.chunk_type != AUTH);
+        assert_eq!(written, make_blorb(&[(USAGE_PICT, 1, 0), (USAGE_PICT, 2, 2)], &[
+            (PNG, b"picture 1"),
+            (FSPC, &[0, 0, 0, 1]),
+            (RECT, &[0, 0, 0, 20, 0, 0, 0, 10]),
+        ]));
+        let reparsed = Blorb::new(&written).unwrap();
+        assert_eq!(reparsed.chunks[0].chunk_type, RIDX);
+        assert_eq!(reparsed.resources.len(), 2);
+        assert!(reparsed.executable().is_none());
+        assert!(matches!(reparsed.resource(ResourceUsage::Picture, 1).unwrap().contents, ChunkContents::Picture(b"picture 1")));
+        assert!(matches!(reparsed.resource(ResourceUsage::Picture, 2).unwrap().contents, ChunkContents::PicturePlaceholder { width: 20, height: 10 }));
+        assert_eq!(reparsed.frontispiece(), Some(1));
+    }
+
+    #[test]
+    fn test_invalid_chunks() {
+        // A bad chunk other than the resource index doesn't stop the Blorb from being used
+        let data = make_blorb(&[(USAGE_EXEC, 0, 1)], &[(FSPC, &[0, 1]), (ZCOD, &[5])]);
+        let blorb = Blorb::new(&data).unwrap();
+        assert!(matches!(&blorb.chunks[1].contents, ChunkContents::Invalid(BlorbError::InvalidChunk { chunk_type: FSPC, reason: "chunk is too short", .. })));
+        assert_eq!(blorb.frontispiece(), None);
+        assert!(matches!(blorb.executable().unwrap().contents, ChunkContents::Executable(&[5])));
+
+        // But a bad resource index does
+        let mut data = make_blorb(&[(USAGE_EXEC, 0, 0)], &[(ZCOD, &[5])]);
+        data[24..28].copy_from_slice(b"Nope");
+        assert_eq!(Blorb::new(&data).err(), Some(BlorbError::UnknownResourceUsage { usage: u32::from_be_bytes(*b"Nope") }));
+    }
+}
@@ -9,41 +9,58 @@ https://github.com/curiousdannii/if-decompiler
 
 */
 
+use std::collections::BTreeSet;
+
 use fnv::FnvHashSet;
 
 use super::*;
 
 impl GlulxState {
-    pub fn disassemble(&mut self, image: &[u8]) -> FnvHashSet<(u32, u32)> {
-        let decoding_table = self.parse_string_decoding_table(image);
+    pub fn disassemble(&mut self, image: &[u8]) -> Result<FnvHashSet<(u32, u32)>, DisassemblyError> {
+        let header = GlulxHeader::new(image)?;
+        let string_decoder = StringDecoder::new(image)?;
+        let image = ImageReader::new(image)?;
 
         let mut edges = FnvHashSet::default();
 
-        let ram_start = self.read_addr(image, 8) as u64;
-        self.ramstart = ram_start as u32;
-        let decoding_table_addr = self.read_addr(image, 28);
-        let root_node_addr = self.read_addr(image, decoding_table_addr + 8);
+        let ram_start = header.ramstart;
+        self.ramstart = ram_start;
+        self.version = header.version;
+        let decoding_table_addr = header.decoding_table;
 
-        let mut cursor = Cursor::new(image);
+        let mut cursor = image;
 
         // If we have debug file data, use it to disassemble all the functions
         if let Some(functions) = &self.debug_function_data {
             for (&addr, func) in functions {
-                cursor.set_position(addr as u64);
-                let function_type = cursor.get_u8();
-                self.functions.insert(func.addr, self.disassemble_function(&mut cursor, &mut edges, addr, Some(func.len), function_type));
+                cursor.set_position(addr);
+                let function_type = cursor.get_u8()?;
+                self.functions.insert(func.addr, self.disassemble_function(&mut cursor, &mut edges, addr, Some(func.len), function_type)?);
             }
-            return edges;
+            return Ok(edges);
+        }
+
+        // Or follow the calls from the start function
+        if self.recursive_descent {
+            self.disassemble_recursively(image, header.start_func, &string_decoder, &mut edges)?;
+            return Ok(edges);
         }
 
         // Otherwise parse the file manually
         // Skip past the header
         cursor.set_position(60);
 
-        // Loop through the ROM until the end of RAM or we find a
+        // Loop through the ROM until the end of RAM or we find an unknown object
         while cursor.position() < ram_start {
-            let addr = cursor.position() as u32;
-            let object_type = cursor.get_u8();
+            let addr = cursor.position();
+
+            // The string decoding table is usually stored between the functions and the strings, so skip over it
+            if addr == decoding_table_addr {
+                cursor.set_position(addr + image.read_u32(addr)?);
+                continue;
+            }
+
+            let object_type = cursor.get_u8()?;
 
             match object_type {
                 // Padding
@@ -51,73 +68,30 @@ impl GlulxState {
 
                 // Functions
                 0xC0 | 0xC1 => {
-                    self.functions.insert(addr, self.disassemble_function(&mut cursor, &mut edges, addr, None, object_type));
+                    self.functions.insert(addr, self.disassemble_function(&mut cursor, &mut edges, addr, None, object_type)?);
                 },
 
-                // Strings - just skip past them for now!
+                // Strings - just skip past them
                 0xE0 => {
                     if self.stop_on_string {
                         break;
                     }
-                    while cursor.get_u8() != 0 {}
+                    while cursor.get_u8()? != 0 {}
                 },
                 0xE2 => {
                     if self.stop_on_string {
                         break;
                     }
-                    cursor.get_u8();
-                    cursor.get_u8();
-                    cursor.get_u8();
-                    while cursor.get_u32() != 0 {}
+                    cursor.get_u8()?;
+                    cursor.get_u8()?;
+                    cursor.get_u8()?;
+                    while cursor.get_u32()? != 0 {}
                 },
-                // Compressed strings will take a bit more work...
                 0xE1 => {
                     if self.stop_on_string {
                         break;
                     }
-
-                    fn get_node<'a>(table: &'a FnvHashMap<u32, DecodingNode>, addr: u32) -> &'a DecodingNode {
-                        table.get(&addr).unwrap()
-                    }
-                    fn get_node_branch_addresses(node: &DecodingNode) -> [u32; 2] {
-                        match node {
-                            DecodingNode::Branch(branch) => {
-                                [branch.left, branch.right]
-                            },
-                            _ => panic!("Decoding node is not a branch"),
-                        }
-                    }
-
-                    let root_node = get_node(&decoding_table, root_node_addr);
-                    let root_branches = get_node_branch_addresses(root_node);
-                    let mut left_node = root_branches[0];
-                    let mut right_node = root_branches[1];
-
-                    let mut byte = cursor.get_u8();
-                    let mut bits = 8;
-                    loop {
-                        let bit = byte & 0x01;
-                        bits -= 1;
-                        byte >>= 1;
-                        let node = get_node(&decoding_table, if bit == 0 {left_node} else {right_node});
-                        match node {
-                            DecodingNode::Terminator => {
-                                break;
-                            },
-                            DecodingNode::Leaf => {
-                                left_node = root_branches[0];
-                                right_node = root_branches[1];
-                            },
-                            DecodingNode::Branch(branch) => {
-                                left_node = branch.left;
-                                right_node = branch.right;
-                            },
-                        }
-                        if bits == 0 {
-                            bits = 8;
-                            byte = cursor.get_u8();
-                        }
-                    }
+                    cursor.set_position(string_decoder.compressed_string_end(addr)?);
                 },
 
                 // Unknown
@@ -128,91 +102,153 @@ impl GlulxState {
             }
         };
 
+        self.disassemble_ram_functions(image, &mut edges)?;
+
         // Return the list of edges
-        edges
+        Ok(edges)
     }
 
-    // Parse the string decoding table, but only so that we can ignore compressed strings
-    pub fn parse_string_decoding_table(&self, image: &[u8]) -> FnvHashMap<u32, DecodingNode> {
-        let mut table = FnvHashMap::default();
-        let mut cursor = Cursor::new(image);
-
-        let decoding_table_addr = self.read_addr(image, 28);
-        let root_node_addr = self.read_addr(image, decoding_table_addr + 8);
-
-        // Keep a list of nodes to process and loop through
-        // I tried doing this recursively but couldn't make it work with the borrow checker
-        let mut nodes_to_process = vec![root_node_addr];
+    // Functions can also be stored in RAM, where they can't be found by scanning through the ROM
+    // So disassemble any which are called by the functions we've already found
+    fn disassemble_ram_functions(&mut self, image: ImageReader, edges: &mut FnvHashSet<(u32, u32)>) -> Result<(), DisassemblyError> {
+        let mut cursor = image;
         loop {
-            let addr = nodes_to_process.pop().unwrap();
-            cursor.set_position(addr as u64);
-            let node_type = cursor.get_u8();
-            let node = match node_type {
-                0x00 => {
-                    let left = cursor.get_u32();
-                    let right = cursor.get_u32();
-                    nodes_to_process.push(left);
-                    nodes_to_process.push(right);
-                    DecodingNode::Branch(DecodingNodeBranch {
-                        left,
-                        right,
-                    })
-                },
-                0x01 => DecodingNode::Terminator,
-                0x02 => {
-                    cursor.get_u8();
-                    DecodingNode::Leaf
-                },
-                0x03 => {
-                    while cursor.get_u8() != 0 {}
-                    DecodingNode::Leaf
-                },
-                0x04 | 0x08 | 0x09 => {
-                    cursor.get_u32();
-                    DecodingNode::Leaf
-                },
-                0x05 => {
-                    while cursor.get_u32() != 0 {}
-                    DecodingNode::Leaf
-                },
-                0x0A | 0x0B => {
-                    let _addr = cursor.get_u32();
-                    let count = cursor.get_u32();
-                    for _ in 0..count {
-                        cursor.get_u32();
-                    }
-                    DecodingNode::Leaf
+            let ram_functions: BTreeSet<u32> = edges.iter()
+                .map(|&(_, callee_addr)| callee_addr)
+                .filter(|&callee_addr| callee_addr >= self.ramstart && !self.functions.contains_key(&callee_addr) && self.is_function(image, callee_addr))
+                .collect();
+            if ram_functions.is_empty() {
+                return Ok(());
+            }
+            for addr in ram_functions {
+                cursor.set_position(addr);
+                let function_type = cursor.get_u8()?;
+                let function = self.disassemble_function(&mut cursor, edges, addr, None, function_type)?;
+                self.functions.insert(addr, function);
+            }
+        }
+    }
+
+    // Find functions by following references from the start function, rather than scanning through the ROM
+    // As function addresses can also be stored in variables or data, guess_functions will follow anything which looks like a function address too
+    fn disassemble_recursively(&mut self, image: ImageReader, start_func: u32, string_decoder: &StringDecoder, edges: &mut FnvHashSet<(u32, u32)>) -> Result<(), DisassemblyError> {
+        let mut cursor = image;
+
+        // Each function to process is paired with whether it was only guessed to be a function
+        let mut functions_to_process = vec![(start_func, false)];
+        functions_to_process.extend(string_decoder.referenced_functions().into_iter().map(|addr| (addr, false)));
+
+        // Functions can also be referred to by data in RAM, such as object properties
+        if self.guess_functions {
+            let mut ram_addr = self.ramstart;
+            while ram_addr + 4 <= image.endmem() {
+                let value = image.read_u32(ram_addr)?;
+                if self.is_function(image, value) {
+                    functions_to_process.push((value, true));
                 }
-                _ => panic!("Invalid string decoding node at {}", addr),
+                ram_addr += 1;
+            }
+        }
+        let mut non_functions = FnvHashSet::default();
+        while let Some((addr, guessed)) = functions_to_process.pop() {
+            if self.functions.contains_key(&addr) {
+                continue;
+            }
+            if !self.is_function(image, addr) {
+                if non_functions.insert(addr) {
+                    self.warnings.push(DisassemblyError::InvalidFunction { addr });
+                }
+                continue;
+            }
+            cursor.set_position(addr);
+            let function_type = cursor.get_u8()?;
+            // A guessed function could just be data which happens to start like a function, so skip it if it can't be fully disassembled
+            let mut function_edges = FnvHashSet::default();
+            let function = match self.disassemble_function(&mut cursor, &mut function_edges, addr, None, function_type) {
+                Ok(function) => function,
+                Err(_) if guessed => continue,
+                Err(err) => return Err(err),
             };
-            table.insert(addr, node);
-            if nodes_to_process.len() == 0 {
-                break;
+            edges.extend(function_edges);
+
+            for block in function.blocks.values() {
+                for instruction in &block.code {
+                    match instruction.opcode {
+                        opcode if opcodes::instruction_calls(opcode) => {
+                            if let Operand::Constant(callee_addr) = instruction.operands[0] {
+                                functions_to_process.push((callee_addr, false));
+                            }
+                        },
+                        opcodes::OP_ACCELFUNC => {
+                            if let Operand::Constant(callee_addr) = instruction.operands[1] {
+                                functions_to_process.push((callee_addr, false));
+                            }
+                        },
+                        _ => {},
+                    }
+                    // Function addresses can also be stored in variables, so check the other constants too
+                    if !self.guess_functions {
+                        continue;
+                    }
+                    let operands_end = instruction.operands.len() - if instruction.branch.is_some() { 1 } else { 0 };
+                    for operand in &instruction.operands[..operands_end] {
+                        if let Operand::Constant(operand_addr) = *operand {
+                            if !self.functions.contains_key(&operand_addr) && self.is_function(image, operand_addr) {
+                                functions_to_process.push((operand_addr, true));
+                            }
+                        }
+                    }
+                }
             }
+            self.functions.insert(addr, function);
         }
 
-        table
+        Ok(())
     }
 
-    fn disassemble_function(&self, cursor: &mut Cursor<&[u8]>, edges: &mut FnvHashSet<(u32, u32)>, addr: u32, len: Option<u32>, function_mode: u8) -> Function {
+    // Check whether an address has a function header followed by valid instructions, up until the first instruction which halts
+    fn is_function(&self, image: ImageReader, addr: u32) -> bool {
+        if addr < 60 {
+            return false;
+        }
+        // Anything which can't be read isn't a function
+        check_function(image.at(addr), self.version).unwrap_or(false)
+    }
+
+    fn disassemble_function(&self, cursor: &mut ImageReader, edges: &mut FnvHashSet<(u32, u32)>, addr: u32, len: Option<u32>, function_mode: u8) -> Result<Function, DisassemblyError> {
         let argument_mode = match function_mode {
             0xC0 => FunctionArgumentMode::Stack,
             0xC1 => FunctionArgumentMode::Locals,
-            _ => unreachable!(),
+            object_type => return Err(DisassemblyError::NotAFunction { addr, object_type }),
         };
 
         // Parse the locals formats
-        let mut locals = 0;
+        let mut locals = Vec::new();
+        let mut locals_format = Vec::new();
+        let mut offset = 0;
         loop {
-            let local_type = cursor.get_u8();
-            let count = cursor.get_u8() as u32;
+            let local_type = cursor.get_u8()?;
+            let count = cursor.get_u8()?;
             if local_type == 0 {
                 break
             }
-            if local_type != 4 {
-                panic!("1 and 2 byte locals are not supported in function {}", addr);
+            if local_type != 1 && local_type != 2 && local_type != 4 {
+                return Err(DisassemblyError::InvalidLocalsType { function: addr, local_type });
+            }
+            locals_format.push(LocalsFormat {
+                size: local_type,
+                count,
+            });
+            // Locals are aligned to their own size (which is always a power of two)
+            let size = local_type as u32;
+            offset = (offset + size - 1) & !(size - 1);
+            for _ in 0..count {
+                locals.push(Local {
+                    offset,
+                    size: local_type,
+                });
+                offset += size;
             }
-            locals += count;
         }
 
         // Basic blocks
@@ -224,7 +260,7 @@ impl GlulxState {
         let mut instructions = Vec::new();
         let mut instruction_addresses = FnvHashSet::default();
         'parse_loop: loop {
-            let instruction = self.disassemble_instruction(cursor);
+            let instruction = self.disassemble_instruction(cursor)?;
             instruction_addresses.insert(instruction.addr);
 
             // If this instruction branches, then update the entry and exit points
@@ -275,7 +311,7 @@ impl GlulxState {
 
             // If we have an end_addr (from a debug file) then use it to determine when to stop decoding
             if let Some(end_addr) = end_addr {
-                if cursor.position() as u32 == end_addr {
+                if cursor.position() == end_addr {
                     break;
                 }
                 continue;
@@ -284,7 +320,7 @@ impl GlulxState {
             if opcodes::instruction_halts(opcode) {
                 // Stop parsing instructions if we don't have any pending entry_points
                 // Short cut - check if the next address is an entry point
-                if !entry_points.contains(&(cursor.position() as u32)) {
+                if !entry_points.contains(&cursor.position()) {
                     // Otherwise check if any entry points haven't already been parsed
                     for _ in entry_points.difference(&instruction_addresses) {
                         continue 'parse_loop;
@@ -292,15 +328,16 @@ impl GlulxState {
 
                     // And check for an unreachable instruction
                     let final_addr = cursor.position();
-                    let potential_opcode = decode_opcode(cursor);
+                    // If we can't read any further then that's the end of the function
+                    let potential_opcode = decode_opcode(cursor).unwrap_or(0);
                     cursor.set_position(final_addr);
                     // Check for 0 first, as it shouldn't be interpreted as a NOP
                     if potential_opcode == 0 {
                         break;
                     }
-                    match opcodes::operands_count(potential_opcode) {
+                    match opcodes::operands_count(potential_opcode, self.version) {
                         Some(_) => {
-                            entry_points.insert(final_addr as u32);
+                            entry_points.insert(final_addr);
                             continue 'parse_loop;
                         },
                         None => break,
@@ -309,50 +346,64 @@ impl GlulxState {
             }
         }
 
-        let safety = self.function_safety(addr, &instructions);
+        let code_end = instructions.iter().map(|instruction| instruction.next).max().unwrap();
+        let mut safety = self.function_safety(addr, &instructions);
+        // Safe functions use C variables for their locals, so any odd local accesses must be handled by the unsafe dispatcher instead
+        if safety == FunctionSafety::SafetyTBD && !check_locals(&locals, &instructions) {
+            safety = FunctionSafety::Unsafe;
+        }
         let blocks = calculate_basic_blocks(instructions, entry_points, exit_branches);
+        let switches = find_switch_chains(&blocks);
 
-        Function {
+        Ok(Function {
             addr,
             argument_mode,
             blocks,
+            end_addr: code_end,
             locals,
+            locals_format,
             safety,
-        }
+            shaped_block: None,
+            switches,
+        })
     }
 
-    fn disassemble_instruction(&self, cursor: &mut Cursor<&[u8]>) -> Instruction {
+    fn disassemble_instruction(&self, cursor: &mut ImageReader) -> Result<Instruction, DisassemblyError> {
         use Operand::*;
 
-        let addr = cursor.position() as u32;
-        let opcode = decode_opcode(cursor);
+        let addr = cursor.position();
+        let opcode = decode_opcode(cursor)?;
 
         // Extract the operands
         let mut operands = Vec::default();
-        let operands_count = opcodes::operands_count(opcode).expect(&format!("Unknown opcode {} at address {}", opcode, addr)) as usize;
+        // Opcodes which are too new for the storyfile's Glulx version are still decoded, and warned about by decompile_rom()
+        let operands_count = match opcodes::operands_count(opcode, u32::MAX) {
+            Some(count) => count as usize,
+            None => return Err(DisassemblyError::UnknownOpcode { addr, opcode }),
+        };
         let mut operand_types = Vec::default();
         while operand_types.len() < operands_count {
-            let types = cursor.get_u8();
+            let types = cursor.get_u8()?;
             operand_types.push(types & 0x0F);
             operand_types.push(types >> 4);
         }
         for i in 0..operands_count {
             let operand = match operand_types[i] {
                 0 => Constant(0),
-                1 => Constant(cursor.get_i8() as i32 as u32),
-                2 => Constant(cursor.get_i16() as i32 as u32),
-                3 => Constant(cursor.get_u32()),
-                5 => Memory(cursor.get_u8() as u32),
-                6 => Memory(cursor.get_u16() as u32),
-                7 => Memory(cursor.get_u32()),
+                1 => Constant(cursor.get_i8()? as i32 as u32),
+                2 => Constant(cursor.get_i16()? as i32 as u32),
+                3 => Constant(cursor.get_u32()?),
+                5 => Memory(cursor.get_u8()? as u32),
+                6 => Memory(cursor.get_u16()? as u32),
+                7 => Memory(cursor.get_u32()?),
                 8 => Stack,
-                9 => Local(cursor.get_u8() as u32),
-                10 => Local(cursor.get_u16() as u32),
-                11 => Local(cursor.get_u32()),
-                13 => RAM(cursor.get_u8() as u32),
-                14 => RAM(cursor.get_u16() as u32),
-                15 => RAM(cursor.get_u32()),
-                x => panic!("Invalid operand mode {} in instruction {}", x, addr),
+                9 => Local(cursor.get_u8()? as u32),
+                10 => Local(cursor.get_u16()? as u32),
+                11 => Local(cursor.get_u32()?),
+                13 => RAM(cursor.get_u8()? as u32),
+                14 => RAM(cursor.get_u16()? as u32),
+                15 => RAM(cursor.get_u32()?),
+                mode => return Err(DisassemblyError::InvalidOperandMode { addr, opcode, mode }),
             };
             operands.push(operand);
         }
@@ -390,15 +441,15 @@ impl GlulxState {
             LastTwoOperands => (operands.pop().unwrap(), operands.pop().unwrap()),
         };
 
-        Instruction {
+        Ok(Instruction {
             addr,
             opcode,
             operands,
             branch,
             storer,
             storer2,
-            next: cursor.position() as u32,
-        }
+            next: cursor.position(),
+        })
     }
 
     // Check the function safety overrides
@@ -417,23 +468,77 @@ impl GlulxState {
     }
 }
 
+// Check that every local operand accesses a whole local
+// Except for copys and copyb, which can access part of one local, but not past its end
+fn check_locals(locals: &[Local], instructions: &[Instruction]) -> bool {
+    let check = |operand: &Operand, size: Option<u32>| match *operand {
+        Operand::Local(offset) => match locals.iter().find(|local| offset >= local.offset && offset < local.offset + local.size as u32) {
+            Some(local) => match size {
+                Some(size) => offset + size <= local.offset + local.size as u32,
+                None => offset == local.offset,
+            },
+            None => false,
+        },
+        _ => true,
+    };
+    instructions.iter().all(|instruction| {
+        let part_size = match instruction.opcode {
+            opcodes::OP_COPYS => Some(2),
+            opcodes::OP_COPYB => Some(1),
+            _ => None,
+        };
+        instruction.operands.iter().all(|operand| check(operand, part_size)) && check(&instruction.storer, None) && check(&instruction.storer2, None)
+    })
+}
+
 // Decode a variable length opcode
-fn decode_opcode(cursor: &mut Cursor<&[u8]>) -> u32 {
-    let opcode_byte = cursor.get_u8();
-    match opcode_byte {
+fn decode_opcode(cursor: &mut ImageReader) -> Result<u32, DisassemblyError> {
+    let opcode_byte = cursor.get_u8()?;
+    Ok(match opcode_byte {
         0 ..= 0x7F => opcode_byte as u32,
-        0x80 ..= 0xBF => ((opcode_byte as u32 & 0x3F) << 8) | cursor.get_u8() as u32,
-        0xC0 ..= 0xFF => ((opcode_byte as u32 & 0x3F) << 24) | ((cursor.get_u8() as u32) << 16) | cursor.get_u16() as u32,
-    }
+        0x80 ..= 0xBF => ((opcode_byte as u32 & 0x3F) << 8) | cursor.get_u8()? as u32,
+        0xC0 ..= 0xFF => ((opcode_byte as u32 & 0x3F) << 24) | ((cursor.get_u8()? as u32) << 16) | cursor.get_u16()? as u32,
+    })
 }
 
-pub enum DecodingNode {
-    Branch(DecodingNodeBranch),
-    Leaf,
-    Terminator,
+// Trial decode a function's header and instructions
+fn check_function(mut cursor: ImageReader, version: u32) -> Result<bool, DisassemblyError> {
+    match cursor.get_u8()? {
+        0xC0 | 0xC1 => {},
+        _ => return Ok(false),
+    };
+    loop {
+        let local_type = cursor.get_u8()?;
+        let count = cursor.get_u8()?;
+        match (local_type, count) {
+            (0, 0) => break,
+            (1, count) | (2, count) | (4, count) if count > 0 => {},
+            _ => return Ok(false),
+        }
+    }
+    loop {
+        let opcode = decode_opcode(&mut cursor)?;
+        let operands_count = match opcodes::operands_count(opcode, version) {
+            Some(count) => count as usize,
+            None => return Ok(false),
+        };
+        let mut operand_types = Vec::default();
+        while operand_types.len() < operands_count {
+            let types = cursor.get_u8()?;
+            operand_types.push(types & 0x0F);
+            operand_types.push(types >> 4);
+        }
+        for operand_type in &operand_types[..operands_count] {
+            match operand_type {
+                0 | 8 => {},
+                1 | 5 | 9 | 13 => {cursor.get_u8()?;},
+                2 | 6 | 10 | 14 => {cursor.get_u16()?;},
+                3 | 7 | 11 | 15 => {cursor.get_u32()?;},
+                _ => return Ok(false),
+            };
+        }
+        if opcodes::instruction_halts(opcode) {
+            return Ok(true);
+        }
+    }
 }
-
-pub struct DecodingNodeBranch {
-    pub left: u32,
-    pub right: u32,
-}
\ No newline at end of file
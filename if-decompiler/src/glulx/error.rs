@@ -0,0 +1,61 @@
+/*
+
+Glulx Disassembly Errors
+========================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::error::Error;
+use std::fmt;
+
+// Something in the storyfile which we couldn't disassemble
+// Addresses are of the instruction, function, node or string with the problem
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisassemblyError {
+    ChecksumMismatch { expected: u32, calculated: u32 },
+    IndirectStringTooDeep { addr: u32 },
+    InvalidDecodingNode { addr: u32, node_type: u8 },
+    InvalidDecodingTableRoot { addr: u32 },
+    InvalidFunction { addr: u32 },
+    InvalidHeader { reason: &'static str },
+    InvalidIndirectReference { addr: u32, object_type: u8 },
+    InvalidLocalsType { function: u32, local_type: u8 },
+    InvalidOperandMode { addr: u32, opcode: u32, mode: u8 },
+    MissingDecodingNode { addr: u32 },
+    NotAFunction { addr: u32, object_type: u8 },
+    NotAString { addr: u32, object_type: u8 },
+    OpcodeTooNew { addr: u32, opcode: u32, version: u32 },
+    OutOfBounds { addr: u32 },
+    UnknownOpcode { addr: u32, opcode: u32 },
+    UnsupportedVersion { version: u32 },
+}
+
+impl fmt::Display for DisassemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use DisassemblyError::*;
+        match self {
+            ChecksumMismatch { expected, calculated } => write!(f, "Checksum mismatch: header has {:08X} but calculated {:08X}", expected, calculated),
+            IndirectStringTooDeep { addr } => write!(f, "Too many nested indirect string references at {}", addr),
+            InvalidDecodingNode { addr, node_type } => write!(f, "Invalid string decoding node type {} at {}", node_type, addr),
+            InvalidDecodingTableRoot { addr } => write!(f, "String decoding table root node at {} is a leaf", addr),
+            InvalidFunction { addr } => write!(f, "Ignoring call to {}, which is not a function", addr),
+            InvalidHeader { reason } => write!(f, "Invalid header: {}", reason),
+            InvalidIndirectReference { addr, object_type } => write!(f, "Indirect string reference to unknown object type {} at {}", object_type, addr),
+            InvalidLocalsType { function, local_type } => write!(f, "Invalid locals type {} in function {}", local_type, function),
+            InvalidOperandMode { addr, opcode, mode } => write!(f, "Invalid operand mode {} in instruction {} (opcode {})", mode, addr, opcode),
+            MissingDecodingNode { addr } => write!(f, "Missing string decoding node {}", addr),
+            NotAFunction { addr, object_type } => write!(f, "Expected a function at {}, but found object type {}", addr, object_type),
+            NotAString { addr, object_type } => write!(f, "Expected a string at {}, but found object type {}", addr, object_type),
+            OpcodeTooNew { addr, opcode, version } => write!(f, "Opcode {} at address {} is not available in Glulx version {}.{}.{}", opcode, addr, version >> 16, (version >> 8) & 0xFF, version & 0xFF),
+            OutOfBounds { addr } => write!(f, "Tried to read past the end of memory at {}", addr),
+            UnknownOpcode { addr, opcode } => write!(f, "Unknown opcode {} at address {}", opcode, addr),
+            UnsupportedVersion { version } => write!(f, "Unsupported Glulx version {}.{}.{}", version >> 16, (version >> 8) & 0xFF, version & 0xFF),
+        }
+    }
+}
+
+impl Error for DisassemblyError {}
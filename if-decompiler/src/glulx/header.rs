@@ -0,0 +1,108 @@
+/*
+
+Glulx Header
+============
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::fmt;
+
+use super::*;
+
+pub const HEADER_LENGTH: u32 = 36;
+const GLULX_MAGIC: u32 = 0x476C756C; // Glul
+
+// The versions of the Glulx spec we know about
+const MIN_VERSION: u32 = 0x00020000;
+const MAX_VERSION: u32 = 0x000301FF;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GlulxHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub ramstart: u32,
+    pub extstart: u32,
+    pub endmem: u32,
+    pub stack_size: u32,
+    pub start_func: u32,
+    pub decoding_table: u32,
+    pub checksum: u32,
+}
+
+impl GlulxHeader {
+    // Read the header fields, without checking them
+    pub fn new(image: &[u8]) -> Result<Self, DisassemblyError> {
+        if image.len() < HEADER_LENGTH as usize {
+            return Err(DisassemblyError::OutOfBounds { addr: image.len() as u32 });
+        }
+        let word = |addr: usize| u32::from_be_bytes([image[addr], image[addr + 1], image[addr + 2], image[addr + 3]]);
+        Ok(GlulxHeader {
+            magic: word(0),
+            version: word(4),
+            ramstart: word(8),
+            extstart: word(12),
+            endmem: word(16),
+            stack_size: word(20),
+            start_func: word(24),
+            decoding_table: word(28),
+            checksum: word(32),
+        })
+    }
+
+    // Check the header the same way that Glulxe does when it loads a storyfile
+    pub fn validate(&self, image_length: u32) -> Result<(), DisassemblyError> {
+        use DisassemblyError::*;
+        if self.magic != GLULX_MAGIC {
+            return Err(InvalidHeader { reason: "not a Glulx file" });
+        }
+        if self.version < MIN_VERSION || self.version > MAX_VERSION {
+            return Err(UnsupportedVersion { version: self.version });
+        }
+        if (self.ramstart | self.extstart | self.endmem | self.stack_size) & 0xFF != 0 {
+            return Err(InvalidHeader { reason: "one of the segment boundaries is not 256-byte aligned" });
+        }
+        if self.ramstart < 0x100 || self.extstart < self.ramstart || self.endmem < self.extstart {
+            return Err(InvalidHeader { reason: "the segment boundaries are in an impossible order" });
+        }
+        if self.start_func < HEADER_LENGTH || self.start_func >= self.extstart {
+            return Err(InvalidHeader { reason: "the start function is outside of the storyfile" });
+        }
+        // Everything below extstart must be in the file
+        if image_length < self.extstart {
+            return Err(InvalidHeader { reason: "the file is shorter than extstart" });
+        }
+        Ok(())
+    }
+
+    // Verify the checksum like @verify does: the sum of every word up to extstart, with the checksum itself treated as 0
+    pub fn verify_checksum(&self, image: &[u8]) -> Result<(), DisassemblyError> {
+        if (image.len() as u32) < self.extstart {
+            return Err(DisassemblyError::OutOfBounds { addr: image.len() as u32 });
+        }
+        let calculated = image[..self.extstart as usize].chunks_exact(4)
+            .map(|word| u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+            .fold(0u32, |sum, word| sum.wrapping_add(word))
+            .wrapping_sub(self.checksum);
+        if calculated != self.checksum {
+            return Err(DisassemblyError::ChecksumMismatch { expected: self.checksum, calculated });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for GlulxHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Glulx version: {}.{}.{}", self.version >> 16, (self.version >> 8) & 0xFF, self.version & 0xFF)?;
+        writeln!(f, "RAM start: {}", self.ramstart)?;
+        writeln!(f, "Extended memory start: {}", self.extstart)?;
+        writeln!(f, "End of memory: {}", self.endmem)?;
+        writeln!(f, "Stack size: {}", self.stack_size)?;
+        writeln!(f, "Start function: {}", self.start_func)?;
+        writeln!(f, "String decoding table: {}", self.decoding_table)?;
+        write!(f, "Checksum: {:08X}", self.checksum)
+    }
+}
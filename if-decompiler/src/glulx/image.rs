@@ -0,0 +1,92 @@
+/*
+
+Glulx Image Reader
+==================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use super::*;
+
+// A bounds checked cursor for reading from a storyfile image
+// Only the first extstart bytes come from the image; from there until endmem the memory is zeroed, and anything past endmem is an error
+// If the image is truncated then the missing bytes below extstart are errors too, rather than zeroes
+#[derive(Copy, Clone)]
+pub struct ImageReader<'a> {
+    data: &'a [u8],
+    extstart: u32,
+    endmem: u32,
+    position: u32,
+}
+
+impl<'a> ImageReader<'a> {
+    pub fn new(image: &'a [u8]) -> Result<Self, DisassemblyError> {
+        let header = GlulxHeader::new(image)?;
+        let data_end = (header.extstart as usize).min(image.len());
+        Ok(ImageReader {
+            data: &image[..data_end],
+            extstart: header.extstart,
+            endmem: header.endmem.max(data_end as u32),
+            position: 0,
+        })
+    }
+
+    // Make a new reader at an address
+    pub fn at(&self, addr: u32) -> Self {
+        ImageReader {
+            position: addr,
+            ..*self
+        }
+    }
+
+    // The length of the memory the storyfile starts with
+    pub fn endmem(&self) -> u32 {
+        self.endmem
+    }
+
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    pub fn set_position(&mut self, addr: u32) {
+        self.position = addr;
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, DisassemblyError> {
+        let addr = self.position;
+        if addr >= self.endmem {
+            return Err(DisassemblyError::OutOfBounds { addr });
+        }
+        let byte = match self.data.get(addr as usize) {
+            Some(&byte) => byte,
+            None if addr >= self.extstart => 0,
+            None => return Err(DisassemblyError::OutOfBounds { addr }),
+        };
+        self.position += 1;
+        Ok(byte)
+    }
+
+    pub fn get_i8(&mut self) -> Result<i8, DisassemblyError> {
+        Ok(self.get_u8()? as i8)
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, DisassemblyError> {
+        Ok(((self.get_u8()? as u16) << 8) | self.get_u8()? as u16)
+    }
+
+    pub fn get_i16(&mut self) -> Result<i16, DisassemblyError> {
+        Ok(self.get_u16()? as i16)
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, DisassemblyError> {
+        Ok(((self.get_u16()? as u32) << 16) | self.get_u16()? as u32)
+    }
+
+    // Read a word without moving the cursor
+    pub fn read_u32(&self, addr: u32) -> Result<u32, DisassemblyError> {
+        self.at(addr).get_u32()
+    }
+}
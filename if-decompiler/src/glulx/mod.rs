@@ -10,45 +10,87 @@ https://github.com/curiousdannii/if-decompiler
 */
 
 use std::collections::BTreeMap;
-use std::io::Cursor;
 
-use bytes::Buf;
+use relooper::{RelooperError, ShapedBlock, try_reloop};
 
 use super::*;
 
 mod disassembler;
+mod error;
+pub use error::*;
+mod header;
+pub use header::*;
+mod image;
+pub use image::*;
 pub mod opcodes;
+mod strings;
+pub use strings::*;
 
 pub struct GlulxState {
     pub debug_function_data: Option<BTreeMap<u32, DebugFunctionData>>,
     pub functions: BTreeMap<u32, Function>,
+    pub guess_functions: bool,
     pub ramstart: u32,
+    pub recursive_descent: bool,
     pub safe_function_overides: Option<Vec<u32>>,
     pub stop_on_string: bool,
     pub unsafe_function_overides: Option<Vec<u32>>,
+    pub version: u32,
+    // Problems found by decompile_rom() which didn't stop it
+    pub warnings: Vec<DisassemblyError>,
 }
 
 impl GlulxState {
-    pub fn new(debug_function_data: Option<BTreeMap<u32, DebugFunctionData>>, safe_function_overides: Option<Vec<u32>>, stop_on_string: bool, unsafe_function_overides: Option<Vec<u32>>) -> Self {
+    pub fn new(debug_function_data: Option<BTreeMap<u32, DebugFunctionData>>, guess_functions: bool, recursive_descent: bool, safe_function_overides: Option<Vec<u32>>, stop_on_string: bool, unsafe_function_overides: Option<Vec<u32>>) -> Self {
         GlulxState {
             debug_function_data,
             functions: BTreeMap::default(),
+            guess_functions,
             ramstart: 0,
+            recursive_descent,
             safe_function_overides,
             stop_on_string,
             unsafe_function_overides,
+            version: 0,
+            warnings: Vec::new(),
         }
     }
 
-    pub fn decompile_rom(&mut self, image: &[u8]) {
-        let edges = self.disassemble(image);
-        self.mark_all_unsafe_functions(edges);
+    pub fn decompile_rom(&mut self, image: &[u8]) -> Result<(), DisassemblyError> {
+        let mut edges = self.disassemble(image)?;
+        // Warn about opcodes which are too new for the storyfile's Glulx version, as they might be misdecoded data
+        for function in self.functions.values() {
+            for instruction in function.blocks.values().flat_map(|block| &block.code) {
+                if opcodes::opcode_version(instruction.opcode) > self.version {
+                    self.warnings.push(DisassemblyError::OpcodeTooNew { addr: instruction.addr, opcode: instruction.opcode, version: self.version });
+                }
+            }
+        }
+        // Remove any edges to things which turned out not to be functions
+        let functions = &self.functions;
+        edges.retain(|(_, callee_addr)| functions.contains_key(callee_addr));
+        self.mark_all_unsafe_functions(edges.clone());
+        // Functions which the relooper can't handle will have to be output as unsafe functions, as will their callers
+        let mut found_unrelooped = false;
+        for function in self.functions.values_mut() {
+            if function.safety == FunctionSafety::SafetyTBD {
+                match function.reloop() {
+                    Ok(block) => function.shaped_block = Some(block),
+                    Err(_) => {
+                        function.safety = FunctionSafety::Unsafe;
+                        found_unrelooped = true;
+                    },
+                }
+            }
+        }
+        if found_unrelooped {
+            self.mark_all_unsafe_functions(edges);
+        }
+        Ok(())
     }
 
-    pub fn read_addr(&self, image: &[u8], addr: u32) -> u32 {
-        let mut cursor = Cursor::new(image);
-        cursor.set_position(addr as u64);
-        cursor.get_u32()
+    pub fn read_addr(&self, image: &[u8], addr: u32) -> Result<u32, DisassemblyError> {
+        ImageReader::new(image)?.read_u32(addr)
     }
 }
 
@@ -73,8 +115,50 @@ pub struct Function {
     pub addr: u32,
     pub argument_mode: FunctionArgumentMode,
     pub blocks: BTreeMap<u32, BasicBlock<Instruction>>,
-    pub locals: u32,
+    // The address just past the function's last instruction
+    pub end_addr: u32,
+    pub locals: Vec<Local>,
+    pub locals_format: Vec<LocalsFormat>,
     pub safety: FunctionSafety,
+    // The relooped blocks of a function which could be safe, set by decompile_rom()
+    pub shaped_block: Option<Box<ShapedBlock<u32>>>,
+    // Chains of comparisons which will be output as switches, keyed by their first block
+    pub switches: BTreeMap<u32, SwitchChain<Operand>>,
+}
+
+impl Function {
+    // Find the local which contains a byte offset
+    pub fn local_at(&self, offset: u32) -> Option<usize> {
+        self.locals.iter().position(|local| offset >= local.offset && offset < local.offset + local.size as u32)
+    }
+
+    pub fn in_ram(&self, ramstart: u32) -> bool {
+        self.addr >= ramstart
+    }
+
+    // Run the relooper over the function's blocks
+    pub fn reloop(&self) -> Result<Box<ShapedBlock<u32>>, RelooperError<u32>> {
+        try_reloop(relooper_blocks(&self.blocks, &self.switches), *self.blocks.keys().next().unwrap())
+    }
+
+    // The length of the function header, including the locals format
+    pub fn header_length(&self) -> u32 {
+        1 + 2 * (self.locals_format.len() as u32 + 1)
+    }
+}
+
+// A local, with its offset from the start of the locals segment
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Local {
+    pub offset: u32,
+    pub size: u8,
+}
+
+// An entry of the locals format from a function header
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LocalsFormat {
+    pub size: u8,
+    pub count: u8,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -95,6 +179,8 @@ pub struct Instruction {
 }
 
 impl VMInstruction for Instruction {
+    type Operand = Operand;
+
     fn addr(&self) -> u32 {
         self.addr
     }
@@ -102,9 +188,37 @@ impl VMInstruction for Instruction {
     fn does_halt(&self) -> bool {
         opcodes::instruction_halts(self.opcode)
     }
+
+    fn comparison(&self) -> Option<Comparison<Operand>> {
+        use opcodes::*;
+        use Operand::*;
+        let target = match self.branch {
+            Some(BranchTarget::Absolute(addr)) => addr,
+            _ => return None,
+        };
+        // The last operand is the branch offset
+        let (value, constant, branches_when_equal) = match (self.opcode, &self.operands[..]) {
+            (OP_JZ, &[value, _]) => (value, 0, true),
+            (OP_JNZ, &[value, _]) => (value, 0, false),
+            (OP_JEQ, &[value, Constant(constant), _]) | (OP_JEQ, &[Constant(constant), value, _]) => (value, constant, true),
+            (OP_JNE, &[value, Constant(constant), _]) | (OP_JNE, &[Constant(constant), value, _]) => (value, constant, false),
+            _ => return None,
+        };
+        // Each comparison in a chain must read the same value
+        if let Constant(_) | Stack = value {
+            return None;
+        }
+        let (equal, unequal) = if branches_when_equal { (target, self.next) } else { (self.next, target) };
+        Some(Comparison {
+            value,
+            constants: vec![constant],
+            equal,
+            unequal,
+        })
+    }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Operand {
     Constant(u32),
     Memory(u32),
@@ -80,6 +80,8 @@ pub const OP_RESTORE: u32 = 0x124;
 pub const OP_SAVEUNDO: u32 = 0x125;
 pub const OP_RESTOREUNDO: u32 = 0x126;
 pub const OP_PROTECT: u32 = 0x127;
+pub const OP_HASUNDO: u32 = 0x128;
+pub const OP_DISCARDUNDO: u32 = 0x129;
 pub const OP_GLK: u32 = 0x130;
 pub const OP_GETSTRINGTBL: u32 = 0x140;
 pub const OP_SETSTRINGTBL: u32 = 0x141;
@@ -127,16 +129,63 @@ pub const OP_JFGT: u32 = 0x1C4;
 pub const OP_JFGE: u32 = 0x1C5;
 pub const OP_JISNAN: u32 = 0x1C8;
 pub const OP_JISINF: u32 = 0x1C9;
+pub const OP_NUMTOD: u32 = 0x200;
+pub const OP_DTONUMZ: u32 = 0x201;
+pub const OP_DTONUMN: u32 = 0x202;
+pub const OP_FTOD: u32 = 0x203;
+pub const OP_DTOF: u32 = 0x204;
+pub const OP_DCEIL: u32 = 0x208;
+pub const OP_DFLOOR: u32 = 0x209;
+pub const OP_DADD: u32 = 0x210;
+pub const OP_DSUB: u32 = 0x211;
+pub const OP_DMUL: u32 = 0x212;
+pub const OP_DDIV: u32 = 0x213;
+pub const OP_DMODR: u32 = 0x214;
+pub const OP_DMODQ: u32 = 0x215;
+pub const OP_DSQRT: u32 = 0x218;
+pub const OP_DEXP: u32 = 0x219;
+pub const OP_DLOG: u32 = 0x21A;
+pub const OP_DPOW: u32 = 0x21B;
+pub const OP_DSIN: u32 = 0x220;
+pub const OP_DCOS: u32 = 0x221;
+pub const OP_DTAN: u32 = 0x222;
+pub const OP_DASIN: u32 = 0x223;
+pub const OP_DACOS: u32 = 0x224;
+pub const OP_DATAN: u32 = 0x225;
+pub const OP_DATAN2: u32 = 0x226;
+pub const OP_JDEQ: u32 = 0x230;
+pub const OP_JDNE: u32 = 0x231;
+pub const OP_JDLT: u32 = 0x232;
+pub const OP_JDLE: u32 = 0x233;
+pub const OP_JDGT: u32 = 0x234;
+pub const OP_JDGE: u32 = 0x235;
+pub const OP_JDISNAN: u32 = 0x238;
+pub const OP_JDISINF: u32 = 0x239;
+
+// The Glulx version which introduced an opcode
+pub fn opcode_version(opcode: u32) -> u32 {
+    match opcode {
+        OP_STREAMUNICHAR | OP_CALLF ..= OP_CALLFIII => 0x00030000,
+        OP_MZERO | OP_MCOPY | OP_MALLOC | OP_MFREE => 0x00030100,
+        OP_ACCELFUNC | OP_ACCELPARAM => 0x00030101,
+        OP_NUMTOF ..= OP_JISINF => 0x00030102,
+        OP_HASUNDO | OP_DISCARDUNDO | OP_NUMTOD ..= OP_JDISINF => 0x00030103,
+        _ => 0x00020000,
+    }
+}
 
 // Return the number of operands an opcode has
-// Also checks for unknown opcodes
-pub fn operands_count(opcode: u32) -> Option<u8> {
+// Also checks for unknown opcodes, and opcodes which are too new for the storyfile's Glulx version
+pub fn operands_count(opcode: u32, version: u32) -> Option<u8> {
+    if version < opcode_version(opcode) {
+        return None;
+    }
     match opcode {
-        OP_NOP | OP_STKSWAP | OP_QUIT | OP_RESTART => Some(0),
+        OP_NOP | OP_STKSWAP | OP_QUIT | OP_RESTART | OP_DISCARDUNDO => Some(0),
         OP_JUMP | OP_RETURN | OP_STKCOUNT | OP_STKCOPY
             | OP_STREAMCHAR ..= OP_STREAMUNICHAR | OP_DEBUGTRAP | OP_GETMEMSIZE
             | OP_JUMPABS | OP_SETRANDOM | OP_VERIFY | OP_SAVEUNDO | OP_RESTOREUNDO
-            | OP_GETSTRINGTBL | OP_SETSTRINGTBL | OP_MFREE => Some(1),
+            | OP_HASUNDO | OP_GETSTRINGTBL | OP_SETSTRINGTBL | OP_MFREE => Some(1),
         OP_NEG | OP_BITNOT | OP_JZ | OP_JNZ | OP_CATCH ..= OP_TAILCALL
             | OP_COPY ..= OP_SEXB | OP_STKPEEK | OP_STKROLL | OP_CALLF
             | OP_SETMEMSIZE | OP_RANDOM | OP_SAVE | OP_RESTORE | OP_PROTECT
@@ -146,10 +195,13 @@ pub fn operands_count(opcode: u32) -> Option<u8> {
         OP_ADD ..= OP_MOD | OP_BITAND ..= OP_BITXOR  | OP_SHIFTL ..= OP_USHIFTR
             | OP_JEQ ..= OP_CALL | OP_ALOAD ..= OP_ASTOREBIT | OP_GESTALT
             | OP_GLK | OP_CALLFI | OP_MCOPY | OP_FADD ..= OP_FDIV | OP_POW
-            | OP_ATAN2 | OP_JFLT ..= OP_JFGE => Some(3),
-        OP_CALLFII | OP_FMOD | OP_JFEQ | OP_JFNE => Some(4),
-        OP_CALLFIII => Some(5),
-        OP_LINKEDSEARCH => Some(7),
+            | OP_ATAN2 | OP_JFLT ..= OP_JFGE | OP_NUMTOD ..= OP_DTOF | OP_JDISNAN
+            | OP_JDISINF => Some(3),
+        OP_CALLFII | OP_FMOD | OP_JFEQ | OP_JFNE | OP_DCEIL | OP_DFLOOR
+            | OP_DSQRT ..= OP_DLOG | OP_DSIN ..= OP_DATAN => Some(4),
+        OP_CALLFIII | OP_JDLT ..= OP_JDGE => Some(5),
+        OP_DADD ..= OP_DMODQ | OP_DPOW | OP_DATAN2 => Some(6),
+        OP_LINKEDSEARCH | OP_JDEQ | OP_JDNE => Some(7),
         OP_LINEARSEARCH | OP_BINARYSEARCH => Some(8),
         _ => None,
     }
@@ -158,7 +210,8 @@ pub fn operands_count(opcode: u32) -> Option<u8> {
 // Whether an instruction branches or jumps
 pub fn instruction_branches(opcode: u32) -> bool {
     match opcode {
-        OP_JUMP ..= OP_JLEU | OP_CATCH | OP_JUMPABS | OP_JFEQ ..= OP_JISINF => true,
+        OP_JUMP ..= OP_JLEU | OP_CATCH | OP_JUMPABS | OP_JFEQ ..= OP_JISINF
+            | OP_JDEQ ..= OP_JDISINF => true,
         _ => false,
     }
 }
@@ -204,10 +257,10 @@ pub fn instruction_stores(opcode: u32) -> StoreMode {
             /* OP_COPYS | OP_COPYB store manually */
             | OP_SEXS ..= OP_ALOADBIT | OP_STKCOUNT ..= OP_STKPEEK
             | OP_GESTALT | OP_GETMEMSIZE | OP_SETMEMSIZE | OP_RANDOM | OP_VERIFY
-            | OP_SAVE ..= OP_RESTOREUNDO | OP_GLK | OP_GETSTRINGTBL
+            | OP_SAVE ..= OP_RESTOREUNDO | OP_HASUNDO | OP_GLK | OP_GETSTRINGTBL
             | OP_LINEARSEARCH ..= OP_CALLFIII | OP_MALLOC | OP_NUMTOF ..= OP_FDIV
-            | OP_SQRT ..= OP_ATAN2 => LastOperand,
-        OP_GETIOSYS | OP_FMOD => LastTwoOperands,
+            | OP_SQRT ..= OP_ATAN2 | OP_DTONUMZ | OP_DTONUMN | OP_DTOF => LastOperand,
+        OP_GETIOSYS | OP_FMOD | OP_NUMTOD | OP_FTOD | OP_DCEIL ..= OP_DATAN2 => LastTwoOperands,
         _ => DoesNotStore,
     }
 }
@@ -220,6 +273,9 @@ pub fn function_safety(instructions: &Vec<Instruction>) -> FunctionSafety {
         match instruction.opcode {
             OP_THROW | OP_QUIT | OP_RESTART ..= OP_RESTOREUNDO => result = Unsafe,
 
+            // Checking for or discarding an undo state doesn't capture or restore the current state, so is safe
+            OP_HASUNDO | OP_DISCARDUNDO => continue,
+
             // Calls to non-constants are unsafe
             OP_CALL | OP_TAILCALL | OP_CALLF ..= OP_CALLFIII => match instruction.operands[0] {
                 Operand::Constant(_) => continue,
@@ -227,7 +283,7 @@ pub fn function_safety(instructions: &Vec<Instruction>) -> FunctionSafety {
             }
 
             // Branches to non-constants are unsafe
-            OP_JUMP ..= OP_JLEU | OP_JUMPABS | OP_JFEQ ..= OP_JISINF => match instruction.operands.last().unwrap() {
+            OP_JUMP ..= OP_JLEU | OP_JUMPABS | OP_JFEQ ..= OP_JISINF | OP_JDEQ ..= OP_JDISINF => match instruction.operands.last().unwrap() {
                 Operand::Constant(_) => continue,
                 _ => return UnsafeDynamicBranches,
             }
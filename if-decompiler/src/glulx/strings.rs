@@ -0,0 +1,414 @@
+/*
+
+Glulx Strings
+=============
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use super::*;
+
+// Indirect references could loop forever, so give up after this many
+const MAX_INDIRECT_DEPTH: u32 = 100;
+
+// Decodes strings using the string decoding table from the header
+// Note that this can't know about tables set at runtime with @setstringtbl
+pub struct StringDecoder<'a> {
+    image: ImageReader<'a>,
+    root_node_addr: u32,
+    table: FnvHashMap<u32, DecodingNode>,
+}
+
+pub enum DecodingNode {
+    Branch(DecodingNodeBranch),
+    Terminator,
+    Char(u8),
+    CString(Vec<u8>),
+    UnicodeChar(u32),
+    UnicodeString(Vec<u32>),
+    Indirect(u32),
+    DoubleIndirect(u32),
+    IndirectWithArgs(u32, Vec<u32>),
+    DoubleIndirectWithArgs(u32, Vec<u32>),
+}
+
+pub struct DecodingNodeBranch {
+    pub left: u32,
+    pub right: u32,
+}
+
+impl<'a> StringDecoder<'a> {
+    pub fn new(image: &'a [u8]) -> Result<Self, DisassemblyError> {
+        let decoding_table_addr = GlulxHeader::new(image)?.decoding_table;
+        let image = ImageReader::new(image)?;
+        let mut table = FnvHashMap::default();
+        let mut cursor = image;
+
+        // A storyfile without compressed strings doesn't need a table
+        if decoding_table_addr == 0 {
+            return Ok(StringDecoder {
+                image,
+                root_node_addr: 0,
+                table,
+            });
+        }
+        cursor.set_position(decoding_table_addr + 8);
+        let root_node_addr = cursor.get_u32()?;
+
+        // Keep a list of nodes to process and loop through
+        // I tried doing this recursively but couldn't make it work with the borrow checker
+        let mut nodes_to_process = vec![root_node_addr];
+        while let Some(addr) = nodes_to_process.pop() {
+            if table.contains_key(&addr) {
+                continue;
+            }
+            cursor.set_position(addr);
+            let node_type = cursor.get_u8()?;
+            let node = match node_type {
+                0x00 => {
+                    let left = cursor.get_u32()?;
+                    let right = cursor.get_u32()?;
+                    nodes_to_process.push(left);
+                    nodes_to_process.push(right);
+                    DecodingNode::Branch(DecodingNodeBranch {
+                        left,
+                        right,
+                    })
+                },
+                0x01 => DecodingNode::Terminator,
+                0x02 => DecodingNode::Char(cursor.get_u8()?),
+                0x03 => {
+                    let mut chars = Vec::new();
+                    loop {
+                        let c = cursor.get_u8()?;
+                        if c == 0 {
+                            break;
+                        }
+                        chars.push(c);
+                    }
+                    DecodingNode::CString(chars)
+                },
+                0x04 => DecodingNode::UnicodeChar(cursor.get_u32()?),
+                0x05 => {
+                    let mut chars = Vec::new();
+                    loop {
+                        let c = cursor.get_u32()?;
+                        if c == 0 {
+                            break;
+                        }
+                        chars.push(c);
+                    }
+                    DecodingNode::UnicodeString(chars)
+                },
+                0x08 => DecodingNode::Indirect(cursor.get_u32()?),
+                0x09 => DecodingNode::DoubleIndirect(cursor.get_u32()?),
+                0x0A | 0x0B => {
+                    let addr = cursor.get_u32()?;
+                    let count = cursor.get_u32()?;
+                    let mut args = Vec::new();
+                    for _ in 0..count {
+                        args.push(cursor.get_u32()?);
+                    }
+                    if node_type == 0x0A {
+                        DecodingNode::IndirectWithArgs(addr, args)
+                    }
+                    else {
+                        DecodingNode::DoubleIndirectWithArgs(addr, args)
+                    }
+                },
+                _ => return Err(DisassemblyError::InvalidDecodingNode { addr, node_type }),
+            };
+            table.insert(addr, node);
+        }
+
+        Ok(StringDecoder {
+            image,
+            root_node_addr,
+            table,
+        })
+    }
+
+    // Find the functions which the decoding table refers to
+    pub fn referenced_functions(&self) -> Vec<u32> {
+        let mut functions = Vec::new();
+        for node in self.table.values() {
+            let addr = match node {
+                DecodingNode::Indirect(addr) | DecodingNode::IndirectWithArgs(addr, _) => *addr,
+                DecodingNode::DoubleIndirect(addr) | DecodingNode::DoubleIndirectWithArgs(addr, _) => match self.image.read_u32(*addr) {
+                    Ok(addr) => addr,
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+            if let Ok(0xC0) | Ok(0xC1) = self.image.at(addr).get_u8() {
+                functions.push(addr);
+            }
+        }
+        functions.sort_unstable();
+        functions
+    }
+
+    // Decode the string at an address
+    // Indirect references to strings are decoded too, but references to functions can only be shown as placeholders
+    pub fn decode(&self, addr: u32) -> Result<String, DisassemblyError> {
+        let mut text = String::new();
+        self.decode_into(&mut text, addr, 0)?;
+        Ok(text)
+    }
+
+    // Find the address just past the end of a compressed string, without decoding it
+    pub fn compressed_string_end(&self, addr: u32) -> Result<u32, DisassemblyError> {
+        self.walk_compressed_string(addr + 1, &mut |_| Ok(()))
+    }
+
+    fn decode_into(&self, text: &mut String, addr: u32, depth: u32) -> Result<(), DisassemblyError> {
+        if depth > MAX_INDIRECT_DEPTH {
+            return Err(DisassemblyError::IndirectStringTooDeep { addr });
+        }
+        let mut cursor = self.image.at(addr);
+        match cursor.get_u8()? {
+            0xE0 => loop {
+                let c = cursor.get_u8()?;
+                if c == 0 {
+                    break;
+                }
+                text.push(c as char);
+            },
+            0xE1 => {
+                self.walk_compressed_string(addr + 1, &mut |node| {
+                    use DecodingNode::*;
+                    match node {
+                        Char(c) => text.push(*c as char),
+                        CString(chars) => text.extend(chars.iter().map(|&c| c as char)),
+                        UnicodeChar(c) => text.push(unicode_char(*c)),
+                        UnicodeString(chars) => text.extend(chars.iter().map(|&c| unicode_char(c))),
+                        Indirect(addr) => return self.decode_indirect(text, *addr, &[], depth),
+                        DoubleIndirect(addr) => return self.decode_indirect(text, self.image.read_u32(*addr)?, &[], depth),
+                        IndirectWithArgs(addr, args) => return self.decode_indirect(text, *addr, args, depth),
+                        DoubleIndirectWithArgs(addr, args) => return self.decode_indirect(text, self.image.read_u32(*addr)?, args, depth),
+                        Branch(_) | Terminator => unreachable!(),
+                    };
+                    Ok(())
+                })?;
+            },
+            0xE2 => {
+                cursor.set_position(addr + 4);
+                loop {
+                    let c = cursor.get_u32()?;
+                    if c == 0 {
+                        break;
+                    }
+                    text.push(unicode_char(c));
+                }
+            },
+            object_type => return Err(DisassemblyError::NotAString { addr, object_type }),
+        }
+        Ok(())
+    }
+
+    // Indirect references can point to either strings or functions
+    fn decode_indirect(&self, text: &mut String, addr: u32, args: &[u32], depth: u32) -> Result<(), DisassemblyError> {
+        match self.image.at(addr).get_u8()? {
+            0xE0 ..= 0xE2 => self.decode_into(text, addr, depth + 1)?,
+            0xC0 | 0xC1 => {
+                if args.is_empty() {
+                    text.push_str(&format!("[function {}]", addr));
+                }
+                else {
+                    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                    text.push_str(&format!("[function {}({})]", addr, args.join(", ")));
+                }
+            },
+            object_type => return Err(DisassemblyError::InvalidIndirectReference { addr, object_type }),
+        }
+        Ok(())
+    }
+
+    // Walk through the bits of a compressed string, passing each leaf node to the visitor, and return the address of the end of the string
+    fn walk_compressed_string(&self, addr: u32, visit: &mut dyn FnMut(&DecodingNode) -> Result<(), DisassemblyError>) -> Result<u32, DisassemblyError> {
+        let root_node = self.get_node(self.root_node_addr)?;
+        let mut cursor = self.image.at(addr);
+        let mut byte = 0;
+        let mut bits = 0;
+        if let DecodingNode::Branch(_) | DecodingNode::Terminator = root_node {}
+        else {
+            return Err(DisassemblyError::InvalidDecodingTableRoot { addr: self.root_node_addr });
+        }
+        let mut node = root_node;
+        loop {
+            match node {
+                DecodingNode::Branch(branch) => {
+                    if bits == 0 {
+                        byte = cursor.get_u8()?;
+                        bits = 8;
+                    }
+                    let bit = byte & 0x01;
+                    bits -= 1;
+                    byte >>= 1;
+                    node = self.get_node(if bit == 0 {branch.left} else {branch.right})?;
+                },
+                DecodingNode::Terminator => break,
+                _ => {
+                    visit(node)?;
+                    node = root_node;
+                },
+            }
+        }
+        Ok(cursor.position())
+    }
+
+    fn get_node(&self, addr: u32) -> Result<&DecodingNode, DisassemblyError> {
+        match self.table.get(&addr) {
+            Some(node) => Ok(node),
+            None => Err(DisassemblyError::MissingDecodingNode { addr }),
+        }
+    }
+}
+
+fn unicode_char(c: u32) -> char {
+    std::char::from_u32(c).unwrap_or(std::char::REPLACEMENT_CHARACTER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a storyfile image containing a string decoding table
+    struct ImageBuilder {
+        image: Vec<u8>,
+    }
+
+    impl ImageBuilder {
+        fn new() -> Self {
+            ImageBuilder {
+                image: vec![0; HEADER_LENGTH as usize],
+            }
+        }
+
+        fn push(&mut self, bytes: &[u8]) -> u32 {
+            let addr = self.image.len() as u32;
+            self.image.extend_from_slice(bytes);
+            addr
+        }
+
+        fn push_words(&mut self, prefix: &[u8], words: &[u32]) -> u32 {
+            let addr = self.push(prefix);
+            for word in words {
+                self.push(&word.to_be_bytes());
+            }
+            addr
+        }
+
+        // Pack the leaf indices of a balanced tree of 16 leaves into a compressed string
+        fn push_compressed_string(&mut self, leaves: &[u8]) -> u32 {
+            let mut bytes = vec![0xE1];
+            let mut bit = 0;
+            for leaf in leaves {
+                // Bits are read from the lowest bit of each byte, and the first bit chooses the branch from the root
+                for shift in (0..4).rev() {
+                    if bit % 8 == 0 {
+                        bytes.push(0);
+                    }
+                    *bytes.last_mut().unwrap() |= ((leaf >> shift) & 1) << (bit % 8);
+                    bit += 1;
+                }
+            }
+            self.push(&bytes)
+        }
+
+        // Join the nodes into a balanced tree and write the table, returning the image
+        fn finish(mut self, mut nodes: Vec<u32>) -> Vec<u8> {
+            while nodes.len() > 1 {
+                nodes = nodes.chunks(2).map(|pair| self.push_words(&[0x00], pair)).collect();
+            }
+            let table_addr = self.push(&[]);
+            self.push(&[0; 8]);
+            self.push(&nodes[0].to_be_bytes());
+            // Round the image up to a page and make it all ROM
+            let length = (self.image.len() as u32 + 0xFF) & !0xFF;
+            self.image.resize(length as usize, 0);
+            for (addr, value) in [(12, length), (16, length), (28, table_addr)] {
+                self.image[addr..addr + 4].copy_from_slice(&value.to_be_bytes());
+            }
+            self.image
+        }
+    }
+
+    struct TestImage {
+        image: Vec<u8>,
+        main: u32,
+        looping: u32,
+        latin1: u32,
+        unicode: u32,
+        function_1: u32,
+        function_2: u32,
+    }
+
+    fn make_image() -> TestImage {
+        let mut builder = ImageBuilder::new();
+        // Strings
+        let main = builder.push_compressed_string(&[1, 2, 3, 4, 5, 6, 7, 9, 0]);
+        // This string refers to itself forever
+        let looping = builder.push_compressed_string(&[8, 0]);
+        let latin1 = builder.push(&[0xE0, b'x', b'y', 0]);
+        let unicode = builder.push_words(&[0xE2, 0, 0, 0], &[0x41, 0x3A9, 0]);
+        // Functions, and pointers for the double indirect references
+        let function_1 = builder.push(&[0xC1, 0, 0]);
+        let function_2 = builder.push(&[0xC0, 0, 0]);
+        let function_1_pointer = builder.push(&function_1.to_be_bytes());
+        let unicode_pointer = builder.push(&unicode.to_be_bytes());
+
+        // The leaf nodes
+        let mut nodes = vec![
+            builder.push(&[0x01]),
+            builder.push(&[0x02, b'a']),
+            builder.push(&[0x03, b'b', b'c', 0]),
+            builder.push_words(&[0x04], &[0xE9]),
+            builder.push_words(&[0x05], &[0x3A9, 0x3A9, 0]),
+            builder.push_words(&[0x08], &[latin1]),
+            builder.push_words(&[0x0B], &[function_1_pointer, 2, 1, 2]),
+            builder.push_words(&[0x08], &[function_2]),
+            builder.push_words(&[0x08], &[looping]),
+            builder.push_words(&[0x09], &[unicode_pointer]),
+        ];
+        while nodes.len() < 16 {
+            let c = b'0' + nodes.len() as u8;
+            nodes.push(builder.push(&[0x02, c]));
+        }
+
+        TestImage {
+            image: builder.finish(nodes),
+            main,
+            looping,
+            latin1,
+            unicode,
+            function_1,
+            function_2,
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let test = make_image();
+        let decoder = StringDecoder::new(&test.image).unwrap();
+        assert_eq!(decoder.decode(test.latin1).unwrap(), "xy");
+        assert_eq!(decoder.decode(test.unicode).unwrap(), "A\u{3A9}");
+        assert_eq!(decoder.decode(test.main).unwrap(), format!("abc\u{E9}\u{3A9}\u{3A9}xy[function {}(1, 2)][function {}]A\u{3A9}", test.function_1, test.function_2));
+        // 9 leaves of 4 bits each take 5 bytes
+        assert_eq!(decoder.compressed_string_end(test.main).unwrap(), test.main + 6);
+        assert_eq!(decoder.compressed_string_end(test.looping).unwrap(), test.looping + 2);
+        assert_eq!(decoder.decode(test.function_1), Err(DisassemblyError::NotAString { addr: test.function_1, object_type: 0xC1 }));
+    }
+
+    #[test]
+    fn test_indirect_references() {
+        let test = make_image();
+        let decoder = StringDecoder::new(&test.image).unwrap();
+        // Only references to functions are returned, not references to strings
+        assert_eq!(decoder.referenced_functions(), vec![test.function_1, test.function_2]);
+        assert_eq!(decoder.decode(test.looping), Err(DisassemblyError::IndirectStringTooDeep { addr: test.looping }));
+    }
+}
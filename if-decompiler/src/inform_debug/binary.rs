@@ -0,0 +1,316 @@
+/*
+
+Inform Binary Debug Files
+=========================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+// The binary debug file format produced by Inform 6.2x and 6.3x, as described in §12.5 of the Inform Technical Manual
+
+use std::collections::BTreeMap;
+
+use fnv::FnvHashMap;
+
+use super::*;
+
+pub const BINARY_DEBUG_MAGIC: [u8; 2] = [0xDE, 0xBF];
+
+const EOF_DBR: u8 = 0;
+const FILE_DBR: u8 = 1;
+const CLASS_DBR: u8 = 2;
+const OBJECT_DBR: u8 = 3;
+const GLOBAL_DBR: u8 = 4;
+const ATTR_DBR: u8 = 5;
+const PROP_DBR: u8 = 6;
+const FAKE_ACTION_DBR: u8 = 7;
+const ACTION_DBR: u8 = 8;
+const HEADER_DBR: u8 = 9;
+const LINEREF_DBR: u8 = 10;
+const ROUTINE_DBR: u8 = 11;
+const ARRAY_DBR: u8 = 12;
+const MAP_DBR: u8 = 13;
+const ROUTINE_END_DBR: u8 = 14;
+
+// Routines are given relative to the code area, and globals by their number, so we need to collect everything before we can work out their addresses
+#[derive(Default)]
+struct PendingRoutine {
+    start: u32,
+    end: u32,
+    name: String,
+    locals: Vec<String>,
+    // Sequence points relative to the routine start
+    sequence_points: Vec<(u32, SourceLocation)>,
+    start_location: Option<SourceLocation>,
+    end_location: Option<SourceLocation>,
+}
+
+pub fn parse_binary_debug_file(data: &[u8]) -> Result<DebugData, InformDebugError> {
+    let mut reader = BinaryReader {
+        data,
+        pos: 0,
+    };
+    if reader.bytes(2)? != BINARY_DEBUG_MAGIC {
+        return Err(InformDebugError::NotADebugFile);
+    }
+    // Skip the debug file version and the Inform version
+    reader.bytes(4)?;
+
+    let mut result = DebugData::default();
+    let mut globals = Vec::new();
+    let mut is_glulx = false;
+    let mut map = FnvHashMap::default();
+    let mut routines: BTreeMap<u32, PendingRoutine> = BTreeMap::new();
+
+    loop {
+        let offset = reader.pos as u32;
+        match reader.byte()? {
+            EOF_DBR => break,
+            FILE_DBR => {
+                let index = reader.byte()? as u32;
+                let given_path = reader.string()?;
+                let resolved_path = reader.string()?;
+                result.sources.insert(index, SourceFile {
+                    index,
+                    given_path,
+                    resolved_path: Some(resolved_path),
+                    language: None,
+                });
+            },
+            // Classes don't have a number in the binary format, so we have nothing to record for them
+            CLASS_DBR => {
+                reader.string()?;
+                reader.location()?;
+                reader.location()?;
+            },
+            OBJECT_DBR => {
+                result.objects.push(reader.symbol()?);
+                reader.location()?;
+                reader.location()?;
+            },
+            GLOBAL_DBR => {
+                let number = reader.byte()? as u32;
+                globals.push((number, reader.string()?));
+            },
+            ATTR_DBR => result.attributes.push(reader.symbol()?),
+            PROP_DBR => result.properties.push(reader.symbol()?),
+            FAKE_ACTION_DBR => result.fake_actions.push(reader.symbol()?),
+            ACTION_DBR => result.actions.push(reader.symbol()?),
+            HEADER_DBR => {
+                is_glulx = reader.bytes(64)?.starts_with(b"Glul");
+            },
+            LINEREF_DBR => {
+                let routine = routines.entry(reader.word()?).or_default();
+                let count = reader.word()?;
+                for _ in 0..count {
+                    let location = reader.location()?;
+                    let pc_offset = reader.word()?;
+                    routine.sequence_points.push((pc_offset, location));
+                }
+            },
+            ROUTINE_DBR => {
+                let routine = routines.entry(reader.word()?).or_default();
+                routine.start_location = Some(reader.location()?);
+                routine.start = reader.address()?;
+                routine.name = reader.string()?;
+                // The list of locals is ended by an empty string
+                loop {
+                    let local = reader.string()?;
+                    if local.is_empty() {
+                        break;
+                    }
+                    routine.locals.push(local);
+                }
+            },
+            // Array sizes are not recorded in the binary format, so we skip them
+            ARRAY_DBR => {
+                reader.word()?;
+                reader.string()?;
+            },
+            MAP_DBR => loop {
+                let name = reader.string()?;
+                if name.is_empty() {
+                    break;
+                }
+                map.insert(name, reader.address()?);
+            },
+            ROUTINE_END_DBR => {
+                let routine = routines.entry(reader.word()?).or_default();
+                routine.end_location = Some(reader.location()?);
+                routine.end = reader.address()?;
+            },
+            record_type => return Err(InformDebugError::UnknownRecordType { offset, record_type }),
+        };
+    }
+
+    // Now we can work out the addresses
+    let code_area = map.get("code area").copied().unwrap_or(0);
+    for routine in routines.into_values() {
+        let addr = code_area + routine.start;
+        result.routines.insert(addr, Routine {
+            addr,
+            len: routine.end.saturating_sub(routine.start),
+            name: routine.name,
+            // Glulx locals are all four bytes
+            locals: routine.locals.into_iter().enumerate().map(|(index, name)| LocalVariable {
+                name,
+                index: Some(index as u32 + 1),
+                frame_offset: if is_glulx { Some(index as u32 * 4) } else { None },
+                scope: None,
+            }).collect(),
+            sequence_points: routine.sequence_points.into_iter().map(|(pc_offset, location)| SequencePoint {
+                addr: addr + pc_offset,
+                location,
+            }).collect(),
+            start_location: routine.start_location,
+            end_location: routine.end_location,
+        });
+    }
+    if let Some(&globals_addr) = map.get("global variables") {
+        let global_size = if is_glulx { 4 } else { 2 };
+        for (number, name) in globals {
+            result.globals.push(GlobalVariable {
+                name,
+                addr: globals_addr + number * global_size,
+            });
+        }
+    }
+
+    Ok(result)
+}
+
+struct BinaryReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BinaryReader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], InformDebugError> {
+        let bytes = self.data.get(self.pos..self.pos + length).ok_or(InformDebugError::UnexpectedEof)?;
+        self.pos += length;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, InformDebugError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn word(&mut self) -> Result<u32, InformDebugError> {
+        let bytes = self.bytes(2)?;
+        Ok((bytes[0] as u32) << 8 | bytes[1] as u32)
+    }
+
+    fn address(&mut self) -> Result<u32, InformDebugError> {
+        let bytes = self.bytes(3)?;
+        Ok((bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32)
+    }
+
+    // Strings are null terminated
+    fn string(&mut self) -> Result<String, InformDebugError> {
+        let length = self.data[self.pos..].iter().position(|&byte| byte == 0).ok_or(InformDebugError::UnexpectedEof)?;
+        let string = String::from_utf8_lossy(self.bytes(length)?).to_string();
+        self.pos += 1;
+        Ok(string)
+    }
+
+    fn location(&mut self) -> Result<SourceLocation, InformDebugError> {
+        Ok(SourceLocation {
+            file_index: self.byte()? as u32,
+            file_position: None,
+            line: self.word()?,
+            character: Some(self.byte()? as u32),
+        })
+    }
+
+    fn symbol(&mut self) -> Result<Symbol, InformDebugError> {
+        let value = self.word()?;
+        Ok(Symbol {
+            name: self.string()?,
+            value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(file_index: u8, line: u16, character: u8) -> Vec<u8> {
+        let mut bytes = vec![file_index];
+        bytes.extend_from_slice(&line.to_be_bytes());
+        bytes.push(character);
+        bytes
+    }
+
+    fn address(addr: u32) -> Vec<u8> {
+        addr.to_be_bytes()[1..].to_vec()
+    }
+
+    // A routine at 272, with two locals and two sequence points, and a global at 520
+    fn make_binary_debug_file() -> Vec<u8> {
+        let mut header = b"Glul".to_vec();
+        header.resize(64, 0);
+        [
+            &BINARY_DEBUG_MAGIC[..],
+            &[0, 0, 6, 35],
+            &[HEADER_DBR], &header,
+            &[FILE_DBR, 0], b"test\0/tmp/test.inf\0",
+            &[GLOBAL_DBR, 2], b"score\0",
+            &[ROUTINE_DBR, 0, 0], &location(0, 4, 1), &address(0x10), b"main\0a\0b\0\0",
+            &[LINEREF_DBR, 0, 0, 0, 2], &location(0, 5, 1), &[0, 0], &location(0, 6, 3), &[0, 7],
+            &[ROUTINE_END_DBR, 0, 0], &location(0, 8, 1), &address(0x30),
+            &[MAP_DBR], b"code area\0", &address(0x100), b"global variables\0", &address(0x200), b"\0",
+            &[EOF_DBR],
+        ].concat()
+    }
+
+    // The same data in the XML format
+    const XML_DEBUG_FILE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<inform-story-file version="1.0" content-creator="Inform" content-creator-version="6.35">
+<source index="0"><given-path>test</given-path><resolved-path>/tmp/test.inf</resolved-path></source>
+<global-variable><identifier>score</identifier><address>520</address></global-variable>
+<routine>
+    <identifier>main</identifier>
+    <value>272</value>
+    <address>272</address>
+    <byte-count>32</byte-count>
+    <source-code-location><file-index>0</file-index><line>4</line><character>1</character></source-code-location>
+    <source-code-location><file-index>0</file-index><line>8</line><character>1</character></source-code-location>
+    <local-variable><identifier>a</identifier><index>1</index><frame-offset>0</frame-offset></local-variable>
+    <local-variable><identifier>b</identifier><index>2</index><frame-offset>4</frame-offset></local-variable>
+    <sequence-point><address>272</address><source-code-location><file-index>0</file-index><line>5</line><character>1</character></source-code-location></sequence-point>
+    <sequence-point><address>279</address><source-code-location><file-index>0</file-index><line>6</line><character>3</character></source-code-location></sequence-point>
+</routine>
+</inform-story-file>
+"#;
+
+    #[test]
+    fn test_matches_xml() {
+        let binary = parse_debug_file(&make_binary_debug_file()[..]).unwrap();
+        let xml = parse_debug_file(XML_DEBUG_FILE.as_bytes()).unwrap();
+
+        let routines = |data: &DebugData| data.routines.values().map(|routine| (
+            routine.addr,
+            routine.len,
+            routine.name.clone(),
+            routine.locals.iter().map(|local| (local.name.clone(), local.index, local.frame_offset, local.scope)).collect::<Vec<_>>(),
+            routine.sequence_points.iter().map(|point| (point.addr, point.location)).collect::<Vec<_>>(),
+            routine.start_location,
+            routine.end_location,
+        )).collect::<Vec<_>>();
+        assert_eq!(routines(&binary), routines(&xml));
+        assert_eq!(binary.routines[&272].sequence_points[1].addr, 279);
+
+        let function_data = |data: &DebugData| data.function_data().into_values().map(|function| (function.addr, function.len, function.name)).collect::<Vec<_>>();
+        assert_eq!(function_data(&binary), vec![(272, 32, String::from("main"))]);
+        assert_eq!(function_data(&binary), function_data(&xml));
+
+        let globals = |data: &DebugData| data.globals.iter().map(|global| (global.name.clone(), global.addr)).collect::<Vec<_>>();
+        assert_eq!(globals(&binary), globals(&xml));
+        let sources = |data: &DebugData| data.sources.values().map(|source| (source.index, source.given_path.clone(), source.resolved_path.clone())).collect::<Vec<_>>();
+        assert_eq!(sources(&binary), sources(&xml));
+    }
+}
@@ -0,0 +1,63 @@
+/*
+
+Inform Debug File Errors
+========================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// Something wrong with an Inform debug file
+#[derive(Debug)]
+pub enum InformDebugError {
+    InvalidNumber { element: String, field: &'static str, value: String },
+    Io(io::Error),
+    MissingField { element: String, field: &'static str },
+    NotADebugFile,
+    UnexpectedEof,
+    UnknownRecordType { offset: u32, record_type: u8 },
+    Xml(quick_xml::Error),
+}
+
+impl fmt::Display for InformDebugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InformDebugError::*;
+        match self {
+            InvalidNumber { element, field, value } => write!(f, "Invalid number {:?} for {} in {} element", value, field, element),
+            Io(err) => write!(f, "Error reading debug file: {}", err),
+            MissingField { element, field } => write!(f, "Missing {} in {} element", field, element),
+            NotADebugFile => write!(f, "Not an Inform debug file"),
+            UnexpectedEof => write!(f, "Debug file ended unexpectedly"),
+            UnknownRecordType { offset, record_type } => write!(f, "Unknown record type {} at {} in binary debug file", record_type, offset),
+            Xml(err) => write!(f, "XML error in debug file: {}", err),
+        }
+    }
+}
+
+impl Error for InformDebugError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InformDebugError::Io(err) => Some(err),
+            InformDebugError::Xml(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for InformDebugError {
+    fn from(err: io::Error) -> Self {
+        InformDebugError::Io(err)
+    }
+}
+
+impl From<quick_xml::Error> for InformDebugError {
+    fn from(err: quick_xml::Error) -> Self {
+        InformDebugError::Xml(err)
+    }
+}
@@ -0,0 +1,357 @@
+/*
+
+Inform Debug Files
+==================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+use quick_xml::events::{BytesStart, Event};
+
+use super::*;
+
+mod binary;
+mod error;
+pub use binary::BINARY_DEBUG_MAGIC;
+pub use error::*;
+
+// Everything we can learn from an Inform debug file (gameinfo.dbg)
+#[derive(Debug, Default)]
+pub struct DebugData {
+    pub actions: Vec<Symbol>,
+    pub arrays: Vec<Array>,
+    pub attributes: Vec<Symbol>,
+    pub classes: Vec<Symbol>,
+    pub constants: Vec<Symbol>,
+    pub fake_actions: Vec<Symbol>,
+    pub globals: Vec<GlobalVariable>,
+    pub objects: Vec<Symbol>,
+    pub properties: Vec<Symbol>,
+    pub routines: BTreeMap<u32, Routine>,
+    pub sources: BTreeMap<u32, SourceFile>,
+    pub story_file_sections: Vec<StoryFileSection>,
+}
+
+// A name with a value, used for constants, objects, properties etc
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+}
+
+#[derive(Debug)]
+pub struct Array {
+    pub name: String,
+    pub addr: u32,
+    pub byte_count: u32,
+    pub bytes_per_element: u32,
+    pub zeroth_element_holds_length: bool,
+}
+
+#[derive(Debug)]
+pub struct GlobalVariable {
+    pub name: String,
+    pub addr: u32,
+}
+
+#[derive(Debug)]
+pub struct Routine {
+    pub addr: u32,
+    pub len: u32,
+    pub name: String,
+    pub locals: Vec<LocalVariable>,
+    pub sequence_points: Vec<SequencePoint>,
+    // Where the routine starts and ends in the source code
+    pub start_location: Option<SourceLocation>,
+    pub end_location: Option<SourceLocation>,
+}
+
+// Z-Code locals are identified by their index, Glulx locals by their offset in the call frame
+#[derive(Debug)]
+pub struct LocalVariable {
+    pub name: String,
+    pub index: Option<u32>,
+    pub frame_offset: Option<u32>,
+    // The range of addresses in which the local is in scope, if it doesn't cover the whole routine
+    pub scope: Option<(u32, u32)>,
+}
+
+#[derive(Debug)]
+pub struct SequencePoint {
+    pub addr: u32,
+    pub location: SourceLocation,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SourceLocation {
+    pub file_index: u32,
+    pub file_position: Option<u32>,
+    pub line: u32,
+    pub character: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct SourceFile {
+    pub index: u32,
+    pub given_path: String,
+    pub resolved_path: Option<String>,
+    pub language: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct StoryFileSection {
+    pub section_type: String,
+    pub addr: u32,
+    pub end_addr: u32,
+}
+
+impl DebugData {
+    // The routine data needed by the disassemblers
+    pub fn function_data(&self) -> BTreeMap<u32, DebugFunctionData> {
+        self.routines.values().map(|routine| (routine.addr, DebugFunctionData {
+            addr: routine.addr,
+            len: routine.len,
+            name: routine.name.clone(),
+        })).collect()
+    }
+}
+
+// Parse an Inform debug file, either the XML format or the older binary format
+pub fn parse_debug_file<R: BufRead>(mut source: R) -> Result<DebugData, InformDebugError> {
+    if source.fill_buf()?.starts_with(&BINARY_DEBUG_MAGIC) {
+        let mut data = Vec::new();
+        source.read_to_end(&mut data)?;
+        return binary::parse_binary_debug_file(&data);
+    }
+    parse_xml_debug_file(source)
+}
+
+fn parse_xml_debug_file<R: BufRead>(source: R) -> Result<DebugData, InformDebugError> {
+    let mut reader = quick_xml::Reader::from_reader(source);
+    reader.trim_text(true);
+    let mut result = DebugData::default();
+    let mut buf = Vec::new();
+    // The elements we're currently inside, not including the root <inform-story-file>
+    let mut stack: Vec<Element> = Vec::new();
+    let mut depth = 0;
+
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(ref e) => {
+                if depth > 0 {
+                    stack.push(Element::new(e, &reader)?);
+                }
+                else if e.name() != b"inform-story-file" {
+                    return Err(InformDebugError::NotADebugFile);
+                }
+                depth += 1;
+            },
+            Event::Empty(ref e) if depth > 0 => {
+                let element = Element::new(e, &reader)?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => result.add_element(element)?,
+                }
+            },
+            Event::Text(e) => {
+                // The story file prefix is a large base64 blob we have no use for
+                if let Some(element) = stack.last_mut() {
+                    if element.name != "story-file-prefix" {
+                        element.text.push_str(&e.unescape_and_decode(&reader)?);
+                    }
+                }
+            },
+            Event::End(_) => {
+                depth -= 1;
+                if let Some(element) = stack.pop() {
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => result.add_element(element)?,
+                    }
+                }
+            },
+            Event::Eof => {
+                if depth > 0 {
+                    return Err(InformDebugError::UnexpectedEof);
+                }
+                break;
+            },
+            _ => {},
+        };
+        buf.clear();
+    }
+
+    Ok(result)
+}
+
+impl DebugData {
+    // Add a top level element
+    fn add_element(&mut self, element: Element) -> Result<(), InformDebugError> {
+        match element.name.as_str() {
+            "action" => self.actions.push(element.symbol()?),
+            "array" => self.arrays.push(Array {
+                name: element.text("identifier")?,
+                addr: element.number("value")?,
+                byte_count: element.number("byte-count")?,
+                bytes_per_element: element.number("bytes-per-element")?,
+                zeroth_element_holds_length: element.child("zeroth-element-holds-length").map(|child| child.text.trim()) == Some("true"),
+            }),
+            "attribute" => self.attributes.push(element.symbol()?),
+            "class" => self.classes.push(element.symbol()?),
+            "constant" => self.constants.push(element.symbol()?),
+            "fake-action" => self.fake_actions.push(element.symbol()?),
+            "global-variable" => self.globals.push(GlobalVariable {
+                name: element.text("identifier")?,
+                addr: element.number("address")?,
+            }),
+            "object" => self.objects.push(element.symbol()?),
+            "property" => self.properties.push(element.symbol()?),
+            "routine" => {
+                let routine = element.routine()?;
+                self.routines.insert(routine.addr, routine);
+            },
+            "source" => {
+                let index = match element.attributes.iter().find(|(key, _)| key == "index") {
+                    Some((_, value)) => parse_number(&element.name, "index", value)?,
+                    None => return Err(element.missing("index")),
+                };
+                self.sources.insert(index, SourceFile {
+                    index,
+                    given_path: element.text("given-path")?,
+                    resolved_path: element.child("resolved-path").map(|child| child.text.clone()),
+                    language: element.child("language").map(|child| child.text.clone()),
+                });
+            },
+            "story-file-section" => self.story_file_sections.push(StoryFileSection {
+                section_type: element.text("type")?,
+                addr: element.number("address")?,
+                end_addr: element.number("end-address")?,
+            }),
+            _ => {},
+        };
+        Ok(())
+    }
+}
+
+// A simple XML element tree
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    text: String,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn new<R: BufRead>(e: &BytesStart, reader: &quick_xml::Reader<R>) -> Result<Self, InformDebugError> {
+        let mut attributes = Vec::new();
+        for attribute in e.attributes() {
+            let attribute = attribute?;
+            attributes.push((String::from_utf8_lossy(attribute.key).to_string(), attribute.unescape_and_decode_value(reader)?));
+        }
+        Ok(Element {
+            name: String::from_utf8_lossy(e.name()).to_string(),
+            attributes,
+            text: String::new(),
+            children: Vec::new(),
+        })
+    }
+
+    // The first child with a name
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    fn missing(&self, field: &'static str) -> InformDebugError {
+        InformDebugError::MissingField { element: self.name.clone(), field }
+    }
+
+    fn text(&self, field: &'static str) -> Result<String, InformDebugError> {
+        self.child(field).map(|child| child.text.clone()).ok_or_else(|| self.missing(field))
+    }
+
+    fn number(&self, field: &'static str) -> Result<u32, InformDebugError> {
+        match self.child(field) {
+            Some(child) => parse_number(&self.name, field, &child.text),
+            None => Err(self.missing(field)),
+        }
+    }
+
+    fn optional_number(&self, field: &'static str) -> Result<Option<u32>, InformDebugError> {
+        self.child(field).map(|child| parse_number(&self.name, field, &child.text)).transpose()
+    }
+
+    fn location(&self) -> Result<SourceLocation, InformDebugError> {
+        Ok(SourceLocation {
+            file_index: self.number("file-index")?,
+            file_position: self.optional_number("file-position")?,
+            line: self.number("line")?,
+            character: self.optional_number("character")?,
+        })
+    }
+
+    fn symbol(&self) -> Result<Symbol, InformDebugError> {
+        Ok(Symbol {
+            name: self.text("identifier")?,
+            value: self.number("value")?,
+        })
+    }
+
+    fn routine(&self) -> Result<Routine, InformDebugError> {
+        let mut locations = self.children.iter().filter(|child| child.name == "source-code-location");
+        let start_location = locations.next().map(|child| child.location()).transpose()?;
+        let end_location = locations.next().map(|child| child.location()).transpose()?;
+        let mut locals = Vec::new();
+        let mut sequence_points = Vec::new();
+        for child in &self.children {
+            match child.name.as_str() {
+                "local-variable" => {
+                    let scope = match (child.optional_number("scope-address")?, child.optional_number("end-scope-address")?) {
+                        (Some(start), Some(end)) => Some((start, end)),
+                        _ => None,
+                    };
+                    locals.push(LocalVariable {
+                        name: child.text("identifier")?,
+                        index: child.optional_number("index")?,
+                        frame_offset: child.optional_number("frame-offset")?,
+                        scope,
+                    });
+                },
+                "sequence-point" => {
+                    sequence_points.push(SequencePoint {
+                        addr: child.number("address")?,
+                        location: child.child("source-code-location").ok_or_else(|| child.missing("source-code-location"))?.location()?,
+                    });
+                },
+                _ => {},
+            };
+        }
+        Ok(Routine {
+            // The address is more reliable than the value, which is a packed address for Z-Code
+            addr: match self.optional_number("address")? {
+                Some(addr) => addr,
+                None => self.number("value")?,
+            },
+            len: self.number("byte-count")?,
+            name: self.text("identifier")?,
+            locals,
+            sequence_points,
+            start_location,
+            end_location,
+        })
+    }
+}
+
+// Numbers are often padded with spaces
+fn parse_number(element: &str, field: &'static str, value: &str) -> Result<u32, InformDebugError> {
+    value.trim().parse::<u32>().map_err(|_| InformDebugError::InvalidNumber {
+        element: element.to_string(),
+        field,
+        value: value.to_string(),
+    })
+}
@@ -16,7 +16,10 @@ use std::collections::BTreeMap;
 use fnv::{FnvHashMap, FnvHashSet};
 use petgraph::{graph, visit};
 
+pub mod blorb;
 pub mod glulx;
+pub mod inform_debug;
+pub mod zmachine;
 
 // Function data from an Inform debug file
 #[derive(Debug)]
@@ -77,8 +80,20 @@ pub trait VirtualMachine {
 
 // Generic instruction functions
 pub trait VMInstruction {
+    type Operand: Copy + PartialEq;
     fn addr(&self) -> u32;
     fn does_halt(&self) -> bool;
+    // If the instruction branches depending on whether a value equals some constants, return that comparison
+    fn comparison(&self) -> Option<Comparison<Self::Operand>>;
+}
+
+// A branch which compares a value against one or more constants
+pub struct Comparison<O> {
+    pub value: O,
+    pub constants: Vec<u32>,
+    // Where the branch goes when the value equals one of the constants, and where it goes otherwise
+    pub equal: u32,
+    pub unequal: u32,
 }
 
 // A generic basic block
@@ -140,6 +155,107 @@ pub fn calculate_basic_blocks<I: VMInstruction>(instructions: Vec<I>, entry_poin
     blocks
 }
 
+// A chain of comparisons of one value, which can be output as a switch
+// Each comparison after the first is a block by itself, reached only when the comparison before it is unequal
+#[derive(Clone, Debug)]
+pub struct SwitchChain<O> {
+    pub value: O,
+    // Each constant, and the label the chain branches to when the value equals it
+    pub cases: Vec<(u32, u32)>,
+    // Where the chain branches when the value equals none of the constants
+    pub default: u32,
+    // The labels of the comparisons after the first, whose blocks are merged into the first block when relooping
+    pub merged: Vec<u32>,
+}
+
+impl<O> SwitchChain<O> {
+    // The labels the chain branches to, without duplicates
+    pub fn targets(&self) -> Vec<u32> {
+        let mut targets: Vec<u32> = self.cases.iter().map(|&(_, label)| label).collect();
+        targets.push(self.default);
+        targets.sort_unstable();
+        targets.dedup();
+        targets
+    }
+
+    // The constants which branch to a label
+    pub fn constants_for(&self, label: u32) -> Vec<u32> {
+        self.cases.iter().filter(|&&(_, target)| target == label).map(|&(constant, _)| constant).collect()
+    }
+}
+
+// Find chains of comparisons which branch to more than two labels
+pub fn find_switch_chains<I: VMInstruction>(blocks: &BTreeMap<u32, BasicBlock<I>>) -> BTreeMap<u32, SwitchChain<I::Operand>> {
+    let mut predecessors: FnvHashMap<u32, u32> = FnvHashMap::default();
+    for block in blocks.values() {
+        for &branch in &block.branches {
+            *predecessors.entry(branch).or_default() += 1;
+        }
+    }
+    let comparisons: BTreeMap<u32, Comparison<I::Operand>> = blocks.iter()
+        .filter_map(|(&label, block)| block.code.last().and_then(|instruction| instruction.comparison()).map(|comparison| (label, comparison)))
+        .filter(|(_, comparison)| comparison.equal != comparison.unequal)
+        .collect();
+
+    // Whether a comparison could continue a chain from another comparison of the same value
+    let continues = |label: u32, value: I::Operand| blocks[&label].code.len() == 1 && predecessors.get(&label) == Some(&1)
+        && comparisons.get(&label).is_some_and(|comparison| comparison.value == value);
+    let continues_another = |label: u32| comparisons.iter().any(|(&other, comparison)| other != label && comparison.unequal == label && continues(label, comparison.value));
+
+    let mut chains = BTreeMap::new();
+    for (&label, first) in &comparisons {
+        // Chains are found from their first comparison
+        if continues_another(label) {
+            continue;
+        }
+        let mut cases = Vec::new();
+        let mut seen = FnvHashSet::default();
+        for &constant in &first.constants {
+            if seen.insert(constant) {
+                cases.push((constant, first.equal));
+            }
+        }
+        let mut merged = Vec::new();
+        let mut current = first.unequal;
+        while current != label && !merged.contains(&current) && continues(current, first.value) {
+            let comparison = &comparisons[&current];
+            // A constant which has already been compared could never be matched here, and can't be a duplicate case
+            if comparison.constants.iter().any(|constant| seen.contains(constant)) {
+                break;
+            }
+            for &constant in &comparison.constants {
+                if seen.insert(constant) {
+                    cases.push((constant, comparison.equal));
+                }
+            }
+            merged.push(current);
+            current = comparison.unequal;
+        }
+        let chain = SwitchChain {
+            value: first.value,
+            cases,
+            default: current,
+            merged,
+        };
+        if chain.targets().len() > 2 {
+            chains.insert(label, chain);
+        }
+    }
+    chains
+}
+
+// Prepare a function's blocks for the relooper, merging each switch chain into its first block
+pub fn relooper_blocks<I, O>(blocks: &BTreeMap<u32, BasicBlock<I>>, switches: &BTreeMap<u32, SwitchChain<O>>) -> Vec<(u32, Vec<u32>)> {
+    let merged: FnvHashSet<u32> = switches.values().flat_map(|chain| chain.merged.iter().copied()).collect();
+    blocks.iter()
+        .filter(|(label, _)| !merged.contains(label))
+        .map(|(&label, block)| (label, match switches.get(&label) {
+            Some(chain) => chain.targets(),
+            None => block.branches.iter().copied().collect(),
+        }))
+        .collect()
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum BranchTarget {
     Dynamic,
@@ -0,0 +1,554 @@
+/*
+
+Z-Machine Disassembler
+======================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use fnv::FnvHashSet;
+
+use super::*;
+
+// Operand types
+const LARGE_CONSTANT: u8 = 0;
+const SMALL_CONSTANT: u8 = 1;
+const VARIABLE: u8 = 2;
+const OMITTED: u8 = 3;
+
+// How many bytes of unparsable data will be skipped between functions when scanning the ROM
+const MAX_SKIPPED_BYTES: u32 = 8;
+
+impl ZMachineState {
+    pub fn disassemble(&mut self, image: &[u8]) -> Result<FnvHashSet<(u32, u32)>, DisassemblyError> {
+        self.version = self.read_byte(image, 0)?;
+        if self.version == 6 || self.version == 7 {
+            self.routines_offset = self.read_word(image, 0x28)? as u32 * 8;
+            self.strings_offset = self.read_word(image, 0x2A)? as u32 * 8;
+        }
+
+        let mut edges = FnvHashSet::default();
+        let mut functions_to_process = Vec::new();
+
+        // If we have debug file data, use it to disassemble all the functions
+        if let Some(functions) = &self.debug_function_data {
+            for (&addr, func) in functions {
+                match self.disassemble_function(image, addr, Some(func.len)) {
+                    Some(function) => {
+                        functions_to_process.extend(self.function_callees(addr, &function, &mut edges));
+                        self.functions.insert(addr, function);
+                    },
+                    None => self.warnings.push(DisassemblyError::InvalidFunction { addr }),
+                }
+            }
+        }
+        else {
+            // Otherwise scan through high memory, stopping when we find something that isn't a function
+            let alignment = self.function_alignment();
+            let mut addr = align(self.read_word(image, 0x04)? as u32, alignment);
+            let mut skipped = 0;
+            while (addr as usize) < image.len() {
+                match self.disassemble_function(image, addr, None) {
+                    Some(function) => {
+                        let end_addr = function_end(&function);
+                        functions_to_process.extend(self.function_callees(addr, &function, &mut edges));
+                        self.functions.insert(addr, function);
+                        addr = align(end_addr, alignment);
+                        skipped = 0;
+                    },
+                    None => {
+                        if skipped >= MAX_SKIPPED_BYTES {
+                            self.warnings.push(DisassemblyError::EndOfFunctions { addr: addr - skipped });
+                            break;
+                        }
+                        skipped += alignment;
+                        addr += alignment;
+                    },
+                }
+            }
+
+            // The initial PC points to the first instruction of the main function, except in version 6 where it is a packed address
+            let initial_pc = self.read_word(image, 0x06)?;
+            functions_to_process.push(if self.version == 6 {
+                self.unpack_routine_addr(initial_pc)
+            }
+            else {
+                (initial_pc as u32).checked_sub(1).ok_or(DisassemblyError::InvalidHeader { reason: "the initial PC is 0" })?
+            });
+        }
+
+        // Disassemble any called functions we haven't found yet
+        while let Some(addr) = functions_to_process.pop() {
+            if self.functions.contains_key(&addr) {
+                continue;
+            }
+            match self.disassemble_function(image, addr, None) {
+                Some(function) => {
+                    functions_to_process.extend(self.function_callees(addr, &function, &mut edges));
+                    self.functions.insert(addr, function);
+                },
+                None => self.warnings.push(DisassemblyError::InvalidFunction { addr }),
+            }
+        }
+
+        // Remove any edges to functions we couldn't disassemble
+        let functions = &self.functions;
+        edges.retain(|(_, callee_addr)| functions.contains_key(callee_addr));
+
+        Ok(edges)
+    }
+
+    // Functions must start at an address which can be packed
+    fn function_alignment(&self) -> u32 {
+        match self.version {
+            1 ..= 3 => 2,
+            4 ..= 7 => 4,
+            _ => 8,
+        }
+    }
+
+    // Get the addresses of the functions this function calls, and add them to the edges list
+    fn function_callees(&self, addr: u32, function: &Function, edges: &mut FnvHashSet<(u32, u32)>) -> Vec<u32> {
+        let mut callees = Vec::new();
+        for block in function.blocks.values() {
+            for instruction in &block.code {
+                if opcodes::instruction_calls(instruction.opcode, self.version) {
+                    // Calling address 0 does nothing except return false
+                    if let Operand::Constant(packed_addr) = instruction.operands[0] {
+                        if packed_addr > 0 {
+                            let callee_addr = self.unpack_routine_addr(packed_addr);
+                            edges.insert((addr, callee_addr));
+                            callees.push(callee_addr);
+                        }
+                    }
+                }
+            }
+        }
+        callees
+    }
+
+    // Disassemble a function, returning None if it can't be parsed
+    fn disassemble_function(&self, image: &[u8], addr: u32, len: Option<u32>) -> Option<Function> {
+        let mut cursor = Cursor::new(image);
+        cursor.set_position(addr as u64);
+
+        // Parse the locals
+        let locals = read_u8(&mut cursor)? as u32;
+        if locals > 15 {
+            return None;
+        }
+        let mut local_values = Vec::new();
+        for _ in 0..locals {
+            local_values.push(if self.version < 5 { read_u16(&mut cursor)? } else { 0 });
+        }
+        let first_instruction_addr = cursor.position() as u32;
+
+        // Basic blocks
+        let mut entry_points = FnvHashSet::default();
+        let mut exit_branches = FnvHashMap::default();
+
+        // Parse the instructions
+        let end_addr = len.map(|l| addr + l);
+        let mut instructions = Vec::new();
+        let mut instruction_addresses = FnvHashSet::default();
+        loop {
+            let instruction = self.disassemble_instruction(&mut cursor)?;
+            instruction_addresses.insert(instruction.addr);
+
+            // If this instruction branches, then update the entry and exit points
+            if let Some(target) = instruction.branch {
+                if let BranchTarget::Absolute(addr) = target {
+                    if addr < first_instruction_addr {
+                        return None;
+                    }
+                }
+                match instruction.opcode {
+                    opcodes::OP_JUMP => {
+                        let mut branch_targets = Vec::new();
+                        if let BranchTarget::Absolute(addr) = target {
+                            entry_points.insert(addr);
+                            branch_targets.push(addr);
+                        }
+                        exit_branches.insert(instruction.addr, branch_targets);
+                    },
+                    _ => {
+                        // If the branch returns then don't end a basic block here
+                        if let BranchTarget::Absolute(addr) = target {
+                            entry_points.insert(instruction.next);
+                            entry_points.insert(addr);
+                            exit_branches.insert(instruction.addr, vec![instruction.next, addr]);
+                        }
+                    },
+                };
+            }
+            let opcode = instruction.opcode;
+
+            // Add an entry point for instructions which may resume later
+            if opcodes::instruction_resumes(opcode, self.version) {
+                entry_points.insert(instruction.next);
+            }
+
+            instructions.push(instruction);
+
+            // If we have an end_addr (from a debug file) then use it to determine when to stop decoding
+            if let Some(end_addr) = end_addr {
+                let position = cursor.position() as u32;
+                if position == end_addr {
+                    break;
+                }
+                if position > end_addr {
+                    return None;
+                }
+                continue;
+            }
+
+            if opcodes::instruction_halts(opcode) {
+                // Stop parsing instructions if we don't have any pending entry_points
+                let position = cursor.position() as u32;
+                if !entry_points.contains(&position) {
+                    let mut pending = entry_points.difference(&instruction_addresses).peekable();
+                    if pending.peek().is_none() {
+                        break;
+                    }
+                    // A pending entry point that we've already passed must be in the middle of an instruction
+                    if pending.any(|&addr| addr < position) {
+                        return None;
+                    }
+                }
+            }
+        }
+
+        // Check that every entry point is the start of an instruction
+        if !entry_points.is_subset(&instruction_addresses) {
+            return None;
+        }
+
+        let safety = self.function_safety(addr, &instructions);
+        let blocks = calculate_basic_blocks(instructions, entry_points, exit_branches);
+        let switches = find_switch_chains(&blocks);
+
+        Some(Function {
+            addr,
+            blocks,
+            locals,
+            local_values,
+            safety,
+            shaped_block: None,
+            switches,
+        })
+    }
+
+    fn disassemble_instruction(&self, cursor: &mut Cursor<&[u8]>) -> Option<Instruction> {
+        let addr = cursor.position() as u32;
+        let opcode_byte = read_u8(cursor)?;
+
+        // Decode the opcode and operand types for each instruction form
+        let mut operand_types = Vec::new();
+        let opcode = match opcode_byte {
+            // Extended form
+            0xBE if self.version >= 5 => {
+                let opcode = 0x100 + read_u8(cursor)? as u32;
+                read_operand_types(cursor, &mut operand_types)?;
+                opcode
+            },
+            // Long form
+            0x00 ..= 0x7F => {
+                operand_types.push(if opcode_byte & 0x40 != 0 { VARIABLE } else { SMALL_CONSTANT });
+                operand_types.push(if opcode_byte & 0x20 != 0 { VARIABLE } else { SMALL_CONSTANT });
+                (opcode_byte & 0x1F) as u32
+            },
+            // Short form
+            0x80 ..= 0xBF => {
+                let operand_type = (opcode_byte >> 4) & 0x03;
+                if operand_type == OMITTED {
+                    0xB0 + (opcode_byte & 0x0F) as u32
+                }
+                else {
+                    operand_types.push(operand_type);
+                    0x80 + (opcode_byte & 0x0F) as u32
+                }
+            },
+            // Variable form
+            0xC0 ..= 0xFF => {
+                let opcode = if opcode_byte & 0x20 != 0 { 0xE0 } else { 0 } + (opcode_byte & 0x1F) as u32;
+                read_operand_types(cursor, &mut operand_types)?;
+                // These two opcodes can have up to 8 operands
+                if opcode == opcodes::OP_CALL_VS2 || opcode == opcodes::OP_CALL_VN2 {
+                    read_operand_types(cursor, &mut operand_types)?;
+                }
+                opcode
+            },
+        };
+        if !opcodes::opcode_exists(opcode, self.version) {
+            return None;
+        }
+
+        // Extract the operands
+        let mut operands = Vec::default();
+        for operand_type in operand_types {
+            let operand = match operand_type {
+                LARGE_CONSTANT => Operand::Constant(read_u16(cursor)?),
+                SMALL_CONSTANT => Operand::Constant(read_u8(cursor)? as u16),
+                VARIABLE => variable_operand(read_u8(cursor)?),
+                _ => unreachable!(),
+            };
+            operands.push(operand);
+        }
+        if operands.is_empty() && (opcodes::instruction_calls(opcode, self.version) || opcode == opcodes::OP_JUMP) {
+            return None;
+        }
+
+        // Extract the storer
+        let storer = match opcodes::instruction_stores(opcode, self.version) {
+            true => Some(variable_operand(read_u8(cursor)?)),
+            false => None,
+        };
+
+        // Calculate branch targets
+        use BranchTarget::*;
+        let mut branch = None;
+        let mut branch_condition = true;
+        if opcodes::instruction_branches(opcode, self.version) {
+            let byte = read_u8(cursor)?;
+            branch_condition = byte & 0x80 != 0;
+            let offset = if byte & 0x40 != 0 {
+                (byte & 0x3F) as i32
+            }
+            else {
+                // A 14 bit signed offset
+                let offset = (((byte & 0x3F) as i32) << 8) | read_u8(cursor)? as i32;
+                if offset & 0x2000 != 0 { offset - 0x4000 } else { offset }
+            };
+            branch = Some(match offset {
+                0 | 1 => Return(offset as u32),
+                _ => Absolute((cursor.position() as i32 + offset - 2) as u32),
+            });
+        }
+        if opcode == opcodes::OP_JUMP {
+            branch = Some(match operands[0] {
+                Operand::Constant(offset) => Absolute((cursor.position() as i32 + offset as i16 as i32 - 2) as u32),
+                _ => Dynamic,
+            });
+        }
+
+        // Skip past inline strings
+        let mut text = None;
+        if opcodes::instruction_has_text(opcode) {
+            text = Some(cursor.position() as u32);
+            while read_u16(cursor)? & 0x8000 == 0 {}
+        }
+
+        Some(Instruction {
+            addr,
+            opcode,
+            operands,
+            branch,
+            branch_condition,
+            storer,
+            text,
+            next: cursor.position() as u32,
+        })
+    }
+
+    // Check the function safety overrides
+    fn function_safety(&self, addr: u32, instructions: &[Instruction]) -> FunctionSafety {
+        if let Some(functions) = &self.safe_function_overides {
+            if functions.contains(&addr) {
+                return FunctionSafety::SafetyTBD;
+            }
+        }
+        if let Some(functions) = &self.unsafe_function_overides {
+            if functions.contains(&addr) {
+                return FunctionSafety::Unsafe;
+            }
+        }
+        opcodes::function_safety(self.version, instructions)
+    }
+}
+
+fn align(addr: u32, alignment: u32) -> u32 {
+    addr.div_ceil(alignment) * alignment
+}
+
+// The address after the last instruction of a function
+fn function_end(function: &Function) -> u32 {
+    function.blocks.values().flat_map(|block| block.code.iter()).map(|instruction| instruction.next).max().unwrap_or(function.addr + 1)
+}
+
+// Bounds checked reading
+fn read_u8(cursor: &mut Cursor<&[u8]>) -> Option<u8> {
+    if cursor.remaining() < 1 {
+        return None;
+    }
+    Some(cursor.get_u8())
+}
+
+fn read_u16(cursor: &mut Cursor<&[u8]>) -> Option<u16> {
+    if cursor.remaining() < 2 {
+        return None;
+    }
+    Some(cursor.get_u16())
+}
+
+// Read a byte of operand types for the variable and extended forms
+fn read_operand_types(cursor: &mut Cursor<&[u8]>, operand_types: &mut Vec<u8>) -> Option<()> {
+    let types = read_u8(cursor)?;
+    // Once one operand is omitted all the rest must be too
+    let omitted = !operand_types.len().is_multiple_of(4);
+    for i in 0..4 {
+        let operand_type = (types >> (6 - i * 2)) & 0x03;
+        if operand_type == OMITTED || omitted {
+            break;
+        }
+        operand_types.push(operand_type);
+    }
+    Some(())
+}
+
+// Variable 0 is the top of the stack, 1-15 are locals, and 16-255 are globals
+fn variable_operand(var: u8) -> Operand {
+    match var {
+        0 => Operand::Stack,
+        1 ..= 15 => Operand::Local(var - 1),
+        _ => Operand::Global(var - 16),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use BranchTarget::*;
+    use Operand::*;
+
+    fn make_state(version: u8) -> ZMachineState {
+        let mut state = ZMachineState::new(None, None, None);
+        state.version = version;
+        state
+    }
+
+    fn decode(version: u8, bytes: &[u8]) -> Option<Instruction> {
+        make_state(version).disassemble_instruction(&mut Cursor::new(bytes))
+    }
+
+    // A version 5 storyfile with high memory and the main function at 0x40
+    fn make_image(main: &[u8]) -> Vec<u8> {
+        let mut image = vec![0; 0x60];
+        image[0] = 5;
+        image[0x05] = 0x40;
+        image[0x07] = 0x41;
+        image[0x40..0x40 + main.len()].copy_from_slice(main);
+        image
+    }
+
+    #[test]
+    fn test_header() {
+        // main() calls a routine at 0x50 which isn't valid, and then returns
+        let mut image = make_image(&[0x00, 0xE0, 0x3F, 0x00, 0x14, 0x00, 0xB0]);
+        image[0x50] = 0xFF;
+        let mut state = make_state(0);
+        let edges = state.disassemble(&image).unwrap();
+        assert_eq!(state.version, 5);
+        assert_eq!(state.functions.keys().copied().collect::<Vec<u32>>(), vec![0x40]);
+        assert!(edges.is_empty());
+        assert_eq!(state.warnings, vec![
+            DisassemblyError::EndOfFunctions { addr: 0x48 },
+            DisassemblyError::InvalidFunction { addr: 0x50 },
+        ]);
+
+        // The initial PC must point into a function
+        let mut image = make_image(&[0x00, 0xB0]);
+        image[0x07] = 0;
+        assert_eq!(make_state(0).disassemble(&image), Err(DisassemblyError::InvalidHeader { reason: "the initial PC is 0" }));
+
+        // A truncated header
+        assert_eq!(make_state(0).disassemble(&[5, 0, 0, 0]), Err(DisassemblyError::OutOfBounds { addr: 4 }));
+    }
+
+    #[test]
+    fn test_operands() {
+        // Long form: add L00 #05 -> sp
+        let instruction = decode(5, &[0x54, 0x01, 0x05, 0x00]).unwrap();
+        assert_eq!(instruction.opcode, opcodes::OP_ADD);
+        assert_eq!(instruction.operands, vec![Local(0), Constant(5)]);
+
+        // Short form: inc G00, and rtrue with no operands
+        let instruction = decode(5, &[0x95, 0x10]).unwrap();
+        assert_eq!(instruction.opcode, opcodes::OP_INC);
+        assert_eq!(instruction.operands, vec![Constant(0x10)]);
+        let instruction = decode(5, &[0xB0]).unwrap();
+        assert_eq!(instruction.opcode, opcodes::OP_RTRUE);
+        assert!(instruction.operands.is_empty());
+
+        // Variable form: call_vs #1234 L04 -> sp, where the operands stop at the first omitted type
+        let instruction = decode(5, &[0xE0, 0x2F, 0x12, 0x34, 0x05, 0x00]).unwrap();
+        assert_eq!(instruction.opcode, opcodes::OP_CALL_VS);
+        assert_eq!(instruction.operands, vec![Constant(0x1234), Local(4)]);
+        assert_eq!(instruction.next, 6);
+
+        // call_vs2 has a second byte of operand types
+        let instruction = decode(5, &[0xEC, 0x55, 0x7F, 1, 2, 3, 4, 5, 0x00]).unwrap();
+        assert_eq!(instruction.opcode, opcodes::OP_CALL_VS2);
+        assert_eq!(instruction.operands, vec![Constant(1), Constant(2), Constant(3), Constant(4), Constant(5)]);
+
+        // Extended form: save_undo -> G00, which only exists from version 5
+        let instruction = decode(5, &[0xBE, 0x09, 0xFF, 0x10]).unwrap();
+        assert_eq!(instruction.opcode, opcodes::OP_SAVE_UNDO);
+        assert_eq!(instruction.storer, Some(Global(0)));
+        assert!(decode(3, &[0xBE, 0x09, 0xFF, 0x10]).is_none());
+
+        // Running out of data
+        assert!(decode(5, &[0x54, 0x01]).is_none());
+    }
+
+    #[test]
+    fn test_stores_and_branches() {
+        // get_parent L00 -> L01
+        let instruction = decode(5, &[0xA3, 0x01, 0x02]).unwrap();
+        assert_eq!(instruction.storer, Some(Local(1)));
+        assert_eq!(instruction.branch, None);
+
+        // jz L00 ?(+5), with a short branch offset relative to the end of the instruction
+        let instruction = decode(5, &[0xA0, 0x01, 0xC5]).unwrap();
+        assert!(instruction.branch_condition);
+        assert_eq!(instruction.branch, Some(Absolute(6)));
+
+        // jz L00 ?~rtrue
+        let instruction = decode(5, &[0xA0, 0x01, 0x41]).unwrap();
+        assert!(!instruction.branch_condition);
+        assert_eq!(instruction.branch, Some(Return(1)));
+
+        // get_child L00 -> sp ?~(-16), which both stores and has a long branch offset
+        let instruction = decode(5, &[0xA2, 0x01, 0x00, 0x3F, 0xF0]).unwrap();
+        assert_eq!(instruction.storer, Some(Stack));
+        assert!(!instruction.branch_condition);
+        assert_eq!(instruction.branch, Some(Absolute((5 - 16 - 2_i32) as u32)));
+
+        // jump (+4), whose offset is an operand
+        let instruction = decode(5, &[0x8C, 0x00, 0x04]).unwrap();
+        assert_eq!(instruction.branch, Some(Absolute(5)));
+
+        // print with its inline text skipped over
+        let instruction = decode(5, &[0xB2, 0x11, 0xAA, 0x94, 0xA5, 0xB0]).unwrap();
+        assert_eq!(instruction.text, Some(1));
+        assert_eq!(instruction.next, 5);
+    }
+
+    #[test]
+    fn test_versioned_opcodes() {
+        // call_1s #05 -> sp was only added in version 4
+        assert!(decode(3, &[0x98, 0x05, 0x00]).is_none());
+        let instruction = decode(4, &[0x98, 0x05, 0x00]).unwrap();
+        assert_eq!(instruction.opcode, opcodes::OP_CALL_1S);
+        assert_eq!(instruction.storer, Some(Stack));
+
+        // 1OP:0F is not before version 5, and call_1n afterwards
+        let instruction = decode(4, &[0x9F, 0x05, 0x00]).unwrap();
+        assert_eq!(instruction.storer, Some(Stack));
+        let instruction = decode(5, &[0x9F, 0x05]).unwrap();
+        assert_eq!(instruction.opcode, opcodes::OP_CALL_1N);
+        assert_eq!(instruction.storer, None);
+    }
+}
@@ -0,0 +1,36 @@
+/*
+
+Z-Machine Disassembly Errors
+============================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::error::Error;
+use std::fmt;
+
+// Something in the storyfile which we couldn't disassemble
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisassemblyError {
+    EndOfFunctions { addr: u32 },
+    InvalidFunction { addr: u32 },
+    InvalidHeader { reason: &'static str },
+    OutOfBounds { addr: u32 },
+}
+
+impl fmt::Display for DisassemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use DisassemblyError::*;
+        match self {
+            EndOfFunctions { addr } => write!(f, "Stopped scanning for functions at {}, which is not a valid function", addr),
+            InvalidFunction { addr } => write!(f, "Could not disassemble the function at {}", addr),
+            InvalidHeader { reason } => write!(f, "Invalid header: {}", reason),
+            OutOfBounds { addr } => write!(f, "Tried to read past the end of memory at {}", addr),
+        }
+    }
+}
+
+impl Error for DisassemblyError {}
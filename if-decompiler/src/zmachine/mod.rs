@@ -0,0 +1,211 @@
+/*
+
+Z-Machine
+=========
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use bytes::Buf;
+use relooper::{RelooperError, ShapedBlock, try_reloop};
+
+use super::*;
+
+mod disassembler;
+mod error;
+pub use error::*;
+pub mod opcodes;
+
+pub struct ZMachineState {
+    pub debug_function_data: Option<BTreeMap<u32, DebugFunctionData>>,
+    pub functions: BTreeMap<u32, Function>,
+    pub safe_function_overides: Option<Vec<u32>>,
+    pub unsafe_function_overides: Option<Vec<u32>>,
+    pub version: u8,
+    // Only used in versions 6 and 7
+    pub routines_offset: u32,
+    pub strings_offset: u32,
+    // Problems found by decompile_rom() which didn't stop it
+    pub warnings: Vec<DisassemblyError>,
+}
+
+impl ZMachineState {
+    pub fn new(debug_function_data: Option<BTreeMap<u32, DebugFunctionData>>, safe_function_overides: Option<Vec<u32>>, unsafe_function_overides: Option<Vec<u32>>) -> Self {
+        ZMachineState {
+            debug_function_data,
+            functions: BTreeMap::default(),
+            safe_function_overides,
+            unsafe_function_overides,
+            version: 0,
+            routines_offset: 0,
+            strings_offset: 0,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn decompile_rom(&mut self, image: &[u8]) -> Result<(), DisassemblyError> {
+        let edges = self.disassemble(image)?;
+        self.mark_all_unsafe_functions(edges.clone());
+        // Functions which the relooper can't handle will have to be output as unsafe functions, as will their callers
+        let mut found_unrelooped = false;
+        for function in self.functions.values_mut() {
+            if function.safety == FunctionSafety::SafetyTBD {
+                match function.reloop() {
+                    Ok(block) => function.shaped_block = Some(block),
+                    Err(_) => {
+                        function.safety = FunctionSafety::Unsafe;
+                        found_unrelooped = true;
+                    },
+                }
+            }
+        }
+        if found_unrelooped {
+            self.mark_all_unsafe_functions(edges);
+        }
+        Ok(())
+    }
+
+    pub fn read_byte(&self, image: &[u8], addr: u32) -> Result<u8, DisassemblyError> {
+        image.get(addr as usize).copied().ok_or(DisassemblyError::OutOfBounds { addr })
+    }
+
+    pub fn read_word(&self, image: &[u8], addr: u32) -> Result<u16, DisassemblyError> {
+        match image.get(addr as usize..addr as usize + 2) {
+            Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+            None => Err(DisassemblyError::OutOfBounds { addr }),
+        }
+    }
+
+    // Convert a packed routine address into a byte address
+    pub fn unpack_routine_addr(&self, packed_addr: u16) -> u32 {
+        let packed_addr = packed_addr as u32;
+        match self.version {
+            1 ..= 3 => packed_addr * 2,
+            4 | 5 => packed_addr * 4,
+            6 | 7 => packed_addr * 4 + self.routines_offset,
+            _ => packed_addr * 8,
+        }
+    }
+
+    // Convert a packed string address into a byte address
+    pub fn unpack_string_addr(&self, packed_addr: u16) -> u32 {
+        let packed_addr = packed_addr as u32;
+        match self.version {
+            1 ..= 3 => packed_addr * 2,
+            4 | 5 => packed_addr * 4,
+            6 | 7 => packed_addr * 4 + self.strings_offset,
+            _ => packed_addr * 8,
+        }
+    }
+}
+
+impl VirtualMachine for ZMachineState {
+    fn get_functions(&self) -> FnvHashMap<u32, FunctionSafety> {
+        let mut res = FnvHashMap::default();
+        for (&addr, function) in &self.functions {
+            res.insert(addr, function.safety);
+        }
+        res
+    }
+
+    fn mark_function_as_unsafe(&mut self, addr: u32) {
+        let function = self.functions.get_mut(&addr).unwrap();
+        if function.safety == FunctionSafety::SafetyTBD {
+            function.safety = FunctionSafety::Unsafe;
+        }
+    }
+}
+
+pub struct Function {
+    pub addr: u32,
+    pub blocks: BTreeMap<u32, BasicBlock<Instruction>>,
+    pub locals: u32,
+    // Versions 1-4 store initial values for the locals in the routine header, later versions initialise them to 0
+    pub local_values: Vec<u16>,
+    pub safety: FunctionSafety,
+    // The relooped blocks of a function which could be safe, set by decompile_rom()
+    pub shaped_block: Option<Box<ShapedBlock<u32>>>,
+    // Chains of comparisons which will be output as switches, keyed by their first block
+    pub switches: BTreeMap<u32, SwitchChain<Operand>>,
+}
+
+impl Function {
+    // Run the relooper over the function's blocks
+    pub fn reloop(&self) -> Result<Box<ShapedBlock<u32>>, RelooperError<u32>> {
+        try_reloop(relooper_blocks(&self.blocks, &self.switches), *self.blocks.keys().next().unwrap())
+    }
+}
+
+pub struct Instruction {
+    pub addr: u32,
+    pub opcode: u32,
+    pub operands: Vec<Operand>,
+    pub branch: Option<BranchTarget>,
+    // Whether the branch is taken when the condition is true or false
+    pub branch_condition: bool,
+    pub storer: Option<Operand>,
+    // The address of the inline string for OP_PRINT and OP_PRINT_RET
+    pub text: Option<u32>,
+    pub next: u32,
+}
+
+impl VMInstruction for Instruction {
+    type Operand = Operand;
+
+    fn addr(&self) -> u32 {
+        self.addr
+    }
+
+    fn does_halt(&self) -> bool {
+        opcodes::instruction_halts(self.opcode)
+    }
+
+    fn comparison(&self) -> Option<Comparison<Operand>> {
+        use opcodes::*;
+        use Operand::*;
+        let target = match self.branch {
+            Some(BranchTarget::Absolute(addr)) => addr,
+            _ => return None,
+        };
+        let (value, constants) = match (self.opcode, &self.operands[..]) {
+            (OP_JZ, &[value]) => (value, vec![0]),
+            // je branches if its first operand equals any of the others
+            (OP_JE, &[value, ref others @ ..]) if !others.is_empty() => {
+                let mut constants = Vec::new();
+                for &other in others {
+                    match other {
+                        Constant(constant) => constants.push(constant as u32),
+                        _ => return None,
+                    }
+                }
+                (value, constants)
+            },
+            _ => return None,
+        };
+        // Each comparison in a chain must read the same value
+        if let Constant(_) | Stack = value {
+            return None;
+        }
+        let (equal, unequal) = if self.branch_condition { (target, self.next) } else { (self.next, target) };
+        Some(Comparison {
+            value,
+            constants,
+            equal,
+            unequal,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operand {
+    Constant(u16),
+    Stack,
+    Local(u8),
+    Global(u8),
+}
@@ -0,0 +1,253 @@
+/*
+
+Z-Machine Opcodes
+=================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use super::*;
+
+// Opcodes are numbered by their form: 2OP opcodes are 0x00-0x1F, 1OP are 0x80-0x8F, 0OP are 0xB0-0xBF, VAR are 0xE0-0xFF, and EXT are 0x100-0x1FF
+// Some opcode numbers were reused in later versions, so they have multiple names
+pub const OP_JE: u32 = 0x01;
+pub const OP_JL: u32 = 0x02;
+pub const OP_JG: u32 = 0x03;
+pub const OP_DEC_CHK: u32 = 0x04;
+pub const OP_INC_CHK: u32 = 0x05;
+pub const OP_JIN: u32 = 0x06;
+pub const OP_TEST: u32 = 0x07;
+pub const OP_OR: u32 = 0x08;
+pub const OP_AND: u32 = 0x09;
+pub const OP_TEST_ATTR: u32 = 0x0A;
+pub const OP_SET_ATTR: u32 = 0x0B;
+pub const OP_CLEAR_ATTR: u32 = 0x0C;
+pub const OP_STORE: u32 = 0x0D;
+pub const OP_INSERT_OBJ: u32 = 0x0E;
+pub const OP_LOADW: u32 = 0x0F;
+pub const OP_LOADB: u32 = 0x10;
+pub const OP_GET_PROP: u32 = 0x11;
+pub const OP_GET_PROP_ADDR: u32 = 0x12;
+pub const OP_GET_NEXT_PROP: u32 = 0x13;
+pub const OP_ADD: u32 = 0x14;
+pub const OP_SUB: u32 = 0x15;
+pub const OP_MUL: u32 = 0x16;
+pub const OP_DIV: u32 = 0x17;
+pub const OP_MOD: u32 = 0x18;
+pub const OP_CALL_2S: u32 = 0x19;
+pub const OP_CALL_2N: u32 = 0x1A;
+pub const OP_SET_COLOUR: u32 = 0x1B;
+pub const OP_THROW: u32 = 0x1C;
+pub const OP_JZ: u32 = 0x80;
+pub const OP_GET_SIBLING: u32 = 0x81;
+pub const OP_GET_CHILD: u32 = 0x82;
+pub const OP_GET_PARENT: u32 = 0x83;
+pub const OP_GET_PROP_LEN: u32 = 0x84;
+pub const OP_INC: u32 = 0x85;
+pub const OP_DEC: u32 = 0x86;
+pub const OP_PRINT_ADDR: u32 = 0x87;
+pub const OP_CALL_1S: u32 = 0x88;
+pub const OP_REMOVE_OBJ: u32 = 0x89;
+pub const OP_PRINT_OBJ: u32 = 0x8A;
+pub const OP_RET: u32 = 0x8B;
+pub const OP_JUMP: u32 = 0x8C;
+pub const OP_PRINT_PADDR: u32 = 0x8D;
+pub const OP_LOAD: u32 = 0x8E;
+pub const OP_NOT_V1: u32 = 0x8F;
+pub const OP_CALL_1N: u32 = 0x8F;
+pub const OP_RTRUE: u32 = 0xB0;
+pub const OP_RFALSE: u32 = 0xB1;
+pub const OP_PRINT: u32 = 0xB2;
+pub const OP_PRINT_RET: u32 = 0xB3;
+pub const OP_NOP: u32 = 0xB4;
+pub const OP_SAVE_V1: u32 = 0xB5;
+pub const OP_RESTORE_V1: u32 = 0xB6;
+pub const OP_RESTART: u32 = 0xB7;
+pub const OP_RET_POPPED: u32 = 0xB8;
+pub const OP_POP: u32 = 0xB9;
+pub const OP_CATCH: u32 = 0xB9;
+pub const OP_QUIT: u32 = 0xBA;
+pub const OP_NEW_LINE: u32 = 0xBB;
+pub const OP_SHOW_STATUS: u32 = 0xBC;
+pub const OP_VERIFY: u32 = 0xBD;
+pub const OP_PIRACY: u32 = 0xBF;
+pub const OP_CALL_VS: u32 = 0xE0;
+pub const OP_STOREW: u32 = 0xE1;
+pub const OP_STOREB: u32 = 0xE2;
+pub const OP_PUT_PROP: u32 = 0xE3;
+pub const OP_READ: u32 = 0xE4;
+pub const OP_PRINT_CHAR: u32 = 0xE5;
+pub const OP_PRINT_NUM: u32 = 0xE6;
+pub const OP_RANDOM: u32 = 0xE7;
+pub const OP_PUSH: u32 = 0xE8;
+pub const OP_PULL: u32 = 0xE9;
+pub const OP_SPLIT_WINDOW: u32 = 0xEA;
+pub const OP_SET_WINDOW: u32 = 0xEB;
+pub const OP_CALL_VS2: u32 = 0xEC;
+pub const OP_ERASE_WINDOW: u32 = 0xED;
+pub const OP_ERASE_LINE: u32 = 0xEE;
+pub const OP_SET_CURSOR: u32 = 0xEF;
+pub const OP_GET_CURSOR: u32 = 0xF0;
+pub const OP_SET_TEXT_STYLE: u32 = 0xF1;
+pub const OP_BUFFER_MODE: u32 = 0xF2;
+pub const OP_OUTPUT_STREAM: u32 = 0xF3;
+pub const OP_INPUT_STREAM: u32 = 0xF4;
+pub const OP_SOUND_EFFECT: u32 = 0xF5;
+pub const OP_READ_CHAR: u32 = 0xF6;
+pub const OP_SCAN_TABLE: u32 = 0xF7;
+pub const OP_NOT: u32 = 0xF8;
+pub const OP_CALL_VN: u32 = 0xF9;
+pub const OP_CALL_VN2: u32 = 0xFA;
+pub const OP_TOKENISE: u32 = 0xFB;
+pub const OP_ENCODE_TEXT: u32 = 0xFC;
+pub const OP_COPY_TABLE: u32 = 0xFD;
+pub const OP_PRINT_TABLE: u32 = 0xFE;
+pub const OP_CHECK_ARG_COUNT: u32 = 0xFF;
+pub const OP_SAVE: u32 = 0x100;
+pub const OP_RESTORE: u32 = 0x101;
+pub const OP_LOG_SHIFT: u32 = 0x102;
+pub const OP_ART_SHIFT: u32 = 0x103;
+pub const OP_SET_FONT: u32 = 0x104;
+pub const OP_DRAW_PICTURE: u32 = 0x105;
+pub const OP_PICTURE_DATA: u32 = 0x106;
+pub const OP_ERASE_PICTURE: u32 = 0x107;
+pub const OP_SET_MARGINS: u32 = 0x108;
+pub const OP_SAVE_UNDO: u32 = 0x109;
+pub const OP_RESTORE_UNDO: u32 = 0x10A;
+pub const OP_PRINT_UNICODE: u32 = 0x10B;
+pub const OP_CHECK_UNICODE: u32 = 0x10C;
+pub const OP_SET_TRUE_COLOUR: u32 = 0x10D;
+pub const OP_MOVE_WINDOW: u32 = 0x110;
+pub const OP_WINDOW_SIZE: u32 = 0x111;
+pub const OP_WINDOW_STYLE: u32 = 0x112;
+pub const OP_GET_WIND_PROP: u32 = 0x113;
+pub const OP_SCROLL_WINDOW: u32 = 0x114;
+pub const OP_POP_STACK: u32 = 0x115;
+pub const OP_READ_MOUSE: u32 = 0x116;
+pub const OP_MOUSE_WINDOW: u32 = 0x117;
+pub const OP_PUSH_STACK: u32 = 0x118;
+pub const OP_PUT_WIND_PROP: u32 = 0x119;
+pub const OP_PRINT_FORM: u32 = 0x11A;
+pub const OP_MAKE_MENU: u32 = 0x11B;
+pub const OP_PICTURE_TABLE: u32 = 0x11C;
+pub const OP_BUFFER_SCREEN: u32 = 0x11D;
+
+// Check whether an opcode exists in this version
+pub fn opcode_exists(opcode: u32, version: u8) -> bool {
+    match opcode {
+        OP_JE ..= OP_MOD | OP_JZ ..= OP_PRINT_ADDR | OP_REMOVE_OBJ ..= OP_LOAD | OP_RTRUE ..= OP_NOP | OP_RESTART ..= OP_NEW_LINE => true,
+        OP_NOT_V1 | OP_CALL_VS ..= OP_SET_WINDOW | OP_ERASE_WINDOW ..= OP_SOUND_EFFECT => true,
+        OP_SAVE_V1 | OP_RESTORE_V1 => version < 5,
+        OP_SHOW_STATUS | OP_VERIFY => version >= 3,
+        OP_CALL_2S | OP_CALL_1S | OP_CALL_VS2 | OP_READ_CHAR | OP_SCAN_TABLE => version >= 4,
+        OP_CALL_2N ..= OP_THROW | OP_PIRACY | OP_NOT ..= OP_CHECK_ARG_COUNT => version >= 5,
+        OP_SAVE ..= OP_SET_FONT | OP_SAVE_UNDO ..= OP_SET_TRUE_COLOUR => version >= 5,
+        OP_DRAW_PICTURE ..= OP_SET_MARGINS | OP_MOVE_WINDOW ..= OP_BUFFER_SCREEN => version == 6,
+        _ => false,
+    }
+}
+
+// Whether an instruction is followed by a branch offset
+// (OP_JUMP is not included, as its target is an operand)
+pub fn instruction_branches(opcode: u32, version: u8) -> bool {
+    match opcode {
+        OP_JE ..= OP_TEST | OP_TEST_ATTR | OP_JZ ..= OP_GET_CHILD
+            | OP_VERIFY | OP_PIRACY | OP_SCAN_TABLE | OP_CHECK_ARG_COUNT
+            | OP_PICTURE_DATA | OP_PUSH_STACK | OP_MAKE_MENU => true,
+        OP_SAVE_V1 | OP_RESTORE_V1 => version < 4,
+        _ => false,
+    }
+}
+
+// Whether an instruction calls
+pub fn instruction_calls(opcode: u32, version: u8) -> bool {
+    match opcode {
+        OP_CALL_2S | OP_CALL_2N | OP_CALL_1S | OP_CALL_VS | OP_CALL_VS2
+            | OP_CALL_VN | OP_CALL_VN2 => true,
+        OP_CALL_1N => version >= 5,
+        _ => false,
+    }
+}
+
+// Whether an instruction halts rather than continuing at the next instruction
+pub fn instruction_halts(opcode: u32) -> bool {
+    match opcode {
+        OP_THROW | OP_RET | OP_JUMP | OP_RTRUE | OP_RFALSE | OP_PRINT_RET
+            | OP_RESTART | OP_RET_POPPED | OP_QUIT => true,
+        _ => false,
+    }
+}
+
+// Whether an instruction is followed by an inline string
+pub fn instruction_has_text(opcode: u32) -> bool {
+    match opcode {
+        OP_PRINT | OP_PRINT_RET => true,
+        _ => false,
+    }
+}
+
+// Some instructions may cause execution to resume at the next instruction at some later time
+pub fn instruction_resumes(opcode: u32, version: u8) -> bool {
+    match opcode {
+        OP_SAVE_V1 => version < 5,
+        OP_SAVE | OP_SAVE_UNDO => true,
+        _ => instruction_calls(opcode, version),
+    }
+}
+
+// Whether an instruction stores
+pub fn instruction_stores(opcode: u32, version: u8) -> bool {
+    match opcode {
+        OP_OR | OP_AND | OP_LOADW ..= OP_CALL_2S | OP_GET_SIBLING ..= OP_GET_PROP_LEN
+            | OP_CALL_1S | OP_LOAD | OP_CALL_VS | OP_RANDOM | OP_CALL_VS2
+            | OP_READ_CHAR | OP_SCAN_TABLE | OP_NOT | OP_SAVE ..= OP_SET_FONT
+            | OP_SAVE_UNDO | OP_RESTORE_UNDO | OP_CHECK_UNICODE | OP_GET_WIND_PROP
+            | OP_BUFFER_SCREEN => true,
+        OP_NOT_V1 => version < 5,
+        OP_SAVE_V1 | OP_RESTORE_V1 => version == 4,
+        OP_CATCH | OP_READ => version >= 5,
+        OP_PULL => version == 6,
+        _ => false,
+    }
+}
+
+// Return the FunctionSafety for a function's instructions
+pub fn function_safety(version: u8, instructions: &[Instruction]) -> FunctionSafety {
+    use FunctionSafety::*;
+    let mut result = SafetyTBD;
+    for instruction in instructions {
+        match instruction.opcode {
+            OP_THROW | OP_RESTART | OP_QUIT | OP_SAVE | OP_RESTORE | OP_SAVE_UNDO
+                | OP_RESTORE_UNDO => result = Unsafe,
+            OP_SAVE_V1 | OP_RESTORE_V1 => result = Unsafe,
+            // OP_CATCH was OP_POP before version 5
+            OP_CATCH => if version >= 5 {
+                result = Unsafe;
+            },
+
+            // Jumps to non-constants are unsafe
+            OP_JUMP => match instruction.operands[0] {
+                Operand::Constant(_) => continue,
+                _ => return UnsafeDynamicBranches,
+            },
+
+            // Indirect references to non-constant variables need the locals to be kept on the stack
+            OP_DEC_CHK | OP_INC_CHK | OP_STORE | OP_INC | OP_DEC | OP_LOAD | OP_PULL => match instruction.operands.first() {
+                Some(Operand::Constant(_)) => continue,
+                _ => result = Unsafe,
+            },
+
+            // Calls to non-constants are unsafe
+            _ if instruction_calls(instruction.opcode, version) => match instruction.operands[0] {
+                Operand::Constant(_) => continue,
+                _ => result = Unsafe,
+            },
+
+            _ => continue,
+        };
+    }
+    result
+}
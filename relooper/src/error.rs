@@ -0,0 +1,58 @@
+/*
+
+Relooper Errors
+===============
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::error::Error;
+use std::fmt;
+
+use super::*;
+
+// Something went wrong while relooping, either because of bad input or because the graph has a shape we can't handle yet
+#[derive(Clone, Debug, PartialEq)]
+pub enum RelooperError<L: RelooperLabel> {
+    CyclicGraph,
+    EmptyLoop,
+    InvalidLoopBreak,
+    InvalidMultiple,
+    LoopWithoutDominator,
+    NestedLoopHeader,
+    NoBlocks,
+    NoDominatorWithNext,
+    NodeWithoutLabel,
+    NoOutput,
+    RootWithNextNodes,
+    UnknownLabel(L),
+    UnknownLoop(LoopId),
+    UnsortedBlocks(L),
+}
+
+impl<L: RelooperLabel> fmt::Display for RelooperError<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use RelooperError::*;
+        match self {
+            CyclicGraph => write!(f, "Graph still contains cycles after processing loops"),
+            EmptyLoop => write!(f, "Loop does not contain any blocks"),
+            InvalidLoopBreak => write!(f, "Loop break to a node that is not one of its dominator's branch merges"),
+            InvalidMultiple => write!(f, "Multiple block with entries which can't fall through to each other"),
+            LoopWithoutDominator => write!(f, "Could not find the dominator of a loop with multiple parents"),
+            NestedLoopHeader => write!(f, "Loop header is already the header of another loop"),
+            NoBlocks => write!(f, "No blocks were provided"),
+            NoDominatorWithNext => write!(f, "Could not find a dominator with a next node for a merged branch"),
+            NodeWithoutLabel => write!(f, "Could not find the label of a node"),
+            NoOutput => write!(f, "Relooping did not produce any blocks"),
+            RootWithNextNodes => write!(f, "Root node has next nodes"),
+            UnknownLabel(label) => write!(f, "Branch to unknown label {:?}", label),
+            UnknownLoop(loop_id) => write!(f, "Branch to unknown loop {}", loop_id),
+            UnsortedBlocks(label) => write!(f, "Blocks were not provided in sorted order (at label {:?})", label),
+        }
+    }
+}
+
+impl<L: RelooperLabel> Error for RelooperError<L> {}
@@ -18,13 +18,17 @@ https://github.com/emscripten-core/emscripten/blob/master/docs/paper.pdf
 #![forbid(unsafe_code)]
 
 use core::hash::Hash;
+use std::collections::hash_map::Entry;
 use std::fmt::Debug;
 use std::iter::FromIterator;
 
 use fnv::{FnvHashMap, FnvHashSet};
 use petgraph::prelude::*;
 use petgraph::algo;
-use petgraph::visit::{EdgeFiltered, Visitable, VisitMap};
+use petgraph::visit::{EdgeFiltered, NodeFiltered, Visitable, VisitMap};
+
+mod error;
+pub use error::*;
 
 #[cfg(test)]
 mod tests;
@@ -38,21 +42,44 @@ type LoopId = u16;
 
 // The Relooper accepts a map of block labels to the labels each block can branch to
 pub fn reloop<L: RelooperLabel>(blocks: Vec<(L, Vec<L>)>, first_label: L) -> Box<ShapedBlock<L>> {
-    let mut relooper = Relooper::new(blocks, first_label);
-    relooper.process_loops();
-    relooper.process_rejoined_branches();
-    relooper.output(vec![relooper.graph_root], false).unwrap()
+    try_reloop(blocks, first_label).unwrap()
+}
+
+// Or if you'd rather handle unexpected graphs yourself
+pub fn try_reloop<L: RelooperLabel>(blocks: Vec<(L, Vec<L>)>, first_label: L) -> Result<Box<ShapedBlock<L>>, RelooperError<L>> {
+    Relooper::new(blocks, first_label)?.reloop()
+}
+
+// Node splitting duplicates small blocks so that loops with multiple entries can be turned into loops with a single entry, which don't need a label variable
+// Blocks which aren't in block_sizes are assumed to have a size of 1, and budget is the maximum total size of the blocks which may be duplicated
+// Duplicated blocks will appear more than once in the output, but with the same label
+pub fn try_reloop_with_node_splitting<L: RelooperLabel>(blocks: Vec<(L, Vec<L>)>, first_label: L, block_sizes: &FnvHashMap<L, u32>, budget: u32) -> Result<Box<ShapedBlock<L>>, RelooperError<L>> {
+    let mut relooper = Relooper::new(blocks.clone(), first_label)?;
+    if relooper.split_nodes(block_sizes, budget) {
+        // Splitting can make a graph we can't handle, so go back to the unsplit graph if relooping fails
+        // Or if a duplicated block and its original ended up in the same Multiple then they couldn't be told apart
+        if let Ok(result) = relooper.reloop() {
+            if !result.has_duplicate_handled_labels() {
+                return Ok(result);
+            }
+        }
+    }
+    try_reloop(blocks, first_label)
 }
 
 // And returns a ShapedBlock tree
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ShapedBlock<L: RelooperLabel> {
     Simple(SimpleBlock<L>),
     Loop(LoopBlock<L>),
     Multiple(MultipleBlock<L>),
+    Switch(SwitchBlock<L>),
+    // These are only produced by recognise_loop_shapes()
+    While(WhileBlock<L>),
+    DoWhile(DoWhileBlock<L>),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct SimpleBlock<L: RelooperLabel> {
     pub label: L,
     pub immediate: Option<Box<ShapedBlock<L>>>,
@@ -72,26 +99,299 @@ pub enum BranchMode {
     SetLabelAndBreak,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct LoopBlock<L: RelooperLabel> {
     pub loop_id: LoopId,
     pub inner: Box<ShapedBlock<L>>,
     pub next: Option<Box<ShapedBlock<L>>>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct MultipleBlock<L: RelooperLabel> {
     // It would be nicer to use a Hashmap here, but if the graph ever has triple branches it's possible you'd have a Multiple going into a LoopMulti, so we need a Vec of handled labels
     pub handled: Vec<HandledBlock<L>>,
 }
 
-#[derive(Debug, PartialEq)]
+// A block which branches to more than two labels, such as a jump table
+// The cases are labelled with the branch targets, and a case which doesn't break will fall through to the next case
+// The other branches are also cases of the switch, so a SetLabelAndBreak branch will break out of the switch itself
+#[derive(Clone, Debug, PartialEq)]
+pub struct SwitchBlock<L: RelooperLabel> {
+    pub label: L,
+    pub cases: Vec<HandledBlock<L>>,
+    pub branches: FnvHashMap<L, BranchMode>,
+    pub next: Option<Box<ShapedBlock<L>>>,
+}
+
+// A loop which checks its condition at the start of each iteration
+// The condition block branches to the exit label to leave the loop, or otherwise into the body
+#[derive(Clone, Debug, PartialEq)]
+pub struct WhileBlock<L: RelooperLabel> {
+    pub loop_id: LoopId,
+    pub condition: L,
+    pub exit: L,
+    pub body: Box<ShapedBlock<L>>,
+    pub next: Option<Box<ShapedBlock<L>>>,
+}
+
+// A loop which checks its condition at the end of each iteration
+// The condition block is not included in the body, and branches to the exit label to leave the loop, or otherwise back to the start of the body
+#[derive(Clone, Debug, PartialEq)]
+pub struct DoWhileBlock<L: RelooperLabel> {
+    pub loop_id: LoopId,
+    pub body: Option<Box<ShapedBlock<L>>>,
+    pub condition: L,
+    pub exit: L,
+    pub next: Option<Box<ShapedBlock<L>>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct HandledBlock<L: RelooperLabel> {
     pub labels: Vec<L>,
     pub inner: ShapedBlock<L>,
     pub break_after: bool,
 }
 
+impl<L: RelooperLabel> ShapedBlock<L> {
+    // A post-pass which turns Loops which start or end with their only conditional exit into While or DoWhile loops
+    pub fn recognise_loop_shapes(self) -> ShapedBlock<L> {
+        self.recognise_loop_shapes_with(&|_, _| true)
+    }
+
+    // As above, but only for loops whose condition block can be tested by the caller
+    // The callback is given the labels of the condition block and the loop's exit
+    pub fn recognise_loop_shapes_with<F: Fn(L, L) -> bool>(self, can_test: &F) -> ShapedBlock<L> {
+        use ShapedBlock::*;
+        let recognise = |block: Option<Box<ShapedBlock<L>>>| block.map(|block| Box::new(block.recognise_loop_shapes_with(can_test)));
+        let recognise_handled = |handled: Vec<HandledBlock<L>>| handled.into_iter().map(|handled| HandledBlock {
+            inner: handled.inner.recognise_loop_shapes_with(can_test),
+            ..handled
+        }).collect();
+        match self {
+            Simple(block) => Simple(SimpleBlock {
+                immediate: recognise(block.immediate),
+                next: recognise(block.next),
+                ..block
+            }),
+            Loop(block) => {
+                let loop_block = LoopBlock {
+                    loop_id: block.loop_id,
+                    inner: Box::new(block.inner.recognise_loop_shapes_with(can_test)),
+                    next: recognise(block.next),
+                };
+                loop_block.into_while(can_test).or_else(|loop_block| loop_block.into_do_while(can_test)).unwrap_or_else(Loop)
+            },
+            Multiple(block) => Multiple(MultipleBlock {
+                handled: recognise_handled(block.handled),
+            }),
+            Switch(block) => Switch(SwitchBlock {
+                cases: recognise_handled(block.cases),
+                next: recognise(block.next),
+                ..block
+            }),
+            While(block) => While(WhileBlock {
+                body: Box::new(block.body.recognise_loop_shapes_with(can_test)),
+                next: recognise(block.next),
+                ..block
+            }),
+            DoWhile(block) => DoWhile(DoWhileBlock {
+                body: recognise(block.body),
+                next: recognise(block.next),
+                ..block
+            }),
+        }
+    }
+
+    // Count the branches with a particular mode
+    fn count_branches(&self, mode: BranchMode) -> usize {
+        let count = |block: &Option<Box<ShapedBlock<L>>>| block.as_ref().map_or(0, |block| block.count_branches(mode));
+        let count_handled = |handled: &Vec<HandledBlock<L>>| handled.iter().map(|handled| handled.inner.count_branches(mode)).sum::<usize>();
+        match self {
+            ShapedBlock::Simple(block) => block.branches.values().filter(|&&branch| branch == mode).count() + count(&block.immediate) + count(&block.next),
+            ShapedBlock::Loop(block) => block.inner.count_branches(mode) + count(&block.next),
+            ShapedBlock::Multiple(block) => count_handled(&block.handled),
+            ShapedBlock::Switch(block) => block.branches.values().filter(|&&branch| branch == mode).count() + count_handled(&block.cases) + count(&block.next),
+            ShapedBlock::While(block) => block.body.count_branches(mode) + count(&block.next),
+            ShapedBlock::DoWhile(block) => count(&block.body) + count(&block.next),
+        }
+    }
+
+    // Find the SimpleBlock at the end of a straight line of blocks
+    fn tail(&self) -> Option<&SimpleBlock<L>> {
+        match self {
+            ShapedBlock::Simple(block) if block.is_tail() => Some(block),
+            ShapedBlock::Simple(block) => block.next.as_ref().or(block.immediate.as_ref()).unwrap().tail(),
+            _ => None,
+        }
+    }
+
+    // And remove it, as long as it's not this block itself
+    fn take_tail(&mut self) -> Option<SimpleBlock<L>> {
+        let block = match self {
+            ShapedBlock::Simple(block) if !block.is_tail() => block,
+            _ => return None,
+        };
+        let from_immediate = block.next.is_none();
+        let child = if from_immediate { &mut block.immediate } else { &mut block.next };
+        match child.as_deref_mut() {
+            Some(ShapedBlock::Simple(child_block)) if child_block.is_tail() => match child.take().map(|child| *child) {
+                Some(ShapedBlock::Simple(tail)) => {
+                    // The tail will now be below this block
+                    if from_immediate {
+                        block.branches.insert(tail.label, BranchMode::MergedBranch);
+                    }
+                    Some(tail)
+                },
+                _ => None,
+            },
+            Some(child) => child.take_tail(),
+            None => None,
+        }
+    }
+
+    // Check whether any Multiple handles the same label more than once
+    fn has_duplicate_handled_labels(&self) -> bool {
+        let check = |block: &Option<Box<ShapedBlock<L>>>| block.as_ref().is_some_and(|block| block.has_duplicate_handled_labels());
+        match self {
+            ShapedBlock::Simple(block) => check(&block.immediate) || check(&block.next),
+            ShapedBlock::Loop(block) => block.inner.has_duplicate_handled_labels() || check(&block.next),
+            ShapedBlock::Multiple(block) => has_duplicate_labels(&block.handled),
+            ShapedBlock::Switch(block) => has_duplicate_labels(&block.cases) || check(&block.next),
+            ShapedBlock::While(block) => block.body.has_duplicate_handled_labels() || check(&block.next),
+            ShapedBlock::DoWhile(block) => check(&block.body) || check(&block.next),
+        }
+    }
+}
+
+impl<L: RelooperLabel> LoopBlock<L> {
+    // A loop whose first block branches out of the loop or into the rest of the loop
+    fn into_while<F: Fn(L, L) -> bool>(self, can_test: &F) -> Result<ShapedBlock<L>, LoopBlock<L>> {
+        // Find the exit, which is either a loop break, or a handled block which we can move after the loop
+        let (exit_index, condition, exit_label) = match &*self.inner {
+            ShapedBlock::Simple(inner) if inner.next.is_none() => match (&inner.immediate, inner.branches.len()) {
+                (Some(_), 1) => match inner.branches.iter().next() {
+                    Some((&label, &BranchMode::LoopBreak(loop_id))) if loop_id == self.loop_id => (None, inner.label, label),
+                    _ => return Err(self),
+                },
+                (Some(immediate), 0) if self.can_move_exit() => match &**immediate {
+                    ShapedBlock::Multiple(multiple) if multiple.handled.len() == 2 => {
+                        let exits: Vec<usize> = (0..2).filter(|&index| self.is_exit(&multiple.handled[index])).collect();
+                        if exits.len() != 1 {
+                            return Err(self);
+                        }
+                        (Some(exits[0]), inner.label, multiple.handled[exits[0]].labels[0])
+                    },
+                    _ => return Err(self),
+                },
+                _ => return Err(self),
+            },
+            _ => return Err(self),
+        };
+        if !can_test(condition, exit_label) {
+            return Err(self);
+        }
+        let inner = match *self.inner {
+            ShapedBlock::Simple(inner) => inner,
+            _ => unreachable!(),
+        };
+        let (body, next) = match exit_index {
+            None => (inner.immediate.unwrap(), self.next),
+            Some(index) => match *inner.immediate.unwrap() {
+                ShapedBlock::Multiple(mut multiple) => {
+                    let exit = multiple.handled.remove(index);
+                    let body = multiple.handled.remove(0);
+                    (Box::new(body.inner), Some(Box::new(exit.inner)))
+                },
+                _ => unreachable!(),
+            },
+        };
+        Ok(ShapedBlock::While(WhileBlock {
+            loop_id: self.loop_id,
+            condition,
+            exit: exit_label,
+            body,
+            next,
+        }))
+    }
+
+    // A loop whose last block branches out of the loop or back to the start, and which has no other continues
+    fn into_do_while<F: Fn(L, L) -> bool>(self, can_test: &F) -> Result<ShapedBlock<L>, LoopBlock<L>> {
+        let header = match &*self.inner {
+            ShapedBlock::Simple(inner) => inner.label,
+            _ => return Err(self),
+        };
+        let continue_mode = BranchMode::LoopContinue(self.loop_id);
+        if self.inner.count_branches(continue_mode) != 1 || self.inner.count_branches(BranchMode::LoopContinueIntoMulti(self.loop_id)) > 0 {
+            return Err(self);
+        }
+        // Find the latch, checking it first before taking it out of the loop
+        let is_latch = |block: &SimpleBlock<L>| block.next.is_none() && block.branches.get(&header) == Some(&continue_mode)
+            && match (&block.immediate, block.branches.len()) {
+                (None, 2) => block.branches.values().any(|&branch| branch == BranchMode::LoopBreak(self.loop_id)),
+                (Some(immediate), 1) if self.can_move_exit() => matches!(&**immediate, ShapedBlock::Multiple(multiple) if multiple.handled.len() == 1 && self.is_exit(&multiple.handled[0])),
+                _ => false,
+            };
+        let (condition, exit) = match self.inner.tail() {
+            Some(latch) if is_latch(latch) => match latch.immediate.as_deref() {
+                Some(ShapedBlock::Multiple(multiple)) => (latch.label, multiple.handled[0].labels[0]),
+                _ => (latch.label, *latch.branches.keys().find(|&&label| label != header).unwrap()),
+            },
+            _ => return Err(self),
+        };
+        if !can_test(condition, exit) {
+            return Err(self);
+        }
+        let (body, latch) = match *self.inner {
+            ShapedBlock::Simple(inner) if inner.is_tail() => (None, inner),
+            mut inner => {
+                let tail = inner.take_tail().unwrap();
+                (Some(Box::new(inner)), tail)
+            },
+        };
+        let next = match latch.immediate {
+            Some(immediate) => match *immediate {
+                ShapedBlock::Multiple(mut multiple) => Some(Box::new(multiple.handled.remove(0).inner)),
+                _ => unreachable!(),
+            },
+            None => self.next,
+        };
+        Ok(ShapedBlock::DoWhile(DoWhileBlock {
+            loop_id: self.loop_id,
+            body,
+            condition,
+            exit,
+            next,
+        }))
+    }
+
+    // An exit can only be moved after the loop if nothing else leaves the loop
+    fn can_move_exit(&self) -> bool {
+        self.next.is_none() && self.inner.count_branches(BranchMode::LoopBreak(self.loop_id)) == 0 && self.inner.count_branches(BranchMode::LoopBreakIntoMulti(self.loop_id)) == 0
+    }
+
+    // Whether a handled block never returns to the loop or falls through, so that it can be moved after the loop
+    // Merged branches are checked conservatively, even when they are handled within the block itself
+    fn is_exit(&self, handled: &HandledBlock<L>) -> bool {
+        use BranchMode::*;
+        let loop_id = self.loop_id;
+        handled.labels.len() == 1 && handled.break_after
+            && [LoopBreak(loop_id), LoopBreakIntoMulti(loop_id), LoopContinue(loop_id), LoopContinueIntoMulti(loop_id), MergedBranch, MergedBranchIntoMulti, SetLabelAndBreak].iter()
+                .all(|&mode| handled.inner.count_branches(mode) == 0)
+    }
+}
+
+impl<L: RelooperLabel> SimpleBlock<L> {
+    // Whether this block ends a straight line of blocks, ignoring any Multiple of conditional branches
+    fn is_tail(&self) -> bool {
+        self.next.is_none() && matches!(self.immediate.as_deref(), None | Some(ShapedBlock::Multiple(_)))
+    }
+}
+
+fn has_duplicate_labels<L: RelooperLabel>(handled: &[HandledBlock<L>]) -> bool {
+    let mut labels = FnvHashSet::default();
+    handled.iter().any(|handled| handled.labels.iter().any(|&label| !labels.insert(label)) || handled.inner.has_duplicate_handled_labels())
+}
+
 /* =======================
    Internal implementation
    ======================= */
@@ -115,6 +415,7 @@ enum Edge<L> {
     ForwardMultiViaNext(L),
     LoopBreak(LoopId),
     LoopBreakIntoMulti(LoopId),
+    LoopBreakViaNext(LoopId, L), // A ForwardMultiViaNext which also breaks out of a loop
     LoopContinue(LoopId),
     LoopContinueIntoMulti(LoopId),
     MergedBranch,
@@ -126,20 +427,14 @@ enum Edge<L> {
 
 fn filter_edges<L>(edge: petgraph::graph::EdgeReference<Edge<L>>) -> bool {
     use Edge::*;
-    match edge.weight() {
-        Forward | ForwardMulti(_) | Next(_) => true,
-        _ => false,
-    }
+    matches!(edge.weight(), Forward | ForwardMulti(_) | Next(_))
 }
 
 fn filter_edges_including_processed<L>(edge: petgraph::graph::EdgeReference<Edge<L>>) -> bool {
     use Edge::*;
-    match edge.weight() {
-        Forward | ForwardMulti(_) | Next(_) | ForwardMultiViaNext(_)
-            | LoopBreak(_) | LoopBreakIntoMulti(_) | MergedBranch
-            | MergedBranchIntoMulti | SetLabelAndBreak | SwitchFallThrough => true,
-        _ => false,
-    }
+    matches!(edge.weight(), Forward | ForwardMulti(_) | Next(_) | ForwardMultiViaNext(_) | LoopBreakViaNext(_, _)
+        | LoopBreak(_) | LoopBreakIntoMulti(_) | MergedBranch
+        | MergedBranchIntoMulti | SetLabelAndBreak | SwitchFallThrough)
 }
 
 // The Relooper algorithm
@@ -148,18 +443,24 @@ struct Relooper<L: RelooperLabel> {
     graph: Graph<Node<L>, Edge<L>>,
     graph_root: NodeIndex,
     root: NodeIndex,
+    // Blocks with more than two branches, which will be output as Switches
+    switches: FnvHashSet<L>,
+    // Loop headers which can only be reached from inside their loops
+    internal_headers: FnvHashSet<NodeIndex>,
 }
 
 impl<L: RelooperLabel> Relooper<L> {
-    fn new(blocks: Vec<(L, Vec<L>)>, root_label: L) -> Relooper<L> {
+    fn new(blocks: Vec<(L, Vec<L>)>, root_label: L) -> Result<Relooper<L>, RelooperError<L>> {
         let mut graph = Graph::new();
         let mut nodes = FnvHashMap::default();
 
         // Check the blocks are sorted
         // Replace with ._is_sorted() when stable (https://github.com/rust-lang/rust/issues/53485)
-        let mut label = blocks[0].0;
+        let mut label = blocks.first().ok_or(RelooperError::NoBlocks)?.0;
         for block in &blocks[1..] {
-            assert!(block.0 > label, "Blocks were not provided in sorted order");
+            if block.0 <= label {
+                return Err(RelooperError::UnsortedBlocks(block.0));
+            }
             label = block.0;
         }
 
@@ -168,38 +469,129 @@ impl<L: RelooperLabel> Relooper<L> {
         let graph_root = graph.add_node(Node::Root);
 
         // Add nodes for each block
+        let mut switches = FnvHashSet::default();
         for (label, branches) in &blocks {
+            if branches.iter().collect::<FnvHashSet<_>>().len() > 2 {
+                switches.insert(*label);
+            }
             nodes.insert(*label, graph.add_node(if branches.len() > 1 { Node::Multiple(*label) } else { Node::Basic(*label) }));
         }
 
         // Add the edges
         for (label, branches) in &blocks {
             for branch in branches {
-                graph.add_edge(nodes[&label], nodes[&branch], Edge::Forward);
+                let target = *nodes.get(branch).ok_or(RelooperError::UnknownLabel(*branch))?;
+                graph.add_edge(nodes[label], target, Edge::Forward);
             }
         }
 
         // Connect the root node to the first label
-        graph.add_edge(graph_root, nodes[&root_label], Edge::Forward);
+        let root = *nodes.get(&root_label).ok_or(RelooperError::UnknownLabel(root_label))?;
+        graph.add_edge(graph_root, root, Edge::Forward);
 
         // Remove orphan nodes
+        // Removing a node moves the last node into its index, so go backwards to keep the other orphans' indices valid
         let dominators = algo::dominators::simple_fast(&graph, graph_root);
-        for node in graph.node_indices() {
-            if node != graph_root && dominators.immediate_dominator(node).is_none() {
-                graph.remove_node(node);
-            }
+        let orphans: Vec<NodeIndex> = graph.node_indices().filter(|&node| node != graph_root && dominators.immediate_dominator(node).is_none()).collect();
+        for &node in orphans.iter().rev() {
+            graph.remove_node(node);
         }
+        // The first label's node may have been moved too
+        let root = graph.neighbors(graph_root).next().ok_or(RelooperError::UnknownLabel(root_label))?;
 
-        Relooper {
+        Ok(Relooper {
             counter: 0,
             graph,
             graph_root,
-            root: nodes[&root_label],
+            root,
+            switches,
+            internal_headers: FnvHashSet::default(),
+        })
+    }
+
+    // Run the main Relooper algorithm
+    fn reloop(mut self) -> Result<Box<ShapedBlock<L>>, RelooperError<L>> {
+        self.process_loops()?;
+        self.process_rejoined_branches()?;
+        self.output(vec![self.graph_root], false)?.ok_or(RelooperError::NoOutput)
+    }
+
+    // Duplicate the entries of multi-entry loops until every loop has a single entry or we run out of budget
+    // Returns whether any nodes were split
+    fn split_nodes(&mut self, block_sizes: &FnvHashMap<L, u32>, mut budget: u32) -> bool {
+        let mut split_any = false;
+        loop {
+            let nodes = self.graph.node_indices().filter(|&node| node != self.graph_root).collect();
+            let (node, parents, size) = match self.find_node_to_split(&nodes, block_sizes, budget) {
+                Some(result) => result,
+                None => break,
+            };
+            budget -= size;
+            split_any = true;
+
+            // Make the duplicate node with the same branches as the original
+            let duplicate = self.graph.add_node(self.graph[node]);
+            let targets: Vec<NodeIndex> = self.graph.neighbors(node).collect();
+            for target in targets {
+                self.graph.add_edge(duplicate, target, Edge::Forward);
+            }
+
+            // And redirect the edges from outside the loop
+            for parent in parents {
+                while let Some(edge) = self.graph.find_edge(parent, node) {
+                    self.graph.remove_edge(edge);
+                }
+                self.graph.add_edge(parent, duplicate, Edge::Forward);
+            }
         }
+        split_any
+    }
+
+    // Find a loop entry to duplicate, along with the parent nodes from outside the loop and its size
+    fn find_node_to_split(&self, nodes: &FnvHashSet<NodeIndex>, block_sizes: &FnvHashMap<L, u32>, budget: u32) -> Option<(NodeIndex, Vec<NodeIndex>, u32)> {
+        let filtered_graph = NodeFiltered::from_fn(&self.graph, |node| nodes.contains(&node));
+        for scc in algo::kosaraju_scc(&filtered_graph) {
+            // A single node loop can only have one entry
+            if scc.len() == 1 {
+                continue;
+            }
+            let scc_nodes: FnvHashSet<NodeIndex> = scc.iter().copied().collect();
+
+            // Find the loop entries and their parents from outside the loop
+            let mut entries = Vec::new();
+            for &node in &scc {
+                let mut parents: Vec<NodeIndex> = self.graph.neighbors_directed(node, Incoming).filter(|parent| !scc_nodes.contains(parent)).collect();
+                if !parents.is_empty() {
+                    parents.sort();
+                    parents.dedup();
+                    let label = self.get_basic_node_label(node).ok()?;
+                    let size = block_sizes.get(&label).copied().unwrap_or(1).max(1);
+                    let is_first = parents.contains(&self.graph_root);
+                    entries.push((node, parents, size, label, is_first));
+                }
+            }
+
+            // A loop with only one entry could still have multi-entry loops nested inside it
+            if entries.len() == 1 {
+                let mut inner_nodes = scc_nodes;
+                inner_nodes.remove(&entries[0].0);
+                if let Some(result) = self.find_node_to_split(&inner_nodes, block_sizes, budget) {
+                    return Some(result);
+                }
+                continue;
+            }
+
+            // Keep the first block of the function or the largest entry, and duplicate the smallest of the others if it fits within the budget
+            entries.sort_by(|a, b| b.4.cmp(&a.4).then(b.2.cmp(&a.2)).then(a.3.cmp(&b.3)));
+            if let Some((node, parents, size, _, _)) = entries.into_iter().skip(1).filter(|entry| entry.2 <= budget).min_by_key(|entry| (entry.2, entry.3)) {
+                return Some((node, parents, size));
+            }
+        }
+        None
     }
 
     // Process loops by adding loop nodes and converting back edges
-    fn process_loops(&mut self) {
+    fn process_loops(&mut self) -> Result<(), RelooperError<L>> {
         // Loop until we have no more SCCs
         loop {
             let mut found_loop = false;
@@ -236,11 +628,22 @@ impl<L: RelooperLabel> Relooper<L> {
                         }
                     }
                 }
+                // If there are multiple headers, then any other node which isn't dominated by one of them must also be an entry to the loop
+                if loop_headers.len() > 1 {
+                    let filtered_graph = EdgeFiltered::from_fn(&self.graph, filter_edges);
+                    let dominators = algo::dominators::simple_fast(&filtered_graph, self.graph_root);
+                    for &node in &scc {
+                        let dominator = dominators.immediate_dominator(node).ok_or(RelooperError::LoopWithoutDominator)?;
+                        if !scc.contains(&dominator) && loop_headers.insert(node) {
+                            self.internal_headers.insert(node);
+                        }
+                    }
+                }
                 let loop_headers = Vec::from_iter(loop_headers);
                 let loop_parents = Vec::from_iter(loop_parents);
 
                 let scc_test = |i| !&scc.contains(&i);
-                let (loop_node, loop_id) = self.make_loop(&loop_headers, &loop_parents, scc_test);
+                let (loop_node, loop_id) = self.make_loop(&loop_headers, &loop_parents, scc_test, None)?;
 
                 // Fix edges which are branching outside the loop
                 let filtered_graph = EdgeFiltered::from_fn(&self.graph, filter_edges);
@@ -255,6 +658,28 @@ impl<L: RelooperLabel> Relooper<L> {
                     if discovered.visit(node) {
                         let mut edges = self.graph.neighbors(node).detach();
                         'edge_loop: while let Some((edge, target)) = edges.next(&self.graph) {
+                            // Branches into a LoopMulti with multiple parents must break out of this loop if the LoopMulti is outside it
+                            if let Edge::ForwardMultiViaNext(label) | Edge::LoopBreakViaNext(_, label) = self.graph[edge] {
+                                if !loop_at_root && !dominators.dominators(target).into_iter().flatten().any(|dom| dom == loop_node) {
+                                    let breaks_enclosing_loop = match self.graph[edge] {
+                                        Edge::LoopBreakViaNext(other_loop_id, _) => !dominators.dominators(self.find_loop_node(other_loop_id)?).into_iter().flatten().any(|dom| dom == loop_node),
+                                        _ => false,
+                                    };
+                                    if !breaks_enclosing_loop {
+                                        self.graph[edge] = Edge::LoopBreakViaNext(loop_id, label);
+                                    }
+                                }
+                                continue 'edge_loop;
+                            }
+
+                            // Next edges from a previous loop can lead to more of this loop, but don't need converting
+                            if let Edge::Next(_) = self.graph[edge] {
+                                if !discovered.is_visited(&target) && (loop_at_root || dominators.dominators(target).into_iter().flatten().any(|dom| dom == loop_node)) {
+                                    stack.push(target);
+                                }
+                                continue 'edge_loop;
+                            }
+
                             // Look for not just Forward edges, but also LoopBreaks from a previous loop
                             if let Edge::Forward | Edge::ForwardMulti(_) | Edge::LoopBreak(_) | Edge::LoopBreakIntoMulti(_) = self.graph[edge] {
                                 // When the root node is a loop there can't be any un-dominated nodes, so just push to the stack
@@ -265,9 +690,11 @@ impl<L: RelooperLabel> Relooper<L> {
                                     continue 'edge_loop;
                                 }
 
-                                let target_dominators = dominators.strict_dominators(target).unwrap();
+                                // Nodes outside of a multi-headed loop can be dominated by the loop node itself, but they still need a LoopBreak
+                                let is_exit = !scc.contains(&target) && dominators.immediate_dominator(target) == Some(loop_node);
+                                let target_dominators = dominators.strict_dominators(target).into_iter().flatten();
                                 for dom in target_dominators {
-                                    if dom == loop_node {
+                                    if dom == loop_node && !is_exit {
                                         // This node is dominated by the structural dominator, so add it to the stack
                                         if !discovered.is_visited(&target) {
                                             stack.push(target);
@@ -276,9 +703,16 @@ impl<L: RelooperLabel> Relooper<L> {
                                     }
                                 }
 
+                                // LoopBreaks from a loop which contains this one already leave this loop
+                                if let Edge::LoopBreak(other_loop_id) | Edge::LoopBreakIntoMulti(other_loop_id) = self.graph[edge] {
+                                    if !dominators.dominators(self.find_loop_node(other_loop_id)?).into_iter().flatten().any(|dom| dom == loop_node) {
+                                        continue 'edge_loop;
+                                    }
+                                }
+
                                 // Not dominated, so convert the edges
                                 // Add a next edge to the dominator if there isn't one already
-                                let dominator = dominators.immediate_dominator(target).unwrap();
+                                let dominator = dominators.immediate_dominator(target).ok_or(RelooperError::NoDominatorWithNext)?;
                                 if !self.graph.contains_edge(dominator, target) {
                                     self.graph.add_edge(dominator, target, Edge::Next(false));
                                 }
@@ -299,17 +733,20 @@ impl<L: RelooperLabel> Relooper<L> {
                 }
             }
 
-            if found_loop == false {
+            if !found_loop {
                 break;
             }
         }
 
         let filtered_graph = EdgeFiltered::from_fn(&self.graph, filter_edges);
-        assert!(!algo::is_cyclic_directed(&filtered_graph), "Graph should not contain any cycles");
+        if algo::is_cyclic_directed(&filtered_graph) {
+            return Err(RelooperError::CyclicGraph);
+        }
+        Ok(())
     }
 
     // Handle branches that merge back together
-    fn process_rejoined_branches(&mut self) {
+    fn process_rejoined_branches(&mut self) -> Result<(), RelooperError<L>> {
         struct MergingBranches {
             dominator: NodeIndex,
             dominator_order: usize,
@@ -321,7 +758,7 @@ impl<L: RelooperLabel> Relooper<L> {
         let filtered_graph = EdgeFiltered::from_fn(&self.graph, filter_edges_including_processed);
         let mut space = algo::DfsSpace::new(&filtered_graph);
         let dominators = algo::dominators::simple_fast(&filtered_graph, self.graph_root);
-        let sorted_nodes = algo::toposort(&filtered_graph, None).unwrap();
+        let sorted_nodes = algo::toposort(&filtered_graph, None).map_err(|_| RelooperError::CyclicGraph)?;
         let mut nodes_to_process = FnvHashMap::default();
 
         // Now go through the nodes in order, looking for those that have multiple parents
@@ -336,17 +773,17 @@ impl<L: RelooperLabel> Relooper<L> {
                 }
             }
             if parent_nodes.len() > 1 {
-                let dominator_id = dominators.immediate_dominator(node).unwrap();
-                if !nodes_to_process.contains_key(&dominator_id) {
-                    // Insert into the map the dominator's topological position and an empty vec for the dominated nodes
-                    nodes_to_process.insert(dominator_id, MergingBranches {
+                let dominator_id = dominators.immediate_dominator(node).ok_or(RelooperError::NoDominatorWithNext)?;
+                // Insert into the map the dominator's topological position and an empty vec for the dominated nodes
+                let branch = match nodes_to_process.entry(dominator_id) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(MergingBranches {
                         dominator: dominator_id,
-                        dominator_order: sorted_nodes.iter().position(|&n| n == dominator_id).unwrap(),
+                        dominator_order: sorted_nodes.iter().position(|&n| n == dominator_id).ok_or(RelooperError::NoDominatorWithNext)?,
                         dominated_nodes: Vec::default(),
                         parent_nodes: FnvHashSet::default(),
-                    });
-                }
-                let branch = nodes_to_process.get_mut(&dominator_id).unwrap();
+                    }),
+                };
                 branch.dominated_nodes.push(node);
                 branch.dominated_nodes.sort();
                 for parent in parent_nodes {
@@ -356,7 +793,7 @@ impl<L: RelooperLabel> Relooper<L> {
         }
         // Sort the dominator nodes in topological order
         let mut nodes_to_process = Vec::from_iter(nodes_to_process.values());
-        nodes_to_process.sort_by(|a, b| a.dominator_order.cmp(&b.dominator_order));
+        nodes_to_process.sort_by_key(|branch| branch.dominator_order);
 
         // Now go through the dominator nodes, processing each merging node it dominates
         for merged_branch in nodes_to_process {
@@ -369,23 +806,22 @@ impl<L: RelooperLabel> Relooper<L> {
             while let Some((edge_id, target)) = dominator_edges.next(&self.graph) {
                 if let Edge::Next(_) = self.graph[edge_id] {
                     if !dominated_nodes.contains(&target) {
-                        panic!("Loop break to a node that is not one of this dominator's branch merges");
+                        return Err(RelooperError::InvalidLoopBreak);
                     }
                     if into_multi {
                         // Convert the LoopBreak nodes into LoopBreakIntoMulti if this is a multi node
                         let mut target_edges = self.graph.neighbors_directed(target, Incoming).detach();
                         while let Some((edge_id, _)) = target_edges.next(&self.graph) {
-                            match self.graph[edge_id] {
-                                Edge::LoopBreak(loop_id) => { self.graph[edge_id] = Edge::LoopBreakIntoMulti(loop_id); },
-                                _ => {},
-                            };
+                            if let Edge::LoopBreak(loop_id) = self.graph[edge_id] {
+                                self.graph[edge_id] = Edge::LoopBreakIntoMulti(loop_id);
+                            }
                         }
                     }
                 }
             }
 
             // Simple case - only one merged branch, branches that can't reach each other, or branches that only reach the next branch in order
-            if !into_multi || self.can_merged_nodes_use_multiple(&dominated_nodes) {
+            if !into_multi || self.can_merged_nodes_use_multiple(dominated_nodes, &dominators) {
                 let dominated_nodes_count = dominated_nodes.len();
                 for index in 0..dominated_nodes_count {
                     let node = dominated_nodes[index];
@@ -408,35 +844,53 @@ impl<L: RelooperLabel> Relooper<L> {
                         if algo::has_path_connecting(&filtered_graph, node, next_node, Some(&mut space)) {
                             // Turn any MergedBranch that go outside the dominator into SetLabelAndBreak edges
                             // That means another manual search
-                            let mut stack = vec![node];
+                            // Also keep track of the outermost loop we are inside, as a fall through from inside a loop must break out of it
+                            let top_node = node;
+                            let mut stack = vec![(node, None)];
                             let mut discovered = self.graph.visit_map();
-                            while let Some(node) = stack.pop() {
+                            while let Some((node, outer_loop)) = stack.pop() {
                                 if discovered.visit(node) {
+                                    let outer_loop = outer_loop.or(match self.graph[node] {
+                                        Node::Loop(loop_id) | Node::LoopMulti(loop_id) => Some(loop_id),
+                                        _ => None,
+                                    });
                                     let mut edges = self.graph.neighbors(node).detach();
                                     'edges_loop: while let Some((edge, target)) = edges.next(&self.graph) {
                                         // If the target is the next node, convert the edge to a SwitchFallThrough
                                         if target == next_node {
-                                            self.graph[edge] = Edge::SwitchFallThrough;
+                                            self.graph[edge] = match outer_loop {
+                                                Some(loop_id) => Edge::LoopBreak(loop_id),
+                                                None => Edge::SwitchFallThrough,
+                                            };
                                             continue;
                                         }
                                         match self.graph[edge] {
-                                            Edge::Forward | Edge::ForwardMulti(_) | Edge::Next(_) => {
-                                                if !discovered.is_visited(&target) {
-                                                    stack.push(target);
-                                                }
+                                            Edge::Forward | Edge::ForwardMulti(_) | Edge::Next(_) if !discovered.is_visited(&target) => {
+                                                stack.push((target, outer_loop));
                                             },
-                                            Edge::MergedBranch | Edge::MergedBranchIntoMulti => {
+                                            // Fall throughs from an enclosing Multiple must also break out of this one
+                                            Edge::MergedBranch | Edge::MergedBranchIntoMulti | Edge::SwitchFallThrough => {
                                                 // If the target node is dominated by the top node then add it to the stack
-                                                let target_dominators = dominators.strict_dominators(target).unwrap();
+                                                let target_dominators = dominators.strict_dominators(target).into_iter().flatten();
                                                 for dom in target_dominators {
-                                                    if dom == node && !discovered.is_visited(&target) {
-                                                        stack.push(target);
+                                                    if dom == top_node {
+                                                        if !discovered.is_visited(&target) {
+                                                            stack.push((target, outer_loop));
+                                                        }
                                                         continue 'edges_loop;
                                                     }
                                                 }
                                                 // This edge branches outside the top node, so convert to a SetLabelAndBreak
                                                 self.graph[edge] = Edge::SetLabelAndBreak;
                                             },
+                                            // Branches into a LoopMulti with multiple parents are also merged branches
+                                            // Send the SetLabelAndBreak to the loop header, so that it will use the header's label
+                                            // LoopMultis made in this function won't be in the dominators list, but they will always be outside the top node
+                                            Edge::ForwardMultiViaNext(label) if !dominators.strict_dominators(target).into_iter().flatten().any(|dom| dom == top_node) => {
+                                                let header = self.graph.neighbors(target).find(|&header| self.get_basic_node_label(header) == Ok(label)).ok_or(RelooperError::NodeWithoutLabel)?;
+                                                self.graph[edge] = Edge::Removed;
+                                                self.graph.add_edge(node, header, Edge::SetLabelAndBreak);
+                                            },
                                             _ => {},
                                         };
                                     }
@@ -449,26 +903,60 @@ impl<L: RelooperLabel> Relooper<L> {
             }
 
             // Multiple nodes get turned into a LoopMulti
-            let loop_headers = dominated_nodes.iter().map(|&i| i).collect();
-            let loop_parents: Vec<NodeIndex> = merged_branch.parent_nodes.difference(&FnvHashSet::from_iter(dominated_nodes.iter().map(|&i| i))).map(|&i| i).collect();
+            let loop_headers = dominated_nodes.to_vec();
+            let loop_parents: Vec<NodeIndex> = merged_branch.parent_nodes.difference(&FnvHashSet::from_iter(dominated_nodes.iter().copied())).copied().collect();
 
             let loop_parents_test = |i| loop_parents.contains(&i);
-            self.make_loop(&loop_headers, &loop_parents, loop_parents_test);
+            let (loop_node, loop_id) = self.make_loop(&loop_headers, &loop_parents, loop_parents_test, Some(dominator))?;
+
+            // Merged branches and loop breaks which go outside the new loop must now break out of it
+            let filtered_graph = EdgeFiltered::from_fn(&self.graph, filter_edges);
+            let loop_dominators = algo::dominators::simple_fast(&filtered_graph, self.graph_root);
+            let mut stack = vec![loop_node];
+            let mut discovered = self.graph.visit_map();
+            while let Some(node) = stack.pop() {
+                if discovered.visit(node) {
+                    let mut edges = self.graph.neighbors(node).detach();
+                    while let Some((edge, target)) = edges.next(&self.graph) {
+                        let inside_loop = loop_dominators.dominators(target).into_iter().flatten().any(|dom| dom == loop_node);
+                        match self.graph[edge] {
+                            Edge::Forward | Edge::ForwardMulti(_) | Edge::Next(_) if inside_loop => { stack.push(target); },
+                            Edge::ForwardMultiViaNext(label) if !inside_loop => { self.graph[edge] = Edge::LoopBreakViaNext(loop_id, label); },
+                            Edge::MergedBranch | Edge::SwitchFallThrough if !inside_loop => { self.graph[edge] = Edge::LoopBreak(loop_id); },
+                            Edge::MergedBranchIntoMulti if !inside_loop => { self.graph[edge] = Edge::LoopBreakIntoMulti(loop_id); },
+                            // Breaks from loops inside the new loop must break out of it too
+                            Edge::LoopBreak(inner_loop_id) | Edge::LoopBreakIntoMulti(inner_loop_id) | Edge::LoopBreakViaNext(inner_loop_id, _)
+                                if !inside_loop && loop_dominators.dominators(self.find_loop_node(inner_loop_id)?).into_iter().flatten().any(|dom| dom == loop_node) => {
+                                self.graph[edge] = match self.graph[edge] {
+                                    Edge::LoopBreak(_) => Edge::LoopBreak(loop_id),
+                                    Edge::LoopBreakViaNext(_, label) => Edge::LoopBreakViaNext(loop_id, label),
+                                    _ => Edge::LoopBreakIntoMulti(loop_id),
+                                };
+                            },
+                            _ => {},
+                        };
+                    }
+                }
+            }
         }
 
-        // Look for MergedBranch|LoopBreak edges which don't go to the right place
+        // The dominators must be recalculated to include the LoopMultis made above
+        let filtered_graph = EdgeFiltered::from_fn(&self.graph, filter_edges_including_processed);
+        let dominators = algo::dominators::simple_fast(&filtered_graph, self.graph_root);
+
+        // Look for MergedBranch|LoopBreak|ViaNext|SwitchFallThrough edges which don't go to the right place
         for &node in sorted_nodes.iter() {
             let mut edges = self.graph.neighbors(node).detach();
             while let Some((edge, target)) = edges.next(&self.graph) {
-                if let Edge::MergedBranch | Edge::MergedBranchIntoMulti | Edge::LoopBreak(_) | Edge::LoopBreakIntoMulti(_) = self.graph[edge] {
+                if let Edge::ForwardMultiViaNext(_) | Edge::MergedBranch | Edge::MergedBranchIntoMulti | Edge::LoopBreak(_) | Edge::LoopBreakIntoMulti(_) | Edge::LoopBreakViaNext(_, _) | Edge::SwitchFallThrough = self.graph[edge] {
                     // Go through the node's dominators (including itself)
                     let mut change_to_multi = false;
                     let mut processed_nodes = Vec::new();
-                    let node_dominators = dominators.dominators(node).unwrap();
+                    let node_dominators = dominators.dominators(node).ok_or(RelooperError::NoDominatorWithNext)?;
                     'dominator_loop: for dominator in node_dominators {
                         // If we reach the root node then something has gone wrong
                         if dominator == self.graph_root {
-                            panic!("No dominator of {:?} found with Next", target);
+                            return Err(RelooperError::NoDominatorWithNext);
                         }
                         processed_nodes.push(dominator);
 
@@ -480,10 +968,12 @@ impl<L: RelooperLabel> Relooper<L> {
                                 next_nodes.push(target);
                             }
                         }
+                        // A dominator can have duplicate next edges to the same node
                         next_nodes.sort();
+                        next_nodes.dedup();
 
                         // No next nodes is fine
-                        if next_nodes.len() == 0 {
+                        if next_nodes.is_empty() {
                             continue 'dominator_loop;
                         }
                         // If there is one next node...
@@ -529,7 +1019,7 @@ impl<L: RelooperLabel> Relooper<L> {
                         let mut incoming_edges = self.graph.neighbors_directed(target, Incoming).detach();
                         while let Some((edge, _)) = incoming_edges.next(&self.graph) {
                             match self.graph[edge] {
-                                Edge::MergedBranch => { self.graph[edge] = Edge::MergedBranchIntoMulti; },
+                                Edge::MergedBranch | Edge::SwitchFallThrough => { self.graph[edge] = Edge::MergedBranchIntoMulti; },
                                 Edge::LoopBreak(loop_id) => { self.graph[edge] = Edge::LoopBreakIntoMulti(loop_id); },
                                 Edge::Next(_) => { self.graph[edge] = Edge::Next(true); },
                                 _ => {},
@@ -539,12 +1029,23 @@ impl<L: RelooperLabel> Relooper<L> {
                 }
             }
         }
+
+        // Check that the next nodes of each node can be output as a Multiple, now that we know which are Multiples
+        for node in self.graph.node_indices() {
+            let mut next_nodes: Vec<NodeIndex> = self.graph.edges(node).filter(|edge| matches!(edge.weight(), Edge::Next(_))).map(|edge| edge.target()).collect();
+            next_nodes.sort();
+            next_nodes.dedup();
+            if next_nodes.len() > 1 && !self.can_next_nodes_use_multiple(&next_nodes, &dominators) {
+                return Err(RelooperError::InvalidMultiple);
+            }
+        }
+        Ok(())
     }
 
     // Output the graph as blocks
-    fn output(&self, entries: Vec<NodeIndex>, force_multi: bool) -> Option<Box<ShapedBlock<L>>> {
-        if entries.len() == 0 {
-            return None
+    fn output(&self, entries: Vec<NodeIndex>, force_multi: bool) -> Result<Option<Box<ShapedBlock<L>>>, RelooperError<L>> {
+        if entries.is_empty() {
+            return Ok(None)
         }
 
         // If we have one entry, then return the appropriate block
@@ -560,18 +1061,22 @@ impl<L: RelooperLabel> Relooper<L> {
                     continue;
                 }
                 let target = edge.target();
-                let mut add_branch = |target, branch| outgoing_branches.insert(self.get_basic_node_label(target), branch);
+                let mut add_branch = |target, branch| -> Result<(), RelooperError<L>> {
+                    outgoing_branches.insert(self.get_basic_node_label(target)?, branch);
+                    Ok(())
+                };
                 match edge.weight() {
                     Edge::Forward | Edge::ForwardMulti(_) => { immediate_entries.insert(target); },
                     Edge::Next(is_multi) => { next_entries.insert(target); next_multi |= is_multi; },
                     Edge::ForwardMultiViaNext(label) => { outgoing_branches.insert(*label, BranchMode::MergedBranchIntoMulti); },
-                    Edge::LoopBreak(loop_id) => { add_branch(target, BranchMode::LoopBreak(*loop_id)); },
-                    Edge::LoopBreakIntoMulti(loop_id) => { add_branch(target, BranchMode::LoopBreakIntoMulti(*loop_id)); },
-                    Edge::LoopContinue(loop_id) => { add_branch(target, BranchMode::LoopContinue(*loop_id)); },
-                    Edge::LoopContinueIntoMulti(loop_id) => { add_branch(target, BranchMode::LoopContinueIntoMulti(*loop_id)); },
-                    Edge::MergedBranch | Edge::SwitchFallThrough => { add_branch(target, BranchMode::MergedBranch); },
-                    Edge::MergedBranchIntoMulti => { add_branch(target, BranchMode::MergedBranchIntoMulti); },
-                    Edge::SetLabelAndBreak => { add_branch(target, BranchMode::SetLabelAndBreak); },
+                    Edge::LoopBreak(loop_id) => { add_branch(target, BranchMode::LoopBreak(*loop_id))?; },
+                    Edge::LoopBreakIntoMulti(loop_id) => { add_branch(target, BranchMode::LoopBreakIntoMulti(*loop_id))?; },
+                    Edge::LoopBreakViaNext(loop_id, label) => { outgoing_branches.insert(*label, BranchMode::LoopBreakIntoMulti(*loop_id)); },
+                    Edge::LoopContinue(loop_id) => { add_branch(target, BranchMode::LoopContinue(*loop_id))?; },
+                    Edge::LoopContinueIntoMulti(loop_id) => { add_branch(target, BranchMode::LoopContinueIntoMulti(*loop_id))?; },
+                    Edge::MergedBranch | Edge::SwitchFallThrough => { add_branch(target, BranchMode::MergedBranch)?; },
+                    Edge::MergedBranchIntoMulti => { add_branch(target, BranchMode::MergedBranchIntoMulti)?; },
+                    Edge::SetLabelAndBreak => { add_branch(target, BranchMode::SetLabelAndBreak)?; },
                     Edge::Removed => {},
                 };
             }
@@ -580,74 +1085,143 @@ impl<L: RelooperLabel> Relooper<L> {
             let next_entries = Vec::from_iter(next_entries);
             let is_multi = match node {
                 Node::Multiple(_) | Node::LoopMulti(_) => true,
+                // A LoopMulti must be inside a Multiple so that we know which of its headers are entered
+                Node::Basic(_) => immediate_entries.iter().any(|&entry| matches!(self.graph[entry], Node::LoopMulti(_))),
                 _ => false,
             };
 
-            return match node {
+            return Ok(match node {
                 Node::Root => {
-                    assert_eq!(next_entries.len(), 0, "Root node should have no next entries");
-                    self.output(immediate_entries, false)
+                    if !next_entries.is_empty() {
+                        return Err(RelooperError::RootWithNextNodes);
+                    }
+                    self.output(immediate_entries, false)?
+                },
+                Node::Multiple(label) if self.switches.contains(label) => {
+                    Some(Box::new(ShapedBlock::Switch(SwitchBlock {
+                        label: *label,
+                        cases: if immediate_entries.is_empty() { Vec::new() } else { self.output_multiple_handled(immediate_entries)? },
+                        branches: outgoing_branches,
+                        next: self.output(next_entries, next_multi)?,
+                    })))
                 },
                 Node::Basic(label) | Node::Multiple(label) => {
                     Some(Box::new(ShapedBlock::Simple(SimpleBlock {
                         label: *label,
-                        immediate: self.output(immediate_entries, is_multi),
-                        next: self.output(next_entries, next_multi),
+                        immediate: self.output(immediate_entries, is_multi)?,
+                        next: self.output(next_entries, next_multi)?,
                         branches: outgoing_branches,
                     })))
                 },
                 Node::Loop(loop_id) | Node::LoopMulti(loop_id) => {
                     Some(Box::new(ShapedBlock::Loop(LoopBlock {
                         loop_id: *loop_id,
-                        inner: self.output(immediate_entries, is_multi).unwrap(),
-                        next: self.output(next_entries, next_multi),
+                        inner: self.output(immediate_entries, is_multi)?.ok_or(RelooperError::EmptyLoop)?,
+                        next: self.output(next_entries, next_multi)?,
                     })))
                 },
-            }
+            })
         }
 
         // Multiples
-        let handled = self.output_multiple_handled(entries);
-        Some(Box::new(ShapedBlock::Multiple(MultipleBlock {
+        let handled = self.output_multiple_handled(entries)?;
+        Ok(Some(Box::new(ShapedBlock::Multiple(MultipleBlock {
             handled,
-        })))
+        }))))
     }
 
     // Can any of these nodes reach any other?
-    fn can_merged_nodes_use_multiple(&self, nodes: &Vec<NodeIndex>) -> bool {
-        // Filter the graph to ignore processed back edges
-        let filtered_graph = EdgeFiltered::from_fn(&self.graph, filter_edges);
+    fn can_merged_nodes_use_multiple(&self, nodes: &[NodeIndex], dominators: &algo::dominators::Dominators<NodeIndex>) -> bool {
+        // Filter the graph to ignore processed back edges, but include loop breaks and merged branches
+        let filtered_graph = EdgeFiltered::from_fn(&self.graph, filter_edges_including_processed);
         let mut space = algo::DfsSpace::new(&filtered_graph);
         for (x_index, &x) in nodes.iter().enumerate() {
             for (y_index, &y) in nodes.iter().enumerate() {
-                // Skip nodes that are the same, or when y is immediately after x.
-                if x_index == y_index || y_index == x_index + 1 {
+                // Skip nodes that are the same
+                if x_index == y_index {
                     continue;
                 }
                 if algo::has_path_connecting(&filtered_graph, x, y, Some(&mut space)) {
-                    return false;
+                    // Reaching the node immediately after is fine, as long as the node can fall through to it
+                    if y_index != x_index + 1 || !self.can_fall_through(x, y, dominators) {
+                        return false;
+                    }
                 }
             }
         }
-        return true;
+        true
+    }
+
+    // Like can_merged_nodes_use_multiple, but for nodes which aren't in order yet
+    // Each node can reach at most one other node, which no other node can reach, as it will then be put right after it
+    fn can_next_nodes_use_multiple(&self, nodes: &[NodeIndex], dominators: &algo::dominators::Dominators<NodeIndex>) -> bool {
+        let filtered_graph = EdgeFiltered::from_fn(&self.graph, filter_edges_including_processed);
+        let mut space = algo::DfsSpace::new(&filtered_graph);
+        let mut reached = FnvHashSet::default();
+        for &x in nodes {
+            let targets: Vec<NodeIndex> = nodes.iter().copied().filter(|&y| y != x && algo::has_path_connecting(&filtered_graph, x, y, Some(&mut space))).collect();
+            match targets.as_slice() {
+                [] => {},
+                [y] if reached.insert(*y) && self.can_fall_through(x, *y, dominators) => {},
+                _ => return false,
+            };
+        }
+        true
     }
 
-    fn get_basic_node_label(&self, id: NodeIndex) -> L {
+    // A node can only fall through to the next if branches to other nodes outside it can break out of the Multiple
+    // That's impossible from inside a loop or a switch, or after another Multiple
+    fn can_fall_through(&self, node: NodeIndex, next_node: NodeIndex, dominators: &algo::dominators::Dominators<NodeIndex>) -> bool {
+        // A node might be reached both nested and not, so track them separately
+        let mut stack = vec![(node, false)];
+        let mut discovered = FnvHashSet::default();
+        while let Some((current, nested)) = stack.pop() {
+            if discovered.insert((current, nested)) {
+                let is_switch = matches!(self.graph[current], Node::Multiple(label) if self.switches.contains(&label));
+                let nested_inside = nested || is_switch || matches!(self.graph[current], Node::Loop(_) | Node::LoopMulti(_));
+                let next_count = self.graph.edges(current).filter(|edge| matches!(edge.weight(), Edge::Next(_))).count();
+                for edge in self.graph.edges(current) {
+                    let target = edge.target();
+                    let dominated = target == node || dominators.strict_dominators(target).into_iter().flatten().any(|dom| dom == node);
+                    match edge.weight() {
+                        Edge::Forward | Edge::ForwardMulti(_) => { stack.push((target, nested_inside)); },
+                        Edge::Next(is_multi) => { stack.push((target, nested || *is_multi || next_count > 1)); },
+                        Edge::MergedBranch | Edge::MergedBranchIntoMulti | Edge::ForwardMultiViaNext(_)
+                            | Edge::LoopBreak(_) | Edge::LoopBreakIntoMulti(_) | Edge::LoopBreakViaNext(_, _) | Edge::SwitchFallThrough if dominated => { stack.push((target, nested)); },
+                        Edge::MergedBranch | Edge::MergedBranchIntoMulti | Edge::ForwardMultiViaNext(_)
+                            | Edge::LoopBreak(_) | Edge::LoopBreakIntoMulti(_) | Edge::LoopBreakViaNext(_, _) | Edge::SetLabelAndBreak | Edge::SwitchFallThrough
+                            // A switch's own branches are inside it too
+                            if (nested || is_switch) && target != next_node => {
+                            return false;
+                        },
+                        _ => {},
+                    };
+                }
+            }
+        }
+        true
+    }
+
+    fn find_loop_node(&self, loop_id: LoopId) -> Result<NodeIndex, RelooperError<L>> {
+        self.graph.node_indices().find(|&node| matches!(self.graph[node], Node::Loop(id) | Node::LoopMulti(id) if id == loop_id)).ok_or(RelooperError::UnknownLoop(loop_id))
+    }
+
+    fn get_basic_node_label(&self, id: NodeIndex) -> Result<L, RelooperError<L>> {
         match self.graph[id] {
-            Node::Basic(label) | Node::Multiple(label) => label,
+            Node::Basic(label) | Node::Multiple(label) => Ok(label),
             Node::Loop(_) => {
                 let mut edges = self.graph.neighbors(id).detach();
                 while let Some((edge, target)) = edges.next(&self.graph) {
                     if let Edge::Forward = self.graph[edge] {
-                        match self.graph[target] {
-                            Node::Basic(label) | Node::Multiple(label) => return label,
-                            other => panic!("Cannot get label of node inside loop: {:?}", other),
-                        }
+                        return match self.graph[target] {
+                            Node::Basic(label) | Node::Multiple(label) => Ok(label),
+                            _ => Err(RelooperError::NodeWithoutLabel),
+                        };
                     }
                 }
-                panic!("No inner node within loop node: {:?}", self.graph[id]);
+                Err(RelooperError::NodeWithoutLabel)
             },
-            other => panic!("Cannot get label of node: {:?}", other),
+            _ => Err(RelooperError::NodeWithoutLabel),
         }
     }
 
@@ -659,8 +1233,21 @@ impl<L: RelooperLabel> Relooper<L> {
     }
 
     // Make a loop
-    fn make_loop<F: Fn(NodeIndex) -> bool>(&mut self, loop_headers: &Vec<NodeIndex>, loop_parents: &Vec<NodeIndex>, loop_parent_filter: F) -> (NodeIndex, LoopId) {
+    // The dominator can be given if it is already known, as the loop node may only be reachable through processed edges
+    fn make_loop<F: Fn(NodeIndex) -> bool>(&mut self, loop_headers: &Vec<NodeIndex>, loop_parents: &Vec<NodeIndex>, loop_parent_filter: F, dominator: Option<NodeIndex>) -> Result<(NodeIndex, LoopId), RelooperError<L>> {
         let multi_loop = loop_headers.len() > 1;
+        let mut entered_by_loop_breaks = false;
+
+        // Find the nodes inside the loop, as branches from them will become back edges instead
+        let filtered_graph = EdgeFiltered::from_fn(&self.graph, filter_edges);
+        let mut inside_loop = FnvHashSet::default();
+        let mut dfs = Dfs::empty(&filtered_graph);
+        for &header in loop_headers {
+            dfs.move_to(header);
+            while let Some(node) = dfs.next(&filtered_graph) {
+                inside_loop.insert(node);
+            }
+        }
 
         // Add the new node
         let loop_id = self.counter;
@@ -671,18 +1258,26 @@ impl<L: RelooperLabel> Relooper<L> {
         for &node in loop_headers {
             let mut edges = self.graph.neighbors_directed(node, Incoming).detach();
             while let Some((edge_id, parent)) = edges.next(&self.graph) {
-                if loop_parent_filter(parent) {
+                if loop_parent_filter(parent) && !inside_loop.contains(&parent) {
                     match self.graph[edge_id] {
                         // Forward edges get replaced
                         Edge::Forward => {
-                            self.graph.add_edge(parent, loop_node, if multi_loop { Edge::ForwardMulti(self.get_basic_node_label(node)) } else { Edge::Forward });
+                            let edge = if multi_loop { Edge::ForwardMulti(self.get_basic_node_label(node)?) } else { Edge::Forward };
+                            self.graph.add_edge(parent, loop_node, edge);
                             self.graph[edge_id] = Edge::Removed;
                         },
-                        Edge::ForwardMulti(_) => unreachable!("A loop node should never be a loop header of a second loop"),
+                        Edge::ForwardMulti(_) => return Err(RelooperError::NestedLoopHeader),
                         // Next edges get removed
                         Edge::Next(_) => {
                             self.graph[edge_id] = Edge::Removed;
                         },
+                        // Loop breaks into a LoopMulti must set the label
+                        Edge::LoopBreak(id) | Edge::LoopBreakIntoMulti(id) if multi_loop => {
+                            let label = self.get_basic_node_label(node)?;
+                            self.graph.add_edge(parent, loop_node, Edge::LoopBreakViaNext(id, label));
+                            self.graph[edge_id] = Edge::Removed;
+                            entered_by_loop_breaks = true;
+                        },
                         // Other edges are left as they are
                         _ => {},
                     };
@@ -695,6 +1290,12 @@ impl<L: RelooperLabel> Relooper<L> {
             self.graph.add_edge(loop_node, header, Edge::Forward);
         }
 
+        // A loop which is entered by loop breaks must be the next node of its dominator
+        let via_next = loop_parents.len() > 1 || entered_by_loop_breaks;
+        if entered_by_loop_breaks {
+            self.graph.add_edge(dominator.ok_or(RelooperError::LoopWithoutDominator)?, loop_node, Edge::Next(false));
+        }
+
         // If the loop parent of a LoopMulti was a Multiple, check if we can turn it into a Simple now.
         if multi_loop {
             for &node in loop_parents {
@@ -708,7 +1309,7 @@ impl<L: RelooperLabel> Relooper<L> {
                         };
                     }
                     if neighbors.len() == 1 {
-                        self.graph[node] = Node::Basic(self.get_basic_node_label(node));
+                        self.graph[node] = Node::Basic(self.get_basic_node_label(node)?);
                     }
                 }
             }
@@ -732,11 +1333,9 @@ impl<L: RelooperLabel> Relooper<L> {
                         match self.graph[edge] {
                             Edge::Forward | Edge::ForwardMulti(_) | Edge::Next(_) => {
                                 // If the target node is dominated by the loop node then add it to the stack
-                                let target_dominators = dominators.strict_dominators(target).unwrap();
-                                for dom in target_dominators {
-                                    if dom == loop_node && !discovered.is_visited(&target) {
-                                        stack.push(target);
-                                    }
+                                let mut target_dominators = dominators.strict_dominators(target).into_iter().flatten();
+                                if target_dominators.any(|dom| dom == loop_node) && !discovered.is_visited(&target) {
+                                    stack.push(target);
                                 }
                             },
                             _ => {},
@@ -747,55 +1346,72 @@ impl<L: RelooperLabel> Relooper<L> {
         }
 
         // If we have multiple parents, fix the ForwardMulti edges so that the loop won't be outputted multiple times
-        if loop_parents.len() > 1 {
-            let dominator = dominators.immediate_dominator(loop_node).unwrap();
+        if via_next {
             for &node in loop_parents {
                 let mut edges = self.graph.neighbors(node).detach();
-                while let Some((edge, _)) = edges.next(&self.graph) {
+                while let Some((edge, target)) = edges.next(&self.graph) {
                     if let Edge::ForwardMulti(label) = self.graph[edge] {
-                        self.graph[edge] = Edge::ForwardMultiViaNext(label);
+                        if target == loop_node {
+                            self.graph[edge] = Edge::ForwardMultiViaNext(label);
+                        }
                     }
                 };
             }
-            self.graph.add_edge(dominator, loop_node, Edge::Next(false));
+            if !entered_by_loop_breaks {
+                let dominator = dominator.or_else(|| dominators.immediate_dominator(loop_node)).ok_or(RelooperError::LoopWithoutDominator)?;
+                self.graph.add_edge(dominator, loop_node, Edge::Next(false));
+            }
         }
 
-        (loop_node, loop_id)
+        Ok((loop_node, loop_id))
     }
 
-    fn output_multiple_handled(&self, mut entries: Vec<NodeIndex>) -> Vec<HandledBlock<L>> {
+    fn output_multiple_handled(&self, entries: Vec<NodeIndex>) -> Result<Vec<HandledBlock<L>>, RelooperError<L>> {
         let filtered_graph = EdgeFiltered::from_fn(&self.graph, filter_edges_including_processed);
         let mut space = algo::DfsSpace::new(&filtered_graph);
-        let mut handled = Vec::default();
-        let entries_count = entries.len();
-        // TODO: require input to be sorted and just check instead?
+        let mut entries: Vec<(Vec<L>, NodeIndex)> = entries.into_iter().map(|entry| Ok((match self.graph[entry] {
+            Node::Basic(label) | Node::Multiple(label) => vec![label],
+            Node::Loop(_) | Node::LoopMulti(_) => {
+                let mut labels = Vec::default();
+                let mut edges = self.graph.neighbors(entry).detach();
+                while let Some((edge, target)) = edges.next(&self.graph) {
+                    if let Edge::Forward = self.graph[edge] {
+                        if !self.internal_headers.contains(&target) {
+                            labels.push(self.get_basic_node_label(target)?);
+                        }
+                    }
+                }
+                labels.sort();
+                labels
+            },
+            Node::Root => return Err(RelooperError::NodeWithoutLabel),
+        }, entry))).collect::<Result<_, _>>()?;
+        // Sort so that the tests will work, but an entry which can reach another must come just before it so that it can fall through
         entries.sort();
-        for index in 0..entries_count {
-            let entry = entries[index];
-            let next_entry = entries.get(index + 1);
+        let mut ordered: Vec<(Vec<L>, NodeIndex)> = Vec::with_capacity(entries.len());
+        while !entries.is_empty() {
+            let mut unblocked = Vec::new();
+            for (index, &(_, entry)) in entries.iter().enumerate() {
+                if !entries.iter().any(|&(_, other)| other != entry && algo::has_path_connecting(&filtered_graph, other, entry, Some(&mut space))) {
+                    unblocked.push(index);
+                }
+            }
+            let after_previous = ordered.last().and_then(|&(_, previous)| unblocked.iter().copied().find(|&index| algo::has_path_connecting(&filtered_graph, previous, entries[index].1, Some(&mut space))));
+            let index = after_previous.or_else(|| unblocked.first().copied()).unwrap_or(0);
+            ordered.push(entries.remove(index));
+        }
+        let entries = ordered;
+        let mut handled = Vec::default();
+        for index in 0..entries.len() {
+            let entry = entries[index].1;
+            let next_entry = entries.get(index + 1).map(|(_, next)| next);
             handled.push(HandledBlock {
-                labels: match self.graph[entry] {
-                    Node::Basic(label) | Node::Multiple(label) => vec![label],
-                    Node::Loop(_) | Node::LoopMulti(_) => {
-                        let mut labels = Vec::default();
-                        let mut edges = self.graph.neighbors(entry).detach();
-                        while let Some((edge, target)) = edges.next(&self.graph) {
-                            if let Edge::Forward = self.graph[edge] {
-                                labels.push(self.get_basic_node_label(target));
-                            }
-                        }
-                        labels.sort();
-                        labels
-                    },
-                    _ => unimplemented!(),
-                },
-                inner: *self.output(vec![entry], false).unwrap(),
+                labels: entries[index].0.clone(),
+                inner: *self.output(vec![entry], false)?.ok_or(RelooperError::NoOutput)?,
                 // false if this entry can reach the next, otherwise true
-                break_after: next_entry.map_or(true, |next| !algo::has_path_connecting(&filtered_graph, entry, *next, Some(&mut space))),
+                break_after: next_entry.is_none_or(|next| !algo::has_path_connecting(&filtered_graph, entry, *next, Some(&mut space))),
             });
         }
-        // Sort so that the tests will work
-        handled.sort_by(|a, b| a.labels.cmp(&b.labels));
-        handled
+        Ok(handled)
     }
 }
\ No newline at end of file
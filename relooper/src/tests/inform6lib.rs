@@ -81,7 +81,7 @@ fn revisemulti() {
         loop_id: loop21162id,
         inner: Box::new(Multiple(MultipleBlock {
             handled: vec![
-                basic_handled_without_break(21162, Simple(SimpleBlock {
+                basic_handled(21162, Simple(SimpleBlock {
                     label: 21162,
                     immediate: Some(Box::new(Multiple(MultipleBlock {
                         handled: vec![
@@ -120,7 +120,7 @@ fn revisemulti() {
                         branches: branch_to(21162, MergedBranchIntoMulti),
                         next: Some(loop21162),
                     })),
-                    basic_handled(21217, end_node(21217, Some(branch_to(21225, MergedBranch)))),
+                    basic_handled(21217, end_node(21217, Some(branch_to(21225, LoopBreakIntoMulti(loop21130id))))),
                 ],
             }))),
             branches: FnvHashMap::default(),
@@ -286,7 +286,7 @@ fn aotearoa_461194() {
                     immediate: Some(Box::new(Multiple(MultipleBlock {
                         handled: vec![
                             basic_handled(461733, end_node(461733, None)),
-                            basic_handled(461736, end_node(461736, Some(branch_to(461945, MergedBranch)))),
+                            basic_handled(461736, end_node(461736, Some(branch_to(461945, MergedBranchIntoMulti)))),
                         ],
                     }))),
                     branches: FnvHashMap::default(),
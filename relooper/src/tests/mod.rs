@@ -9,15 +9,18 @@ https://github.com/curiousdannii/if-decompiler
 
 */
 
+use std::collections::BTreeSet;
 use std::iter::FromIterator;
 
 use super::*;
 use BranchMode::*;
 use ShapedBlock::*;
+use transitions::reconstruct_transitions;
 
 mod glulxercise;
 mod inform6lib;
 mod inform7;
+mod transitions;
 
 fn basic_handled<T: RelooperLabel>(label: T, inner: ShapedBlock<T>) -> HandledBlock<T> {
     HandledBlock {
@@ -72,6 +75,26 @@ fn test_basic_blocks() {
     })));
 }
 
+// Blocks which can't be reached are ignored, even when there are several of them
+#[test]
+fn test_unreachable_blocks() {
+    let blocks = vec![
+        (0, vec![]),
+        (1, vec![]),
+        (2, vec![2]),
+    ];
+    let result = reloop(blocks, 0);
+    assert_eq!(result, Box::new(end_node(0, None)));
+
+    let blocks = vec![
+        (0, vec![1]),
+        (1, vec![1, 2]),
+        (2, vec![]),
+    ];
+    let result = reloop(blocks, 1);
+    assert_eq!(reconstruct_transitions(&result), Ok(BTreeSet::from_iter(vec![(1, 1), (1, 2)])));
+}
+
 // Some basic loops
 #[test]
 fn test_basic_loops() {
@@ -524,45 +547,43 @@ fn test_spaghetti() {
         (7, vec![]),
     ];
     let result = reloop(blocks, 0);
-    assert_eq!(result, Box::new(Simple(SimpleBlock {
+    assert_eq!(result, Box::new(Switch(SwitchBlock {
         label: 0,
-        immediate: Some(Box::new(Multiple(MultipleBlock {
-            handled: vec![
-                basic_handled(1, end_node(1, Some(branch_to(6, MergedBranchIntoMulti)))),
-                basic_handled(2, Loop(LoopBlock {
-                    loop_id: 0,
-                    inner: Box::new(Simple(SimpleBlock {
-                        label: 2,
-                        immediate: Some(Box::new(Multiple(MultipleBlock {
-                            handled: vec![
-                                basic_handled(4, Simple(SimpleBlock {
-                                    label: 4,
-                                    immediate: None,
-                                    branches: FnvHashMap::from_iter(vec![
-                                        (2, LoopContinue(0)),
-                                        (6, LoopBreakIntoMulti(0)),
-                                    ]),
-                                    next: None,
-                                })),
-                                basic_handled(5, Simple(SimpleBlock {
-                                    label: 5,
-                                    immediate: None,
-                                    branches: FnvHashMap::from_iter(vec![
-                                        (2, LoopContinue(0)),
-                                        (7, LoopBreakIntoMulti(0)),
-                                    ]),
-                                    next: None,
-                                })),
-                            ],
-                        }))),
-                        branches: FnvHashMap::default(),
-                        next: None,
-                    })),
+        cases: vec![
+            basic_handled(1, end_node(1, Some(branch_to(6, MergedBranchIntoMulti)))),
+            basic_handled(2, Loop(LoopBlock {
+                loop_id: 0,
+                inner: Box::new(Simple(SimpleBlock {
+                    label: 2,
+                    immediate: Some(Box::new(Multiple(MultipleBlock {
+                        handled: vec![
+                            basic_handled(4, Simple(SimpleBlock {
+                                label: 4,
+                                immediate: None,
+                                branches: FnvHashMap::from_iter(vec![
+                                    (2, LoopContinue(0)),
+                                    (6, LoopBreakIntoMulti(0)),
+                                ]),
+                                next: None,
+                            })),
+                            basic_handled(5, Simple(SimpleBlock {
+                                label: 5,
+                                immediate: None,
+                                branches: FnvHashMap::from_iter(vec![
+                                    (2, LoopContinue(0)),
+                                    (7, LoopBreakIntoMulti(0)),
+                                ]),
+                                next: None,
+                            })),
+                        ],
+                    }))),
+                    branches: FnvHashMap::default(),
                     next: None,
                 })),
-                basic_handled(3, end_node(3, Some(branch_to(7, MergedBranchIntoMulti)))),
-            ],
-        }))),
+                next: None,
+            })),
+            basic_handled(3, end_node(3, Some(branch_to(7, MergedBranchIntoMulti)))),
+        ],
         branches: FnvHashMap::default(),
         next: Some(Box::new(Multiple(MultipleBlock {
             handled: vec![
@@ -589,50 +610,59 @@ fn test_stackifier_multiloop() {
     let result = reloop(blocks, 'A');
     assert_eq!(result, Box::new(Simple(SimpleBlock {
         label: 'A',
-        immediate: Some(Box::new(Loop(LoopBlock {
-            loop_id: 0,
-            inner: Box::new(Multiple(MultipleBlock {
-                handled: vec![
-                    basic_handled('B', Simple(SimpleBlock {
-                        label: 'B',
-                        immediate: Some(Box::new(Multiple(MultipleBlock {
+        immediate: Some(Box::new(Multiple(MultipleBlock {
+            handled: vec![
+                HandledBlock {
+                    labels: vec!['B', 'C'],
+                    inner: Loop(LoopBlock {
+                        loop_id: 0,
+                        inner: Box::new(Multiple(MultipleBlock {
                             handled: vec![
-                                basic_handled('D', Simple(SimpleBlock {
-                                    label: 'D',
-                                    immediate: None,
-                                    branches: FnvHashMap::from_iter(vec![
-                                        ('B', LoopContinueIntoMulti(0)),
-                                        ('C', LoopContinueIntoMulti(0)),
-                                    ]),
+                                basic_handled('B', Simple(SimpleBlock {
+                                    label: 'B',
+                                    immediate: Some(Box::new(Multiple(MultipleBlock {
+                                        handled: vec![
+                                            basic_handled('D', Simple(SimpleBlock {
+                                                label: 'D',
+                                                immediate: None,
+                                                branches: FnvHashMap::from_iter(vec![
+                                                    ('B', LoopContinueIntoMulti(0)),
+                                                    ('C', LoopContinueIntoMulti(0)),
+                                                ]),
+                                                next: None,
+                                            })),
+                                        ],
+                                    }))),
+                                    branches: branch_to('E', LoopContinueIntoMulti(0)),
                                     next: None,
                                 })),
+                                basic_handled('C', end_node('C', Some(branch_to('E', LoopContinueIntoMulti(0))))),
+                                basic_handled('E', Simple(SimpleBlock {
+                                    label: 'E',
+                                    immediate: Some(Box::new(Multiple(MultipleBlock {
+                                        handled: vec![
+                                            basic_handled('F', end_node('F', Some(branch_to('G', MergedBranch)))),
+                                        ],
+                                    }))),
+                                    branches: branch_to('G', MergedBranch),
+                                    next: Some(Box::new(Simple(SimpleBlock {
+                                        label: 'G',
+                                        immediate: Some(Box::new(Multiple(MultipleBlock {
+                                            handled: vec![
+                                                basic_handled('H', end_node('H', None)),
+                                            ],
+                                        }))),
+                                        branches: branch_to('B', LoopContinueIntoMulti(0)),
+                                        next: None,
+                                    }))),
+                                })),
                             ],
-                        }))),
-                        branches: branch_to('E', MergedBranch),
+                        })),
                         next: None,
-                    })),
-                    basic_handled('C', end_node('C', Some(branch_to('E', MergedBranch)))),
-                ],
-            })),
-            next: Some(Box::new(Simple(SimpleBlock {
-                label: 'E',
-                immediate: Some(Box::new(Multiple(MultipleBlock {
-                    handled: vec![
-                        basic_handled('F', end_node('F', Some(branch_to('G', MergedBranch)))),
-                    ],
-                }))),
-                branches: branch_to('G', MergedBranch),
-                next: Some(Box::new(Simple(SimpleBlock {
-                    label: 'G',
-                    immediate: Some(Box::new(Multiple(MultipleBlock {
-                        handled: vec![
-                            basic_handled('H', end_node('H', None)),
-                        ],
-                    }))),
-                    branches: branch_to('B', LoopContinueIntoMulti(0)),
-                    next: None,
-                }))),
-            }))),
+                    }),
+                    break_after: true,
+                },
+            ],
         }))),
         branches: FnvHashMap::default(),
         next: None,
@@ -650,32 +680,30 @@ fn test_loopmulti() {
         (5, vec![3]),
     ];
     let result = reloop(blocks, 1);
-    assert_eq!(result, Box::new(Simple(SimpleBlock {
+    assert_eq!(result, Box::new(Switch(SwitchBlock {
         label: 1,
-        immediate: Some(Box::new(Multiple(MultipleBlock {
-            handled: vec![
-                basic_handled(2, end_node(2, None)),
-                HandledBlock {
-                    labels: vec![3, 4],
-                    inner: Loop(LoopBlock {
-                        loop_id: 0,
-                        inner: Box::new(Multiple(MultipleBlock {
-                            handled: vec![
-                                basic_handled(3, end_node(3, Some(branch_to(4, LoopContinueIntoMulti(0))))),
-                                basic_handled(4, Simple(SimpleBlock {
-                                    label: 4,
-                                    immediate: Some(Box::new(end_node(5, Some(branch_to(3, LoopContinueIntoMulti(0)))))),
-                                    branches: FnvHashMap::default(),
-                                    next: None,
-                                })),
-                            ],
-                        })),
-                        next: None,
-                    }),
-                    break_after: true,
-                },
-            ],
-        }))),
+        cases: vec![
+            basic_handled(2, end_node(2, None)),
+            HandledBlock {
+                labels: vec![3, 4],
+                inner: Loop(LoopBlock {
+                    loop_id: 0,
+                    inner: Box::new(Multiple(MultipleBlock {
+                        handled: vec![
+                            basic_handled(3, end_node(3, Some(branch_to(4, LoopContinueIntoMulti(0))))),
+                            basic_handled(4, Simple(SimpleBlock {
+                                label: 4,
+                                immediate: Some(Box::new(end_node(5, Some(branch_to(3, LoopContinueIntoMulti(0)))))),
+                                branches: FnvHashMap::default(),
+                                next: None,
+                            })),
+                        ],
+                    })),
+                    next: None,
+                }),
+                break_after: true,
+            },
+        ],
         branches: FnvHashMap::default(),
         next: None,
     })));
@@ -720,22 +748,23 @@ fn test_loopmulti() {
                                     immediate: None,
                                     branches: FnvHashMap::from_iter(vec![
                                         (4, LoopContinueIntoMulti(0)),
-                                        (5, MergedBranch),
+                                        (5, LoopContinueIntoMulti(0)),
+                                    ]),
+                                    next: None,
+                                })),
+                                basic_handled(4, end_node(4, Some(branch_to(5, LoopContinueIntoMulti(0))))),
+                                basic_handled(5, Simple(SimpleBlock {
+                                    label: 5,
+                                    immediate: None,
+                                    branches: FnvHashMap::from_iter(vec![
+                                        (3, LoopContinueIntoMulti(0)),
+                                        (6, LoopBreak(0)),
                                     ]),
                                     next: None,
                                 })),
-                                basic_handled(4, end_node(4, Some(branch_to(5, MergedBranch)))),
                             ],
                         })),
-                        next: Some(Box::new(Simple(SimpleBlock {
-                            label: 5,
-                            immediate: None,
-                            branches: FnvHashMap::from_iter(vec![
-                                (3, LoopContinueIntoMulti(0)),
-                                (6, LoopBreak(0)),
-                            ]),
-                            next: None,
-                        }))),
+                        next: None,
                     }))),
                 })),
             ],
@@ -743,4 +772,299 @@ fn test_loopmulti() {
         branches: branch_to(6, MergedBranch),
         next: Some(Box::new(end_node(6, None))),
     })));
-}
\ No newline at end of file
+}
+// Invalid input should return an error rather than panicking
+#[test]
+fn test_errors() {
+    let blocks: Vec<(u32, Vec<u32>)> = vec![];
+    assert_eq!(try_reloop(blocks, 0), Err(RelooperError::NoBlocks));
+
+    let blocks = vec![
+        (0, vec![2]),
+        (2, vec![1]),
+        (1, vec![]),
+    ];
+    assert_eq!(try_reloop(blocks, 0), Err(RelooperError::UnsortedBlocks(1)));
+
+    let blocks = vec![
+        (0, vec![1]),
+        (1, vec![3]),
+        (2, vec![]),
+    ];
+    assert_eq!(try_reloop(blocks, 0), Err(RelooperError::UnknownLabel(3)));
+
+    let blocks = vec![
+        (0, vec![1]),
+        (1, vec![]),
+    ];
+    assert_eq!(try_reloop(blocks, 5), Err(RelooperError::UnknownLabel(5)));
+}
+
+// An irreducible loop, which node splitting can turn into a loop with one entry
+#[test]
+fn test_node_splitting() {
+    let blocks = vec![
+        (0, vec![1, 2]),
+        (1, vec![2, 3]),
+        (2, vec![1]),
+        (3, vec![]),
+    ];
+
+    // Without enough budget we get a LoopMulti
+    let unsplit = try_reloop(blocks.clone(), 0);
+    assert_eq!(try_reloop_with_node_splitting(blocks.clone(), 0, &FnvHashMap::default(), 0), unsplit);
+    let mut block_sizes = FnvHashMap::default();
+    block_sizes.insert(1, 20);
+    block_sizes.insert(2, 10);
+    assert_eq!(try_reloop_with_node_splitting(blocks.clone(), 0, &block_sizes, 5), unsplit);
+
+    // But with enough budget the smaller block 2 gets duplicated
+    let result = try_reloop_with_node_splitting(blocks, 0, &block_sizes, 10).unwrap();
+    assert_eq!(result, Box::new(Simple(SimpleBlock {
+        label: 0,
+        immediate: Some(Box::new(Multiple(MultipleBlock {
+            handled: vec![
+                basic_handled(2, end_node(2, Some(branch_to(1, MergedBranch)))),
+            ],
+        }))),
+        branches: branch_to(1, MergedBranch),
+        next: Some(Box::new(Loop(LoopBlock {
+            loop_id: 0,
+            inner: Box::new(Simple(SimpleBlock {
+                label: 1,
+                immediate: Some(Box::new(Multiple(MultipleBlock {
+                    handled: vec![
+                        basic_handled(2, end_node(2, Some(branch_to(1, LoopContinue(0))))),
+                        basic_handled(3, end_node(3, None)),
+                    ],
+                }))),
+                branches: FnvHashMap::default(),
+                next: None,
+            })),
+            next: None,
+        }))),
+    })));
+
+    // If the split graph can't be relooped, then we fall back to the unsplit graph
+    let blocks = vec![
+        (0, vec![1, 2]),
+        (1, vec![2]),
+        (2, vec![1, 2, 3]),
+        (3, vec![]),
+    ];
+    let unsplit = try_reloop(blocks.clone(), 0);
+    assert!(unsplit.is_ok());
+    assert_eq!(try_reloop_with_node_splitting(blocks, 0, &FnvHashMap::default(), 5), unsplit);
+}
+
+// Blocks with more than two branches become Switches
+#[test]
+fn test_switches() {
+    let blocks = vec![
+        (0, vec![1, 2, 3]),
+        (1, vec![4]),
+        (2, vec![4]),
+        (3, vec![4]),
+        (4, vec![]),
+    ];
+    let result = reloop(blocks, 0);
+    assert_eq!(result, Box::new(Switch(SwitchBlock {
+        label: 0,
+        cases: vec![
+            basic_handled(1, end_node(1, Some(branch_to(4, MergedBranch)))),
+            basic_handled(2, end_node(2, Some(branch_to(4, MergedBranch)))),
+            basic_handled(3, end_node(3, Some(branch_to(4, MergedBranch)))),
+        ],
+        branches: FnvHashMap::default(),
+        next: Some(Box::new(end_node(4, None))),
+    })));
+
+    // One case merging into another
+    let blocks = vec![
+        (0, vec![1, 2, 3]),
+        (1, vec![2]),
+        (2, vec![4]),
+        (3, vec![4]),
+        (4, vec![]),
+    ];
+    let result = reloop(blocks, 0);
+    assert_eq!(result, Box::new(Switch(SwitchBlock {
+        label: 0,
+        cases: vec![
+            basic_handled(1, end_node(1, Some(branch_to(2, MergedBranchIntoMulti)))),
+            basic_handled(3, end_node(3, Some(branch_to(4, MergedBranchIntoMulti)))),
+        ],
+        branches: branch_to(2, MergedBranchIntoMulti),
+        next: Some(Box::new(Multiple(MultipleBlock {
+            handled: vec![
+                basic_handled_without_break(2, end_node(2, Some(branch_to(4, MergedBranch)))),
+                basic_handled(4, end_node(4, None)),
+            ],
+        }))),
+    })));
+
+    // A switch inside a loop
+    let blocks = vec![
+        (0, vec![1]),
+        (1, vec![2, 3, 4, 5]),
+        (2, vec![1]),
+        (3, vec![1]),
+        (4, vec![5]),
+        (5, vec![]),
+    ];
+    let result = reloop(blocks, 0);
+    assert_eq!(result, Box::new(Simple(SimpleBlock {
+        label: 0,
+        immediate: Some(Box::new(Loop(LoopBlock {
+            loop_id: 0,
+            inner: Box::new(Switch(SwitchBlock {
+                label: 1,
+                cases: vec![
+                    basic_handled(2, end_node(2, Some(branch_to(1, LoopContinue(0))))),
+                    basic_handled(3, end_node(3, Some(branch_to(1, LoopContinue(0))))),
+                    basic_handled(4, end_node(4, Some(branch_to(5, MergedBranch)))),
+                ],
+                branches: branch_to(5, MergedBranch),
+                next: Some(Box::new(end_node(5, None))),
+            })),
+            next: None,
+        }))),
+        branches: FnvHashMap::default(),
+        next: None,
+    })));
+}
+
+#[test]
+fn test_loop_shapes() {
+    // While loop, with its exit handled inside the loop
+    let blocks = vec![
+        (0, vec![1]),
+        (1, vec![2, 3]),
+        (2, vec![1]),
+        (3, vec![]),
+    ];
+    let result = reloop(blocks, 0).recognise_loop_shapes();
+    assert_eq!(result, Simple(SimpleBlock {
+        label: 0,
+        immediate: Some(Box::new(While(WhileBlock {
+            loop_id: 0,
+            condition: 1,
+            exit: 3,
+            body: Box::new(end_node(2, Some(branch_to(1, LoopContinue(0))))),
+            next: Some(Box::new(end_node(3, None))),
+        }))),
+        branches: FnvHashMap::default(),
+        next: None,
+    }));
+
+    // While loop which breaks to a node also reached from outside the loop
+    let blocks = vec![
+        (0, vec![1, 4]),
+        (1, vec![2, 4]),
+        (2, vec![1]),
+        (4, vec![]),
+    ];
+    let result = reloop(blocks, 0).recognise_loop_shapes();
+    assert_eq!(result, Simple(SimpleBlock {
+        label: 0,
+        immediate: Some(Box::new(Multiple(MultipleBlock {
+            handled: vec![
+                basic_handled(1, While(WhileBlock {
+                    loop_id: 0,
+                    condition: 1,
+                    exit: 4,
+                    body: Box::new(Multiple(MultipleBlock {
+                        handled: vec![
+                            basic_handled(2, end_node(2, Some(branch_to(1, LoopContinue(0))))),
+                        ],
+                    })),
+                    next: None,
+                })),
+            ],
+        }))),
+        branches: branch_to(4, MergedBranch),
+        next: Some(Box::new(end_node(4, None))),
+    }));
+
+    // Do-while loop
+    let blocks = vec![
+        (0, vec![1]),
+        (1, vec![2]),
+        (2, vec![1, 3]),
+        (3, vec![]),
+    ];
+    let result = reloop(blocks, 0).recognise_loop_shapes();
+    assert_eq!(result, Simple(SimpleBlock {
+        label: 0,
+        immediate: Some(Box::new(DoWhile(DoWhileBlock {
+            loop_id: 0,
+            body: Some(Box::new(end_node(1, Some(branch_to(2, MergedBranch))))),
+            condition: 2,
+            exit: 3,
+            next: Some(Box::new(end_node(3, None))),
+        }))),
+        branches: FnvHashMap::default(),
+        next: None,
+    }));
+
+    // A block which loops to itself
+    let blocks = vec![
+        (0, vec![1]),
+        (1, vec![1, 2]),
+        (2, vec![]),
+    ];
+    let result = reloop(blocks, 0).recognise_loop_shapes();
+    assert_eq!(result, Simple(SimpleBlock {
+        label: 0,
+        immediate: Some(Box::new(DoWhile(DoWhileBlock {
+            loop_id: 0,
+            body: None,
+            condition: 1,
+            exit: 2,
+            next: Some(Box::new(end_node(2, None))),
+        }))),
+        branches: FnvHashMap::default(),
+        next: None,
+    }));
+
+    // A loop with two exits stays as it is
+    let blocks = vec![
+        (0, vec![1]),
+        (1, vec![2, 4]),
+        (2, vec![1, 5]),
+        (4, vec![6]),
+        (5, vec![6]),
+        (6, vec![]),
+    ];
+    let result = reloop(blocks.clone(), 0);
+    assert_eq!(result.recognise_loop_shapes(), *reloop(blocks, 0));
+}
+
+#[test]
+fn test_loop_shapes_with_untestable_conditions() {
+    // While loop, checking that the callback is given the condition and exit
+    let blocks = vec![
+        (0, vec![1]),
+        (1, vec![2, 3]),
+        (2, vec![1]),
+        (3, vec![]),
+    ];
+    let result = reloop(blocks.clone(), 0).recognise_loop_shapes_with(&|condition, exit| {
+        assert_eq!((condition, exit), (1, 3));
+        false
+    });
+    assert_eq!(result, *reloop(blocks, 0));
+
+    // Do-while loop
+    let blocks = vec![
+        (0, vec![1]),
+        (1, vec![2]),
+        (2, vec![1, 3]),
+        (3, vec![]),
+    ];
+    let result = reloop(blocks.clone(), 0).recognise_loop_shapes_with(&|condition, exit| {
+        assert_eq!((condition, exit), (2, 3));
+        false
+    });
+    assert_eq!(result, *reloop(blocks, 0));
+}
@@ -0,0 +1,524 @@
+/*
+
+Control Flow Checker
+====================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use petgraph::algo;
+use petgraph::graph::{Graph, NodeIndex};
+use quickcheck::{Arbitrary, Gen, QuickCheck, TestResult};
+
+use super::*;
+
+// Reconstruct every block-to-block transition of a ShapedBlock, by following each branch the way generated code would
+// A branch which doesn't end up at a block, or which needs to dispatch on the label variable when it wasn't set, is an error
+pub fn reconstruct_transitions<L: RelooperLabel>(block: &ShapedBlock<L>) -> Result<BTreeSet<(L, L)>, String> {
+    let mut checker = Checker {
+        nodes: Vec::new(),
+        fuel: 0,
+    };
+    checker.add(block, None);
+    let mut transitions = BTreeSet::new();
+    for index in 0..checker.nodes.len() {
+        let node = &checker.nodes[index];
+        let mut actions = Vec::new();
+        match node.shape {
+            Simple(block) => {
+                for (&target, &mode) in &block.branches {
+                    actions.push((block.label, Action::Branch(target, mode)));
+                }
+                if let Some(immediate) = node.immediate {
+                    for target in entries(checker.nodes[immediate].shape) {
+                        if !block.branches.contains_key(&target) {
+                            actions.push((block.label, Action::Enter(immediate, Some(target))));
+                        }
+                    }
+                }
+            },
+            Switch(block) => {
+                for (&target, &mode) in &block.branches {
+                    actions.push((block.label, Action::Branch(target, mode)));
+                }
+                for (handled, &case) in block.cases.iter().zip(&node.cases) {
+                    for &target in &handled.labels {
+                        actions.push((block.label, Action::Enter(case, Some(target))));
+                    }
+                }
+            },
+            While(block) => {
+                for target in entries(&block.body) {
+                    actions.push((block.condition, Action::Enter(node.inner.unwrap(), Some(target))));
+                }
+                actions.push((block.condition, Action::Finish(index, None)));
+            },
+            DoWhile(block) => {
+                actions.push((block.condition, Action::Continue(index, None)));
+                actions.push((block.condition, Action::Finish(index, None)));
+            },
+            Loop(_) | Multiple(_) => {},
+        };
+        for (from, action) in actions {
+            // Each action starts with a fresh allowance of steps, so that we can detect infinite loops
+            checker.fuel = checker.nodes.len() * 4;
+            let to = checker.act(index, action).map_err(|err| format!("Branch from {:?}: {}", from, err))?;
+            transitions.insert((from, to));
+        }
+    }
+    Ok(transitions)
+}
+
+// The labels which a block could be entered with
+fn entries<L: RelooperLabel>(block: &ShapedBlock<L>) -> Vec<L> {
+    match block {
+        Simple(block) => vec![block.label],
+        Loop(block) => entries(&block.inner),
+        Multiple(block) => block.handled.iter().flat_map(|handled| handled.labels.iter().copied()).collect(),
+        Switch(block) => vec![block.label],
+        While(block) => vec![block.condition],
+        DoWhile(block) => match &block.body {
+            Some(body) => entries(body),
+            None => vec![block.condition],
+        },
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Role {
+    Immediate,
+    Inner,
+    Next,
+    Case(usize),
+}
+
+// What to do next, with the value of the label variable if it is known
+#[derive(Clone, Copy)]
+enum Action<L: RelooperLabel> {
+    Branch(L, BranchMode),
+    Continue(usize, Option<L>),
+    Enter(usize, Option<L>),
+    Finish(usize, Option<L>),
+}
+
+struct Node<'a, L: RelooperLabel> {
+    shape: &'a ShapedBlock<L>,
+    parent: Option<(usize, Role)>,
+    immediate: Option<usize>,
+    inner: Option<usize>,
+    next: Option<usize>,
+    cases: Vec<usize>,
+}
+
+struct Checker<'a, L: RelooperLabel> {
+    nodes: Vec<Node<'a, L>>,
+    fuel: usize,
+}
+
+impl<'a, L: RelooperLabel> Checker<'a, L> {
+    // Flatten the tree so that we can walk back up it
+    fn add(&mut self, shape: &'a ShapedBlock<L>, parent: Option<(usize, Role)>) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            shape,
+            parent,
+            immediate: None,
+            inner: None,
+            next: None,
+            cases: Vec::new(),
+        });
+        let add_child = |checker: &mut Self, child: &'a Option<Box<ShapedBlock<L>>>, role| child.as_deref().map(|child| checker.add(child, Some((index, role))));
+        let (immediate, inner, next, cases) = match shape {
+            Simple(block) => (add_child(self, &block.immediate, Role::Immediate), None, add_child(self, &block.next, Role::Next), Vec::new()),
+            Loop(block) => (None, Some(self.add(&block.inner, Some((index, Role::Inner)))), add_child(self, &block.next, Role::Next), Vec::new()),
+            Multiple(block) => (None, None, None, block.handled.iter().enumerate().map(|(i, handled)| self.add(&handled.inner, Some((index, Role::Case(i))))).collect()),
+            Switch(block) => (None, None, add_child(self, &block.next, Role::Next), block.cases.iter().enumerate().map(|(i, handled)| self.add(&handled.inner, Some((index, Role::Case(i))))).collect()),
+            While(block) => (None, Some(self.add(&block.body, Some((index, Role::Inner)))), add_child(self, &block.next, Role::Next), Vec::new()),
+            DoWhile(block) => (None, add_child(self, &block.body, Role::Inner), add_child(self, &block.next, Role::Next), Vec::new()),
+        };
+        let node = &mut self.nodes[index];
+        node.immediate = immediate;
+        node.inner = inner;
+        node.next = next;
+        node.cases = cases;
+        index
+    }
+
+    // Follow an action until we reach a block, and return its label
+    fn act(&mut self, from: usize, action: Action<L>) -> Result<L, String> {
+        match action {
+            Action::Branch(target, mode) => {
+                use BranchMode::*;
+                let label = match mode {
+                    LoopBreakIntoMulti(_) | LoopContinueIntoMulti(_) | MergedBranchIntoMulti | SetLabelAndBreak => Some(target),
+                    _ => None,
+                };
+                match mode {
+                    LoopBreak(loop_id) | LoopBreakIntoMulti(loop_id) => self.act(from, Action::Finish(self.find_loop(from, loop_id)?, label)),
+                    LoopContinue(loop_id) | LoopContinueIntoMulti(loop_id) => self.act(from, Action::Continue(self.find_loop(from, loop_id)?, label)),
+                    MergedBranch | MergedBranchIntoMulti => self.act(from, Action::Finish(from, label)),
+                    SetLabelAndBreak => self.set_label_and_break(from, label),
+                }
+            },
+            Action::Continue(index, label) => match self.nodes[index].shape {
+                Loop(_) => self.act(index, Action::Enter(self.nodes[index].inner.unwrap(), label)),
+                While(block) => Ok(block.condition),
+                DoWhile(block) => match self.nodes[index].inner {
+                    Some(body) => self.act(index, Action::Enter(body, label)),
+                    None => Ok(block.condition),
+                },
+                _ => unreachable!(),
+            },
+            Action::Enter(index, label) => {
+                self.burn()?;
+                match self.nodes[index].shape {
+                    Simple(block) => Ok(block.label),
+                    Loop(_) => self.act(index, Action::Enter(self.nodes[index].inner.unwrap(), label)),
+                    Multiple(block) => {
+                        let label = label.ok_or("entered a Multiple without setting the label")?;
+                        match block.handled.iter().position(|handled| handled.labels.contains(&label)) {
+                            Some(i) => self.act(index, Action::Enter(self.nodes[index].cases[i], Some(label))),
+                            None => self.exit(index, Some(label)),
+                        }
+                    },
+                    Switch(block) => Ok(block.label),
+                    While(block) => Ok(block.condition),
+                    DoWhile(block) => match self.nodes[index].inner {
+                        Some(body) => self.act(index, Action::Enter(body, label)),
+                        None => Ok(block.condition),
+                    },
+                }
+            },
+            Action::Finish(index, label) => match self.nodes[index].next {
+                Some(next) => self.act(index, Action::Enter(next, label)),
+                None => self.exit(index, label),
+            },
+        }
+    }
+
+    // Leave a block and everything after it
+    fn exit(&mut self, index: usize, label: Option<L>) -> Result<L, String> {
+        self.burn()?;
+        match self.nodes[index].parent {
+            None => Err("fell off the end of the function".into()),
+            Some((parent, Role::Immediate)) => self.act(parent, Action::Finish(parent, label)),
+            Some((parent, Role::Next)) => self.exit(parent, label),
+            // Falling off the end of a loop's body starts the next iteration, or checks the condition of a do-while loop
+            Some((parent, Role::Inner)) => match self.nodes[parent].shape {
+                DoWhile(block) => Ok(block.condition),
+                _ => self.act(parent, Action::Continue(parent, label)),
+            },
+            Some((parent, Role::Case(i))) => {
+                let (handled, is_switch) = match self.nodes[parent].shape {
+                    Multiple(block) => (&block.handled, false),
+                    Switch(block) => (&block.cases, true),
+                    _ => unreachable!(),
+                };
+                if !handled[i].break_after && i + 1 < handled.len() {
+                    self.act(parent, Action::Enter(self.nodes[parent].cases[i + 1], label))
+                }
+                else if is_switch {
+                    self.act(parent, Action::Finish(parent, label))
+                }
+                else {
+                    self.exit(parent, label)
+                }
+            },
+        }
+    }
+
+    // Break out of the innermost switch or loop
+    // Multiples which are immediate blocks are output as if statements rather than switches, so they are skipped
+    // A Switch's own branches are cases of its switch statement, so they break out of it
+    fn set_label_and_break(&mut self, from: usize, label: Option<L>) -> Result<L, String> {
+        if let Switch(_) = self.nodes[from].shape {
+            return self.act(from, Action::Finish(from, label));
+        }
+        let mut index = from;
+        while let Some((parent, role)) = self.nodes[index].parent {
+            match (role, self.nodes[parent].shape) {
+                (Role::Case(_), Multiple(_)) if !matches!(self.nodes[parent].parent, Some((_, Role::Immediate))) => return self.exit(parent, label),
+                (Role::Case(_), Switch(_)) | (Role::Inner, _) => return self.act(parent, Action::Finish(parent, label)),
+                _ => index = parent,
+            };
+        }
+        Err("SetLabelAndBreak outside of a Multiple".into())
+    }
+
+    fn find_loop(&self, from: usize, loop_id: LoopId) -> Result<usize, String> {
+        let mut index = from;
+        while let Some((parent, _)) = self.nodes[index].parent {
+            match self.nodes[parent].shape {
+                Loop(LoopBlock {loop_id: id, ..}) | While(WhileBlock {loop_id: id, ..}) | DoWhile(DoWhileBlock {loop_id: id, ..}) if *id == loop_id => return Ok(parent),
+                _ => index = parent,
+            };
+        }
+        Err(format!("loop {} is not an ancestor", loop_id))
+    }
+
+    fn burn(&mut self) -> Result<(), String> {
+        if self.fuel == 0 {
+            return Err("stuck in an infinite loop".into());
+        }
+        self.fuel -= 1;
+        Ok(())
+    }
+}
+
+// A random control flow graph, in which every block can be reached from the first
+#[derive(Clone, Debug)]
+struct RandomGraph {
+    blocks: Vec<(u32, Vec<u32>)>,
+}
+
+impl RandomGraph {
+    fn edges(&self) -> BTreeSet<(u32, u32)> {
+        self.blocks.iter().flat_map(|(label, branches)| branches.iter().map(move |&target| (*label, target))).collect()
+    }
+
+    fn all_reachable(&self) -> bool {
+        let mut reached = vec![false; self.blocks.len()];
+        let mut stack = vec![0];
+        while let Some(label) = stack.pop() {
+            if !reached[label as usize] {
+                reached[label as usize] = true;
+                stack.extend(&self.blocks[label as usize].1);
+            }
+        }
+        reached.into_iter().all(|reached| reached)
+    }
+
+    // A graph is reducible if removing the edges to a dominator leaves it acyclic
+    fn is_reducible(&self) -> bool {
+        let mut graph: Graph<(), ()> = Graph::new();
+        let nodes: Vec<NodeIndex> = self.blocks.iter().map(|_| graph.add_node(())).collect();
+        for (from, to) in self.edges() {
+            graph.add_edge(nodes[from as usize], nodes[to as usize], ());
+        }
+        let dominators = algo::dominators::simple_fast(&graph, nodes[0]);
+        graph.retain_edges(|graph, edge| {
+            let (from, to) = graph.edge_endpoints(edge).unwrap();
+            !dominators.dominators(from).unwrap().any(|dom| dom == to)
+        });
+        !algo::is_cyclic_directed(&graph)
+    }
+}
+
+impl Arbitrary for RandomGraph {
+    // First connect each block to an earlier block, and then add random edges in any direction, which makes for plenty of irreducible loops
+    fn arbitrary(g: &mut Gen) -> Self {
+        let count = u32::arbitrary(g) % 12 + 1;
+        let mut branches = vec![BTreeSet::new(); count as usize];
+        for label in 1..count {
+            branches[(u32::arbitrary(g) % label) as usize].insert(label);
+        }
+        for _ in 0..(u32::arbitrary(g) % (count * 2)) {
+            branches[(u32::arbitrary(g) % count) as usize].insert(u32::arbitrary(g) % count);
+        }
+        RandomGraph {
+            blocks: branches.into_iter().enumerate().map(|(label, branches)| (label as u32, branches.into_iter().collect())).collect(),
+        }
+    }
+
+    // Try removing each block after the first, and then each edge
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let graph = self.clone();
+        let without_blocks = (1..self.blocks.len() as u32).map(move |removed| {
+            let relabel = |label: u32| if label > removed { label - 1 } else { label };
+            RandomGraph {
+                blocks: graph.blocks.iter().filter(|(label, _)| *label != removed).map(|(label, branches)| {
+                    (relabel(*label), branches.iter().filter(|&&target| target != removed).map(|&target| relabel(target)).collect())
+                }).collect(),
+            }
+        });
+        let graph = self.clone();
+        let without_edges = self.edges().into_iter().map(move |(from, to)| {
+            let mut smaller = graph.clone();
+            smaller.blocks[from as usize].1.retain(|&target| target != to);
+            smaller
+        });
+        Box::new(without_blocks.chain(without_edges).filter(|graph| graph.all_reachable()))
+    }
+}
+
+thread_local! {
+    // How many irreducible graphs each test has relooped, by result
+    static RESULTS: RefCell<BTreeMap<String, usize>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+// Check that a relooped graph has exactly the transitions of the original graph
+// We can't handle every irreducible graph, so a RelooperError for one is counted and discarded, but reducible graphs must always succeed
+fn check_transitions(graph: &RandomGraph, result: Result<Box<ShapedBlock<u32>>, RelooperError<u32>>) -> TestResult {
+    let reducible = graph.is_reducible();
+    let count = |kind: String| if !reducible {
+        RESULTS.with(|results| *results.borrow_mut().entry(kind).or_default() += 1);
+    };
+    let result = match result {
+        Ok(result) => result,
+        Err(err) if !reducible => {
+            count(format!("{:?}", err));
+            return TestResult::discard();
+        },
+        Err(err) => return TestResult::error(format!("Reducible graph was refused: {}", err)),
+    };
+    count("Ok".into());
+    let expected = graph.edges();
+    match reconstruct_transitions(&result) {
+        Ok(transitions) if transitions == expected => {},
+        Ok(transitions) => return TestResult::error(format!("Transitions don't match\nExpected: {:?}\nFound: {:?}\nResult: {:?}", expected, transitions, result)),
+        Err(err) => return TestResult::error(format!("{}\nResult: {:?}", err, result)),
+    };
+    match reconstruct_transitions(&result.recognise_loop_shapes()) {
+        Ok(transitions) if transitions == expected => TestResult::passed(),
+        Ok(transitions) => TestResult::error(format!("Transitions don't match after recognising loop shapes\nExpected: {:?}\nFound: {:?}", expected, transitions)),
+        Err(err) => TestResult::error(format!("After recognising loop shapes: {}", err)),
+    }
+}
+
+// Report how many irreducible graphs were refused, and check that it's no more than the given percentage
+// Reducible graphs are never refused, so they don't count towards this
+fn check_refusals(max_percent: usize) {
+    let results = RESULTS.with(|results| results.take());
+    let total: usize = results.values().sum();
+    let refused = total - results.get("Ok").copied().unwrap_or(0);
+    println!("Relooped {} irreducible graphs: {:?}", total, results);
+    assert!(refused * 100 <= total * max_percent, "Too many irreducible graphs were refused: {:?}", results);
+}
+
+#[test]
+fn test_transitions() {
+    let blocks = vec![
+        (0, vec![1]),
+        (1, vec![2, 3]),
+        (2, vec![1, 3]),
+        (3, vec![]),
+    ];
+    let result = reloop(blocks, 0);
+    assert_eq!(reconstruct_transitions(&result), Ok(BTreeSet::from_iter(vec![(0, 1), (1, 2), (1, 3), (2, 1), (2, 3)])));
+
+    // A multi-headed loop, which must break out to 3
+    let blocks = vec![
+        (0, vec![1, 2]),
+        (1, vec![2, 3]),
+        (2, vec![1, 3]),
+        (3, vec![]),
+    ];
+    let result = reloop(blocks, 0);
+    assert_eq!(reconstruct_transitions(&result), Ok(BTreeSet::from_iter(vec![(0, 1), (0, 2), (1, 2), (1, 3), (2, 1), (2, 3)])));
+
+    // A multi-headed loop whose headers merge back together
+    let blocks = vec![
+        (0, vec![1, 2]),
+        (1, vec![3]),
+        (2, vec![3]),
+        (3, vec![1, 2, 4]),
+        (4, vec![]),
+    ];
+    let result = reloop(blocks, 0);
+    assert_eq!(reconstruct_transitions(&result), Ok(BTreeSet::from_iter(vec![(0, 1), (0, 2), (1, 3), (2, 3), (3, 1), (3, 2), (3, 4)])));
+
+    // Merged branches where the later block falls through to the earlier one
+    let blocks = vec![
+        (0, vec![1, 2, 3]),
+        (1, vec![3]),
+        (2, vec![]),
+        (3, vec![2, 3]),
+    ];
+    let result = reloop(blocks, 0);
+    assert_eq!(reconstruct_transitions(&result), Ok(BTreeSet::from_iter(vec![(0, 1), (0, 2), (0, 3), (1, 3), (3, 2), (3, 3)])));
+
+    // An acyclic graph where 1 falls through to 7, with a fall through from 2 to 5 inside it
+    let blocks = vec![
+        (0, vec![1, 4]),
+        (1, vec![2, 3]),
+        (2, vec![5]),
+        (3, vec![2, 5]),
+        (4, vec![6, 7]),
+        (5, vec![7]),
+        (6, vec![1]),
+        (7, vec![]),
+    ];
+    let result = reloop(blocks, 0);
+    assert_eq!(reconstruct_transitions(&result), Ok(BTreeSet::from_iter(vec![(0, 1), (0, 4), (1, 2), (1, 3), (2, 5), (3, 2), (3, 5), (4, 6), (4, 7), (5, 7), (6, 1)])));
+
+    // A broken result, where the branch to 1 sets the label but then continues on to 2
+    let broken = Simple(SimpleBlock {
+        label: 0,
+        immediate: None,
+        branches: branch_to(1, MergedBranchIntoMulti),
+        next: Some(Box::new(Simple(SimpleBlock {
+            label: 2,
+            immediate: None,
+            branches: FnvHashMap::default(),
+            next: None,
+        }))),
+    });
+    assert_eq!(reconstruct_transitions(&broken), Ok(BTreeSet::from_iter(vec![(0, 2)])));
+
+    // A Multiple entered without setting the label
+    let broken = Simple(SimpleBlock {
+        label: 0,
+        immediate: None,
+        branches: branch_to(1, MergedBranch),
+        next: Some(Box::new(Multiple(MultipleBlock {
+            handled: vec![basic_handled(1, end_node(1, None))],
+        }))),
+    });
+    assert!(reconstruct_transitions(&broken).is_err());
+}
+
+#[test]
+fn test_random_graphs() {
+    fn prop(graph: RandomGraph) -> TestResult {
+        let result = try_reloop(graph.blocks.clone(), 0);
+        check_transitions(&graph, result)
+    }
+    QuickCheck::new().tests(5000).quickcheck(prop as fn(RandomGraph) -> TestResult);
+    // About a third of these irreducible graphs are refused, mostly with InvalidLoopBreak
+    check_refusals(42);
+}
+
+#[test]
+fn test_random_graphs_with_node_splitting() {
+    fn prop(graph: RandomGraph) -> TestResult {
+        let sizes = graph.blocks.iter().map(|(label, _)| (*label, 1)).collect();
+        let result = try_reloop_with_node_splitting(graph.blocks.clone(), 0, &sizes, 5);
+        // Splitting must never turn a graph we could reloop into one we can't
+        if result.is_err() && try_reloop(graph.blocks.clone(), 0).is_ok() {
+            return TestResult::error("Node splitting refused a graph which could be relooped without it");
+        }
+        check_transitions(&graph, result)
+    }
+    QuickCheck::new().tests(5000).quickcheck(prop as fn(RandomGraph) -> TestResult);
+    // Node splitting makes most of them reducible, so only about one in seven are refused
+    check_refusals(20);
+}
+
+#[test]
+fn test_try_reloop_never_panics() {
+    // Any list of blocks, even if unsorted or with unknown labels, must give a result or a RelooperError
+    fn prop(blocks: Vec<(u8, Vec<u8>)>, first_label: u8) -> bool {
+        let blocks: Vec<(u8, Vec<u8>)> = blocks.into_iter().take(20).map(|(label, branches)| (label, branches.into_iter().take(20).collect())).collect();
+        let _ = try_reloop(blocks.clone(), first_label);
+        // Most of those are refused straight away, so also try them with the labels sorted and every branch going to a real block
+        let mut labels: Vec<u8> = blocks.iter().map(|(label, _)| *label).collect();
+        labels.sort();
+        labels.dedup();
+        if !labels.is_empty() {
+            let valid_label = |label: u8| labels[label as usize % labels.len()];
+            let valid_blocks = labels.iter().map(|&label| {
+                let branches = blocks.iter().find(|(other, _)| *other == label).map(|(_, branches)| branches.iter().map(|&target| valid_label(target)).collect()).unwrap_or_default();
+                (label, branches)
+            }).collect();
+            let _ = try_reloop(valid_blocks, valid_label(first_label));
+        }
+        true
+    }
+    QuickCheck::new().tests(5000).quickcheck(prop as fn(Vec<(u8, Vec<u8>)>, u8) -> bool);
+}
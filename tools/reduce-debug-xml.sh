@@ -8,12 +8,8 @@ pattern+="<array>.+?</array>|"
 pattern+="<attribute>.+?</attribute>|"
 pattern+="<class>.+?</class>|"
 pattern+="<constant>.+?</constant>|"
-pattern+="<global-variable>.+?</global-variable>|"
-pattern+="<local-variable>.+?</local-variable>|"
 pattern+="<object>.+?</object>|"
 pattern+="<property>.+?</property>|"
-pattern+="<sequence-point>.+?</sequence-point>|"
-pattern+="<source-code-location>.+?</source-code-location>|"
 pattern+="<story-file-section>.+?</story-file-section>|"
 pattern+="<table-entry>.+?</table-entry>"
 
@@ -0,0 +1,135 @@
+/*
+
+ztoc - Decompile a Z-Code file into C code
+==========================================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::env;
+use std::error::Error;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Instant;
+use std::thread;
+
+use structopt::StructOpt;
+
+use if_decompiler::blorb::{self, Blorb};
+
+mod output;
+
+#[derive(StructOpt)]
+#[structopt(name = "ztoc", about = "Decompile a Z-Code file into C code")]
+struct Cli {
+    /// The path of the Z-Code storyfile
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+
+    /// Output folder
+    #[structopt(long, parse(from_os_str))]
+    out_dir: Option<PathBuf>,
+
+    /// Stack size (MB) (for the ztoc app, not the stack of the Z-Code file being decompiled)
+    #[structopt(short, long, default_value = "8")]
+    stack_size: usize,
+
+    /// Disassembler mode - only disassemble, do not optimise or generate structured code
+    #[structopt(short, long)]
+    disassemble: bool,
+
+    /// Safe function overrides
+    #[structopt(long, use_delimiter = true)]
+    safe_function_overrides: Option<Vec<u32>>,
+
+    /// Unsafe function overrides
+    #[structopt(long, use_delimiter = true)]
+    unsafe_function_overrides: Option<Vec<u32>>,
+}
+
+fn main() -> Result<(), Box<std::io::Error>> {
+    // Process arguments
+    let args: Cli = Cli::from_args();
+
+    let child = thread::Builder::new()
+        .name("run".into())
+        .stack_size(args.stack_size * 1024 * 1024)
+        .spawn(move || -> Result<(), Box<std::io::Error>> { run(args)?; Ok(()) })
+        .unwrap();
+
+    child.join().unwrap()?;
+
+    Ok(())
+}
+
+fn run(args: Cli) -> Result<(), Box<std::io::Error>> {
+    // Start processing args
+    let mut storyfile_path = env::current_dir()?;
+    storyfile_path.push(args.path);
+    let name = storyfile_path.file_stem().expect("storyfile should not be relative").to_str().unwrap().to_string();
+
+    let out_dir = match args.out_dir {
+        Some(path) => path,
+        None => {
+            let mut path = storyfile_path.clone();
+            let mut name = path.file_name().unwrap().to_os_string();
+            name.push(if args.disassemble { ".disassembled" } else { ".decompiled" });
+            path.pop();
+            path.push(name);
+            path
+        }
+    };
+
+    // Read the storyfile
+    println!("Starting to decompile {:?}", storyfile_path);
+    let start = Instant::now();
+    let data = std::fs::read(storyfile_path)?;
+
+    // Check for a blorb
+    let image = if data.starts_with(b"FORM") {
+        let blorb = Blorb::new(&data).map_err(invalid_data)?;
+        match blorb.executable() {
+            Some(chunk) if chunk.chunk_type == blorb::ZCOD => chunk.data,
+            _ => return Err(invalid_data("Blorb file does not have a ZCOD chunk")),
+        }
+    }
+    else {
+        &*data
+    };
+    // Z-Code files have no magic number, so check the version instead
+    if image.len() < 64 || !(1..=8).contains(&image[0]) {
+        return Err(invalid_data("Unrecognised file format"));
+    }
+
+    // Decompile the storyfile
+    print!("Disassembling the storyfile...");
+    io::stdout().flush().unwrap();
+    let start_disassemble = Instant::now();
+    let mut decompiler = if_decompiler::zmachine::ZMachineState::new(None, args.safe_function_overrides, args.unsafe_function_overrides);
+    if let Err(err) = decompiler.decompile_rom(image) {
+        println!();
+        return Err(invalid_data(err));
+    }
+    let duration = start_disassemble.elapsed();
+    println!(" completed in {:?}", duration);
+    for warning in &decompiler.warnings {
+        println!("Warning: {}", warning);
+    }
+
+    // Output the C files
+    let mut output = output::ZOutput::new(args.disassemble, image, name, out_dir, decompiler).map_err(invalid_data)?;
+    output.output(image)?;
+
+    let duration = start.elapsed();
+    println!("Total decompilation time: {:?}", duration);
+
+    Ok(())
+}
+
+fn invalid_data<E: Into<Box<dyn Error + Send + Sync>>>(err: E) -> Box<io::Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, err))
+}
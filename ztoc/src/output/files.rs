@@ -0,0 +1,52 @@
+/*
+
+Create files
+============
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use super::*;
+use std::time::Instant;
+
+impl ZOutput {
+    pub fn output_from_templates(&self, data: &[u8]) -> std::io::Result<()> {
+        let start = Instant::now();
+
+        // Output the image
+        let mut output_path = self.out_dir.clone();
+        output_path.push("image.data");
+        fs::write(output_path, data)?;
+
+        // Output the template files
+        let templates = [
+            ("CMakeLists.txt", include_str!("templates/CMakeLists.txt")),
+            ("LICENSE", include_str!("templates/LICENSE")),
+            ("runtime.c", include_str!("templates/runtime.c")),
+            ("unixstrt.c", include_str!("templates/unixstrt.c")),
+            ("ztoc.h", include_str!("templates/ztoc.h")),
+        ];
+        let replacements = [
+            ["IMAGE_LENGTH_VALUE", &data.len().to_string()],
+            ["EXENAME", &self.name],
+        ];
+
+        for template_name in &templates {
+            let mut file = String::from(template_name.1);
+            for replacement in &replacements {
+                file = file.replace(replacement[0], replacement[1]);
+            }
+
+            let mut output_path = self.out_dir.clone();
+            output_path.push(template_name.0);
+            fs::write(output_path, file)?;
+        }
+
+        let duration = start.elapsed();
+        println!("Time outputting files from templates: {:?}", duration);
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+/*
+
+Output common functions
+=======================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use if_decompiler::*;
+use zmachine::*;
+use Operand::*;
+
+use super::*;
+
+impl ZOutput {
+
+    // Output an instruction body
+    pub fn output_common_instruction(&self, instruction: &Instruction, args: Vec<String>, safe: bool) -> String {
+        let opcode = instruction.opcode;
+        let version = self.state.version;
+        let null = String::from("0");
+        let op_a = args.first().unwrap_or(&null);
+        let op_b = args.get(1).unwrap_or(&null);
+        use opcodes::*;
+        match opcode {
+            // 2OP
+            OP_JE => {
+                if args.len() <= 2 {
+                    return format!("{} == {}", op_a, op_b);
+                }
+                // Each operand must be evaluated exactly once, in order
+                let temps: Vec<String> = args.iter().enumerate().map(|(i, arg)| format!("temp{} = {}", i, arg)).collect();
+                let tests: Vec<String> = (1..args.len()).map(|i| format!("temp0 == temp{}", i)).collect();
+                format!("({}, {})", temps.join(", "), tests.join(" || "))
+            },
+            OP_JL => format_safe_stack_pops_expression("(zsword) {} < (zsword) {}", &args),
+            OP_JG => format_safe_stack_pops_expression("(zsword) {} > (zsword) {}", &args),
+            OP_DEC_CHK => {
+                let (prelude, read, write) = self.indirect_variable(instruction, &args, safe);
+                format!("({}temp0 = {}, {}, (zsword) {} < (zsword) temp0)", prelude, op_b, write(format!("{} - 1", read)), read)
+            },
+            OP_INC_CHK => {
+                let (prelude, read, write) = self.indirect_variable(instruction, &args, safe);
+                format!("({}temp0 = {}, {}, (zsword) {} > (zsword) temp0)", prelude, op_b, write(format!("{} + 1", read)), read)
+            },
+            OP_JIN => runtime("OP_JIN", &args),
+            OP_TEST => runtime("OP_TEST", &args),
+            OP_OR => format!("{} | {}", op_a, op_b),
+            OP_AND => format!("{} & {}", op_a, op_b),
+            OP_TEST_ATTR => runtime("OP_TEST_ATTR", &args),
+            OP_SET_ATTR => runtime("OP_SET_ATTR", &args),
+            OP_CLEAR_ATTR => runtime("OP_CLEAR_ATTR", &args),
+            OP_STORE => {
+                let (prelude, _, write) = self.indirect_variable(instruction, &args, safe);
+                format!("({}{})", prelude, write(op_b.clone()))
+            },
+            OP_INSERT_OBJ => runtime("OP_INSERT_OBJ", &args),
+            OP_LOADW => format_safe_stack_pops_expression("Mem2((zword) ({} + 2 * {}))", &args),
+            OP_LOADB => format_safe_stack_pops_expression("Mem1((zword) ({} + {}))", &args),
+            OP_GET_PROP => runtime("OP_GET_PROP", &args),
+            OP_GET_PROP_ADDR => runtime("OP_GET_PROP_ADDR", &args),
+            OP_GET_NEXT_PROP => runtime("OP_GET_NEXT_PROP", &args),
+            OP_ADD => format!("(zword) ({} + {})", op_a, op_b),
+            OP_SUB => format_safe_stack_pops_expression("(zword) ({} - {})", &args),
+            OP_MUL => format!("(zword) ({} * {})", op_a, op_b),
+            OP_DIV => runtime("OP_DIV", &args),
+            OP_MOD => runtime("OP_MOD", &args),
+            // OP_CALL_2S | OP_CALL_2N
+            OP_SET_COLOUR => runtime_with_defaults("OP_SET_COLOUR", &args, &["0", "0", "0"]),
+            // OP_THROW
+
+            // 1OP
+            OP_JZ => format!("{} == 0", op_a),
+            OP_GET_SIBLING => runtime("OP_GET_SIBLING", &args),
+            OP_GET_CHILD => runtime("OP_GET_CHILD", &args),
+            OP_GET_PARENT => runtime("OP_GET_PARENT", &args),
+            OP_GET_PROP_LEN => runtime("OP_GET_PROP_LEN", &args),
+            OP_INC => {
+                let (prelude, read, write) = self.indirect_variable(instruction, &args, safe);
+                format!("({}{})", prelude, write(format!("{} + 1", read)))
+            },
+            OP_DEC => {
+                let (prelude, read, write) = self.indirect_variable(instruction, &args, safe);
+                format!("({}{})", prelude, write(format!("{} - 1", read)))
+            },
+            OP_PRINT_ADDR => format!("print_zstring({})", op_a),
+            // OP_CALL_1S
+            OP_REMOVE_OBJ => runtime("OP_REMOVE_OBJ", &args),
+            OP_PRINT_OBJ => runtime("OP_PRINT_OBJ", &args),
+            // OP_RET
+            OP_JUMP => String::new(),
+            OP_PRINT_PADDR => format!("print_zstring(unpack_string({}))", op_a),
+            OP_LOAD => {
+                let (prelude, read, _) = self.indirect_variable(instruction, &args, safe);
+                format!("({}{})", prelude, read)
+            },
+            // OP_CALL_1N in version 5+
+            OP_NOT_V1 => format!("(zword) ~{}", op_a),
+
+            // 0OP
+            // OP_RTRUE | OP_RFALSE
+            OP_PRINT => format!("print_zstring({})", instruction.text.unwrap()),
+            // OP_PRINT_RET
+            OP_NOP => String::new(),
+            // OP_SAVE_V1 | OP_RESTORE_V1 | OP_RESTART | OP_RET_POPPED
+            // OP_CATCH in version 5+
+            OP_POP => String::from("PopStack()"),
+            // OP_QUIT
+            OP_NEW_LINE => String::from("zprint_char(13)"),
+            OP_SHOW_STATUS => String::from("show_status()"),
+            OP_VERIFY => String::from("OP_VERIFY()"),
+            OP_PIRACY => String::from("1"),
+
+            // VAR
+            // OP_CALL_VS
+            OP_STOREW => format_safe_stack_pops_expression("MemW2((zword) ({} + 2 * {}), {})", &args),
+            OP_STOREB => format_safe_stack_pops_expression("MemW1((zword) ({} + {}), {})", &args),
+            OP_PUT_PROP => runtime("OP_PUT_PROP", &args),
+            OP_READ => runtime_with_defaults("OP_READ", &args, &["0", "0", "0", "0"]),
+            OP_PRINT_CHAR => format!("zprint_char({})", op_a),
+            OP_PRINT_NUM => format!("print_num({})", op_a),
+            OP_RANDOM => runtime("OP_RANDOM", &args),
+            OP_PUSH => format!("PushStack({})", op_a),
+            OP_PULL => {
+                if version == 6 {
+                    // The version 6 user stacks are not supported
+                    return String::from("PopStack()");
+                }
+                let (prelude, _, write) = self.indirect_variable(instruction, &args, safe);
+                format!("({}temp0 = PopStack(), {})", prelude, write(String::from("temp0")))
+            },
+            OP_SPLIT_WINDOW => runtime("OP_SPLIT_WINDOW", &args),
+            OP_SET_WINDOW => runtime("OP_SET_WINDOW", &args),
+            // OP_CALL_VS2
+            OP_ERASE_WINDOW => runtime("OP_ERASE_WINDOW", &args),
+            OP_ERASE_LINE => runtime("OP_ERASE_LINE", &args),
+            OP_SET_CURSOR => runtime_with_defaults("OP_SET_CURSOR", &args, &["0", "0", "0"]),
+            OP_GET_CURSOR => runtime("OP_GET_CURSOR", &args),
+            OP_SET_TEXT_STYLE => runtime("OP_SET_TEXT_STYLE", &args),
+            OP_BUFFER_MODE => runtime("OP_BUFFER_MODE", &args),
+            OP_OUTPUT_STREAM => runtime_with_defaults("OP_OUTPUT_STREAM", &args, &["0", "0", "0"]),
+            OP_INPUT_STREAM => runtime("OP_INPUT_STREAM", &args),
+            OP_SOUND_EFFECT => runtime_with_defaults("OP_SOUND_EFFECT", &args, &["0", "0", "0", "0"]),
+            OP_READ_CHAR => runtime_with_defaults("OP_READ_CHAR", &args, &["1", "0", "0"]),
+            OP_SCAN_TABLE => runtime_with_defaults("OP_SCAN_TABLE", &args, &["0", "0", "0", "130"]),
+            OP_NOT => format!("(zword) ~{}", op_a),
+            // OP_CALL_VN | OP_CALL_VN2
+            OP_TOKENISE => runtime_with_defaults("OP_TOKENISE", &args, &["0", "0", "0", "0"]),
+            OP_ENCODE_TEXT => runtime("OP_ENCODE_TEXT", &args),
+            OP_COPY_TABLE => runtime("OP_COPY_TABLE", &args),
+            OP_PRINT_TABLE => runtime_with_defaults("OP_PRINT_TABLE", &args, &["0", "0", "1", "0"]),
+            OP_CHECK_ARG_COUNT => format!("{} >= {}", if safe { "argc" } else { "ArgCount()" }, op_a),
+
+            // EXT
+            // OP_SAVE | OP_RESTORE
+            OP_LOG_SHIFT => runtime("OP_LOG_SHIFT", &args),
+            OP_ART_SHIFT => runtime("OP_ART_SHIFT", &args),
+            OP_SET_FONT => runtime_with_defaults("OP_SET_FONT", &args, &["0", "0"]),
+            // OP_SAVE_UNDO | OP_RESTORE_UNDO
+            OP_PRINT_UNICODE => format!("zprint_unicode({})", op_a),
+            OP_CHECK_UNICODE => runtime("OP_CHECK_UNICODE", &args),
+            OP_SET_TRUE_COLOUR => runtime_with_defaults("OP_SET_TRUE_COLOUR", &args, &["0", "0", "0"]),
+
+            // The version 6 graphical opcodes are not supported
+            OP_DRAW_PICTURE ..= OP_SET_MARGINS | OP_MOVE_WINDOW ..= OP_BUFFER_SCREEN => format!("(fatal_error_i(\"Unsupported opcode:\", {}), 0)", opcode),
+            // Any other opcode fails when it is run, rather than stopping the whole decompilation
+            _ => format!("(fatal_error_i(\"Unsupported opcode:\", {}), 0)", opcode),
+        }
+    }
+
+    // Map an operand into a string
+    pub fn output_operand(&self, operand: Operand, safe: bool) -> String {
+        match operand {
+            Constant(val) => val.to_string(),
+            Stack => String::from("PopStack()"),
+            Local(num) => if safe { format!("l{}", num) } else { format!("ReadLocal({})", num) },
+            Global(num) => format!("Mem2({})", self.globals_addr + num as u32 * 2),
+        }
+    }
+
+    // Store a value into a variable
+    pub fn output_store(&self, storer: Operand, inner: String, safe: bool) -> String {
+        match storer {
+            Constant(_) => inner, // Must still output the inner code in case there are side-effects
+            Stack => format!("PushStack({})", inner),
+            Local(num) => if safe { format!("l{} = {}", num, inner) } else { format!("WriteLocal({}, {})", num, inner) },
+            Global(num) => format!("MemW2({}, {})", self.globals_addr + num as u32 * 2, inner),
+        }
+    }
+
+    // Apply an instruction's storer
+    pub fn output_storer(&self, instruction: &Instruction, inner: String, safe: bool) -> String {
+        match instruction.storer {
+            None => inner,
+            Some(storer) => {
+                // Instructions which both store and branch branch on the stored value
+                if instruction.branch.is_some() {
+                    format!("({}, temp5 != 0)", self.output_store(storer, format!("temp5 = {}", inner), safe))
+                }
+                else {
+                    self.output_store(storer, inner, safe)
+                }
+            },
+        }
+    }
+
+    // The indirect variable opcodes take a variable number as their first operand
+    // Returns a prelude, an expression to read the variable, and a function to write to it
+    fn indirect_variable(&self, instruction: &Instruction, args: &[String], safe: bool) -> (String, String, Box<dyn Fn(String) -> String + '_>) {
+        match instruction.operands[0] {
+            // Indirect references to the stack read and write the top value in place
+            Constant(0) => (String::new(), String::from("PeekStack()"), Box::new(|value| format!("PokeStack({})", value))),
+            Constant(var) => {
+                let operand = variable_operand(var);
+                (String::new(), self.output_operand(operand, safe), Box::new(move |value| self.output_store(operand, value, safe)))
+            },
+            _ => (format!("temp1 = {}, ", args[0]), String::from("ReadVariableIndirect(temp1)"), Box::new(|value| format!("WriteVariableIndirect(temp1, {})", value))),
+        }
+    }
+}
+
+fn runtime(name: &str, operands: &[String]) -> String {
+    let (prelude, new_operands) = safe_stack_pops(operands, false);
+    if prelude.is_empty() {
+        return format!("{}({})", name, operands.join(", "));
+    }
+    format!("({}, {}({}))", prelude, name, new_operands.join(", "))
+}
+
+// Some opcodes have optional operands, so fill in the missing ones
+fn runtime_with_defaults(name: &str, operands: &[String], defaults: &[&str]) -> String {
+    let mut operands = operands.to_vec();
+    for default in defaults.iter().skip(operands.len()) {
+        operands.push(String::from(*default));
+    }
+    runtime(name, &operands)
+}
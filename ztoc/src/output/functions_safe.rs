@@ -0,0 +1,637 @@
+/*
+
+Output safe functions
+=====================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::io::prelude::*;
+use std::time::Instant;
+
+use if_decompiler::*;
+use zmachine::*;
+use Operand::*;
+use relooper::*;
+use BranchMode::*;
+use ShapedBlock::*;
+
+use super::*;
+
+type ZSimpleBlock = SimpleBlock<u32>;
+
+impl ZOutput {
+    pub fn output_safe_functions(&self) -> std::io::Result<()> {
+        print!("Outputting safe functions...");
+        io::stdout().flush().unwrap();
+        let start = Instant::now();
+
+        let mut code_file = self.make_file("functions_safe.c")?;
+        let mut header_file = self.make_file("functions_safe.h")?;
+
+        // Output the headers
+        write!(code_file, "#include \"functions_safe.h\"
+#include \"glk.h\"
+#include \"ztoc.h\"
+
+")?;
+        write!(header_file, "#include \"glk.h\"
+#include \"ztoc.h\"
+
+")?;
+
+        // Output the function bodies
+        for addr in &self.safe_functions {
+            let function = &self.state.functions[addr];
+
+            let function_spec = format!("zword VM_FUNC_{}({})", addr, function_arguments(function.locals));
+            writeln!(code_file, "{} {{
+    int base_sp = sp;
+    glui32 label = 0;
+    zword res, temp0, temp1, temp2, temp3, temp4, temp5, temp6;", function_spec)?;
+            code_file.write_all(self.output_function_body(function)?.as_bytes())?;
+            writeln!(code_file, "    ZRETURN(0);
+}}
+")?;
+
+            // And the header declaration
+            writeln!(header_file, "extern {};", function_spec)?;
+        }
+
+        // Output the VM_FUNC_IS_SAFE function
+        writeln!(code_file, "int VM_FUNC_IS_SAFE(glui32 addr) {{
+    switch (addr) {{")?;
+        for row in self.safe_functions.chunks(5) {
+            write!(code_file, "        ")?;
+            let mut row_str = String::new();
+            for addr in row {
+                row_str.push_str(&format!("case {}: ", addr));
+            }
+            row_str.truncate(row_str.len() - 1);
+            writeln!(code_file, "{}", row_str)?;
+        }
+        writeln!(code_file, "            return 1;
+        default:
+            return 0;
+    }}
+}}
+")?;
+
+        // Output the VM_CALL_SAFE_FUNCTION function
+        writeln!(code_file, "#define ARG(num, initial) (argc > num ? args[num] : initial)
+zword VM_CALL_SAFE_FUNCTION(glui32 addr, int argc, zword *args) {{
+    switch (addr) {{")?;
+        for addr in &self.safe_functions {
+            let function = &self.state.functions[addr];
+            let mut args = vec![String::from("argc")];
+            for (i, value) in function.local_values.iter().enumerate() {
+                args.push(format!("ARG({}, {})", i, value));
+            }
+            writeln!(code_file, "        case {}: return VM_FUNC_{}({});", addr, addr, args.join(", "))?;
+        }
+        write!(code_file, "        default: fatal_error_i(\"VM_CALL_SAFE_FUNCTION called with non-safe function address:\", addr);
+    }}
+    return 0;
+}}")?;
+
+        let duration = start.elapsed();
+        println!(" completed in {:?}", duration);
+        Ok(())
+    }
+
+    // Output a function
+    fn output_function_body(&self, function: &Function) -> io::Result<String> {
+        // Functions which can't be relooped will have already been marked as unsafe
+        // Loops whose condition can't be output as a C expression will be kept as they are
+        let mut block = function.shaped_block.clone().unwrap().recognise_loop_shapes_with(&|condition, exit| loop_condition_exits(function, condition, exit).is_some());
+        self.output_shaped_block(function, &mut block, 1)
+    }
+
+    // Output a shaped block
+    fn output_shaped_block(&self, function: &Function, shaped_block: &mut ShapedBlock<u32>, uncapped_indents: usize) -> io::Result<String> {
+        let indents = if uncapped_indents > 30 { 30 } else { uncapped_indents };
+        let indent = "    ".repeat(indents);
+        let mut output = String::new();
+        match shaped_block {
+            Simple(block) => {
+                let mut last_next_instruction = 0;
+                let basicblock = function.blocks.get(&block.label).unwrap();
+                for instruction in &basicblock.code {
+                    output.push_str(&format!("{}/* {:>3X}/{} */ {}\n", indent, instruction.opcode, instruction.addr, self.output_instruction_safe(function, block, instruction, indents)?));
+                    last_next_instruction = instruction.next;
+                }
+                // We might have one last branch left over, going to the next instruction
+                if block.branches.len() == 1 {
+                    if let Some(branch_mode) = block.branches.get(&last_next_instruction) {
+                        if branch_mode != &MergedBranch {
+                            output.push_str(&format!("{}/* Branching to next */ {};\n", indent, output_branchmode(branch_mode, last_next_instruction)));
+                        }
+                        block.branches.clear();
+                    }
+                }
+                if !block.branches.is_empty() {
+                    return Err(unsupported(format!("Unhandled leftover branch in function {}", function.addr)));
+                }
+                if let Some(immediate) = block.immediate.as_deref_mut() {
+                    output.push_str(&self.output_shaped_block(function, immediate, indents)?);
+                }
+                if let Some(next) = block.next.as_deref_mut() {
+                    output.push_str(&self.output_shaped_block(function, next, indents)?);
+                }
+            },
+            Loop(block) => {
+                output.push_str(&format!("{}while (1) {{\n{}    loop_{}_continue:\n", indent, indent, block.loop_id));
+                output.push_str(&self.output_shaped_block(function, &mut block.inner, indents + 1)?);
+                output.push_str(&format!("{}}}\n{}loop_{}_break:;\n", indent, indent, block.loop_id));
+                if let Some(next) = block.next.as_deref_mut() {
+                    output.push_str(&self.output_shaped_block(function, next, indents)?);
+                }
+            },
+            Multiple(block) => {
+                output.push_str(&format!("{}switch (label) {{\n", indent));
+                for handled in block.handled.iter_mut() {
+                    for label in &handled.labels {
+                        output.push_str(&format!("{}    case {}:\n", indent, label));
+                    }
+                    output.push_str(&self.output_shaped_block(function, &mut handled.inner, indents + 2)?);
+                    if handled.break_after {
+                        output.push_str(&format!("{}        break;\n", indent));
+                    }
+                }
+                output.push_str(&format!("{}}}\n", indent));
+            },
+            While(block) => {
+                let (leading, condition, comment) = self.output_loop_condition(function, block.condition, block.exit, indents + 1)?;
+                // If the condition block only has the branch instruction then it can go in the while statement itself
+                if leading.is_empty() {
+                    output.push_str(&format!("{}{} while ({}) {{\n", indent, comment, condition));
+                    output.push_str(&self.output_shaped_block(function, &mut block.body, indents + 1)?);
+                    output.push_str(&format!("{}    loop_{}_continue:;\n", indent, block.loop_id));
+                }
+                else {
+                    output.push_str(&format!("{}while (1) {{\n{}    loop_{}_continue:\n{}", indent, indent, block.loop_id, leading));
+                    output.push_str(&format!("{}    {} if (!({})) {{\n{}        break;\n{}    }}\n", indent, comment, condition, indent, indent));
+                    output.push_str(&self.output_shaped_block(function, &mut block.body, indents + 1)?);
+                }
+                output.push_str(&format!("{}}}\n{}loop_{}_break:;\n", indent, indent, block.loop_id));
+                if let Some(next) = block.next.as_deref_mut() {
+                    output.push_str(&self.output_shaped_block(function, next, indents)?);
+                }
+            },
+            DoWhile(block) => {
+                let (leading, condition, comment) = self.output_loop_condition(function, block.condition, block.exit, indents + 1)?;
+                output.push_str(&format!("{}do {{\n", indent));
+                if let Some(body) = block.body.as_deref_mut() {
+                    output.push_str(&self.output_shaped_block(function, body, indents + 1)?);
+                }
+                output.push_str(&format!("{}{}}} while ({}); {}\n{}loop_{}_break:;\n", leading, indent, condition, comment, indent, block.loop_id));
+                if let Some(next) = block.next.as_deref_mut() {
+                    output.push_str(&self.output_shaped_block(function, next, indents)?);
+                }
+            },
+            Switch(block) => {
+                let (leading, branch) = self.output_leading_instructions(function, block.label, indents)?;
+                let chain = &function.switches[&block.label];
+                output.push_str(&leading);
+                output.push_str(&format!("{}/* {:>3X}/{} */ switch ({}) {{\n", indent, branch.opcode, branch.addr, self.output_operand(chain.value, true)));
+                // Output the cases which leave the switch first, so that no other case can fall through into them
+                let mut branches: Vec<(u32, BranchMode)> = block.branches.iter().map(|(&label, &branch_mode)| (label, branch_mode)).collect();
+                branches.sort_by_key(|&(label, _)| label);
+                for (label, branch_mode) in branches {
+                    output.push_str(&output_switch_cases(chain, label, indents + 1));
+                    output.push_str(&format!("{}        {};\n", indent, output_branchmode(&branch_mode, label)));
+                    if let MergedBranch | MergedBranchIntoMulti = branch_mode {
+                        output.push_str(&format!("{}        break;\n", indent));
+                    }
+                }
+                for handled in block.cases.iter_mut() {
+                    for &label in &handled.labels {
+                        output.push_str(&output_switch_cases(chain, label, indents + 1));
+                    }
+                    output.push_str(&self.output_shaped_block(function, &mut handled.inner, indents + 2)?);
+                    if handled.break_after {
+                        output.push_str(&format!("{}        break;\n", indent));
+                    }
+                }
+                output.push_str(&format!("{}}}\n", indent));
+                if let Some(next) = block.next.as_deref_mut() {
+                    output.push_str(&self.output_shaped_block(function, next, indents)?);
+                }
+            },
+        };
+        Ok(output)
+    }
+
+    // Output an instruction
+    fn output_instruction_safe(&self, function: &Function, block: &mut ZSimpleBlock, instruction: &Instruction, indents: usize) -> io::Result<String> {
+        let body_with_storer = self.output_instruction_body_safe(instruction)?;
+        let condition = if instruction.branch_condition { body_with_storer } else { format!("!({})", body_with_storer) };
+        self.output_branch_safe(function, block, instruction, condition, indents)
+    }
+
+    // Output an instruction without its branch
+    fn output_instruction_body_safe(&self, instruction: &Instruction) -> io::Result<String> {
+        let opcode = instruction.opcode;
+        let operands = self.map_operands_safe(instruction);
+        let null = String::from("0");
+        let op_a = operands.first().unwrap_or(&null);
+        use opcodes::*;
+        let body = match opcode {
+            _ if opcodes::instruction_calls(opcode, self.state.version) => self.output_call_safe(instruction, operands)?,
+            OP_RET => format!("ZRETURN({})", op_a),
+            OP_RTRUE => String::from("ZRETURN(1)"),
+            OP_RFALSE => String::from("ZRETURN(0)"),
+            OP_PRINT_RET => format!("print_zstring({}); zprint_char(13); ZRETURN(1)", instruction.text.unwrap()),
+            OP_RET_POPPED => String::from("ZRETURN(PopStack())"),
+            _ => self.output_common_instruction(instruction, operands, true),
+        };
+        Ok(self.output_storer(instruction, body, true))
+    }
+
+    // Output the instructions of a block before its final branch, which is returned for the caller to output
+    fn output_leading_instructions<'a>(&self, function: &'a Function, label: u32, indents: usize) -> io::Result<(String, &'a Instruction)> {
+        let indent = "    ".repeat(indents);
+        let basicblock = function.blocks.get(&label).unwrap();
+        let (branch, leading_instructions) = basicblock.code.split_last().unwrap();
+        // None of the leading instructions can branch to another block, so they won't use the SimpleBlock
+        let mut block = ZSimpleBlock {
+            label,
+            immediate: None,
+            branches: Default::default(),
+            next: None,
+        };
+        let mut leading = String::new();
+        for instruction in leading_instructions {
+            leading.push_str(&format!("{}/* {:>3X}/{} */ {}\n", indent, instruction.opcode, instruction.addr, self.output_instruction_safe(function, &mut block, instruction, indents)?));
+        }
+        Ok((leading, branch))
+    }
+
+    // Output a While or DoWhile loop's condition block
+    // Returns the instructions before the branch, the condition for staying in the loop, and a comment for the branch instruction
+    fn output_loop_condition(&self, function: &Function, label: u32, exit: u32, indents: usize) -> io::Result<(String, String, String)> {
+        let (leading, branch) = self.output_leading_instructions(function, label, indents)?;
+        let body = self.output_instruction_body_safe(branch)?;
+        // Other conditions were left as plain loops by output_function_body()
+        let branches_to_exit = loop_condition_exits(function, label, exit).unwrap_or(false);
+        // Stay in the loop when the branch isn't taken to the exit
+        let condition = if branch.branch_condition != branches_to_exit { body } else { format!("!({})", body) };
+        Ok((leading, condition, format!("/* {:>3X}/{} */", branch.opcode, branch.addr)))
+    }
+
+    // Map operands into strings
+    fn map_operands_safe(&self, instruction: &Instruction) -> Vec<String> {
+        instruction.operands.iter().map(|&operand| self.output_operand(operand, true)).collect()
+    }
+
+    // Construct a call
+    fn output_call_safe(&self, instruction: &Instruction, operands: Vec<String>) -> io::Result<String> {
+        let packed_addr = match instruction.operands[0] {
+            Constant(addr) => addr,
+            _ => return Err(unsupported(format!("Dynamic call not supported at {}", instruction.addr))),
+        };
+        // Extract all the stack pops first, so that they're done in the right order even if they are surplus args
+        let (prelude, new_operands) = safe_stack_pops(&operands[1..], true);
+        let prelude = if prelude.is_empty() { prelude } else { format!("{}, ", prelude) };
+        let provided_args = new_operands.len();
+
+        // Calling address 0 does nothing and returns false
+        if packed_addr == 0 {
+            return Ok(format!("({}0)", prelude));
+        }
+
+        let callee_addr = self.state.unpack_routine_addr(packed_addr);
+        let callee = match self.state.functions.get(&callee_addr) {
+            Some(callee) => callee,
+            None => return Ok(format!("({}fatal_error_i(\"Called unknown function:\", {}), 0)", prelude, callee_addr)),
+        };
+
+        // Account for extra args, and not enough args
+        let mut args = vec![provided_args.to_string()];
+        for (i, value) in callee.local_values.iter().enumerate() {
+            args.push(match new_operands.get(i) {
+                Some(arg) => arg.clone(),
+                None => value.to_string(),
+            });
+        }
+        Ok(format!("({}VM_FUNC_{}({}))", prelude, callee_addr, args.join(", ")))
+    }
+
+    fn output_branch_safe(&self, function: &Function, simple_block: &mut ZSimpleBlock, instruction: &Instruction, condition: String, indents: usize) -> io::Result<String> {
+        use BranchTarget::*;
+        use opcodes::*;
+        let indent = "    ".repeat(indents);
+        match instruction.branch {
+            None => Ok(format!("{};", condition)),
+            Some(target) => {
+                match target {
+                    Dynamic => Err(unsupported(format!("Dynamic branch in safe function at {}", instruction.addr))),
+                    Absolute(addr) => {
+                        // Handle OP_JUMP: it should have its action in the branches map, or jump into a SimpleBlock immediate
+                        if instruction.opcode == OP_JUMP {
+                            if let Some(branch_mode) = simple_block.branches.get(&addr) {
+                                if simple_block.branches.len() != 1 {
+                                    return Err(unsupported(format!("Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                }
+                                let output = format!("{};", output_branchmode(branch_mode, addr));
+                                simple_block.branches.clear();
+                                return Ok(output);
+                            }
+                            if let Some(immediate_block) = simple_block.immediate.as_deref_mut() {
+                                if let Simple(_) | Switch(_) = immediate_block {
+                                    if !simple_block.branches.is_empty() {
+                                        return Err(unsupported(format!("Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                    }
+                                    let output = format!("/* Jumping into immediate */\n{}", self.output_shaped_block(function, immediate_block, indents)?);
+                                    simple_block.immediate = None;
+                                    return Ok(output);
+                                }
+                                // We can also jump into a loop which starts with the target
+                                if loop_starts_with(immediate_block, addr) {
+                                    if !simple_block.branches.is_empty() {
+                                        return Err(unsupported(format!("Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                    }
+                                    let output = format!("/* Jumping into immediate */\n{}", self.output_shaped_block(function, immediate_block, indents)?);
+                                    simple_block.immediate = None;
+                                    return Ok(output);
+                                }
+                            }
+                        }
+
+                        if let Some(Multiple(ref mut multiple_block)) = simple_block.immediate.as_deref_mut() {
+                            // Check if the next instruction is in the immediate block
+                            if let Some(next_block_index) = find_multiple(&multiple_block.handled, instruction.next) {
+                                // if-else with both blocks in handled
+                                if let Some(if_block_index) = find_multiple(&multiple_block.handled, addr) {
+                                    if multiple_block.handled.len() != 2 {
+                                        return Err(unsupported(format!("Unhandled multiple block at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                    }
+                                    if !simple_block.branches.is_empty() {
+                                        return Err(unsupported(format!("Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                    }
+                                    let output = format!("if ({}) {{\n{}{}}}\n{}else {{\n{}{}}}", condition, self.output_multiple(function, &mut multiple_block.handled, if_block_index, indents + 1)?, indent, indent, self.output_multiple(function, &mut multiple_block.handled, next_block_index, indents + 1)?, indent);
+                                    simple_block.immediate = None;
+                                    return Ok(output);
+                                }
+
+                                // A simple if branch, where the branch target is a MergedBranch
+                                if let Some(MergedBranch) = simple_block.branches.get(&addr) {
+                                    if multiple_block.handled.len() != 1 {
+                                        return Err(unsupported(format!("Unhandled multiple block at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                    }
+                                    if simple_block.branches.len() != 1 {
+                                        return Err(unsupported(format!("Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                    }
+                                    let output = format!("if (!({})) {{\n{}{}}}", condition, self.output_multiple(function, &mut multiple_block.handled, next_block_index, indents + 1)?, indent);
+                                    simple_block.immediate = None;
+                                    simple_block.branches.clear();
+                                    return Ok(output);
+                                }
+
+                                // Some other kind of branch action
+                                if let Some(branch_mode) = simple_block.branches.get(&addr) {
+                                    if multiple_block.handled.len() != 1 {
+                                        return Err(unsupported(format!("Unhandled multiple block at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                    }
+                                    if simple_block.branches.len() != 1 {
+                                        return Err(unsupported(format!("Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                    }
+                                    let output = format!("if ({}) {{\n{}    {};\n{}}}\n{}else {{\n{}{}}}", condition, indent, output_branchmode(branch_mode, addr), indent, indent, self.output_multiple(function, &mut multiple_block.handled, next_block_index, indents + 1)?, indent);
+                                    simple_block.immediate = None;
+                                    simple_block.branches.clear();
+                                    return Ok(output);
+                                }
+                            }
+
+                            // Otherwise the branch target could be in immediate, and the next in the branches map
+                            if let Some(target_block_index) = find_multiple(&multiple_block.handled, addr) {
+                                if let Some(branch_mode) = simple_block.branches.get(&instruction.next) {
+                                    if multiple_block.handled.len() != 1 {
+                                        return Err(unsupported(format!("Unhandled multiple block at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                    }
+                                    if simple_block.branches.len() != 1 {
+                                        return Err(unsupported(format!("Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                    }
+                                    let output = format!("if ({}) {{\n{}{}}}\n{}else {{\n{}    {};\n{}}}", condition, self.output_multiple(function, &mut multiple_block.handled, target_block_index, indents + 1)?, indent, indent, indent, output_branchmode(branch_mode, instruction.next), indent);
+                                    simple_block.immediate = None;
+                                    simple_block.branches.clear();
+                                    return Ok(output);
+                                }
+                            }
+                        }
+
+                        // Both target and next are in the branches map
+                        if let Some(target_branch_mode) = simple_block.branches.get(&addr) {
+                            if let Some(next_branch_mode) = simple_block.branches.get(&instruction.next) {
+                                // The branches must have two entries, unless addr == next
+                                if simple_block.branches.len() != 2 && addr != instruction.next {
+                                    return Err(unsupported(format!("Unhandled branch at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                }
+                                let output = format!("if ({}) {{\n{}    {};\n{}}}\n{}else {{\n{}    {};\n{}}}", condition, indent, output_branchmode(target_branch_mode, addr), indent, indent, indent, output_branchmode(next_branch_mode, instruction.next), indent);
+                                simple_block.branches.clear();
+                                return Ok(output);
+                            }
+                        }
+
+                        // If the branch is empty then the target == next, and immediate will be a Simple rather than a Multiple
+                        if let Some(immediate_block) = simple_block.immediate.as_deref_mut() {
+                            if let Simple(ref mut block) = immediate_block {
+                                if block.label == addr && block.label == instruction.next {
+                                    if simple_block.next.is_some() {
+                                        return Err(unsupported(format!("Unhandled next at address {}\nBlock: {:?}", instruction.addr, simple_block)));
+                                    }
+                                    // Output the condition by itself as it may have side effects
+                                    let output = format!("{};\n{}{}", condition, indent, self.output_shaped_block(function, immediate_block, indents)?);
+                                    simple_block.immediate = None;
+                                    return Ok(output);
+                                }
+                            }
+                        }
+
+                        Err(unsupported(format!("Unsupported branch at address {}, branching to {:?}, next {}\nBlock: {:?}", instruction.addr, target, instruction.next, simple_block)))
+                    },
+                    Return(val) => Ok(format!("if ({}) {{ ZRETURN({}); }}", condition, val)),
+                }
+            },
+        }
+    }
+
+    fn output_multiple(&self, function: &Function, handled: &mut [HandledBlock<u32>], index: usize, indents: usize) -> io::Result<String> {
+        let block = &mut handled.get_mut(index).unwrap().inner;
+        self.output_shaped_block(function, block, indents)
+    }
+}
+
+// An error for code which can't be output in a safe function
+fn unsupported(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Whether a loop's condition block branches to the exit (or else to the rest of the loop)
+// Returns None if it doesn't end with a branch which can tell them apart
+fn loop_condition_exits(function: &Function, label: u32, exit: u32) -> Option<bool> {
+    let branch = function.blocks.get(&label)?.code.last()?;
+    if matches!(branch.opcode, opcodes::OP_JUMP) {
+        return None;
+    }
+    match branch.branch {
+        Some(BranchTarget::Absolute(addr)) if addr == exit && branch.next != exit => Some(true),
+        Some(BranchTarget::Absolute(addr)) if addr != exit && branch.next == exit => Some(false),
+        _ => None,
+    }
+}
+
+fn find_multiple(handled: &[HandledBlock<u32>], label: u32) -> Option<usize> {
+    for (index, block) in handled.iter().enumerate() {
+        if block.labels.contains(&label) {
+            return Some(index)
+        }
+    }
+    None
+}
+
+// Whether a loop starts with a SimpleBlock (or a loop condition) with this label
+fn loop_starts_with(block: &ShapedBlock<u32>, label: u32) -> bool {
+    match block {
+        Loop(block) => matches!(&*block.inner, Simple(SimpleBlock { label: inner, .. }) | Switch(SwitchBlock { label: inner, .. }) if *inner == label),
+        While(block) => block.condition == label,
+        DoWhile(block) => match block.body.as_deref() {
+            Some(Simple(inner)) => inner.label == label,
+            Some(_) => false,
+            None => block.condition == label,
+        },
+        _ => false,
+    }
+}
+
+// Output the case labels of a switch which branch to a label
+fn output_switch_cases(chain: &SwitchChain<Operand>, label: u32, indents: usize) -> String {
+    let indent = "    ".repeat(indents);
+    let mut output = String::new();
+    for constant in chain.constants_for(label) {
+        output.push_str(&format!("{}case {}:\n", indent, constant));
+    }
+    if chain.default == label {
+        output.push_str(&format!("{}default:\n", indent));
+    }
+    output
+}
+
+// Safe functions are also passed the number of arguments given, for OP_CHECK_ARG_COUNT
+fn function_arguments(count: u32) -> String {
+    let mut output = String::from("int argc");
+    for arg in 0..count {
+        output.push_str(&format!(", zword l{}", arg));
+    }
+    output
+}
+
+fn output_branchmode(branch_mode: &BranchMode, addr: u32) -> String {
+    match branch_mode {
+        LoopBreak(loop_id) => format!("goto loop_{}_break", loop_id),
+        LoopBreakIntoMulti(loop_id) => format!("label = {}; goto loop_{}_break", addr, loop_id),
+        LoopContinue(loop_id) => format!("goto loop_{}_continue", loop_id),
+        LoopContinueIntoMulti(loop_id) => format!("label = {}; goto loop_{}_continue", addr, loop_id),
+        MergedBranch => format!("/* Branch to {} continues below */", addr),
+        MergedBranchIntoMulti => format!("label = {} /* Branch continues below */", addr),
+        SetLabelAndBreak => format!("label = {}; break /* Branch continues below */", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn instruction(addr: u32, opcode: u32, operands: Vec<Operand>, branch: Option<BranchTarget>, storer: Option<Operand>, next: u32) -> Instruction {
+        Instruction {
+            addr,
+            opcode,
+            operands,
+            branch,
+            branch_condition: true,
+            storer,
+            text: None,
+            next,
+        }
+    }
+
+    // Make a function with one local, and reloop it as decompile_rom() would
+    fn make_function(code: Vec<Instruction>) -> Function {
+        let branches = |instruction: &Instruction| match instruction.branch {
+            Some(BranchTarget::Absolute(addr)) if instruction.opcode == opcodes::OP_JUMP => vec![addr],
+            Some(BranchTarget::Absolute(addr)) => vec![addr, instruction.next],
+            _ => vec![],
+        };
+        let entry_points = code.iter().flat_map(branches).collect();
+        let exit_branches = code.iter().filter(|instruction| instruction.branch.is_some()).map(|instruction| (instruction.addr, branches(instruction))).collect();
+        let mut function = Function {
+            addr: 0x100,
+            blocks: calculate_basic_blocks(code, entry_points, exit_branches),
+            locals: 1,
+            local_values: vec![0],
+            safety: FunctionSafety::SafetyTBD,
+            shaped_block: None,
+            switches: BTreeMap::new(),
+        };
+        function.shaped_block = Some(function.reloop().unwrap());
+        function
+    }
+
+    fn make_output() -> ZOutput {
+        let mut state = ZMachineState::new(None, None, None);
+        state.version = 5;
+        ZOutput::new(false, &[0; 64], String::from("test"), PathBuf::new(), state).unwrap()
+    }
+
+    // while (l0 < 10) { l0 += 1; }
+    fn while_function() -> Function {
+        let mut condition = instruction(0x101, opcodes::OP_JL, vec![Local(0), Constant(10)], Some(BranchTarget::Absolute(0x10D)), None, 0x106);
+        condition.branch_condition = false;
+        make_function(vec![
+            condition,
+            instruction(0x106, opcodes::OP_ADD, vec![Local(0), Constant(1)], None, Some(Local(0)), 0x10A),
+            instruction(0x10A, opcodes::OP_JUMP, vec![Constant(0xFFF7)], Some(BranchTarget::Absolute(0x101)), None, 0x10D),
+            instruction(0x10D, opcodes::OP_RTRUE, vec![], None, None, 0x10E),
+        ])
+    }
+
+    #[test]
+    fn test_while_loop() {
+        let function = while_function();
+        let output = make_output().output_function_body(&function).unwrap();
+        assert!(output.contains("while ((zsword) l0 < (zsword) 10) {"), "{}", output);
+        assert!(!output.contains("while (1)"), "{}", output);
+    }
+
+    #[test]
+    fn test_do_while_loop() {
+        // do { l0 += 1; } while (l0 < 10);
+        let function = make_function(vec![
+            instruction(0x101, opcodes::OP_ADD, vec![Local(0), Constant(1)], None, Some(Local(0)), 0x105),
+            instruction(0x105, opcodes::OP_JL, vec![Local(0), Constant(10)], Some(BranchTarget::Absolute(0x101)), None, 0x10A),
+            instruction(0x10A, opcodes::OP_RTRUE, vec![], None, None, 0x10B),
+        ]);
+        let output = make_output().output_function_body(&function).unwrap();
+        assert!(output.contains("do {"), "{}", output);
+        assert!(output.contains("} while ((zsword) l0 < (zsword) 10);"), "{}", output);
+        assert!(!output.contains("while (1)"), "{}", output);
+    }
+
+    #[test]
+    fn test_untestable_loop_condition() {
+        // Only the conditional branch can be the loop's condition, not the jump back to it
+        let function = while_function();
+        assert_eq!(loop_condition_exits(&function, 0x101, 0x10D), Some(true));
+        assert_eq!(loop_condition_exits(&function, 0x106, 0x10D), None);
+
+        // A loop which isn't given a while shape is output as an infinite loop with a break
+        let output = make_output().output_shaped_block(&function, &mut function.shaped_block.clone().unwrap(), 1).unwrap();
+        assert!(output.contains("while (1) {"), "{}", output);
+        assert!(output.contains("(zsword) l0 < (zsword) 10"), "{}", output);
+    }
+}
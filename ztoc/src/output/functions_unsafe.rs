@@ -0,0 +1,185 @@
+/*
+
+Output unsafe functions
+=======================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::time::Instant;
+
+use if_decompiler::*;
+use FunctionSafety::*;
+use zmachine::*;
+
+use super::*;
+
+impl ZOutput {
+    pub fn output_unsafe_functions(&mut self) -> std::io::Result<()> {
+        print!("Outputting unsafe functions...");
+        io::stdout().flush().unwrap();
+        let start = Instant::now();
+
+        let mut code_file = self.make_file("functions_unsafe.c")?;
+
+        // Output the header
+        writeln!(code_file, "#include \"glk.h\"
+#include \"ztoc.h\"
+")?;
+
+        let mut function_chunks = Vec::new();
+
+        for (chunk_num, chunk) in self.unsafe_functions.chunks(1000).enumerate() {
+            writeln!(code_file, "static int execute_chunk_{}(void) {{
+    zword temp0, temp1, temp2, temp3, temp4, temp5, temp6;
+    switch (pc) {{", chunk_num)?;
+            let first_func = self.output_functions_chunk(&mut code_file, chunk)?;
+            function_chunks.push(first_func);
+            writeln!(code_file, "        default:
+            fatal_error_i(\"Branched to invalid address:\", pc);
+    }}
+    return 0;
+}}
+")?;
+        }
+
+        // A storyfile with no unsafe functions will never call execute_loop
+        if function_chunks.is_empty() {
+            writeln!(code_file, "void execute_loop(void) {{}}")?;
+        }
+        else {
+            function_chunks.remove(0);
+
+            writeln!(code_file, "void execute_loop(void) {{
+    int ret = 0;
+    while (1) {{
+        if (0) {{}}")?;
+
+            for (index, chunk) in function_chunks.iter().enumerate() {
+                writeln!(code_file, "        else if (pc < {}) {{
+            ret = execute_chunk_{}();
+        }}", chunk, index)?;
+            }
+
+            writeln!(code_file, "        else {{
+            ret = execute_chunk_{}();
+        }}
+        if (ret) {{
+            return;
+        }}
+    }}
+}}", function_chunks.len())?;
+        }
+
+        let duration = start.elapsed();
+        println!(" completed in {:?}", duration);
+        Ok(())
+    }
+
+    // Output a chunk of functions
+    fn output_functions_chunk(&self, code_file: &mut BufWriter<File>, functions: &[u32]) -> std::io::Result<u32> {
+        use opcodes::*;
+
+        // Output the function bodies
+        for addr in functions {
+            let function = &self.state.functions[addr];
+            writeln!(code_file, "        // VM Function {}", addr)?;
+
+            for (label, block) in &function.blocks {
+                if function.safety != UnsafeDynamicBranches {
+                    writeln!(code_file, "        case {}:", label)?;
+                }
+                for instruction in &block.code {
+                    // Restoring a game resumes at the save instruction, so it needs a label too
+                    let needs_label = function.safety == UnsafeDynamicBranches || (instruction.addr != *label && match instruction.opcode {
+                        OP_SAVE | OP_SAVE_UNDO => true,
+                        OP_SAVE_V1 => self.state.version < 5,
+                        _ => false,
+                    });
+                    let instruction_label = if needs_label { format!("case {}: ", instruction.addr) } else { String::new() };
+                    writeln!(code_file, "            {}/* {:>3X}/{} */ {};", instruction_label, instruction.opcode, instruction.addr, self.output_instruction_unsafe(instruction))?;
+                }
+            }
+        }
+
+        Ok(functions[0])
+    }
+
+    // Output an instruction
+    fn output_instruction_unsafe(&self, instruction: &Instruction) -> String {
+        let opcode = instruction.opcode;
+        let version = self.state.version;
+        let operands = self.map_operands_unsafe(instruction);
+        let null = String::from("0");
+        let op_a = operands.first().unwrap_or(&null);
+        use opcodes::*;
+        let body = match opcode {
+            _ if opcodes::instruction_calls(opcode, version) => self.output_call_unsafe(instruction, &operands),
+            OP_RET => format!("if (VM_RETURN({})) {{return 1;}} break", op_a),
+            OP_RTRUE => String::from("if (VM_RETURN(1)) {return 1;} break"),
+            OP_RFALSE => String::from("if (VM_RETURN(0)) {return 1;} break"),
+            OP_PRINT_RET => format!("print_zstring({}); zprint_char(13); if (VM_RETURN(1)) {{return 1;}} break", instruction.text.unwrap()),
+            OP_RET_POPPED => String::from("if (VM_RETURN(PopStack())) {return 1;} break"),
+            OP_THROW => format!("if ({}) {{return 1;}} break", format_safe_stack_pops_expression("OP_THROW({}, {})", &operands)),
+            OP_SAVE_V1 | OP_SAVE => format!("OP_SAVE({})", instruction.addr),
+            // Restoring changes the pc, otherwise the restore failed
+            OP_RESTORE_V1 if version < 4 => return format!("if (OP_RESTORE()) {{break;}} {}", self.output_branch_unsafe(instruction, String::from("0"))),
+            OP_RESTORE_V1 | OP_RESTORE => format!("if (OP_RESTORE()) {{break;}} {}", self.output_storer(instruction, String::from("0"), false)),
+            OP_SAVE_UNDO => format!("OP_SAVE_UNDO({})", instruction.addr),
+            OP_RESTORE_UNDO => format!("if (OP_RESTORE_UNDO()) {{break;}} {}", self.output_storer(instruction, String::from("0"), false)),
+            OP_RESTART => String::from("OP_RESTART(); break"),
+            OP_QUIT => String::from("return 1"),
+            OP_CATCH if version >= 5 => String::from("fp"),
+            _ => self.output_common_instruction(instruction, operands, false),
+        };
+        // Calls and restores handle their own storers
+        let body_with_storer = match opcode {
+            _ if opcodes::instruction_calls(opcode, version) => body,
+            OP_RESTORE_V1 | OP_RESTORE | OP_RESTORE_UNDO => body,
+            _ => self.output_storer(instruction, body, false),
+        };
+        self.output_branch_unsafe(instruction, body_with_storer)
+    }
+
+    // Map operands into strings
+    fn map_operands_unsafe(&self, instruction: &Instruction) -> Vec<String> {
+        instruction.operands.iter().map(|&operand| self.output_operand(operand, false)).collect()
+    }
+
+    fn output_branch_unsafe(&self, instruction: &Instruction, condition: String) -> String {
+        use opcodes::*;
+        match instruction.branch {
+            None => condition,
+            Some(target) => match instruction.opcode {
+                OP_JUMP => format!("{}; break", self.output_branch_action_unsafe(instruction, target)),
+                _ => {
+                    let condition = if instruction.branch_condition { condition } else { format!("!({})", condition) };
+                    format!("if ({}) {{{}; break;}}", condition, self.output_branch_action_unsafe(instruction, target))
+                },
+            },
+        }
+    }
+
+    fn output_branch_action_unsafe(&self, instruction: &Instruction, branch: BranchTarget) -> String {
+        use BranchTarget::*;
+        match branch {
+            Dynamic => format!("pc = {} + (zsword) {} - 2", instruction.next, self.output_operand(instruction.operands[0], false)),
+            Absolute(addr) => format!("pc = {}", addr),
+            Return(val) => format!("if (VM_RETURN({})) {{return 1;}}", val),
+        }
+    }
+
+    fn output_call_unsafe(&self, instruction: &Instruction, operands: &[String]) -> String {
+        // Extract all the stack pops first, so that they're done in the right order
+        let (prelude, new_operands) = safe_stack_pops(operands, true);
+        let prelude_out = if prelude.is_empty() { String::new() } else { format!("{}; ", prelude) };
+        let args = if new_operands.len() > 1 { format!("(zword[]) {{{}}}", new_operands[1..].join(", ")) } else { String::from("NULL") };
+        format!("{}if (VM_CALL_FUNCTION({}, {}, {}, {}, {})) {{break;}}", prelude_out, new_operands[0], new_operands.len() - 1, args, variable_number(instruction.storer), instruction.next)
+    }
+}
@@ -0,0 +1,1472 @@
+/*
+
+Runtime functions - a Z-Machine interpreter minus the instruction decoding
+===========================================================================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+#include "glk.h"
+#include "ztoc.h"
+
+zbyte *memory = NULL;
+zword stack[STACK_SIZE];
+int sp = 0;
+int fp = TOP_FRAME;
+glui32 pc = 0;
+int max_undo_level = 8;
+
+static int version;
+static glui32 static_base;
+static glui32 file_length;
+static glui32 routines_offset;
+static glui32 strings_offset;
+static glui32 abbreviations_table;
+static glui32 alphabet_table;
+static glui32 dictionary_table;
+static glui32 objects_table;
+static glui32 unicode_table;
+
+// Set when restoring, so that the save instruction knows it is being resumed rather than executed
+static int restoring = 0;
+
+static winid_t main_win = NULL;
+static winid_t status_win = NULL;
+static winid_t upper_win = NULL;
+static int upper_window_selected = 0;
+static glui32 upper_x = 1, upper_y = 1;
+static int current_font = 1;
+static int current_style = 0;
+
+static int stream1_enabled = 1;
+#define STREAM3_MAX_DEPTH 16
+static glui32 stream3_tables[STREAM3_MAX_DEPTH];
+static int stream3_depth = 0;
+
+static glui32 random_state = 1;
+static zword random_sequence_limit = 0;
+static zword random_sequence_counter = 0;
+
+// The default ZSCII to Unicode table for ZSCII 155-223
+static const zword default_unicode_table[] = {
+    0xe4, 0xf6, 0xfc, 0xc4, 0xd6, 0xdc, 0xdf, 0xbb, 0xab, 0xeb, 0xef, 0xff, 0xcb, 0xcf, 0xe1, 0xe9,
+    0xed, 0xf3, 0xfa, 0xfd, 0xc1, 0xc9, 0xcd, 0xd3, 0xda, 0xdd, 0xe0, 0xe8, 0xec, 0xf2, 0xf9, 0xc0,
+    0xc8, 0xcc, 0xd2, 0xd9, 0xe2, 0xea, 0xee, 0xf4, 0xfb, 0xc2, 0xca, 0xce, 0xd4, 0xdb, 0xe5, 0xc5,
+    0xf8, 0xd8, 0xe3, 0xf1, 0xf5, 0xc3, 0xd1, 0xd5, 0xe6, 0xc6, 0xe7, 0xc7, 0xfe, 0xf0, 0xde, 0xd0,
+    0xa3, 0x153, 0x152, 0xa1, 0xbf,
+};
+
+static const char *default_alphabets[3] = {
+    "abcdefghijklmnopqrstuvwxyz",
+    "ABCDEFGHIJKLMNOPQRSTUVWXYZ",
+    " \n0123456789.,!?_#'\"/\\-:()",
+};
+static const char *alphabet_v1 = " 0123456789.,!?_#'\"/\\<-:()";
+
+void fatal_error(char *msg) {
+    fatal_error_i(msg, 0);
+}
+
+void fatal_error_i(char *msg, glui32 val) {
+    char buf[20];
+    if (!main_win) {
+        main_win = glk_window_open(0, 0, 0, wintype_TextBuffer, 0);
+    }
+    if (main_win) {
+        glk_set_window(main_win);
+        glk_set_style(style_Normal);
+        glk_put_string("\n\nZtoc fatal error: ");
+        glk_put_string(msg);
+        if (val) {
+            sprintf(buf, " %ld", (long) val);
+            glk_put_string(buf);
+        }
+        glk_put_string("\n");
+    }
+    glk_exit();
+}
+
+/* Memory and variables */
+
+glui32 unpack_string(zword packed_addr) {
+    switch (version) {
+        case 1: case 2: case 3: return packed_addr * 2;
+        case 4: case 5: return packed_addr * 4;
+        case 6: case 7: return packed_addr * 4 + strings_offset;
+        default: return packed_addr * 8;
+    }
+}
+
+static glui32 unpack_routine(zword packed_addr) {
+    switch (version) {
+        case 1: case 2: case 3: return packed_addr * 2;
+        case 4: case 5: return packed_addr * 4;
+        case 6: case 7: return packed_addr * 4 + routines_offset;
+        default: return packed_addr * 8;
+    }
+}
+
+zword ReadVariableIndirect(zword var) {
+    if (var == 0) {
+        return PeekStack();
+    }
+    if (var < 16) {
+        return ReadLocal(var - 1);
+    }
+    return Mem2(Mem2(0x0C) + 2 * (var - 16));
+}
+
+void WriteVariableIndirect(zword var, zword val) {
+    if (var == 0) {
+        PokeStack(val);
+    }
+    else if (var < 16) {
+        WriteLocal(var - 1, val);
+    }
+    else {
+        MemW2(Mem2(0x0C) + 2 * (var - 16), val);
+    }
+}
+
+// Store a function's result; unlike the indirect variable opcodes this pushes to the stack
+static void store_variable(int var, zword val) {
+    if (var == 0) {
+        PushStack(val);
+    }
+    else if (var < 16) {
+        WriteLocal(var - 1, val);
+    }
+    else if (var < STORE_DISCARD) {
+        MemW2(Mem2(0x0C) + 2 * (var - 16), val);
+    }
+}
+
+/* Functions */
+
+static void push_frame(int locals, int argc, int storevar, glui32 next) {
+    int new_fp = sp;
+    PushStack(next >> 16);
+    PushStack(next & 0xFFFF);
+    PushStack(storevar);
+    PushStack(fp);
+    PushStack(argc);
+    PushStack(locals);
+    fp = new_fp;
+}
+
+static void enter_function(glui32 addr, int argc, zword *args, int storevar, glui32 next) {
+    int i, locals = Mem1(addr);
+    push_frame(locals, argc, storevar, next);
+    for (i = 0; i < locals; i++) {
+        PushStack(i < argc ? args[i] : (version < 5 ? Mem2(addr + 1 + 2 * i) : 0));
+    }
+    pc = addr + 1 + (version < 5 ? 2 * locals : 0);
+}
+
+// Call a function from an unsafe function. Returns 1 if the pc has been changed
+int VM_CALL_FUNCTION(zword packed_addr, int argc, zword *args, int storevar, glui32 next) {
+    glui32 addr;
+    if (packed_addr == 0) {
+        store_variable(storevar, 0);
+        return 0;
+    }
+    addr = unpack_routine(packed_addr);
+    if (VM_FUNC_IS_SAFE(addr)) {
+        store_variable(storevar, VM_CALL_SAFE_FUNCTION(addr, argc, args));
+        return 0;
+    }
+    enter_function(addr, argc, args, storevar, next);
+    return 1;
+}
+
+// Return from an unsafe function. Returns 1 if the main routine has returned
+int VM_RETURN(zword val) {
+    glui32 ret_pc = (stack[fp] << 16) | stack[fp + 1];
+    int storevar = stack[fp + 2];
+    int old_fp = stack[fp + 3];
+    sp = fp;
+    if (old_fp == TOP_FRAME) {
+        return 1;
+    }
+    fp = old_fp;
+    pc = ret_pc;
+    store_variable(storevar, val);
+    return 0;
+}
+
+int OP_THROW(zword val, zword frame) {
+    fp = frame;
+    return VM_RETURN(val);
+}
+
+// Set up the main routine. Returns 1 if the main routine is a safe function which has been run
+static int start_main_routine(void) {
+    glui32 addr;
+    sp = 0;
+    fp = TOP_FRAME;
+    if (version == 6) {
+        addr = unpack_routine(Mem2(0x06));
+        if (VM_FUNC_IS_SAFE(addr)) {
+            VM_CALL_SAFE_FUNCTION(addr, 0, NULL);
+            return 1;
+        }
+        enter_function(addr, 0, NULL, STORE_DISCARD, 0);
+    }
+    else {
+        pc = Mem2(0x06);
+        // The main routine has no routine header, but the disassembler treats it as a function without locals
+        if (VM_FUNC_IS_SAFE(pc - 1)) {
+            VM_CALL_SAFE_FUNCTION(pc - 1, 0, NULL);
+            return 1;
+        }
+        push_frame(0, 0, STORE_DISCARD, 0);
+    }
+    return 0;
+}
+
+/* Text */
+
+static zword zscii_to_unicode(zword c) {
+    if (c >= 155 && c <= 251) {
+        if (unicode_table) {
+            if (c - 155 < Mem1(unicode_table)) {
+                return Mem2(unicode_table + 1 + 2 * (c - 155));
+            }
+            return '?';
+        }
+        if (c <= 223) {
+            return default_unicode_table[c - 155];
+        }
+        return '?';
+    }
+    return c;
+}
+
+static zword unicode_to_zscii(glui32 c) {
+    int i;
+    if (c == '\n' || c == '\r') {
+        return 13;
+    }
+    if (c >= 32 && c <= 126) {
+        return c;
+    }
+    if (unicode_table) {
+        for (i = 0; i < Mem1(unicode_table); i++) {
+            if (Mem2(unicode_table + 1 + 2 * i) == c) {
+                return 155 + i;
+            }
+        }
+        return 0;
+    }
+    for (i = 0; i < 69; i++) {
+        if (default_unicode_table[i] == c) {
+            return 155 + i;
+        }
+    }
+    return 0;
+}
+
+static void put_char_window(glui32 c) {
+    if (upper_window_selected && upper_win) {
+        if (c == '\n') {
+            upper_y++;
+            upper_x = 1;
+        }
+        else {
+            upper_x++;
+        }
+    }
+    glk_put_char_uni(c);
+}
+
+void zprint_char(zword c) {
+    if (stream3_depth > 0) {
+        glui32 table = stream3_tables[stream3_depth - 1];
+        zword len = Mem2(table);
+        MemW1(table + 2 + len, c);
+        MemW2(table, len + 1);
+        return;
+    }
+    if (!stream1_enabled || c == 0) {
+        return;
+    }
+    put_char_window(c == 13 ? '\n' : zscii_to_unicode(c));
+}
+
+void zprint_unicode(zword c) {
+    if (stream3_depth > 0) {
+        zword zscii = unicode_to_zscii(c);
+        zprint_char(zscii ? zscii : '?');
+        return;
+    }
+    if (stream1_enabled) {
+        put_char_window(c);
+    }
+}
+
+void print_num(zword val) {
+    char buf[10];
+    int i;
+    sprintf(buf, "%d", (zsword) val);
+    for (i = 0; buf[i]; i++) {
+        zprint_char(buf[i]);
+    }
+}
+
+static zbyte alphabet_char(int alphabet, int index) {
+    if (alphabet_table) {
+        return Mem1(alphabet_table + alphabet * 26 + index);
+    }
+    if (version == 1 && alphabet == 2) {
+        return alphabet_v1[index];
+    }
+    return default_alphabets[alphabet][index];
+}
+
+// Decode a Z-string, sending each ZSCII character to the output function
+static glui32 decode_zstring(glui32 addr, void (*output)(zword)) {
+    int i, alphabet = 0, lock = 0, abbreviation = 0, escape = 0, escape_high = 0;
+    zword word, zchar;
+    do {
+        word = Mem2(addr);
+        addr += 2;
+        for (i = 10; i >= 0; i -= 5) {
+            zchar = (word >> i) & 0x1F;
+            if (abbreviation) {
+                decode_zstring(Mem2(abbreviations_table + 2 * (32 * (abbreviation - 1) + zchar)) * 2, output);
+                abbreviation = 0;
+                continue;
+            }
+            if (escape == 1) {
+                escape_high = zchar;
+                escape = 2;
+                continue;
+            }
+            if (escape == 2) {
+                output((escape_high << 5) | zchar);
+                escape = 0;
+                continue;
+            }
+            if (zchar == 0) {
+                output(32);
+            }
+            else if (zchar == 1 && version == 1) {
+                output(13);
+            }
+            else if (zchar < 4 && (version > 2 || zchar == 1)) {
+                abbreviation = zchar;
+                continue;
+            }
+            else if (zchar < 6) {
+                // Versions 1 and 2 have shift locks
+                if (version < 3) {
+                    switch (zchar) {
+                        case 2: alphabet = (lock + 1) % 3; break;
+                        case 3: alphabet = (lock + 2) % 3; break;
+                        case 4: lock = alphabet = (lock + 1) % 3; break;
+                        case 5: lock = alphabet = (lock + 2) % 3; break;
+                    }
+                }
+                else {
+                    alphabet = zchar - 3;
+                }
+                continue;
+            }
+            else if (alphabet == 2 && zchar == 6) {
+                escape = 1;
+            }
+            else if (alphabet == 2 && zchar == 7 && version > 1) {
+                output(13);
+            }
+            else {
+                output(alphabet_char(alphabet, zchar - 6));
+            }
+            alphabet = lock;
+        }
+    } while (!(word & 0x8000));
+    return addr;
+}
+
+void print_zstring(glui32 addr) {
+    decode_zstring(addr, zprint_char);
+}
+
+// Encode some ZSCII text for the dictionary
+static void encode_text(zbyte *text, int length, zword *encoded) {
+    int i, alphabet, index, count = 0;
+    int resolution = version < 4 ? 6 : 9;
+    zbyte zchars[12];
+    for (i = 0; i < length && count < resolution; i++) {
+        zbyte c = text[i];
+        for (alphabet = 0; alphabet < 3; alphabet++) {
+            for (index = (alphabet == 2 ? 2 : 0); index < 26; index++) {
+                if (alphabet_char(alphabet, index) == c) {
+                    goto found;
+                }
+            }
+        }
+        // Not in an alphabet, so use a 10-bit ZSCII escape
+        zchars[count++] = 5;
+        zchars[count++] = 6;
+        zchars[count++] = c >> 5;
+        zchars[count++] = c & 0x1F;
+        continue;
+    found:
+        if (alphabet) {
+            zchars[count++] = (version < 3 ? 1 : 3) + alphabet;
+        }
+        zchars[count++] = index + 6;
+    }
+    while (count < resolution) {
+        zchars[count++] = 5;
+    }
+    for (i = 0; i < resolution / 3; i++) {
+        encoded[i] = (zchars[i * 3] << 10) | (zchars[i * 3 + 1] << 5) | zchars[i * 3 + 2];
+    }
+    encoded[resolution / 3 - 1] |= 0x8000;
+}
+
+/* Objects */
+
+static glui32 object_addr(zword obj) {
+    if (obj == 0) {
+        fatal_error("Tried to access object 0.");
+    }
+    if (version < 4) {
+        return objects_table + 62 + (obj - 1) * 9;
+    }
+    return objects_table + 126 + (obj - 1) * 14;
+}
+
+#define OBJECT_PARENT 0
+#define OBJECT_SIBLING 1
+#define OBJECT_CHILD 2
+
+static zword object_get(zword obj, int field) {
+    if (obj == 0) {
+        return 0;
+    }
+    if (version < 4) {
+        return Mem1(object_addr(obj) + 4 + field);
+    }
+    return Mem2(object_addr(obj) + 6 + 2 * field);
+}
+
+static void object_set(zword obj, int field, zword val) {
+    if (version < 4) {
+        MemW1(object_addr(obj) + 4 + field, val);
+    }
+    else {
+        MemW2(object_addr(obj) + 6 + 2 * field, val);
+    }
+}
+
+static glui32 object_properties(zword obj) {
+    return Mem2(object_addr(obj) + (version < 4 ? 7 : 12));
+}
+
+zword OP_JIN(zword obj1, zword obj2) {
+    return object_get(obj1, OBJECT_PARENT) == obj2;
+}
+
+zword OP_TEST(zword bitmap, zword flags) {
+    return (bitmap & flags) == flags;
+}
+
+zword OP_TEST_ATTR(zword obj, zword attr) {
+    if (obj == 0) {
+        return 0;
+    }
+    return (Mem1(object_addr(obj) + attr / 8) & (0x80 >> (attr & 7))) != 0;
+}
+
+void OP_SET_ATTR(zword obj, zword attr) {
+    glui32 addr;
+    if (obj == 0) {
+        return;
+    }
+    addr = object_addr(obj) + attr / 8;
+    MemW1(addr, Mem1(addr) | (0x80 >> (attr & 7)));
+}
+
+void OP_CLEAR_ATTR(zword obj, zword attr) {
+    glui32 addr;
+    if (obj == 0) {
+        return;
+    }
+    addr = object_addr(obj) + attr / 8;
+    MemW1(addr, Mem1(addr) & ~(0x80 >> (attr & 7)));
+}
+
+void OP_REMOVE_OBJ(zword obj) {
+    zword parent, sibling, child;
+    if (obj == 0) {
+        return;
+    }
+    parent = object_get(obj, OBJECT_PARENT);
+    if (parent == 0) {
+        return;
+    }
+    sibling = object_get(obj, OBJECT_SIBLING);
+    child = object_get(parent, OBJECT_CHILD);
+    if (child == obj) {
+        object_set(parent, OBJECT_CHILD, sibling);
+    }
+    else {
+        while (child) {
+            if (object_get(child, OBJECT_SIBLING) == obj) {
+                object_set(child, OBJECT_SIBLING, sibling);
+                break;
+            }
+            child = object_get(child, OBJECT_SIBLING);
+        }
+    }
+    object_set(obj, OBJECT_PARENT, 0);
+    object_set(obj, OBJECT_SIBLING, 0);
+}
+
+void OP_INSERT_OBJ(zword obj, zword dest) {
+    if (obj == 0 || dest == 0) {
+        return;
+    }
+    OP_REMOVE_OBJ(obj);
+    object_set(obj, OBJECT_PARENT, dest);
+    object_set(obj, OBJECT_SIBLING, object_get(dest, OBJECT_CHILD));
+    object_set(dest, OBJECT_CHILD, obj);
+}
+
+zword OP_GET_SIBLING(zword obj) {
+    return object_get(obj, OBJECT_SIBLING);
+}
+
+zword OP_GET_CHILD(zword obj) {
+    return object_get(obj, OBJECT_CHILD);
+}
+
+zword OP_GET_PARENT(zword obj) {
+    return object_get(obj, OBJECT_PARENT);
+}
+
+void OP_PRINT_OBJ(zword obj) {
+    if (obj == 0) {
+        return;
+    }
+    print_zstring(object_properties(obj) + 1);
+}
+
+// Property addresses point to the size byte, and we return the property number, data address and length
+static glui32 property_info(glui32 addr, zword *number, glui32 *length) {
+    zbyte size = Mem1(addr);
+    if (version < 4) {
+        *number = size & 0x1F;
+        *length = (size >> 5) + 1;
+        return addr + 1;
+    }
+    *number = size & 0x3F;
+    if (size & 0x80) {
+        *length = Mem1(addr + 1) & 0x3F;
+        if (*length == 0) {
+            *length = 64;
+        }
+        return addr + 2;
+    }
+    *length = (size & 0x40) ? 2 : 1;
+    return addr + 1;
+}
+
+static glui32 first_property(zword obj) {
+    glui32 addr = object_properties(obj);
+    return addr + 1 + 2 * Mem1(addr);
+}
+
+static glui32 find_property(zword obj, zword prop, glui32 *length) {
+    glui32 addr, data;
+    zword number;
+    if (obj == 0) {
+        return 0;
+    }
+    addr = first_property(obj);
+    while (Mem1(addr)) {
+        data = property_info(addr, &number, length);
+        if (number == prop) {
+            return data;
+        }
+        if (number < prop) {
+            break;
+        }
+        addr = data + *length;
+    }
+    return 0;
+}
+
+zword OP_GET_PROP(zword obj, zword prop) {
+    glui32 length;
+    glui32 data = find_property(obj, prop, &length);
+    if (data == 0) {
+        return Mem2(objects_table + 2 * (prop - 1));
+    }
+    return length == 1 ? Mem1(data) : Mem2(data);
+}
+
+zword OP_GET_PROP_ADDR(zword obj, zword prop) {
+    glui32 length;
+    return find_property(obj, prop, &length);
+}
+
+zword OP_GET_NEXT_PROP(zword obj, zword prop) {
+    glui32 addr, length;
+    if (obj == 0) {
+        return 0;
+    }
+    if (prop == 0) {
+        addr = first_property(obj);
+    }
+    else {
+        addr = find_property(obj, prop, &length);
+        if (addr == 0) {
+            fatal_error_i("Tried to find the next property of a non-existent property:", prop);
+        }
+        addr += length;
+    }
+    return Mem1(addr) & (version < 4 ? 0x1F : 0x3F);
+}
+
+zword OP_GET_PROP_LEN(zword addr) {
+    zbyte size;
+    if (addr == 0) {
+        return 0;
+    }
+    size = Mem1(addr - 1);
+    if (version < 4) {
+        return (size >> 5) + 1;
+    }
+    if (size & 0x80) {
+        return (size & 0x3F) ? (size & 0x3F) : 64;
+    }
+    return (size & 0x40) ? 2 : 1;
+}
+
+void OP_PUT_PROP(zword obj, zword prop, zword val) {
+    glui32 length;
+    glui32 data = find_property(obj, prop, &length);
+    if (data == 0) {
+        fatal_error_i("Tried to set a non-existent property:", prop);
+    }
+    if (length == 1) {
+        MemW1(data, val);
+    }
+    else {
+        MemW2(data, val);
+    }
+}
+
+/* Arithmetic */
+
+zword OP_DIV(zword arg0, zword arg1) {
+    if (arg1 == 0) {
+        fatal_error("Division by zero.");
+    }
+    return (zword) ((zsword) arg0 / (zsword) arg1);
+}
+
+zword OP_MOD(zword arg0, zword arg1) {
+    if (arg1 == 0) {
+        fatal_error("Division by zero doing remainder.");
+    }
+    return (zword) ((zsword) arg0 % (zsword) arg1);
+}
+
+zword OP_LOG_SHIFT(zword number, zword places) {
+    zsword shift = (zsword) places;
+    if (shift >= 16 || shift <= -16) {
+        return 0;
+    }
+    return shift >= 0 ? (zword) (number << shift) : number >> -shift;
+}
+
+zword OP_ART_SHIFT(zword number, zword places) {
+    zsword shift = (zsword) places;
+    if (shift >= 16) {
+        return 0;
+    }
+    if (shift <= -16) {
+        return (zsword) number < 0 ? 0xFFFF : 0;
+    }
+    return shift >= 0 ? (zword) (number << shift) : (zword) ((zsword) number >> -shift);
+}
+
+zword OP_RANDOM(zword range) {
+    zsword r = (zsword) range;
+    if (r > 0) {
+        if (random_sequence_limit) {
+            random_sequence_counter = random_sequence_counter % random_sequence_limit + 1;
+            return (random_sequence_counter - 1) % r + 1;
+        }
+        // xorshift32
+        random_state ^= random_state << 13;
+        random_state ^= random_state >> 17;
+        random_state ^= random_state << 5;
+        return random_state % r + 1;
+    }
+    // Small negative numbers start a predictable sequence, other values reseed the generator
+    if (r < 0 && r > -1000) {
+        random_sequence_limit = -r;
+        random_sequence_counter = 0;
+    }
+    else {
+        random_sequence_limit = 0;
+        random_state = r ? (glui32) -r : (glui32) time(NULL);
+        if (random_state == 0) {
+            random_state = 1;
+        }
+    }
+    return 0;
+}
+
+zword OP_VERIFY(void) {
+    glui32 addr;
+    zword sum = 0;
+    for (addr = 0x40; addr < file_length && addr < ZCODE_IMAGE_LENGTH; addr++) {
+        sum += ZCODE_IMAGE[addr];
+    }
+    return sum == ((ZCODE_IMAGE[0x1C] << 8) | ZCODE_IMAGE[0x1D]);
+}
+
+/* Tables */
+
+zword OP_SCAN_TABLE(zword x, zword table, zword len, zword form) {
+    int field_length = form & 0x7F;
+    int i;
+    for (i = 0; i < len; i++) {
+        if (form & 0x80 ? Mem2(table) == x : Mem1(table) == x) {
+            return table;
+        }
+        table += field_length;
+    }
+    return 0;
+}
+
+void OP_COPY_TABLE(zword first, zword second, zword size) {
+    zsword length = (zsword) size;
+    int i;
+    if (second == 0) {
+        for (i = 0; i < (length < 0 ? -length : length); i++) {
+            MemW1(first + i, 0);
+        }
+    }
+    else if (length < 0) {
+        // Copy forwards, even if that corrupts the table
+        for (i = 0; i < -length; i++) {
+            MemW1(second + i, Mem1(first + i));
+        }
+    }
+    else {
+        memmove(memory + second, memory + first, length);
+    }
+}
+
+void OP_PRINT_TABLE(zword text, zword width, zword height, zword skip) {
+    glui32 x = upper_x;
+    int i, j;
+    for (i = 0; i < height; i++) {
+        if (i > 0) {
+            if (upper_window_selected && upper_win) {
+                upper_y++;
+                upper_x = x;
+                glk_window_move_cursor(upper_win, upper_x - 1, upper_y - 1);
+            }
+            else {
+                zprint_char(13);
+            }
+        }
+        for (j = 0; j < width; j++) {
+            zprint_char(Mem1(text++));
+        }
+        text += skip;
+    }
+}
+
+/* Windows and streams */
+
+static void update_style(void) {
+    int style = current_style | (current_font == 4 ? 8 : 0);
+    if (style & 2) {
+        glk_set_style(style_Subheader);
+    }
+    else if (style & 4) {
+        glk_set_style(style_Emphasized);
+    }
+    else if (style & 8) {
+        glk_set_style(style_Preformatted);
+    }
+    else {
+        glk_set_style(style_Normal);
+    }
+}
+
+static void put_char_status(zword c) {
+    glk_put_char_uni(zscii_to_unicode(c));
+}
+
+void show_status(void) {
+    glui32 width, height, right_len;
+    char right[40];
+    zword location;
+    if (version > 3 || !status_win) {
+        return;
+    }
+    glk_window_get_size(status_win, &width, &height);
+    glk_set_window(status_win);
+    glk_window_clear(status_win);
+    glk_window_move_cursor(status_win, 1, 0);
+    // Bypass output stream 3 while printing the location's name
+    location = Mem2(Mem2(0x0C));
+    if (location) {
+        decode_zstring(object_properties(location) + 1, put_char_status);
+    }
+    if (Mem1(0x01) & 0x02) {
+        zword hours = Mem2(Mem2(0x0C) + 2), minutes = Mem2(Mem2(0x0C) + 4);
+        sprintf(right, "Time: %d:%02d %s", (hours + 11) % 12 + 1, minutes, hours < 12 ? "am" : "pm");
+    }
+    else {
+        sprintf(right, "Score: %d  Moves: %d", (zsword) Mem2(Mem2(0x0C) + 2), (zsword) Mem2(Mem2(0x0C) + 4));
+    }
+    right_len = strlen(right);
+    if (width > right_len + 1) {
+        glk_window_move_cursor(status_win, width - right_len - 1, 0);
+        glk_put_string(right);
+    }
+    glk_set_window(upper_window_selected && upper_win ? upper_win : main_win);
+}
+
+void OP_SPLIT_WINDOW(zword lines) {
+    if (lines == 0) {
+        if (upper_win) {
+            glk_window_close(upper_win, NULL);
+            upper_win = NULL;
+        }
+        upper_window_selected = 0;
+        glk_set_window(main_win);
+        return;
+    }
+    if (upper_win) {
+        glk_window_set_arrangement(glk_window_get_parent(upper_win), winmethod_Above | winmethod_Fixed, lines, upper_win);
+    }
+    else {
+        upper_win = glk_window_open(main_win, winmethod_Above | winmethod_Fixed, lines, wintype_TextGrid, 0);
+    }
+    // Version 3 clears the upper window whenever it is split
+    if (version == 3 && upper_win) {
+        glk_window_clear(upper_win);
+    }
+}
+
+void OP_SET_WINDOW(zword window) {
+    if (window == 1 && upper_win) {
+        upper_window_selected = 1;
+        upper_x = upper_y = 1;
+        glk_window_move_cursor(upper_win, 0, 0);
+        glk_set_window(upper_win);
+    }
+    else {
+        upper_window_selected = 0;
+        glk_set_window(main_win);
+    }
+    update_style();
+}
+
+void OP_ERASE_WINDOW(zword window) {
+    switch ((zsword) window) {
+        case -1:
+            OP_SPLIT_WINDOW(0);
+            glk_window_clear(main_win);
+            break;
+        case -2:
+            if (upper_win) {
+                glk_window_clear(upper_win);
+            }
+            glk_window_clear(main_win);
+            break;
+        case 0:
+            glk_window_clear(main_win);
+            break;
+        case 1:
+            if (upper_win) {
+                glk_window_clear(upper_win);
+            }
+            break;
+    }
+}
+
+void OP_ERASE_LINE(zword val) {
+    glui32 width, x;
+    if (val != 1 || !upper_window_selected || !upper_win) {
+        return;
+    }
+    glk_window_get_size(upper_win, &width, NULL);
+    for (x = upper_x; x <= width; x++) {
+        glk_put_char(' ');
+    }
+    glk_window_move_cursor(upper_win, upper_x - 1, upper_y - 1);
+}
+
+void OP_SET_CURSOR(zword line, zword column, zword window) {
+    if (!upper_window_selected || !upper_win || (zsword) line < 1) {
+        return;
+    }
+    upper_y = line;
+    upper_x = column ? column : 1;
+    glk_window_move_cursor(upper_win, upper_x - 1, upper_y - 1);
+}
+
+void OP_GET_CURSOR(zword array) {
+    MemW2(array, upper_window_selected ? upper_y : 1);
+    MemW2(array + 2, upper_window_selected ? upper_x : 1);
+}
+
+void OP_SET_TEXT_STYLE(zword style) {
+    current_style = style ? current_style | style : 0;
+    update_style();
+}
+
+zword OP_SET_FONT(zword font, zword window) {
+    int previous = current_font;
+    if (font == 0) {
+        return current_font;
+    }
+    if (font != 1 && font != 4) {
+        return 0;
+    }
+    current_font = font;
+    update_style();
+    return previous;
+}
+
+void OP_BUFFER_MODE(zword flag) {}
+void OP_SET_COLOUR(zword foreground, zword background, zword window) {}
+void OP_SET_TRUE_COLOUR(zword foreground, zword background, zword window) {}
+void OP_INPUT_STREAM(zword number) {}
+
+void OP_SOUND_EFFECT(zword number, zword effect, zword volume, zword routine) {
+    // Only the bleeps are supported, and Glk has no way to make them
+}
+
+zword OP_CHECK_UNICODE(zword c) {
+    return 3;
+}
+
+void OP_OUTPUT_STREAM(zword number, zword table, zword width) {
+    switch ((zsword) number) {
+        case 1: stream1_enabled = 1; break;
+        case -1: stream1_enabled = 0; break;
+        case 2: MemW2(0x10, Mem2(0x10) | 1); break;
+        case -2: MemW2(0x10, Mem2(0x10) & ~1); break;
+        case 3:
+            if (stream3_depth == STREAM3_MAX_DEPTH) {
+                fatal_error("Output stream 3 nested too deeply.");
+            }
+            stream3_tables[stream3_depth++] = table;
+            MemW2(table, 0);
+            break;
+        case -3:
+            if (stream3_depth > 0) {
+                stream3_depth--;
+            }
+            break;
+    }
+}
+
+/* Input */
+
+static void lower_case(glui32 *buf, glui32 len) {
+    glui32 i;
+    for (i = 0; i < len; i++) {
+        if (buf[i] >= 'A' && buf[i] <= 'Z') {
+            buf[i] += 32;
+        }
+    }
+#ifdef GLK_MODULE_UNICODE
+    glk_buffer_to_lower_case_uni(buf, len, len);
+#endif
+}
+
+static zword lookup_word(glui32 dictionary, zbyte *text, int length) {
+    zword encoded[3];
+    int i, words = version < 4 ? 2 : 3;
+    int separators = Mem1(dictionary);
+    int entry_length = Mem1(dictionary + 1 + separators);
+    zsword entries = (zsword) Mem2(dictionary + 2 + separators);
+    glui32 base = dictionary + 4 + separators;
+    encode_text(text, length, encoded);
+
+    // A sorted dictionary can be binary searched
+    if (entries > 0) {
+        int low = 0, high = entries - 1;
+        while (low <= high) {
+            int mid = (low + high) / 2;
+            glui32 entry = base + mid * entry_length;
+            int cmp = 0;
+            for (i = 0; i < words && cmp == 0; i++) {
+                cmp = (int) encoded[i] - (int) Mem2(entry + 2 * i);
+            }
+            if (cmp == 0) {
+                return entry;
+            }
+            if (cmp < 0) {
+                high = mid - 1;
+            }
+            else {
+                low = mid + 1;
+            }
+        }
+        return 0;
+    }
+    for (entries = -entries; entries > 0; entries--, base += entry_length) {
+        for (i = 0; i < words; i++) {
+            if (encoded[i] != Mem2(base + 2 * i)) {
+                break;
+            }
+        }
+        if (i == words) {
+            return base;
+        }
+    }
+    return 0;
+}
+
+static void add_parsed_word(glui32 text, glui32 parse, glui32 dictionary, int flag, int start, int length) {
+    zword count = Mem1(parse + 1);
+    glui32 entry;
+    zword word;
+    if (count >= Mem1(parse)) {
+        return;
+    }
+    entry = parse + 2 + 4 * count;
+    word = lookup_word(dictionary, memory + text + start, length);
+    // When the flag is set unrecognised words are left alone
+    if (word || !flag) {
+        MemW2(entry, word);
+        MemW1(entry + 2, length);
+        MemW1(entry + 3, start);
+    }
+    MemW1(parse + 1, count + 1);
+}
+
+void OP_TOKENISE(zword text, zword parse, zword dictionary, zword flag) {
+    int start = version < 5 ? 1 : 2;
+    int length, i, j, word_start = -1;
+    int separators;
+    if (dictionary == 0) {
+        dictionary = dictionary_table;
+    }
+    separators = Mem1(dictionary);
+    if (version < 5) {
+        length = 0;
+        while (Mem1(text + start + length)) {
+            length++;
+        }
+    }
+    else {
+        length = Mem1(text + 1);
+    }
+    MemW1(parse + 1, 0);
+    for (i = start; i <= start + length; i++) {
+        zbyte c = i < start + length ? Mem1(text + i) : ' ';
+        int is_separator = 0;
+        for (j = 0; j < separators; j++) {
+            if (Mem1(dictionary + 1 + j) == c) {
+                is_separator = 1;
+            }
+        }
+        if (c == ' ' || is_separator) {
+            if (word_start >= 0) {
+                add_parsed_word(text, parse, dictionary, flag, word_start, i - word_start);
+                word_start = -1;
+            }
+            if (is_separator) {
+                add_parsed_word(text, parse, dictionary, flag, i, 1);
+            }
+        }
+        else if (word_start < 0) {
+            word_start = i;
+        }
+    }
+}
+
+void OP_ENCODE_TEXT(zword text, zword length, zword from, zword coded) {
+    zword encoded[3];
+    int i;
+    encode_text(memory + text + from, length, encoded);
+    for (i = 0; i < (version < 4 ? 2 : 3); i++) {
+        MemW2(coded + 2 * i, encoded[i]);
+    }
+}
+
+zword OP_READ(zword text, zword parse, zword time, zword routine) {
+    glui32 buf[256];
+    glui32 max_length, initial_length = 0, i;
+    event_t ev;
+
+    if (version < 4) {
+        show_status();
+    }
+    max_length = Mem1(text) - (version < 5 ? 1 : 0);
+    if (max_length > 255) {
+        max_length = 255;
+    }
+    // Version 5 games may preload the input
+    if (version >= 5) {
+        initial_length = Mem1(text + 1);
+        if (initial_length > max_length) {
+            initial_length = max_length;
+        }
+        for (i = 0; i < initial_length; i++) {
+            buf[i] = zscii_to_unicode(Mem1(text + 2 + i));
+        }
+    }
+
+    glk_request_line_event_uni(main_win, buf, max_length, initial_length);
+    do {
+        glk_select(&ev);
+    } while (ev.type != evtype_LineInput);
+    lower_case(buf, ev.val1);
+
+    for (i = 0; i < ev.val1; i++) {
+        zword c = unicode_to_zscii(buf[i]);
+        MemW1(text + (version < 5 ? 1 : 2) + i, c ? c : '?');
+    }
+    if (version < 5) {
+        MemW1(text + 1 + ev.val1, 0);
+    }
+    else {
+        MemW1(text + 1, ev.val1);
+    }
+    if (parse) {
+        OP_TOKENISE(text, parse, 0, 0);
+    }
+    return 13;
+}
+
+zword OP_READ_CHAR(zword one, zword time, zword routine) {
+    event_t ev;
+    glk_request_char_event_uni(upper_window_selected && upper_win ? upper_win : main_win);
+    do {
+        glk_select(&ev);
+    } while (ev.type != evtype_CharInput);
+    switch (ev.val1) {
+        case keycode_Return: return 13;
+        case keycode_Delete: return 8;
+        case keycode_Escape: return 27;
+        case keycode_Up: return 129;
+        case keycode_Down: return 130;
+        case keycode_Left: return 131;
+        case keycode_Right: return 132;
+        case keycode_Func1: case keycode_Func2: case keycode_Func3: case keycode_Func4:
+        case keycode_Func5: case keycode_Func6: case keycode_Func7: case keycode_Func8:
+        case keycode_Func9: case keycode_Func10: case keycode_Func11: case keycode_Func12:
+            return 133 + (keycode_Func1 - ev.val1);
+    }
+    if (ev.val1 == '\n' || ev.val1 == '\r') {
+        return 13;
+    }
+    {
+        zword c = unicode_to_zscii(ev.val1);
+        return c ? c : '?';
+    }
+}
+
+/* Header and restarting */
+
+static void set_header(void) {
+    glui32 width = 80, height;
+    zbyte flags1 = Mem1(0x01);
+    if (main_win) {
+        glk_window_get_size(main_win, &width, &height);
+    }
+    if (width == 0 || width > 255) {
+        width = 80;
+    }
+    if (version < 4) {
+        // The status line is available, the screen can be split, and the default font is not fixed width
+        flags1 = (flags1 & ~0x50) | 0x20;
+    }
+    else {
+        // Bold, italic and fixed width are available
+        flags1 = 0x1C;
+    }
+    MemW1(0x01, flags1);
+    // Clear the flags for pictures, mouse, colours, sound and menus
+    MemW2(0x10, Mem2(0x10) & ~0x01E8);
+    MemW1(0x1E, 6);
+    MemW1(0x1F, 'Z');
+    MemW1(0x20, 255);
+    MemW1(0x21, width);
+    if (version >= 5) {
+        MemW2(0x22, width);
+        MemW2(0x24, 255);
+        MemW1(0x26, 1);
+        MemW1(0x27, 1);
+    }
+    MemW1(0x32, 1);
+    MemW1(0x33, 1);
+}
+
+static void reset_memory(void) {
+    // Preserve the transcripting and fixed font bits
+    zword flags2 = memory ? Mem2(0x10) & 0x03 : 0;
+    if (!memory) {
+        memory = malloc(ZCODE_IMAGE_LENGTH);
+        if (!memory) {
+            fatal_error("Could not allocate memory.");
+        }
+        memcpy(memory, ZCODE_IMAGE, ZCODE_IMAGE_LENGTH);
+    }
+    else {
+        memcpy(memory, ZCODE_IMAGE, static_base);
+    }
+    MemW2(0x10, (Mem2(0x10) & ~0x03) | flags2);
+    set_header();
+    stream3_depth = 0;
+}
+
+void OP_RESTART(void) {
+    reset_memory();
+    if (start_main_routine()) {
+        glk_exit();
+    }
+}
+
+/* Saving and restoring */
+
+// The save instruction is resumed when restoring, and returns 2 in versions 4+
+static zword resume_save(void) {
+    restoring = 0;
+    return version < 4 ? 1 : 2;
+}
+
+static void write_word(strid_t str, glui32 val) {
+    glk_put_char_stream(str, (val >> 24) & 0xFF);
+    glk_put_char_stream(str, (val >> 16) & 0xFF);
+    glk_put_char_stream(str, (val >> 8) & 0xFF);
+    glk_put_char_stream(str, val & 0xFF);
+}
+
+static glui32 read_word(strid_t str) {
+    unsigned char buf[4];
+    if (glk_get_buffer_stream(str, (char *) buf, 4) != 4) {
+        return 0xFFFFFFFF;
+    }
+    return (buf[0] << 24) | (buf[1] << 16) | (buf[2] << 8) | buf[3];
+}
+
+zword OP_SAVE(glui32 addr) {
+    frefid_t fref;
+    strid_t str;
+    int i;
+    if (restoring) {
+        return resume_save();
+    }
+    fref = glk_fileref_create_by_prompt(fileusage_SavedGame | fileusage_BinaryMode, filemode_Write, 0);
+    if (!fref) {
+        return 0;
+    }
+    str = glk_stream_open_file(fref, filemode_Write, 0);
+    glk_fileref_destroy(fref);
+    if (!str) {
+        return 0;
+    }
+    // Identify the storyfile by its release, serial and checksum
+    glk_put_buffer_stream(str, "ZTOC", 4);
+    glk_put_buffer_stream(str, (char *) ZCODE_IMAGE + 0x02, 2);
+    glk_put_buffer_stream(str, (char *) ZCODE_IMAGE + 0x12, 6);
+    glk_put_buffer_stream(str, (char *) ZCODE_IMAGE + 0x1C, 2);
+    write_word(str, addr);
+    write_word(str, fp);
+    write_word(str, sp);
+    for (i = 0; i < sp; i++) {
+        write_word(str, stack[i]);
+    }
+    glk_put_buffer_stream(str, (char *) memory, static_base);
+    glk_stream_close(str, NULL);
+    return 1;
+}
+
+int OP_RESTORE(void) {
+    frefid_t fref;
+    strid_t str;
+    char header[14];
+    zbyte *new_memory;
+    zword *new_stack;
+    glui32 new_pc, new_fp, new_sp, i;
+    fref = glk_fileref_create_by_prompt(fileusage_SavedGame | fileusage_BinaryMode, filemode_Read, 0);
+    if (!fref) {
+        return 0;
+    }
+    str = glk_stream_open_file(fref, filemode_Read, 0);
+    glk_fileref_destroy(fref);
+    if (!str) {
+        return 0;
+    }
+    if (glk_get_buffer_stream(str, header, 14) != 14 || memcmp(header, "ZTOC", 4)
+        || memcmp(header + 4, ZCODE_IMAGE + 0x02, 2) || memcmp(header + 6, ZCODE_IMAGE + 0x12, 6) || memcmp(header + 12, ZCODE_IMAGE + 0x1C, 2)) {
+        glk_stream_close(str, NULL);
+        return 0;
+    }
+    new_pc = read_word(str);
+    new_fp = read_word(str);
+    new_sp = read_word(str);
+    if (new_sp > STACK_SIZE) {
+        glk_stream_close(str, NULL);
+        return 0;
+    }
+    new_stack = malloc(sizeof(zword) * (new_sp + 1));
+    new_memory = malloc(static_base);
+    if (!new_stack || !new_memory) {
+        fatal_error("Could not allocate memory.");
+    }
+    for (i = 0; i < new_sp; i++) {
+        new_stack[i] = read_word(str);
+    }
+    if (glk_get_buffer_stream(str, (char *) new_memory, static_base) != static_base) {
+        glk_stream_close(str, NULL);
+        free(new_stack);
+        free(new_memory);
+        return 0;
+    }
+    glk_stream_close(str, NULL);
+
+    // Everything checks out, so now restore the state
+    i = Mem2(0x10) & 0x03;
+    memcpy(memory, new_memory, static_base);
+    MemW2(0x10, (Mem2(0x10) & ~0x03) | i);
+    set_header();
+    memcpy(stack, new_stack, sizeof(zword) * new_sp);
+    sp = new_sp;
+    fp = new_fp;
+    pc = new_pc;
+    free(new_stack);
+    free(new_memory);
+    restoring = 1;
+    return 1;
+}
+
+struct undo_state {
+    zbyte *memory;
+    zword *stack;
+    int sp;
+    int fp;
+    glui32 pc;
+    struct undo_state *next;
+};
+static struct undo_state *undo_chain = NULL;
+
+zword OP_SAVE_UNDO(glui32 addr) {
+    struct undo_state *state, *prev;
+    int count;
+    if (restoring) {
+        return resume_save();
+    }
+    state = malloc(sizeof(struct undo_state));
+    if (!state) {
+        return 0;
+    }
+    state->memory = malloc(static_base);
+    state->stack = malloc(sizeof(zword) * (sp + 1));
+    if (!state->memory || !state->stack) {
+        free(state->memory);
+        free(state->stack);
+        free(state);
+        return 0;
+    }
+    memcpy(state->memory, memory, static_base);
+    memcpy(state->stack, stack, sizeof(zword) * sp);
+    state->sp = sp;
+    state->fp = fp;
+    state->pc = addr;
+    state->next = undo_chain;
+    undo_chain = state;
+
+    // Discard the oldest states
+    for (count = 1, prev = undo_chain; prev->next; count++, prev = prev->next) {
+        if (count == max_undo_level) {
+            while (prev->next) {
+                state = prev->next;
+                prev->next = state->next;
+                free(state->memory);
+                free(state->stack);
+                free(state);
+            }
+            break;
+        }
+    }
+    return 1;
+}
+
+int OP_RESTORE_UNDO(void) {
+    struct undo_state *state = undo_chain;
+    zword flags2;
+    if (!state) {
+        return 0;
+    }
+    undo_chain = state->next;
+    flags2 = Mem2(0x10) & 0x03;
+    memcpy(memory, state->memory, static_base);
+    MemW2(0x10, (Mem2(0x10) & ~0x03) | flags2);
+    set_header();
+    memcpy(stack, state->stack, sizeof(zword) * state->sp);
+    sp = state->sp;
+    fp = state->fp;
+    pc = state->pc;
+    free(state->memory);
+    free(state->stack);
+    free(state);
+    restoring = 1;
+    return 1;
+}
+
+/* Starting up */
+
+void glk_main(void) {
+    if (ZCODE_IMAGE_LENGTH < 64) {
+        fatal_error("The storyfile is too short.");
+    }
+    version = ZCODE_IMAGE[0];
+    static_base = (ZCODE_IMAGE[0x0E] << 8) | ZCODE_IMAGE[0x0F];
+    file_length = ((ZCODE_IMAGE[0x1A] << 8) | ZCODE_IMAGE[0x1B]) * (version < 4 ? 2 : version < 6 ? 4 : 8);
+    if (version < 1 || version > 8 || static_base > ZCODE_IMAGE_LENGTH) {
+        fatal_error("This is not a valid Z-Code storyfile.");
+    }
+
+    main_win = glk_window_open(0, 0, 0, wintype_TextBuffer, 1);
+    if (!main_win) {
+        fatal_error("Could not open the main window.");
+    }
+    glk_set_window(main_win);
+    if (version < 4) {
+        status_win = glk_window_open(main_win, winmethod_Above | winmethod_Fixed, 1, wintype_TextGrid, 0);
+    }
+
+    reset_memory();
+    routines_offset = Mem2(0x28) * 8;
+    strings_offset = Mem2(0x2A) * 8;
+    abbreviations_table = Mem2(0x18);
+    alphabet_table = version >= 5 ? Mem2(0x34) : 0;
+    dictionary_table = Mem2(0x08);
+    objects_table = Mem2(0x0A);
+    if (version >= 5 && Mem2(0x36) && Mem2(Mem2(0x36)) >= 3) {
+        unicode_table = Mem2(Mem2(0x36) + 6);
+    }
+    OP_RANDOM(0);
+
+    if (start_main_routine()) {
+        return;
+    }
+    execute_loop();
+}
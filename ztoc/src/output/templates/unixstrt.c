@@ -0,0 +1,45 @@
+/*
+
+Unix startup code for ztoc
+==========================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+#include <stdlib.h>
+#include <string.h>
+#include "glk.h"
+#include "glkstart.h" /* This comes with the Glk library. */
+#include "ztoc.h"
+
+/* With ztoc the only argument is the number of undo states.
+*/
+glkunix_argumentlist_t glkunix_arguments[] = {
+  { "--undo", glkunix_arg_ValueFollows, "Number of undo states to store." },
+  { "", glkunix_arg_ValueFollows, "filename: Ignored" },
+  { NULL, glkunix_arg_End, NULL }
+};
+
+int glkunix_startup_code(glkunix_startup_t *data)
+{
+  int ix;
+
+  /* As usual for Unix, the zeroth argument is the executable name. */
+  for (ix=1; ix<data->argc; ix++) {
+    if (!strcmp(data->argv[ix], "--undo")) {
+      ix++;
+      if (ix<data->argc) {
+        int val = atoi(data->argv[ix]);
+        if (val > 0) {
+          max_undo_level = val;
+        }
+      }
+      continue;
+    }
+  }
+
+  return TRUE;
+}
@@ -0,0 +1,161 @@
+/*
+
+C output files from ztoc
+========================
+
+Copyright (c) 2021 Dannii Willis
+MIT licenced
+https://github.com/curiousdannii/if-decompiler
+
+*/
+
+#ifndef ZTOC_H
+#define ZTOC_H
+
+#include <stddef.h>
+#include <stdint.h>
+#include "glk.h"
+
+typedef uint8_t zbyte;
+typedef uint16_t zword;
+typedef int16_t zsword;
+
+// The stack holds both call frames and evaluation stack values
+// A call frame consists of the return pc (two words), the variable to store the result in, the previous frame pointer, the argument count, the number of locals, and then the locals
+#define STACK_SIZE 0xFF00
+#define FRAME_ARGC 4
+#define FRAME_LOCALS_COUNT 5
+#define FRAME_SIZE 6
+#define TOP_FRAME 0xFFFF
+#define STORE_DISCARD 0x100
+
+// image.data
+#define ZCODE_IMAGE_LENGTH IMAGE_LENGTH_VALUE
+extern char _binary_image_data_start[];
+#define ZCODE_IMAGE ((zbyte *) _binary_image_data_start)
+
+// runtime.c
+extern zbyte *memory;
+extern zword stack[STACK_SIZE];
+extern int sp;
+extern int fp;
+extern glui32 pc;
+extern int max_undo_level;
+extern void fatal_error(char *msg);
+extern void fatal_error_i(char *msg, glui32 val);
+
+static inline zbyte Mem1(glui32 addr) {
+    return memory[addr];
+}
+static inline zword Mem2(glui32 addr) {
+    return (memory[addr] << 8) | memory[addr + 1];
+}
+static inline void MemW1(glui32 addr, zword val) {
+    memory[addr] = val & 0xFF;
+}
+static inline void MemW2(glui32 addr, zword val) {
+    memory[addr] = val >> 8;
+    memory[addr + 1] = val & 0xFF;
+}
+static inline zword PopStack(void) {
+    if (sp <= 0) {
+        fatal_error("Stack underflow.");
+    }
+    return stack[--sp];
+}
+static inline void PushStack(zword val) {
+    if (sp >= STACK_SIZE) {
+        fatal_error("Stack overflow.");
+    }
+    stack[sp++] = val;
+}
+// Indirect references to the stack access the top value in place
+static inline zword PeekStack(void) {
+    if (sp <= 0) {
+        fatal_error("Stack underflow.");
+    }
+    return stack[sp - 1];
+}
+static inline void PokeStack(zword val) {
+    if (sp <= 0) {
+        fatal_error("Stack underflow.");
+    }
+    stack[sp - 1] = val;
+}
+
+#define ReadLocal(num) (stack[fp + FRAME_SIZE + (num)])
+#define WriteLocal(num, val) (stack[fp + FRAME_SIZE + (num)] = (val))
+#define ArgCount() (stack[fp + FRAME_ARGC])
+
+// Safe functions must reset the stack pointer when they return
+#define ZRETURN(val) do { res = (val); sp = base_sp; return res; } while (0)
+
+extern glui32 unpack_string(zword packed_addr);
+extern zword ReadVariableIndirect(zword var);
+extern void WriteVariableIndirect(zword var, zword val);
+extern void print_zstring(glui32 addr);
+extern void zprint_char(zword c);
+extern void zprint_unicode(zword c);
+extern void print_num(zword val);
+extern void show_status(void);
+extern int VM_CALL_FUNCTION(zword packed_addr, int argc, zword *args, int storevar, glui32 next);
+extern int VM_RETURN(zword val);
+extern zword OP_JIN(zword obj1, zword obj2);
+extern zword OP_TEST(zword bitmap, zword flags);
+extern zword OP_TEST_ATTR(zword obj, zword attr);
+extern void OP_SET_ATTR(zword obj, zword attr);
+extern void OP_CLEAR_ATTR(zword obj, zword attr);
+extern void OP_INSERT_OBJ(zword obj, zword dest);
+extern zword OP_GET_PROP(zword obj, zword prop);
+extern zword OP_GET_PROP_ADDR(zword obj, zword prop);
+extern zword OP_GET_NEXT_PROP(zword obj, zword prop);
+extern zword OP_DIV(zword arg0, zword arg1);
+extern zword OP_MOD(zword arg0, zword arg1);
+extern void OP_SET_COLOUR(zword foreground, zword background, zword window);
+extern int OP_THROW(zword val, zword frame);
+extern zword OP_GET_SIBLING(zword obj);
+extern zword OP_GET_CHILD(zword obj);
+extern zword OP_GET_PARENT(zword obj);
+extern zword OP_GET_PROP_LEN(zword addr);
+extern void OP_REMOVE_OBJ(zword obj);
+extern void OP_PRINT_OBJ(zword obj);
+extern zword OP_SAVE(glui32 addr);
+extern int OP_RESTORE(void);
+extern void OP_RESTART(void);
+extern zword OP_VERIFY(void);
+extern void OP_PUT_PROP(zword obj, zword prop, zword val);
+extern zword OP_READ(zword text, zword parse, zword time, zword routine);
+extern zword OP_RANDOM(zword range);
+extern void OP_SPLIT_WINDOW(zword lines);
+extern void OP_SET_WINDOW(zword window);
+extern void OP_ERASE_WINDOW(zword window);
+extern void OP_ERASE_LINE(zword val);
+extern void OP_SET_CURSOR(zword line, zword column, zword window);
+extern void OP_GET_CURSOR(zword array);
+extern void OP_SET_TEXT_STYLE(zword style);
+extern void OP_BUFFER_MODE(zword flag);
+extern void OP_OUTPUT_STREAM(zword number, zword table, zword width);
+extern void OP_INPUT_STREAM(zword number);
+extern void OP_SOUND_EFFECT(zword number, zword effect, zword volume, zword routine);
+extern zword OP_READ_CHAR(zword one, zword time, zword routine);
+extern zword OP_SCAN_TABLE(zword x, zword table, zword len, zword form);
+extern void OP_TOKENISE(zword text, zword parse, zword dictionary, zword flag);
+extern void OP_ENCODE_TEXT(zword text, zword length, zword from, zword coded);
+extern void OP_COPY_TABLE(zword first, zword second, zword size);
+extern void OP_PRINT_TABLE(zword text, zword width, zword height, zword skip);
+extern zword OP_LOG_SHIFT(zword number, zword places);
+extern zword OP_ART_SHIFT(zword number, zword places);
+extern zword OP_SET_FONT(zword font, zword window);
+extern zword OP_SAVE_UNDO(glui32 addr);
+extern int OP_RESTORE_UNDO(void);
+extern zword OP_CHECK_UNICODE(zword c);
+extern void OP_SET_TRUE_COLOUR(zword foreground, zword background, zword window);
+
+// functions_safe.c
+extern int VM_FUNC_IS_SAFE(glui32 addr);
+extern zword VM_CALL_SAFE_FUNCTION(glui32 addr, int argc, zword *args);
+
+// functions_unsafe.c
+extern void execute_loop(void);
+
+#endif